@@ -0,0 +1,204 @@
+use bitcoin::bip32::DerivationPath;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use serde_json::json;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::models::Invoice;
+use crate::psbt::{PsbtError, WalletDescriptor};
+use crate::state::AppState;
+use crate::trezor::TrezorError;
+
+const DEFAULT_SAMPLE_SIZE: usize = 5;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    NoAccountKey,
+    Multisig,
+    NotDerived,
+    InvalidPath(String),
+    WrongDevice(String),
+    Trezor(TrezorError),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::NoAccountKey => write!(f, "No wallet account key is configured"),
+            VerifyError::Multisig => write!(f, "Multisig addresses cannot be verified on a single device"),
+            VerifyError::NotDerived => write!(f, "Invoice address was not derived from the wallet"),
+            VerifyError::InvalidPath(msg) => write!(f, "Invalid derivation path: {}", msg),
+            VerifyError::WrongDevice(msg) => write!(f, "Connected Trezor does not hold this wallet: {}", msg),
+            VerifyError::Trezor(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<TrezorError> for VerifyError {
+    fn from(error: TrezorError) -> Self {
+        VerifyError::Trezor(error)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressCheck {
+    pub invoice_id: String,
+    pub derivation_path: String,
+    pub address: String,
+    pub device_address: String,
+    pub matches: bool,
+}
+
+// Result of one periodic sample check
+#[derive(Debug, Clone, Serialize)]
+pub struct SampleReport {
+    pub checked_at: DateTime<Utc>,
+    pub checks: Vec<AddressCheck>,
+    pub mismatches: usize,
+    pub errors: Vec<String>,
+}
+
+// Derive the next receive address of the wallet, reserving its index
+pub fn derive_receive_address(
+    data: &AppState,
+    wallet: &WalletDescriptor,
+    network: Network,
+) -> Result<(Address, DerivationPath), PsbtError> {
+    let index = data
+        .db
+        .next_derivation_index(&wallet.id())
+        .map_err(|e| PsbtError::InvalidAccountKey(format!("reserving address index: {}", e)))?;
+
+    let secp = Secp256k1::verification_only();
+    let derived = wallet.derive(&secp, 0, index)?;
+    let address = Address::from_script(&derived.script_pubkey, network)
+        .map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))?;
+    Ok((address, derived.path))
+}
+
+// Ask the Trezor to derive the invoice's address and compare it with the one
+// the server handed out. With `show_display` the address is also shown on the
+// device so the merchant can compare it with what the customer sees.
+pub async fn verify_invoice_address(
+    data: &AppState,
+    invoice: &Invoice,
+    show_display: bool,
+) -> Result<AddressCheck, VerifyError> {
+    let wallet = data.wallet_for(&invoice.store_id).ok_or(VerifyError::NoAccountKey)?;
+    let account = wallet.single().ok_or(VerifyError::Multisig)?;
+    let path = invoice.derivation_path.as_deref().ok_or(VerifyError::NotDerived)?;
+    let path = DerivationPath::from_str(path).map_err(|e| VerifyError::InvalidPath(e.to_string()))?;
+
+    if !data.trezor_client.is_connected() {
+        data.trezor_client.connect(None).await?;
+    }
+    let fingerprint = data.trezor_client.master_fingerprint().await?;
+    if fingerprint != account.fingerprint {
+        return Err(VerifyError::WrongDevice(format!(
+            "device fingerprint {} does not match account fingerprint {}",
+            fingerprint, account.fingerprint
+        )));
+    }
+
+    let device_address = data
+        .trezor_client
+        .get_address(&path, account.script_type, show_display)
+        .await?;
+    let matches = device_address == invoice.address;
+    if !matches {
+        error!(
+            "Address mismatch for invoice {}: server {} but Trezor derived {}",
+            invoice.id, invoice.address, device_address
+        );
+    }
+
+    Ok(AddressCheck {
+        invoice_id: invoice.id.clone(),
+        derivation_path: path.to_string(),
+        address: invoice.address.clone(),
+        device_address,
+        matches,
+    })
+}
+
+// Periodically compare a random sample of addresses derived since the last
+// run with the Trezor's own derivation. Checks are silent (nothing is shown
+// on the device) and skipped while the device is busy or not connected.
+pub async fn run_sample_checks(data: actix_web::web::Data<AppState>, interval: Duration, sample_size: usize) {
+    let mut since = Utc::now();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let status = data.trezor_client.status();
+        if !status.connected || status.busy {
+            continue;
+        }
+
+        let checked_at = Utc::now();
+        let fresh: Vec<Invoice> = data
+            .invoices
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|invoice| invoice.derivation_path.is_some() && invoice.created_at >= since)
+            .cloned()
+            .collect();
+        since = checked_at;
+        if fresh.is_empty() {
+            continue;
+        }
+
+        let sample: Vec<&Invoice> = fresh.choose_multiple(&mut rand::thread_rng(), sample_size).collect();
+        let mut checks = Vec::with_capacity(sample.len());
+        let mut errors = Vec::new();
+        for invoice in sample {
+            match verify_invoice_address(&data, invoice, false).await {
+                Ok(check) => checks.push(check),
+                Err(e) => errors.push(format!("{}: {}", invoice.id, e)),
+            }
+        }
+
+        let mismatches = checks.iter().filter(|check| !check.matches).count();
+        let report = SampleReport {
+            checked_at,
+            checks,
+            mismatches,
+            errors,
+        };
+
+        if report.mismatches > 0 {
+            warn!("Address sample check found {} mismatching address(es)", report.mismatches);
+            if let Err(e) = data.db.log_audit_event("system", "address.mismatch", None, json!(report)) {
+                error!("Failed to write audit event address.mismatch: {}", e);
+            }
+        } else {
+            info!("Address sample check passed ({} checked)", report.checks.len());
+        }
+        *data.address_sample_report.lock().unwrap() = Some(report);
+    }
+}
+
+// Sample checks are enabled by ADDRESS_SAMPLE_CHECK_INTERVAL_SECS, with
+// ADDRESS_SAMPLE_CHECK_SIZE addresses (default 5) checked per run
+pub fn sample_check_config() -> Option<(Duration, usize)> {
+    let interval = std::env::var("ADDRESS_SAMPLE_CHECK_INTERVAL_SECS")
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|secs| *secs > 0)?;
+    let sample_size = std::env::var("ADDRESS_SAMPLE_CHECK_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_SAMPLE_SIZE);
+    Some((Duration::from_secs(interval), sample_size))
+}
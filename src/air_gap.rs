@@ -0,0 +1,449 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::psbt::{self, Psbt, PsbtError};
+use crate::totp::{base32_decode, base32_encode};
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+const UR_TYPE: &str = "crypto-psbt";
+// Bytes of PSBT data per animated QR frame, small enough for most cameras
+pub const DEFAULT_FRAGMENT_LEN: usize = 200;
+const MIN_FRAGMENT_LEN: usize = 10;
+// BBQr part numbers are two base36 digits
+const BBQR_MAX_PARTS: usize = 36 * 36 - 1;
+const BBQR_HEADER_LEN: usize = 8;
+
+// The 256 Bytewords (BCR-2020-012), four letters each. UR strings use the
+// minimal form: the first and last letter of each word.
+const BYTEWORDS: &str = "\
+    ableacidalsoapexaquaarchatomauntawayaxisbackbaldbarnbeltbetabias\
+    bluebodybragbrewbulbbuzzcalmcashcatschefcityclawcodecolacookcost\
+    cruxcurlcuspcyandarkdatadaysdelidicedietdoordowndrawdropdrumdull\
+    dutyeacheasyechoedgeepicevenexamexiteyesfactfairfernfigsfilmfish\
+    fizzflapflewfluxfoxyfreefrogfuelfundgalagamegeargemsgiftgirlglow\
+    goodgraygrimgurugushgyrohalfhanghardhawkheathelphighhillholyhope\
+    hornhutsicedideaidleinchinkyintoirisironitemjadejazzjoinjoltjowl\
+    judojugsjumpjunkjurykeepkenokeptkeyskickkilnkingkitekiwiknoblamb\
+    lavalazyleaflegsliarlimplionlistlogoloudloveluaulucklungmainmany\
+    mathmazememomenumeowmildmintmissmonknailnavyneednewsnextnoonnote\
+    numbobeyoboeomitonyxopenovalowlspaidpartpeckplaypluspoempoolpose\
+    puffpumapurrquadquizraceramprealredorichroadrockroofrubyruinruns\
+    rustsafesagascarsetssilkskewslotsoapsolosongstubsurfswantacotask\
+    taxitenttiedtimetinytoiltombtoystriptunatwinuglyundouniturgeuser\
+    vastveryvetovialvibeviewvisavoidvowswallwandwarmwaspwavewaxywebs\
+    whatwhenwhizwolfworkyankyawnyellyogayurtzapszerozestzinczonezoom";
+
+// How an exported PSBT is handed to an air-gapped signer
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Base64,
+    Binary, // A .psbt file
+    Ur,     // Animated QR frames (BC-UR crypto-psbt)
+    Bbqr,   // Animated QR frames (Coinkite BBQr)
+}
+
+// Serialize a PSBT as BC-UR frames. A PSBT that fits in one fragment is a
+// single `ur:crypto-psbt/...` frame; larger ones are split into the pure
+// (non fountain-coded) parts `ur:crypto-psbt/<n>-<total>/...`, which any UR
+// decoder can reassemble.
+pub fn ur_frames(psbt: &Psbt, max_fragment_len: usize) -> Vec<String> {
+    let mut message = Vec::new();
+    cbor_bytes(&mut message, &psbt.serialize());
+
+    if message.len() <= max_fragment_len {
+        return vec![format!("ur:{}/{}", UR_TYPE, bytewords_encode(&message))];
+    }
+
+    let fragment_count = message.len().div_ceil(max_fragment_len.max(MIN_FRAGMENT_LEN));
+    let fragment_len = message.len().div_ceil(fragment_count);
+    let checksum = crc32(&message);
+
+    (0..fragment_count)
+        .map(|index| {
+            let start = index * fragment_len;
+            let end = (start + fragment_len).min(message.len());
+            let mut fragment = message[start..end].to_vec();
+            fragment.resize(fragment_len, 0);
+
+            let mut part = vec![0x85]; // CBOR array of 5 items
+            cbor_uint(&mut part, 0, index as u64 + 1);
+            cbor_uint(&mut part, 0, fragment_count as u64);
+            cbor_uint(&mut part, 0, message.len() as u64);
+            cbor_uint(&mut part, 0, checksum as u64);
+            cbor_bytes(&mut part, &fragment);
+
+            format!("ur:{}/{}-{}/{}", UR_TYPE, index + 1, fragment_count, bytewords_encode(&part))
+        })
+        .collect()
+}
+
+// Serialize a PSBT as BBQr frames: `B$2P` (base32, PSBT file type), the
+// total and index as two base36 digits each, then the frame's data
+pub fn bbqr_frames(psbt: &Psbt, max_fragment_len: usize) -> Result<Vec<String>, PsbtError> {
+    let bytes = psbt.serialize();
+    // Base32 packs 5 bytes into 8 characters; every part but the last must
+    // hold whole groups so the parts can be decoded independently
+    let chunk_len = (max_fragment_len.max(MIN_FRAGMENT_LEN) / 5) * 5;
+    let chunks: Vec<&[u8]> = bytes.chunks(chunk_len).collect();
+    if chunks.len() > BBQR_MAX_PARTS {
+        return Err(PsbtError::InvalidEncoding(format!(
+            "PSBT needs {} BBQr parts, more than the {} allowed; use a larger fragment length",
+            chunks.len(),
+            BBQR_MAX_PARTS
+        )));
+    }
+
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            format!("B$2P{}{}{}", base36(chunks.len()), base36(index), base32_encode(chunk))
+        })
+        .collect())
+}
+
+// Parse a signed PSBT uploaded by an air-gapped signer: a binary .psbt file,
+// base64/hex text, or the UR or BBQr frames scanned from the signer's
+// screen (one per line, in any order)
+pub fn decode_upload(body: &[u8]) -> Result<Psbt, PsbtError> {
+    if body.starts_with(PSBT_MAGIC) {
+        return Psbt::deserialize(body).map_err(|e| PsbtError::InvalidEncoding(e.to_string()));
+    }
+
+    let text = std::str::from_utf8(body)
+        .map_err(|_| PsbtError::InvalidEncoding("expected a binary PSBT or PSBT text".to_string()))?;
+    let frames: Vec<&str> = text.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    let first = frames
+        .first()
+        .ok_or_else(|| PsbtError::InvalidEncoding("empty upload".to_string()))?;
+
+    if first.to_ascii_lowercase().starts_with("ur:") {
+        decode_ur_frames(&frames)
+    } else if first.starts_with("B$") {
+        decode_bbqr_frames(&frames)
+    } else if let Ok(psbt) = psbt::decode_psbt(text) {
+        Ok(psbt)
+    } else {
+        let bytes = hex::decode(text.trim())
+            .map_err(|_| PsbtError::InvalidEncoding("expected a binary, base64 or hex PSBT".to_string()))?;
+        Psbt::deserialize(&bytes).map_err(|e| PsbtError::InvalidEncoding(e.to_string()))
+    }
+}
+
+fn decode_ur_frames(frames: &[&str]) -> Result<Psbt, PsbtError> {
+    let invalid = |msg: &str| PsbtError::InvalidEncoding(format!("invalid UR: {}", msg));
+
+    let mut fragments: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    let mut expected: Option<(u64, u64, u64)> = None; // (count, message length, checksum)
+    for frame in frames {
+        // QR alphanumeric mode upper-cases the whole string
+        let frame = frame.to_ascii_lowercase();
+        let components: Vec<&str> = frame
+            .strip_prefix(&format!("ur:{}/", UR_TYPE))
+            .ok_or_else(|| invalid("expected a ur:crypto-psbt frame"))?
+            .split('/')
+            .collect();
+
+        match components.as_slice() {
+            [payload] => {
+                let message = bytewords_decode(payload).ok_or_else(|| invalid("bad bytewords or checksum"))?;
+                return psbt_from_cbor(&message);
+            }
+            [_, payload] => {
+                let part = bytewords_decode(payload).ok_or_else(|| invalid("bad bytewords or checksum"))?;
+                let mut reader = CborReader { data: &part, pos: 0 };
+                if reader.header() != Some((4, 5)) {
+                    return Err(invalid("malformed multi-part frame"));
+                }
+                let header = (|| Some((reader.uint()?, reader.uint()?, reader.uint()?, reader.uint()?, reader.bytes()?)))();
+                let (seq_num, seq_len, message_len, checksum, fragment) =
+                    header.ok_or_else(|| invalid("malformed multi-part frame"))?;
+
+                if *expected.get_or_insert((seq_len, message_len, checksum)) != (seq_len, message_len, checksum) {
+                    return Err(invalid("frames belong to different PSBTs"));
+                }
+                // Fountain-coded parts past the first round mix several
+                // fragments; the pure parts alone are enough
+                if (1..=seq_len).contains(&seq_num) {
+                    fragments.insert(seq_num, fragment.to_vec());
+                }
+            }
+            _ => return Err(invalid("unexpected frame layout")),
+        }
+    }
+
+    let (seq_len, message_len, checksum) = expected.ok_or_else(|| invalid("no frames"))?;
+    if fragments.len() as u64 != seq_len {
+        return Err(invalid(&format!("missing frames: have {} of {}", fragments.len(), seq_len)));
+    }
+    let mut message: Vec<u8> = fragments.into_values().flatten().collect();
+    message.truncate(message_len as usize);
+    if crc32(&message) as u64 != checksum {
+        return Err(invalid("reassembled message checksum mismatch"));
+    }
+    psbt_from_cbor(&message)
+}
+
+fn decode_bbqr_frames(frames: &[&str]) -> Result<Psbt, PsbtError> {
+    let invalid = |msg: &str| PsbtError::InvalidEncoding(format!("invalid BBQr: {}", msg));
+
+    let mut parts: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+    let mut expected: Option<(char, usize)> = None; // (encoding, total)
+    for frame in frames {
+        if frame.len() < BBQR_HEADER_LEN || !frame.is_char_boundary(BBQR_HEADER_LEN) || !frame.starts_with("B$") {
+            return Err(invalid("expected a B$ frame"));
+        }
+        let (header, payload) = frame.split_at(BBQR_HEADER_LEN);
+        let mut header_chars = header[2..].chars();
+        let encoding = header_chars.next().unwrap_or_default();
+        if header_chars.next() != Some('P') {
+            return Err(invalid("frame does not carry a PSBT"));
+        }
+        let total = usize::from_str_radix(&header[4..6], 36).map_err(|_| invalid("bad part count"))?;
+        let index = usize::from_str_radix(&header[6..8], 36).map_err(|_| invalid("bad part index"))?;
+        if *expected.get_or_insert((encoding, total)) != (encoding, total) {
+            return Err(invalid("frames belong to different files"));
+        }
+
+        let data = match encoding {
+            '2' => base32_decode(payload),
+            'H' => hex::decode(payload).ok(),
+            'Z' => return Err(invalid("zlib-compressed frames are not supported; export with base32 or hex")),
+            _ => return Err(invalid(&format!("unknown encoding {:?}", encoding))),
+        };
+        parts.insert(index, data.ok_or_else(|| invalid("bad frame data"))?);
+    }
+
+    let (_, total) = expected.ok_or_else(|| invalid("no frames"))?;
+    if parts.len() != total || parts.keys().any(|index| *index >= total) {
+        return Err(invalid(&format!("missing frames: have {} of {}", parts.len(), total)));
+    }
+    let bytes: Vec<u8> = parts.into_values().flatten().collect();
+    Psbt::deserialize(&bytes).map_err(|e| PsbtError::InvalidEncoding(e.to_string()))
+}
+
+fn psbt_from_cbor(message: &[u8]) -> Result<Psbt, PsbtError> {
+    let mut reader = CborReader { data: message, pos: 0 };
+    let bytes = reader
+        .bytes()
+        .ok_or_else(|| PsbtError::InvalidEncoding("invalid UR: expected a CBOR byte string".to_string()))?;
+    Psbt::deserialize(bytes).map_err(|e| PsbtError::InvalidEncoding(e.to_string()))
+}
+
+fn base36(value: usize) -> String {
+    const DIGITS: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    format!("{}{}", DIGITS[value / 36] as char, DIGITS[value % 36] as char)
+}
+
+// Minimal bytewords of the data followed by its CRC32
+fn bytewords_encode(data: &[u8]) -> String {
+    let words = BYTEWORDS.as_bytes();
+    data.iter()
+        .chain(crc32(data).to_be_bytes().iter())
+        .flat_map(|&byte| {
+            let word = &words[byte as usize * 4..byte as usize * 4 + 4];
+            [word[0] as char, word[3] as char]
+        })
+        .collect()
+}
+
+// Inverse of `bytewords_encode`, checking and stripping the CRC32
+fn bytewords_decode(encoded: &str) -> Option<Vec<u8>> {
+    let words = BYTEWORDS.as_bytes();
+    let letters = encoded.as_bytes();
+    if !letters.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let mut data = letters
+        .chunks(2)
+        .map(|pair| {
+            (0..256).find(|&byte| words[byte * 4] == pair[0] && words[byte * 4 + 3] == pair[1]).map(|byte| byte as u8)
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    if data.len() < 4 {
+        return None;
+    }
+    let checksum = data.split_off(data.len() - 4);
+    (crc32(&data).to_be_bytes()[..] == checksum[..]).then_some(data)
+}
+
+// CRC-32 (IEEE 802.3), as used by UR
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn cbor_uint(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_uint(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// Just enough CBOR to read UR parts: unsigned integers and byte strings
+struct CborReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CborReader<'a> {
+    // Returns the major type and its argument
+    fn header(&mut self) -> Option<(u8, u64)> {
+        let initial = *self.data.get(self.pos)?;
+        self.pos += 1;
+        let len = match initial & 0x1f {
+            value @ 0..=23 => return Some((initial >> 5, value as u64)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return None,
+        };
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some((initial >> 5, bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64)))
+    }
+
+    fn uint(&mut self) -> Option<u64> {
+        match self.header()? {
+            (0, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = match self.header()? {
+            (2, len) => usize::try_from(len).ok()?,
+            _ => return None,
+        };
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+
+    fn test_psbt() -> Psbt {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([7; 32]), 1),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: (0..3)
+                .map(|index| TxOut {
+                    value: 10_000 * (index + 1),
+                    script_pubkey: ScriptBuf::new_v0_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([index as u8; 20])),
+                })
+                .collect(),
+        };
+        Psbt::from_unsigned_tx(tx).unwrap()
+    }
+
+    #[test]
+    fn crc32_matches_check_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        // From the UR specification (BCR-2020-005)
+        assert_eq!(crc32(b"Hello, world!"), 0xebe6_c6e6);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn bytewords_match_bcr_2020_012() {
+        let data = [0x00, 0x01, 0x02, 0x80, 0xff];
+        assert_eq!(bytewords_encode(&data), "aeadaolazmjendeoti");
+        assert_eq!(bytewords_decode("aeadaolazmjendeoti"), Some(data.to_vec()));
+    }
+
+    #[test]
+    fn bytewords_reject_bad_checksum_and_words() {
+        assert_eq!(bytewords_decode("aeadaolazmjendeota"), None);
+        assert_eq!(bytewords_decode("aeadaolazmjendeot"), None);
+        assert_eq!(bytewords_decode("qqadaolazmjendeoti"), None);
+    }
+
+    #[test]
+    fn ur_single_frame_round_trip() {
+        let psbt = test_psbt();
+        let frames = ur_frames(&psbt, DEFAULT_FRAGMENT_LEN * 10);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].starts_with("ur:crypto-psbt/"));
+        assert_eq!(decode_upload(frames[0].as_bytes()).unwrap(), psbt);
+    }
+
+    #[test]
+    fn ur_multi_part_round_trip_in_any_order() {
+        let psbt = test_psbt();
+        let mut frames = ur_frames(&psbt, 30);
+        assert!(frames.len() > 2);
+        assert!(frames[0].starts_with(&format!("ur:crypto-psbt/1-{}/", frames.len())));
+
+        // Scanned out of order, and upper-cased by QR alphanumeric mode
+        frames.reverse();
+        let upload = frames.join("\n").to_ascii_uppercase();
+        assert_eq!(decode_upload(upload.as_bytes()).unwrap(), psbt);
+
+        frames.pop();
+        assert!(decode_upload(frames.join("\n").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn bbqr_round_trip_in_any_order() {
+        let psbt = test_psbt();
+        let mut frames = bbqr_frames(&psbt, 30).unwrap();
+        let total = base36(frames.len());
+        assert!(frames.len() > 2);
+        for (index, frame) in frames.iter().enumerate() {
+            assert!(frame.starts_with(&format!("B$2P{}{}", total, base36(index))));
+        }
+
+        frames.swap(0, 1);
+        assert_eq!(decode_upload(frames.join("\n").as_bytes()).unwrap(), psbt);
+
+        frames.pop();
+        assert!(decode_upload(frames.join("\n").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn bbqr_hex_frames_decode() {
+        let psbt = test_psbt();
+        let frame = format!("B$HP0100{}", hex::encode(psbt.serialize()).to_uppercase());
+        assert_eq!(decode_upload(frame.as_bytes()).unwrap(), psbt);
+    }
+}
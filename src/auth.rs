@@ -1,12 +1,12 @@
 
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
-use actix_web_httpauth::extractors::{
-    bearer::{BearerAuth, Config},
-    AuthenticationError,
-};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -40,30 +40,31 @@ pub fn generate_token(user_id: &str, secret: &[u8]) -> Result<String, jsonwebtok
     )
 }
 
+// Middleware requiring a valid `Authorization: Bearer <token>` header
 pub async fn validator(
     req: ServiceRequest,
-    credentials: BearerAuth,
-) -> Result<ServiceRequest, Error> {
-    let config = req
-        .app_data::<Config>()
-        .cloned()
-        .unwrap_or_else(Default::default);
-    
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
     // Get the JWT secret from app data
     let secret = req.app_data::<actix_web::web::Data<String>>()
         .expect("JWT secret not found in app data")
         .get_ref()
         .as_bytes();
-    
-    match validate_token(credentials.token(), secret) {
-        Ok(claims) => {
+
+    let claims = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| validate_token(token.trim(), secret).ok());
+
+    match claims {
+        Some(claims) => {
             // Store user info in request extensions for handlers to access
             req.extensions_mut().insert(claims);
-            Ok(req)
-        }
-        Err(_) => {
-            Err(AuthenticationError::from(config).into())
+            next.call(req).await
         }
+        None => Err(actix_web::error::ErrorUnauthorized("Invalid or missing bearer token")),
     }
 }
 
@@ -39,7 +39,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     
     // Send the request to create an invoice
-    let response = client.post("http://localhost:8080/invoice")
+    let response = client.post("http://localhost:8080/api/public/invoice")
         .json(&payment_request)
         .send()
         .await?;
@@ -60,7 +60,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Expires at: {}", invoice.expires_at);
     
     // Check payment status
-    let status_url = format!("http://localhost:8080/invoice/{}/check", invoice.id);
+    let status_url = format!("http://localhost:8080/api/public/invoice/{}/check", invoice.id);
     println!("\nChecking payment status...");
     
     let status_response = client.get(&status_url)
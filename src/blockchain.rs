@@ -1,6 +1,72 @@
-use reqwest::Client;
+use reqwest::{Client, Response, StatusCode};
 use log::info;
-use bitcoin::Address;
+use bitcoin::{Address, Transaction, Txid};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// An unspent output of an address, as reported by the Esplora API
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressUtxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+    pub status: TxStatus,
+}
+
+// Fee and size of a transaction, as reported by the Esplora API
+#[derive(Debug, Clone, Deserialize)]
+pub struct TxInfo {
+    pub fee: u64,
+    pub weight: u64,
+    pub status: TxStatus,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TxStatus {
+    pub confirmed: bool,
+    pub block_height: Option<u32>,
+    pub block_hash: Option<String>,
+}
+
+// A transaction involving an address, as reported by the Esplora API
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressTx {
+    pub txid: String,
+    pub vin: Vec<AddressTxInput>,
+    pub vout: Vec<AddressTxOutput>,
+    pub fee: u64,
+    pub weight: u64,
+    pub status: TxStatus,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressTxInput {
+    pub txid: String,
+    pub vout: u32,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressTxOutput {
+    pub scriptpubkey_address: Option<String>,
+    pub value: u64,
+}
+
+// Why `POST /tx` failed: the node refused the transaction, or the API could
+// not be reached at all
+#[derive(Debug)]
+pub enum SubmitError {
+    Rejected(String),
+    Unavailable(String),
+}
+
+// Whether an output has been spent, and by which transaction
+#[derive(Debug, Clone, Deserialize)]
+pub struct Outspend {
+    pub spent: bool,
+    pub txid: Option<String>,
+}
 
 pub struct BlockchainClient {
     http_client: Client,
@@ -15,47 +81,151 @@ impl BlockchainClient {
         }
     }
 
-    // Method to check for address transactions
-    pub async fn check_address_transactions(&self, address: &Address) -> Result<bool, String> {
-        // In a real implementation, you would:
-        // 1. Make an API call to a Bitcoin blockchain explorer
-        // 2. Parse the response to find relevant transactions
-        // 3. Verify transaction details (amount, confirmations)
+    // Submit a signed transaction through Esplora's `POST /tx`, returning the
+    // txid. Rejections carry the node's reason.
+    pub async fn broadcast_transaction(&self, tx_hex: &str) -> Result<String, SubmitError> {
+        let url = format!("{}/tx", self.api_url);
+        info!("Broadcasting transaction to {}", url);
+
+        let response = self.http_client
+            .post(&url)
+            .body(tx_hex.to_string())
+            .send()
+            .await
+            .map_err(|e| SubmitError::Unavailable(format!("Request to {} failed: {}", url, e)))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| SubmitError::Unavailable(format!("Failed to read response: {}", e)))?;
+        if status.is_client_error() {
+            return Err(SubmitError::Rejected(body.trim().to_string()));
+        }
+        if !status.is_success() {
+            return Err(SubmitError::Unavailable(format!("{} answered {}: {}", url, status, body.trim())));
+        }
+        Ok(body.trim().to_string())
+    }
+
+    // Fetch a transaction by id (used to fill in PSBT input data)
+    pub async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, String> {
+        info!("Fetching transaction {}", txid);
+        let tx_hex = self.get_text(&format!("/tx/{}/hex", txid), &format!("transaction {}", txid)).await?;
+        let tx_bytes = hex::decode(tx_hex.trim())
+            .map_err(|e| format!("Invalid transaction hex: {}", e))?;
 
-        let url = format!("{}/address/{}/txs", self.api_url, address);
-        info!("Checking transactions for address: {} at URL: {}", address, url);
+        bitcoin::consensus::deserialize(&tx_bytes)
+            .map_err(|e| format!("Invalid transaction: {}", e))
+    }
+
+    // Current chain tip height
+    pub async fn get_tip_height(&self) -> Result<u32, String> {
+        let height = self.get_text("/blocks/tip/height", "tip height").await?;
+        height.trim().parse().map_err(|e| format!("Invalid tip height: {}", e))
+    }
+
+    // Number of confirmed and unconfirmed transactions involving an address
+    pub async fn get_address_tx_count(&self, address: &Address) -> Result<u64, String> {
+        let stats: serde_json::Value =
+            self.get_json(&format!("/address/{}", address), &format!("address {}", address)).await?;
+        let count = |field: &str| stats[field]["tx_count"].as_u64().unwrap_or(0);
+        Ok(count("chain_stats") + count("mempool_stats"))
+    }
+
+    // Unspent outputs of an address, including unconfirmed ones and excluding
+    // those spent by mempool transactions
+    pub async fn get_address_utxos(&self, address: &Address) -> Result<Vec<AddressUtxo>, String> {
+        self.get_json(&format!("/address/{}/utxo", address), &format!("UTXOs of {}", address)).await
+    }
+
+    // Fee rate estimates in sat/vB, keyed by confirmation target in blocks
+    pub async fn get_fee_estimates(&self) -> Result<HashMap<u32, f64>, String> {
+        let estimates: HashMap<String, f64> = self.get_json("/fee-estimates", "fee estimates").await?;
+        Ok(estimates
+            .into_iter()
+            .filter_map(|(target, fee_rate)| Some((target.parse().ok()?, fee_rate)))
+            .collect())
+    }
 
-        // In a real implementation, we would use self.http_client.get(url).send().await
-        // For demonstration, we'll just return a placeholder
+    pub async fn get_tx_info(&self, txid: &str) -> Result<TxInfo, String> {
+        self.get_json(&format!("/tx/{}", txid), &format!("transaction {}", txid)).await
+    }
 
-        // This uses both fields, eliminating the dead code warning
-        if !self.api_url.is_empty() {
-            // Make a reference to the http_client to eliminate dead code warning
-            let _client = &self.http_client;
-            // Simplified simulation of blockchain API call
-            Ok(false) // No transactions found (default)
-        } else {
-            Ok(false)
+    // Confirmation status of a transaction; None when the backend doesn't
+    // know it, e.g. after it was dropped from the mempool
+    pub async fn get_tx_status(&self, txid: &str) -> Result<Option<TxStatus>, String> {
+        match self.get(&format!("/tx/{}/status", txid), &format!("status of {}", txid)).await? {
+            Some(response) => response.json().await.map(Some).map_err(|e| format!("Failed to read response: {}", e)),
+            None => Ok(None),
         }
     }
 
-    // Method to broadcast a signed transaction
-    pub async fn broadcast_transaction(&self, tx_hex: &str) -> Result<String, String> {
-        // In a real implementation, you would:
-        // 1. Send the signed transaction to the blockchain API
-        // 2. Return the transaction ID
+    // Hash of the block at `height` in the backend's best chain
+    pub async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        let hash = self.get_text(&format!("/block-height/{}", height), &format!("block at height {}", height)).await?;
+        Ok(hash.trim().to_string())
+    }
 
-        let url = format!("{}/tx", self.api_url);
-        info!("Broadcasting transaction: {} to URL: {}", tx_hex, url);
+    // Mempool and recent confirmed transactions involving an address
+    pub async fn get_address_txs(&self, address: &Address) -> Result<Vec<AddressTx>, String> {
+        self.get_json(&format!("/address/{}/txs", address), &format!("transactions of {}", address)).await
+    }
+
+    // Spending status of output `vout` of a transaction, mempool included
+    pub async fn get_outspend(&self, txid: &str, vout: u32) -> Result<Outspend, String> {
+        self.get_json(&format!("/tx/{}/outspend/{}", txid, vout), &format!("spend of {}:{}", txid, vout)).await
+    }
 
-        // In a real implementation, we would use:
-        // self.http_client.post(url).body(tx_hex).send().await
+    async fn get_json<T: DeserializeOwned>(&self, path: &str, what: &str) -> Result<T, String> {
+        self.get(path, what)
+            .await?
+            .ok_or_else(|| format!("Fetching {} failed: not found", what))?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to read response: {}", e))
+    }
+
+    async fn get_text(&self, path: &str, what: &str) -> Result<String, String> {
+        self.get(path, what)
+            .await?
+            .ok_or_else(|| format!("Fetching {} failed: not found", what))?
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response: {}", e))
+    }
 
-        // Simplified simulation of broadcasting - using both fields
-        if !self.api_url.is_empty() {
-            Ok("simulated_transaction_id".to_string())
-        } else {
-            Ok("simulated_transaction_id".to_string())
+    // GET `path` from the API; None on 404. `what` names the resource in errors.
+    async fn get(&self, path: &str, what: &str) -> Result<Option<Response>, String> {
+        let url = format!("{}{}", self.api_url, path);
+        let response = self.http_client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(format!("Fetching {} failed with status {}", what, response.status()));
         }
+        Ok(Some(response))
     }
-}
\ No newline at end of file
+}
+
+// Stand-in for the Esplora API serving `routes` on a local port
+#[cfg(test)]
+pub fn fake_esplora<F>(routes: F) -> BlockchainClient
+where
+    F: Fn(&mut actix_web::web::ServiceConfig) + Send + Clone + 'static,
+{
+    let server = actix_web::HttpServer::new(move || actix_web::App::new().configure(routes.clone()))
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("Failed to bind the fake Esplora API");
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    BlockchainClient::new(format!("http://{}", address))
+}
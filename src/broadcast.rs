@@ -0,0 +1,520 @@
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::message_network::RejectReason as PeerRejectCode;
+use bitcoin::{Network, Transaction, TxOut};
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::Error as SqliteError;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::blockchain::{BlockchainClient, SubmitError};
+use crate::core_rpc::{CoreRpc, CoreRpcError};
+use crate::fee_bump;
+use crate::models::{OutgoingStatus, OutgoingTransaction};
+use crate::p2p;
+use crate::psbt::{self, Psbt, PsbtError};
+use crate::spending_policy::{self, PolicyViolation};
+use crate::state::AppState;
+use crate::trezor::TrezorError;
+use crate::tx_builder;
+use crate::tx_validation::ValidationReport;
+
+// Why the network refused a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    InsufficientFee,
+    MissingInputs,
+    AlreadyInChain,
+    // Spends inputs already spent by a mempool transaction that isn't replaceable
+    Conflict,
+    Other,
+}
+
+#[derive(Debug)]
+pub enum BroadcastError {
+    Finalize(PsbtError),
+    Invalid(ValidationReport),
+    Validation(TrezorError),
+    Rejected { reason: RejectReason, message: String },
+    // Other spends used up the limits since the policy was checked
+    LimitExceeded(Vec<PolicyViolation>),
+    // No backend could be reached
+    Backend(String),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastError::Finalize(e) => write!(f, "{}", e),
+            BroadcastError::Invalid(report) => write!(f, "Transaction validation failed: {}", report),
+            BroadcastError::Validation(e) => write!(f, "{}", e),
+            BroadcastError::Rejected { message, .. } => write!(f, "Transaction rejected: {}", message),
+            BroadcastError::LimitExceeded(violations) => {
+                let violations: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+                write!(f, "Spending limit exceeded: {}", violations.join("; "))
+            }
+            BroadcastError::Backend(msg) => write!(f, "Error broadcasting: {}", msg),
+            BroadcastError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+impl From<SqliteError> for BroadcastError {
+    fn from(error: SqliteError) -> Self {
+        BroadcastError::Database(error)
+    }
+}
+
+const CONFIRMATION_CHECK_INTERVAL_SECS: u64 = 60;
+const DEFAULT_REBROADCAST_INTERVAL_SECS: u64 = 600;
+
+// What one backend made of a submitted transaction
+enum Outcome {
+    Accepted(String),
+    Rejected(RejectReason, String),
+    // Handed to a peer that didn't object; peers don't confirm acceptance
+    Relayed,
+    Unavailable(String),
+}
+
+// Submits transactions to every configured backend: the Esplora API, a
+// Bitcoin Core node (BITCOIN_RPC_URL) and P2P peers (BROADCAST_P2P_PEERS,
+// comma separated host:port)
+pub struct Broadcaster {
+    core_rpc: Option<CoreRpc>,
+    p2p_peers: Vec<String>,
+    network: Network,
+}
+
+impl Broadcaster {
+    pub fn from_env(network: Network) -> Self {
+        let p2p_peers = std::env::var("BROADCAST_P2P_PEERS")
+            .map(|peers| {
+                peers
+                    .split(',')
+                    .map(|peer| peer.trim().to_string())
+                    .filter(|peer| !peer.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            core_rpc: CoreRpc::from_env(),
+            p2p_peers,
+            network,
+        }
+    }
+
+    // Submit to all backends at once. Succeeds if any backend accepts the
+    // transaction; otherwise the most specific rejection is returned.
+    pub async fn submit(&self, blockchain: &BlockchainClient, tx: &Transaction) -> Result<String, BroadcastError> {
+        let txid = tx.txid().to_string();
+        let tx_hex = hex::encode(serialize(tx));
+
+        let esplora = async {
+            match blockchain.broadcast_transaction(&tx_hex).await {
+                Ok(txid) => Outcome::Accepted(txid),
+                Err(SubmitError::Rejected(message)) => rejection(&message, None, &txid),
+                Err(SubmitError::Unavailable(message)) => Outcome::Unavailable(message),
+            }
+        };
+        let core = async {
+            let rpc = match &self.core_rpc {
+                Some(rpc) => rpc,
+                None => return None,
+            };
+            Some(match rpc.call("sendrawtransaction", serde_json::json!([tx_hex])).await {
+                Ok(result) => Outcome::Accepted(result.as_str().unwrap_or_default().to_string()),
+                Err(CoreRpcError::Rpc { code, message }) => rejection(&message, Some(code), &txid),
+                Err(e) => Outcome::Unavailable(e.to_string()),
+            })
+        };
+        let local_txid = &txid;
+        let peers = futures::future::join_all(self.p2p_peers.iter().map(|peer| async move {
+            match p2p::send_transaction(peer, self.network, tx).await {
+                Ok(None) => Outcome::Relayed,
+                Ok(Some(reject)) => match reject.ccode {
+                    PeerRejectCode::Duplicate => Outcome::Accepted(local_txid.clone()),
+                    PeerRejectCode::Fee => Outcome::Rejected(RejectReason::InsufficientFee, reject.reason.into_owned()),
+                    _ => rejection(&reject.reason, None, local_txid),
+                },
+                Err(message) => Outcome::Unavailable(message),
+            }
+        }));
+        let (esplora, core, peers) = tokio::join!(esplora, core, peers);
+
+        let outcomes: Vec<Outcome> = std::iter::once(esplora).chain(core).chain(peers).collect();
+        let mut relayed = false;
+        let mut rejections = Vec::new();
+        let mut failures = Vec::new();
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Accepted(accepted_txid) => {
+                    if accepted_txid != txid {
+                        warn!("Backend reported txid {} for transaction {}", accepted_txid, txid);
+                    }
+                    info!("Broadcast transaction {}", txid);
+                    return Ok(txid);
+                }
+                Outcome::Rejected(reason, message) => rejections.push((reason, message)),
+                Outcome::Relayed => relayed = true,
+                Outcome::Unavailable(message) => failures.push(message),
+            }
+        }
+
+        if let Some(index) = rejections
+            .iter()
+            .position(|(reason, _)| *reason != RejectReason::Other)
+            .or_else(|| (!rejections.is_empty()).then_some(0))
+        {
+            let (reason, message) = rejections.swap_remove(index);
+            return Err(BroadcastError::Rejected { reason, message });
+        }
+        if relayed {
+            info!("Relayed transaction {} to peers", txid);
+            return Ok(txid);
+        }
+        Err(BroadcastError::Backend(failures.join("; ")))
+    }
+}
+
+// Map a node's rejection message (and Bitcoin Core error code) to a reason.
+// Already being in the mempool counts as accepting `txid`.
+fn rejection(message: &str, code: Option<i64>, txid: &str) -> Outcome {
+    let lower = message.to_lowercase();
+    let reason = if lower.contains("txn-already-in-mempool") || lower.contains("txn-already-known") {
+        return Outcome::Accepted(txid.to_string());
+    } else if lower.contains("min relay fee not met")
+        || lower.contains("mempool min fee not met")
+        || lower.contains("insufficient fee")
+    {
+        RejectReason::InsufficientFee
+    } else if lower.contains("missing-inputs") || lower.contains("missingorspent") || lower.contains("missing inputs") {
+        RejectReason::MissingInputs
+    } else if lower.contains("already in block chain") || lower.contains("already in utxo set") {
+        RejectReason::AlreadyInChain
+    } else if lower.contains("txn-mempool-conflict") {
+        RejectReason::Conflict
+    } else {
+        match code {
+            Some(-25) => RejectReason::MissingInputs,
+            Some(-27) => RejectReason::AlreadyInChain,
+            _ => RejectReason::Other,
+        }
+    };
+    Outcome::Rejected(reason, message.to_string())
+}
+
+// Finalize a fully signed PSBT, validate the transaction and broadcast it,
+// recording the spend against the store's limits. Returns the txid.
+pub async fn broadcast_psbt(data: &AppState, signed_psbt: Psbt, store_id: &str, amount: u64) -> Result<String, BroadcastError> {
+    let spent_outputs: Vec<TxOut> = (0..signed_psbt.inputs.len())
+        .filter_map(|index| psbt::spent_output(&signed_psbt, index).cloned())
+        .collect();
+    let fee = signed_psbt.fee().map(|fee| fee.to_sat()).unwrap_or(0);
+    let change_vout = change_output(data, store_id, &signed_psbt);
+    let unsigned_txid = signed_psbt.unsigned_tx.txid().to_string();
+    let encoded = psbt::encode_psbt(&signed_psbt);
+    let signed_tx = psbt::finalize_and_extract(signed_psbt).map_err(BroadcastError::Finalize)?;
+
+    // Refuse to broadcast anything that fails validation
+    let tip_height = match data.blockchain_client.get_tip_height().await {
+        Ok(height) => Some(height),
+        Err(e) => {
+            warn!("Could not fetch tip height, skipping locktime finality check: {}", e);
+            None
+        }
+    };
+    match data.trezor_client.validate_transaction(&signed_tx, &spent_outputs, tip_height) {
+        Ok(_) => {}
+        Err(TrezorError::ValidationFailed(report)) => return Err(BroadcastError::Invalid(report)),
+        Err(e) => return Err(BroadcastError::Validation(e)),
+    }
+
+    // Count the spend before it goes out, giving it back if it doesn't
+    let txid = signed_tx.txid().to_string();
+    let violations = spending_policy::record_spend(&data.db, store_id, &txid, amount)?;
+    if !violations.is_empty() {
+        return Err(BroadcastError::LimitExceeded(violations));
+    }
+    if let Err(e) = data.broadcaster.submit(&data.blockchain_client, &signed_tx).await {
+        if let Err(forget_error) = data.db.forget_outgoing_spend(&txid) {
+            error!("Failed to release spend {} from limits: {}", txid, forget_error);
+        }
+        return Err(e);
+    }
+
+    if let Err(e) = data.db.remove_spent_utxos(&tx_builder::outpoints(&signed_tx)) {
+        error!("Failed to remove the inputs of {} from the wallet: {}", txid, e);
+    }
+    let now = Utc::now();
+    track(data, &signed_tx, OutgoingTransaction {
+        txid: txid.clone(),
+        store_id: store_id.to_string(),
+        psbt: encoded,
+        amount,
+        fee,
+        vsize: signed_tx.vsize() as u64,
+        change_vout,
+        status: OutgoingStatus::Unconfirmed,
+        replaced_by: None,
+        block_height: None,
+        broadcast_at: now,
+        tx_hex: hex::encode(serialize(&signed_tx)),
+        last_broadcast_at: now,
+        broadcast_count: 1,
+    });
+    fee_bump::record_acceleration(data, &unsigned_txid, &txid);
+    Ok(txid)
+}
+
+// The first output carrying a BIP32 derivation from one of the store
+// wallet's keys
+fn change_output(data: &AppState, store_id: &str, psbt: &Psbt) -> Option<u32> {
+    let fingerprints = data.wallet_for(store_id)?.fingerprints();
+    psbt.outputs
+        .iter()
+        .position(|output| {
+            output
+                .bip32_derivation
+                .values()
+                .any(|(fingerprint, _)| fingerprints.contains(fingerprint))
+        })
+        .map(|vout| vout as u32)
+}
+
+// Track a broadcast transaction until it confirms. Unconfirmed transactions
+// of the store spending any of the same inputs have been replaced by it.
+fn track(data: &AppState, tx: &Transaction, outgoing: OutgoingTransaction) {
+    let unconfirmed = match data.db.list_outgoing_transactions(Some(&outgoing.store_id), Some(OutgoingStatus::Unconfirmed)) {
+        Ok(unconfirmed) => unconfirmed,
+        Err(e) => {
+            error!("Failed to load unconfirmed transactions of store {}: {}", outgoing.store_id, e);
+            Vec::new()
+        }
+    };
+    for previous in unconfirmed {
+        let conflicts = psbt::decode_psbt(&previous.psbt)
+            .map(|previous_psbt| {
+                previous_psbt
+                    .unsigned_tx
+                    .input
+                    .iter()
+                    .any(|old| tx.input.iter().any(|new| new.previous_output == old.previous_output))
+            })
+            .unwrap_or(false);
+        if !conflicts || previous.txid == outgoing.txid {
+            continue;
+        }
+        info!("Transaction {} replaced by {}", previous.txid, outgoing.txid);
+        if let Err(e) = data
+            .db
+            .update_outgoing_status(&previous.txid, OutgoingStatus::Replaced, Some(&outgoing.txid), None)
+            .and_then(|_| data.db.forget_outgoing_spend(&previous.txid))
+        {
+            error!("Failed to mark {} as replaced: {}", previous.txid, e);
+        }
+    }
+
+    if let Err(e) = data.db.save_outgoing_transaction(&outgoing) {
+        error!("Failed to track outgoing transaction {}: {}", outgoing.txid, e);
+    }
+}
+
+// Mark tracked transactions confirmed once they are mined, resubmitting
+// those still unconfirmed every `rebroadcast` in case they were dropped
+pub async fn run_tracker(data: actix_web::web::Data<AppState>, rebroadcast: Option<Duration>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(CONFIRMATION_CHECK_INTERVAL_SECS));
+    loop {
+        ticker.tick().await;
+
+        let unconfirmed = match data.db.list_outgoing_transactions(None, Some(OutgoingStatus::Unconfirmed)) {
+            Ok(unconfirmed) => unconfirmed,
+            Err(e) => {
+                error!("Failed to list unconfirmed transactions: {}", e);
+                continue;
+            }
+        };
+        for tx in unconfirmed {
+            match data.blockchain_client.get_tx_status(&tx.txid).await {
+                Ok(Some(status)) if status.confirmed => {
+                    if let Err(e) = data.db.update_outgoing_status(&tx.txid, OutgoingStatus::Confirmed, None, status.block_height) {
+                        error!("Failed to mark {} as confirmed: {}", tx.txid, e);
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Could not check transaction {}: {}", tx.txid, e),
+            }
+
+            let due = rebroadcast
+                .and_then(|interval| chrono::Duration::from_std(interval).ok())
+                .map(|interval| tx.last_broadcast_at + interval <= Utc::now())
+                .unwrap_or(false);
+            if due {
+                rebroadcast_transaction(&data, &tx).await;
+            }
+        }
+    }
+}
+
+async fn rebroadcast_transaction(data: &AppState, tx: &OutgoingTransaction) {
+    let signed_tx: Transaction = match hex::decode(&tx.tx_hex).ok().and_then(|bytes| deserialize(&bytes).ok()) {
+        Some(signed_tx) => signed_tx,
+        None => {
+            warn!("No raw transaction stored for {}, not rebroadcasting", tx.txid);
+            return;
+        }
+    };
+
+    match data.broadcaster.submit(&data.blockchain_client, &signed_tx).await {
+        Ok(_) => {
+            info!("Rebroadcast transaction {} (attempt {})", tx.txid, tx.broadcast_count + 1);
+            if let Err(e) = data.db.record_rebroadcast(&tx.txid, Utc::now()) {
+                error!("Failed to record rebroadcast of {}: {}", tx.txid, e);
+            }
+        }
+        // Its inputs were spent elsewhere, so it can never confirm
+        Err(BroadcastError::Rejected { reason: RejectReason::MissingInputs, message }) => {
+            warn!("Transaction {} can no longer confirm: {}", tx.txid, message);
+            if let Err(e) = data
+                .db
+                .update_outgoing_status(&tx.txid, OutgoingStatus::Replaced, None, None)
+                .and_then(|_| data.db.forget_outgoing_spend(&tx.txid))
+            {
+                error!("Failed to mark {} as replaced: {}", tx.txid, e);
+            }
+        }
+        // Picked up as confirmed on a later check
+        Err(BroadcastError::Rejected { reason: RejectReason::AlreadyInChain, .. }) => {
+            info!("Transaction {} is already mined", tx.txid);
+        }
+        Err(e) => warn!("Rebroadcasting {} failed: {}", tx.txid, e),
+    }
+}
+
+// REBROADCAST_INTERVAL_SECS (default 600); 0 disables rebroadcasting
+pub fn rebroadcast_interval() -> Option<Duration> {
+    let secs = std::env::var("REBROADCAST_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_REBROADCAST_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::fake_esplora;
+    use actix_web::{web, HttpResponse};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::{OutPoint, ScriptBuf, TxIn};
+
+    fn outpoint(vout: u32) -> OutPoint {
+        OutPoint {
+            txid: "1111111111111111111111111111111111111111111111111111111111111111".parse().unwrap(),
+            vout,
+        }
+    }
+
+    // A transaction of the store spending `inputs`, tracked as `track` would
+    fn outgoing(inputs: &[OutPoint], value: u64) -> (Transaction, OutgoingTransaction) {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: inputs.iter().map(|input| TxIn { previous_output: *input, ..TxIn::default() }).collect(),
+            output: vec![TxOut { value, script_pubkey: ScriptBuf::new_op_return(&[]) }],
+        };
+        let now = Utc::now();
+        let outgoing = OutgoingTransaction {
+            txid: tx.txid().to_string(),
+            store_id: "shop".to_string(),
+            psbt: psbt::encode_psbt(&Psbt::from_unsigned_tx(tx.clone()).unwrap()),
+            amount: value,
+            fee: 1_000,
+            vsize: tx.vsize() as u64,
+            change_vout: None,
+            status: OutgoingStatus::Unconfirmed,
+            replaced_by: None,
+            block_height: None,
+            broadcast_at: now,
+            tx_hex: hex::encode(serialize(&tx)),
+            last_broadcast_at: now,
+            broadcast_count: 1,
+        };
+        (tx, outgoing)
+    }
+
+    fn stored(data: &AppState, txid: &str) -> OutgoingTransaction {
+        data.db.get_outgoing_transaction(txid).unwrap().unwrap()
+    }
+
+    #[test]
+    fn rejections_are_classified() {
+        let cases = [
+            ("min relay fee not met, 100 < 141", None, Some(RejectReason::InsufficientFee)),
+            ("bad-txns-inputs-missingorspent", None, Some(RejectReason::MissingInputs)),
+            ("Transaction already in block chain", None, Some(RejectReason::AlreadyInChain)),
+            ("txn-mempool-conflict", None, Some(RejectReason::Conflict)),
+            ("unexpected", Some(-25), Some(RejectReason::MissingInputs)),
+            ("non-mandatory-script-verify-flag", None, Some(RejectReason::Other)),
+            // Already there counts as accepted
+            ("txn-already-in-mempool", None, None),
+        ];
+        for (message, code, expected) in cases {
+            let reason = match rejection(message, code, "txid") {
+                Outcome::Rejected(reason, _) => Some(reason),
+                Outcome::Accepted(txid) => {
+                    assert_eq!(txid, "txid");
+                    None
+                }
+                _ => panic!("{} was neither accepted nor rejected", message),
+            };
+            assert_eq!(reason, expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn transactions_spending_the_same_inputs_are_replaced() {
+        let data = AppState::new(":memory:");
+        let (original_tx, original) = outgoing(&[outpoint(0), outpoint(1)], 50_000);
+        track(&data, &original_tx, original.clone());
+        data.db.record_outgoing_spend(&original.txid, "shop", 50_000).unwrap();
+        let (unrelated_tx, unrelated) = outgoing(&[outpoint(2)], 20_000);
+        track(&data, &unrelated_tx, unrelated.clone());
+
+        // A fee bump spending one of the same inputs
+        let (bump_tx, bump) = outgoing(&[outpoint(1)], 49_000);
+        track(&data, &bump_tx, bump.clone());
+
+        let original = stored(&data, &original.txid);
+        assert_eq!(original.status, OutgoingStatus::Replaced);
+        assert_eq!(original.replaced_by, Some(bump.txid.clone()));
+        assert!(!data.db.has_outgoing_spend(&original.txid).unwrap());
+        assert_eq!(stored(&data, &unrelated.txid).status, OutgoingStatus::Unconfirmed);
+        assert_eq!(stored(&data, &bump.txid).status, OutgoingStatus::Unconfirmed);
+    }
+
+    #[actix_web::test]
+    async fn transactions_whose_inputs_are_gone_are_replaced() {
+        let mut data = AppState::new(":memory:");
+        data.blockchain_client = fake_esplora(|config| {
+            config.route(
+                "/tx",
+                web::post().to(|| async { HttpResponse::BadRequest().body("bad-txns-inputs-missingorspent") }),
+            );
+        });
+        let (tx, outgoing) = outgoing(&[outpoint(0)], 50_000);
+        track(&data, &tx, outgoing.clone());
+        data.db.record_outgoing_spend(&outgoing.txid, "shop", 50_000).unwrap();
+
+        rebroadcast_transaction(&data, &outgoing).await;
+        let outgoing = stored(&data, &outgoing.txid);
+        assert_eq!(outgoing.status, OutgoingStatus::Replaced);
+        assert_eq!(outgoing.replaced_by, None);
+        assert!(!data.db.has_outgoing_spend(&outgoing.txid).unwrap());
+    }
+}
@@ -0,0 +1,652 @@
+use bitcoin::{Address, Network};
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::Error as SqliteError;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::models::{Invoice, InvoicePayment, InvoiceStatus, PaymentStatus};
+use crate::state::AppState;
+use crate::zero_conf;
+
+// Block hashes kept for reorg detection; deeper reorgs go unnoticed
+const TRACKED_BLOCKS: u32 = 24;
+const DEFAULT_WATCH_INTERVAL_SECS: u64 = 30;
+const DEFAULT_INVOICE_CONFIRMATIONS: u32 = 1;
+
+#[derive(Debug)]
+pub enum WatchError {
+    Blockchain(String),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::Blockchain(msg) => write!(f, "Blockchain backend error: {}", msg),
+            WatchError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+impl From<SqliteError> for WatchError {
+    fn from(error: SqliteError) -> Self {
+        WatchError::Database(error)
+    }
+}
+
+// What an invoice has been paid so far
+#[derive(Debug, Serialize)]
+pub struct PaymentSummary {
+    pub invoice_id: String,
+    pub status: InvoiceStatus,
+    pub amount: u64,
+    pub received: u64, // Including unconfirmed payments
+    // With the required confirmations, or accepted unconfirmed under the
+    // store's 0-conf policy
+    pub settled: u64,
+    pub due: u64,
+    pub payments: Vec<InvoicePayment>,
+}
+
+// Follow the chain tip: undo confirmations reorged out, record payments to
+// open invoices and move invoices through their statuses
+pub async fn run_watcher(data: actix_web::web::Data<AppState>, interval: Duration, network: Network) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = poll(&data, network).await {
+            error!("Chain watcher failed: {}", e);
+        }
+    }
+}
+
+async fn poll(data: &AppState, network: Network) -> Result<(), WatchError> {
+    let tip = data.blockchain_client.get_tip_height().await.map_err(WatchError::Blockchain)?;
+
+    if let Some(fork_height) = find_fork(data, tip).await? {
+        handle_reorg(data, fork_height, tip).await?;
+    }
+    record_blocks(data, tip).await?;
+
+    for invoice in open_invoices(data, tip)? {
+        if let Err(e) = track_invoice(data, &invoice, tip, network).await {
+            warn!("Could not check payments of invoice {}: {}", invoice.id, e);
+        }
+    }
+    Ok(())
+}
+
+// Lowest tracked height whose block is no longer in the best chain, if any
+async fn find_fork(data: &AppState, tip: u32) -> Result<Option<u32>, WatchError> {
+    let mut fork_height = None;
+    for (height, hash) in data.db.list_block_hashes()? {
+        // A chain that got shorter reorged everything above its tip
+        if height <= tip {
+            let current = data
+                .blockchain_client
+                .get_block_hash(height)
+                .await
+                .map_err(WatchError::Blockchain)?;
+            if current == hash {
+                break;
+            }
+        }
+        fork_height = Some(height);
+    }
+    Ok(fork_height)
+}
+
+async fn record_blocks(data: &AppState, tip: u32) -> Result<(), WatchError> {
+    let keep_from = tip.saturating_sub(TRACKED_BLOCKS - 1);
+    let highest = data.db.list_block_hashes()?.first().map(|(height, _)| *height);
+    let start = highest.map(|height| height + 1).unwrap_or(keep_from).max(keep_from);
+
+    for height in start..=tip {
+        let hash = data
+            .blockchain_client
+            .get_block_hash(height)
+            .await
+            .map_err(WatchError::Blockchain)?;
+        data.db.save_block_hash(height, &hash)?;
+    }
+    data.db.prune_block_hashes(tip + 1, keep_from)?;
+    Ok(())
+}
+
+// Re-check everything confirmed at or above the fork: payments mined again
+// in the new chain keep their confirmation, those back in the mempool lose
+// it and those gone entirely become invalid
+async fn handle_reorg(data: &AppState, fork_height: u32, tip: u32) -> Result<(), WatchError> {
+    warn!("Chain reorganization detected from height {}", fork_height);
+    data.db.prune_block_hashes(fork_height, 0)?;
+
+    let utxos = data.db.unconfirm_utxos_from(fork_height)?;
+    if utxos > 0 {
+        info!("Marked {} wallet UTXO(s) unconfirmed until the next sync", utxos);
+    }
+
+    let mut affected: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    for mut payment in data.db.list_payments_confirmed_from(fork_height)? {
+        let status = data
+            .blockchain_client
+            .get_tx_status(&payment.txid)
+            .await
+            .map_err(WatchError::Blockchain)?;
+        let previous_height = payment.block_height;
+        match status {
+            Some(status) if status.confirmed => {
+                payment.block_height = status.block_height;
+                payment.block_hash = status.block_hash;
+            }
+            Some(_) => {
+                payment.status = PaymentStatus::Unconfirmed;
+                payment.block_height = None;
+                payment.block_hash = None;
+            }
+            None => {
+                payment.status = PaymentStatus::Invalid;
+                payment.zero_conf_accepted = false;
+            }
+        }
+        data.db.save_invoice_payment(&payment)?;
+
+        affected.entry(payment.invoice_id.clone()).or_default().push(json!({
+            "txid": payment.txid,
+            "vout": payment.vout,
+            "previous_block_height": previous_height,
+            "block_height": payment.block_height,
+            "status": payment.status,
+        }));
+    }
+
+    for (invoice_id, payments) in &affected {
+        data.record_invoice_event(
+            invoice_id,
+            "reorg",
+            format!("Chain reorganization from height {} affected {} payment(s)", fork_height, payments.len()),
+            json!({ "fork_height": fork_height, "payments": payments }),
+        );
+        match data.invoice(invoice_id)? {
+            Some(invoice) => evaluate(data, &invoice, tip).await?,
+            None => warn!("Reorged payment belongs to unknown invoice {}", invoice_id),
+        }
+    }
+
+    data.notify("chain.reorg", json!({
+        "fork_height": fork_height,
+        "tip_height": tip,
+        "invoices": affected.keys().collect::<Vec<_>>(),
+    }))
+    .await;
+    Ok(())
+}
+
+// Invoices that can still receive or confirm payments, from memory and the
+// database. Invoices settled on unconfirmed payments stay watched until those
+// have the required confirmations, so a double spend still reopens them.
+fn open_invoices(data: &AppState, tip: u32) -> Result<Vec<Invoice>, WatchError> {
+    let mut invoices: Vec<Invoice> = data
+        .invoices
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|invoice| is_open(invoice.status))
+        .cloned()
+        .collect();
+    let mut known: BTreeSet<String> = invoices.iter().map(|invoice| invoice.id.clone()).collect();
+    for status in [InvoiceStatus::Pending, InvoiceStatus::Processing] {
+        for invoice in data.db.list_invoices_by_status(status)? {
+            if known.insert(invoice.id.clone()) {
+                invoices.push(invoice);
+            }
+        }
+    }
+
+    let confirmed_below = (tip + 1).saturating_sub(invoice_confirmations());
+    for invoice_id in data.db.list_invoices_with_unconfirmed_zero_conf(confirmed_below)? {
+        if known.contains(&invoice_id) {
+            continue;
+        }
+        if let Some(invoice) = data.invoice(&invoice_id)?.filter(|invoice| invoice.status == InvoiceStatus::Settled) {
+            known.insert(invoice_id);
+            invoices.push(invoice);
+        }
+    }
+    Ok(invoices)
+}
+
+fn is_open(status: InvoiceStatus) -> bool {
+    matches!(status, InvoiceStatus::Pending | InvoiceStatus::Processing)
+}
+
+async fn track_invoice(data: &AppState, invoice: &Invoice, tip: u32, network: Network) -> Result<(), WatchError> {
+    let address = Address::from_str(&invoice.address)
+        .and_then(|address| address.require_network(network))
+        .map_err(|e| WatchError::Blockchain(format!("Invalid invoice address {}: {}", invoice.address, e)))?;
+    let txs = data
+        .blockchain_client
+        .get_address_txs(&address)
+        .await
+        .map_err(WatchError::Blockchain)?;
+
+    let policy = data.db.get_zero_conf_policy(&invoice.store_id)?;
+    let seen: HashSet<String> = txs.iter().map(|tx| tx.txid.clone()).collect();
+    // Unconfirmed payments accepted so far, which count against the store's cap
+    let mut accepted: HashMap<(String, u32), u64> = data
+        .db
+        .list_invoice_payments(&invoice.id)?
+        .into_iter()
+        .filter(|payment| payment.zero_conf_accepted && payment.status == PaymentStatus::Unconfirmed)
+        .map(|payment| ((payment.txid, payment.vout), payment.value))
+        .collect();
+    for tx in txs {
+        let inputs: Vec<String> = tx.vin.iter().map(|input| format!("{}:{}", input.txid, input.vout)).collect();
+        for (vout, output) in tx.vout.iter().enumerate() {
+            if output.scriptpubkey_address.as_deref() != Some(invoice.address.as_str()) {
+                continue;
+            }
+            let confirmed = tx.status.confirmed;
+            let outpoint = (tx.txid.clone(), vout as u32);
+            let exposure = output.value
+                + accepted
+                    .iter()
+                    .filter(|(accepted_outpoint, _)| **accepted_outpoint != outpoint)
+                    .map(|(_, value)| value)
+                    .sum::<u64>();
+            let assessment = if confirmed {
+                None
+            } else {
+                match zero_conf::assess(data, &tx, exposure, policy.as_ref()).await {
+                    Ok(assessment) => Some(assessment),
+                    Err(e) => {
+                        warn!("Could not assess 0-conf risk of {}: {}", tx.txid, e);
+                        None
+                    }
+                }
+            };
+            let zero_conf_accepted = match (&policy, &assessment) {
+                (Some(policy), Some(assessment)) => zero_conf::accepts(policy, exposure, assessment),
+                _ => false,
+            };
+            // Acceptance sticks until the payment confirms or is lost
+            if confirmed {
+                accepted.remove(&outpoint);
+            } else if zero_conf_accepted {
+                accepted.insert(outpoint, output.value);
+            }
+            data.db.save_invoice_payment(&InvoicePayment {
+                invoice_id: invoice.id.clone(),
+                txid: tx.txid.clone(),
+                vout: vout as u32,
+                value: output.value,
+                status: if confirmed { PaymentStatus::Confirmed } else { PaymentStatus::Unconfirmed },
+                block_height: tx.status.block_height.filter(|_| confirmed),
+                block_hash: tx.status.block_hash.clone().filter(|_| confirmed),
+                seen_at: Utc::now(),
+                inputs: inputs.clone(),
+                replaced_by: None,
+                risk_score: assessment.as_ref().map(|assessment| assessment.score),
+                risk_factors: assessment.map(|assessment| assessment.factors).unwrap_or_default(),
+                zero_conf_accepted,
+            })?;
+        }
+    }
+
+    let replaced = detect_replacements(data, invoice, &seen).await?;
+    if !replaced.is_empty() {
+        let due = summarize(data, invoice, tip)?.due;
+        for payment in &replaced {
+            flag_replacement(data, invoice, payment, &seen, due).await;
+        }
+    }
+
+    evaluate(data, invoice, tip).await
+}
+
+// Unconfirmed payments no longer listed for the invoice address, and unknown
+// to the backend, were double-spent or dropped from the mempool. They are
+// marked invalid so they no longer count towards the amount due.
+async fn detect_replacements(
+    data: &AppState,
+    invoice: &Invoice,
+    seen: &HashSet<String>,
+) -> Result<Vec<InvoicePayment>, WatchError> {
+    let mut replaced = Vec::new();
+    for mut payment in data.db.list_invoice_payments(&invoice.id)? {
+        if payment.status != PaymentStatus::Unconfirmed || seen.contains(&payment.txid) {
+            continue;
+        }
+        let status = data
+            .blockchain_client
+            .get_tx_status(&payment.txid)
+            .await
+            .map_err(WatchError::Blockchain)?;
+        if status.is_some() {
+            continue;
+        }
+
+        payment.replaced_by = find_conflict(data, &payment).await?;
+        payment.status = PaymentStatus::Invalid;
+        payment.zero_conf_accepted = false;
+        data.db.save_invoice_payment(&payment)?;
+        replaced.push(payment);
+    }
+    Ok(replaced)
+}
+
+// Transaction now spending one of the payment's inputs, if any
+async fn find_conflict(data: &AppState, payment: &InvoicePayment) -> Result<Option<String>, WatchError> {
+    for input in &payment.inputs {
+        let (txid, vout) = match input.rsplit_once(':').and_then(|(txid, vout)| Some((txid, vout.parse().ok()?))) {
+            Some(outpoint) => outpoint,
+            None => continue,
+        };
+        let outspend = data
+            .blockchain_client
+            .get_outspend(txid, vout)
+            .await
+            .map_err(WatchError::Blockchain)?;
+        match outspend.txid {
+            Some(spender) if outspend.spent && spender != payment.txid => return Ok(Some(spender)),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+// A replacement still paying the invoice (e.g. a fee bump) is benign; one
+// that doesn't is a double spend
+async fn flag_replacement(data: &AppState, invoice: &Invoice, payment: &InvoicePayment, seen: &HashSet<String>, due: u64) {
+    let (event_type, message) = match &payment.replaced_by {
+        Some(replacement) if seen.contains(replacement) => (
+            "payment_replaced",
+            format!("Payment {} was replaced by {}, which also pays this invoice", payment.txid, replacement),
+        ),
+        Some(replacement) => (
+            "double_spend",
+            format!("Payment {} was double-spent by {}", payment.txid, replacement),
+        ),
+        None => (
+            "payment_dropped",
+            format!("Payment {} was dropped from the mempool", payment.txid),
+        ),
+    };
+    warn!("Invoice {}: {}", invoice.id, message);
+
+    let details = json!({
+        "txid": payment.txid,
+        "vout": payment.vout,
+        "value": payment.value,
+        "replaced_by": payment.replaced_by,
+        "due": due,
+    });
+    data.record_invoice_event(&invoice.id, event_type, message, details.clone());
+
+    let mut event = details;
+    event["invoice_id"] = json!(invoice.id);
+    event["store_id"] = json!(invoice.store_id);
+    data.notify(&format!("invoice.{}", event_type), event).await;
+}
+
+pub fn summarize(data: &AppState, invoice: &Invoice, tip: u32) -> Result<PaymentSummary, SqliteError> {
+    let payments = data.db.list_invoice_payments(&invoice.id)?;
+    let required = invoice_confirmations();
+    let valid = || payments.iter().filter(|payment| payment.status != PaymentStatus::Invalid);
+    let received = valid().map(|payment| payment.value).sum::<u64>();
+    let settled = valid()
+        .filter(|payment| {
+            payment.zero_conf_accepted
+                || payment
+                    .block_height
+                    .map(|height| tip + 1 >= height + required)
+                    .unwrap_or(false)
+        })
+        .map(|payment| payment.value)
+        .sum::<u64>();
+
+    Ok(PaymentSummary {
+        invoice_id: invoice.id.clone(),
+        status: invoice.status,
+        amount: invoice.amount,
+        received,
+        settled,
+        due: invoice.amount.saturating_sub(received),
+        payments,
+    })
+}
+
+// Bring an open invoice's status up to date with the payments the watcher
+// recorded, e.g. to expire it between polls. Invoices paid in full stay
+// Processing or Settled whatever their expiry.
+pub async fn refresh_status(data: &AppState, invoice: &Invoice) -> Result<(), WatchError> {
+    if !is_open(invoice.status) {
+        return Ok(());
+    }
+    let tip = data.blockchain_client.get_tip_height().await.map_err(WatchError::Blockchain)?;
+    evaluate(data, invoice, tip).await
+}
+
+// Move an invoice to the status its payments call for. An invoice whose
+// payments disappeared, including one settled on a payment accepted
+// unconfirmed, is open again until it expires, then invalid.
+async fn evaluate(data: &AppState, invoice: &Invoice, tip: u32) -> Result<(), WatchError> {
+    let summary = summarize(data, invoice, tip)?;
+    let lost_payments = summary
+        .payments
+        .iter()
+        .any(|payment| payment.status == PaymentStatus::Invalid);
+    let expired = Utc::now() > invoice.expires_at;
+
+    let status = if summary.settled >= invoice.amount {
+        InvoiceStatus::Settled
+    } else if summary.received >= invoice.amount {
+        InvoiceStatus::Processing
+    } else if !expired {
+        InvoiceStatus::Pending
+    } else if lost_payments {
+        InvoiceStatus::Invalid
+    } else {
+        InvoiceStatus::Expired
+    };
+    if status == invoice.status {
+        return Ok(());
+    }
+
+    set_invoice_status(data, &invoice.id, status)?;
+    data.record_invoice_event(
+        &invoice.id,
+        "status_changed",
+        format!("Invoice moved from {:?} to {:?}", invoice.status, status),
+        json!({
+            "previous_status": invoice.status,
+            "status": status,
+            "received": summary.received,
+            "settled": summary.settled,
+            "due": summary.due,
+        }),
+    );
+    let event_type = format!("invoice.{:?}", status).to_lowercase();
+    data.notify(&event_type, json!({
+        "invoice_id": invoice.id,
+        "store_id": invoice.store_id,
+        "previous_status": invoice.status,
+        "status": status,
+        "amount": invoice.amount,
+        "received": summary.received,
+        "due": summary.due,
+    }))
+    .await;
+    Ok(())
+}
+
+fn set_invoice_status(data: &AppState, invoice_id: &str, status: InvoiceStatus) -> Result<(), SqliteError> {
+    if let Some(invoice) = data.invoices.lock().unwrap().get_mut(invoice_id) {
+        invoice.status = status;
+    }
+    data.db.update_invoice_status(invoice_id, status)
+}
+
+// INVOICE_CONFIRMATIONS (default 1) needed before an invoice is settled
+fn invoice_confirmations() -> u32 {
+    std::env::var("INVOICE_CONFIRMATIONS")
+        .ok()
+        .and_then(|confirmations| confirmations.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_INVOICE_CONFIRMATIONS)
+        .max(1)
+}
+
+// CHAIN_WATCH_INTERVAL_SECS (default 30); 0 disables the watcher
+pub fn watch_interval() -> Option<Duration> {
+    let secs = std::env::var("CHAIN_WATCH_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_WATCH_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{fake_esplora, BlockchainClient};
+    use actix_web::{web, HttpResponse};
+    use chrono::Duration as ChronoDuration;
+
+    fn expired_invoice(data: &AppState) -> Invoice {
+        let invoice = Invoice {
+            id: "invoice".to_string(),
+            address: "tb1qexample".to_string(),
+            amount: 50_000,
+            description: String::new(),
+            status: InvoiceStatus::Pending,
+            created_at: Utc::now() - ChronoDuration::hours(2),
+            expires_at: Utc::now() - ChronoDuration::hours(1),
+            derivation_path: None,
+            store_id: "shop".to_string(),
+        };
+        data.db.save_invoice(&invoice).unwrap();
+        invoice
+    }
+
+    fn payment(txid: &str, value: u64, block_height: Option<u32>) -> InvoicePayment {
+        InvoicePayment {
+            invoice_id: "invoice".to_string(),
+            txid: txid.to_string(),
+            vout: 0,
+            value,
+            status: if block_height.is_some() { PaymentStatus::Confirmed } else { PaymentStatus::Unconfirmed },
+            block_height,
+            block_hash: None,
+            seen_at: Utc::now(),
+            inputs: Vec::new(),
+            replaced_by: None,
+            risk_score: None,
+            risk_factors: Vec::new(),
+            zero_conf_accepted: false,
+        }
+    }
+
+    fn status(data: &AppState) -> InvoiceStatus {
+        data.invoice("invoice").unwrap().unwrap().status
+    }
+
+    #[actix_web::test]
+    async fn paid_invoices_are_not_expired() {
+        let data = AppState::new(":memory:");
+        let invoice = expired_invoice(&data);
+        data.db.save_invoice_payment(&payment("aa", 50_000, None)).unwrap();
+        evaluate(&data, &invoice, 100).await.unwrap();
+        assert_eq!(status(&data), InvoiceStatus::Processing);
+
+        let invoice = data.invoice("invoice").unwrap().unwrap();
+        data.db.save_invoice_payment(&payment("aa", 50_000, Some(100))).unwrap();
+        evaluate(&data, &invoice, 100).await.unwrap();
+        assert_eq!(status(&data), InvoiceStatus::Settled);
+
+        // Settled invoices are left alone
+        refresh_status(&data, &data.invoice("invoice").unwrap().unwrap()).await.unwrap();
+        assert_eq!(status(&data), InvoiceStatus::Settled);
+    }
+
+    #[actix_web::test]
+    async fn unpaid_invoices_expire() {
+        let data = AppState::new(":memory:");
+        let invoice = expired_invoice(&data);
+        data.db.save_invoice_payment(&payment("aa", 20_000, None)).unwrap();
+        evaluate(&data, &invoice, 100).await.unwrap();
+        assert_eq!(status(&data), InvoiceStatus::Expired);
+    }
+
+    // The backend no longer knows payments a1-a3; the inputs of a1 and a2
+    // were spent by b1 and b2, those of a3 are unspent again
+    fn replacing_backend() -> BlockchainClient {
+        fake_esplora(|config| {
+            config
+                .route("/tx/{txid}/status", web::get().to(HttpResponse::NotFound))
+                .route(
+                    "/tx/{txid}/outspend/{vout}",
+                    web::get().to(|path: web::Path<(String, u32)>| async move {
+                        let spender = match path.0.as_str() {
+                            "c1" => Some("b1"),
+                            "c2" => Some("b2"),
+                            _ => None,
+                        };
+                        HttpResponse::Ok().json(json!({ "spent": spender.is_some(), "txid": spender }))
+                    }),
+                );
+        })
+    }
+
+    #[actix_web::test]
+    async fn replaced_payments_are_invalidated_and_flagged() {
+        let mut data = AppState::new(":memory:");
+        data.blockchain_client = replacing_backend();
+        let invoice = expired_invoice(&data);
+        for (txid, input) in [("a1", "c1:0"), ("a2", "c2:0"), ("a3", "c3:0")] {
+            let mut payment = payment(txid, 10_000, None);
+            payment.inputs = vec![input.to_string()];
+            data.db.save_invoice_payment(&payment).unwrap();
+        }
+        // Still listed for the address, or already confirmed: left alone
+        data.db.save_invoice_payment(&payment("listed", 10_000, None)).unwrap();
+        data.db.save_invoice_payment(&payment("mined", 10_000, Some(100))).unwrap();
+
+        // b1 pays the invoice too, so a1 was only fee bumped
+        let seen: HashSet<String> = ["b1", "listed"].iter().map(|txid| txid.to_string()).collect();
+        let replaced = detect_replacements(&data, &invoice, &seen).await.unwrap();
+        let replaced_by: Vec<(&str, Option<&str>)> =
+            replaced.iter().map(|payment| (payment.txid.as_str(), payment.replaced_by.as_deref())).collect();
+        assert_eq!(replaced_by, [("a1", Some("b1")), ("a2", Some("b2")), ("a3", None)]);
+        for payment in &replaced {
+            flag_replacement(&data, &invoice, payment, &seen, 0).await;
+        }
+
+        let statuses: HashMap<String, PaymentStatus> = data
+            .db
+            .list_invoice_payments("invoice")
+            .unwrap()
+            .into_iter()
+            .map(|payment| (payment.txid, payment.status))
+            .collect();
+        for txid in ["a1", "a2", "a3"] {
+            assert_eq!(statuses[txid], PaymentStatus::Invalid, "{}", txid);
+        }
+        assert_eq!(statuses["listed"], PaymentStatus::Unconfirmed);
+        assert_eq!(statuses["mined"], PaymentStatus::Confirmed);
+
+        let events: Vec<String> = data
+            .db
+            .list_invoice_events("invoice")
+            .unwrap()
+            .into_iter()
+            .map(|event| event.event_type)
+            .collect();
+        assert_eq!(events, ["payment_replaced", "double_spend", "payment_dropped"]);
+    }
+}
@@ -0,0 +1,73 @@
+use reqwest::Client;
+use serde_json::{json, Value};
+
+#[derive(Debug)]
+pub enum CoreRpcError {
+    Transport(String),
+    // Error returned by the node, e.g. code -26 for a rejected transaction
+    Rpc { code: i64, message: String },
+}
+
+impl std::fmt::Display for CoreRpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoreRpcError::Transport(msg) => write!(f, "{}", msg),
+            CoreRpcError::Rpc { code, message } => write!(f, "RPC error {}: {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for CoreRpcError {}
+
+// Bitcoin Core JSON-RPC endpoint
+pub struct CoreRpc {
+    http_client: Client,
+    url: String,
+    user: String,
+    password: String,
+}
+
+impl CoreRpc {
+    // BITCOIN_RPC_URL with BITCOIN_RPC_USER/BITCOIN_RPC_PASSWORD
+    pub fn from_env() -> Option<Self> {
+        std::env::var("BITCOIN_RPC_URL").ok().map(|url| Self {
+            http_client: Client::new(),
+            url,
+            user: std::env::var("BITCOIN_RPC_USER").unwrap_or_default(),
+            password: std::env::var("BITCOIN_RPC_PASSWORD").unwrap_or_default(),
+        })
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, CoreRpcError> {
+        let response = self
+            .http_client
+            .post(&self.url)
+            .basic_auth(&self.user, Some(&self.password))
+            .json(&json!({
+                "jsonrpc": "1.0",
+                "id": method,
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await
+            .map_err(|e| CoreRpcError::Transport(format!("Request to {} failed: {}", self.url, e)))?;
+
+        // Core answers RPC errors with a 500 status and the error in the body
+        let status = response.status();
+        let mut body: Value = response
+            .json()
+            .await
+            .map_err(|e| CoreRpcError::Transport(format!("{} failed with status {}: {}", method, status, e)))?;
+        if let Some(error) = body.get("error").filter(|error| !error.is_null()) {
+            return Err(CoreRpcError::Rpc {
+                code: error["code"].as_i64().unwrap_or(0),
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+        if !status.is_success() {
+            return Err(CoreRpcError::Transport(format!("{} failed with status {}", method, status)));
+        }
+        Ok(body["result"].take())
+    }
+}
@@ -1,26 +1,35 @@
 
 use rusqlite::{params, Connection, Result, Error as SqliteError};
-use log::{info, error};
-use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use log::info;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
-use crate::models::{Invoice, InvoiceStatus};
+use crate::models::{
+    AuditEvent, ExportStatus, Invoice, InvoiceEvent, InvoicePayment, InvoiceStatus, OutgoingStatus, OutgoingTransaction, Payout, PayoutStatus,
+    PaymentStatus, ProposalStatus, ProposalVote, PsbtExport, Refund, RefundKind, RefundRate, RefundStatus, Role, SpendProposal,
+    SpendingPolicy, StoreWallet, UserTotp, Utxo, ZeroConfPolicy,
+};
 
 pub struct Database {
-    conn: Connection,
+    // rusqlite connections can't be shared between threads
+    conn: Mutex<Connection>,
 }
 
 impl Database {
     pub fn new(db_path: &str) -> Result<Self, SqliteError> {
         let conn = Connection::open(db_path)?;
-        let db = Self { conn };
+        let db = Self { conn: Mutex::new(conn) };
         db.initialize()?;
         Ok(db)
     }
 
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     fn initialize(&self) -> Result<(), SqliteError> {
-        self.conn.execute(
+        self.conn().execute(
             "CREATE TABLE IF NOT EXISTS invoices (
                 id TEXT PRIMARY KEY,
                 address TEXT NOT NULL,
@@ -28,7 +37,338 @@ impl Database {
                 description TEXT NOT NULL,
                 status TEXT NOT NULL,
                 created_at TEXT NOT NULL,
-                expires_at TEXT NOT NULL
+                expires_at TEXT NOT NULL,
+                derivation_path TEXT,
+                store_id TEXT NOT NULL DEFAULT 'default'
+            )",
+            [],
+        )?;
+        // Databases created before derivation paths and stores were tracked
+        self.add_column_if_missing("invoices", "derivation_path", "TEXT")?;
+        self.add_column_if_missing("invoices", "store_id", "TEXT NOT NULL DEFAULT 'default'")?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS invoice_events (
+                id TEXT PRIMARY KEY,
+                invoice_id TEXT NOT NULL,
+                event_type TEXT NOT NULL,
+                message TEXT NOT NULL,
+                details TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS invoice_payments (
+                txid TEXT NOT NULL,
+                vout INTEGER NOT NULL,
+                invoice_id TEXT NOT NULL,
+                value INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                block_height INTEGER,
+                block_hash TEXT,
+                seen_at TEXT NOT NULL,
+                inputs TEXT NOT NULL DEFAULT '[]',
+                replaced_by TEXT,
+                risk_score INTEGER,
+                risk_factors TEXT NOT NULL DEFAULT '[]',
+                zero_conf_accepted INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (txid, vout)
+            )",
+            [],
+        )?;
+        self.add_column_if_missing("invoice_payments", "inputs", "TEXT NOT NULL DEFAULT '[]'")?;
+        self.add_column_if_missing("invoice_payments", "replaced_by", "TEXT")?;
+        self.add_column_if_missing("invoice_payments", "risk_score", "INTEGER")?;
+        self.add_column_if_missing("invoice_payments", "risk_factors", "TEXT NOT NULL DEFAULT '[]'")?;
+        self.add_column_if_missing("invoice_payments", "zero_conf_accepted", "INTEGER NOT NULL DEFAULT 0")?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS zero_conf_policies (
+                store_id TEXT PRIMARY KEY,
+                enabled INTEGER NOT NULL,
+                max_amount INTEGER NOT NULL,
+                max_risk_score INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Hashes of recent blocks seen by the chain watcher, to detect reorgs
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS chain_blocks (
+                height INTEGER PRIMARY KEY,
+                hash TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS store_wallets (
+                store_id TEXT PRIMARY KEY,
+                descriptor TEXT NOT NULL,
+                source TEXT NOT NULL,
+                label TEXT,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Next unused receive index per account key
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS derivation_indexes (
+                account TEXT PRIMARY KEY,
+                next_index INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Unspent outputs of store wallets, refreshed from the chain backend
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS wallet_utxos (
+                txid TEXT NOT NULL,
+                vout INTEGER NOT NULL,
+                store_id TEXT NOT NULL,
+                address TEXT NOT NULL,
+                value INTEGER NOT NULL,
+                script_pubkey TEXT NOT NULL,
+                derivation_path TEXT NOT NULL,
+                block_height INTEGER,
+                invoice_id TEXT,
+                first_seen TEXT NOT NULL,
+                PRIMARY KEY (txid, vout)
+            )",
+            [],
+        )?;
+
+        // UTXOs picked for a transaction that has not been broadcast yet,
+        // keyed by the unsigned txid of the spend holding them
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS utxo_reservations (
+                txid TEXT NOT NULL,
+                vout INTEGER NOT NULL,
+                store_id TEXT NOT NULL,
+                spend_txid TEXT NOT NULL,
+                reserved_until TEXT NOT NULL,
+                PRIMARY KEY (txid, vout)
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS wallet_syncs (
+                store_id TEXT PRIMARY KEY,
+                synced_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS spending_policies (
+                store_id TEXT PRIMARY KEY,
+                daily_limit INTEGER,
+                weekly_limit INTEGER,
+                allowed_destinations TEXT NOT NULL,
+                max_fee_rate REAL,
+                large_amount_threshold INTEGER,
+                large_amount_delay_secs INTEGER NOT NULL DEFAULT 0,
+                approvals_required INTEGER NOT NULL DEFAULT 0,
+                approval_threshold INTEGER,
+                proposal_ttl_secs INTEGER,
+                max_acceleration_fee INTEGER,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.add_column_if_missing("spending_policies", "approvals_required", "INTEGER NOT NULL DEFAULT 0")?;
+        self.add_column_if_missing("spending_policies", "approval_threshold", "INTEGER")?;
+        self.add_column_if_missing("spending_policies", "proposal_ttl_secs", "INTEGER")?;
+        self.add_column_if_missing("spending_policies", "max_acceleration_fee", "INTEGER")?;
+
+        // Broadcast transactions counted against spend limits
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS outgoing_spends (
+                txid TEXT PRIMARY KEY,
+                store_id TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS outgoing_transactions (
+                txid TEXT PRIMARY KEY,
+                store_id TEXT NOT NULL,
+                psbt TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                fee INTEGER NOT NULL,
+                vsize INTEGER NOT NULL,
+                change_vout INTEGER,
+                status TEXT NOT NULL,
+                replaced_by TEXT,
+                block_height INTEGER,
+                broadcast_at TEXT NOT NULL,
+                tx_hex TEXT NOT NULL DEFAULT '',
+                last_broadcast_at TEXT,
+                broadcast_count INTEGER NOT NULL DEFAULT 1
+            )",
+            [],
+        )?;
+        self.add_column_if_missing("outgoing_transactions", "tx_hex", "TEXT NOT NULL DEFAULT ''")?;
+        self.add_column_if_missing("outgoing_transactions", "last_broadcast_at", "TEXT")?;
+        self.add_column_if_missing("outgoing_transactions", "broadcast_count", "INTEGER NOT NULL DEFAULT 1")?;
+
+        // First submission of large spends, keyed by unsigned txid, for the time delay
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS delayed_spends (
+                txid TEXT PRIMARY KEY,
+                store_id TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                requested_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Invoice accelerations prepared but not yet broadcast, keyed by the
+        // child's unsigned txid
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS pending_accelerations (
+                txid TEXT PRIMARY KEY,
+                invoice_id TEXT NOT NULL,
+                details TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS psbt_exports (
+                id TEXT PRIMARY KEY,
+                store_id TEXT NOT NULL,
+                psbt TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                status TEXT NOT NULL,
+                txid TEXT
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS spend_proposals (
+                id TEXT PRIMARY KEY,
+                store_id TEXT NOT NULL,
+                psbt TEXT NOT NULL,
+                description TEXT,
+                amount INTEGER NOT NULL,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                status TEXT NOT NULL,
+                required_approvals INTEGER NOT NULL,
+                txid TEXT
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS proposal_votes (
+                proposal_id TEXT NOT NULL,
+                username TEXT NOT NULL,
+                approve INTEGER NOT NULL,
+                comment TEXT,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (proposal_id, username)
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS payouts (
+                id TEXT PRIMARY KEY,
+                store_id TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                currency TEXT NOT NULL,
+                description TEXT,
+                status TEXT NOT NULL,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                txid TEXT,
+                proposal_id TEXT
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS refunds (
+                id TEXT PRIMARY KEY,
+                invoice_id TEXT NOT NULL,
+                store_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                rate TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                destination TEXT,
+                payout_id TEXT,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                claimed_at TEXT
+            )",
+            [],
+        )?;
+        self.add_column_if_missing("refunds", "rate", "TEXT NOT NULL DEFAULT 'Original'")?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                password_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS user_roles (
+                username TEXT NOT NULL,
+                role TEXT NOT NULL,
+                PRIMARY KEY (username, role)
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS user_totp (
+                username TEXT PRIMARY KEY,
+                secret TEXT NOT NULL,
+                enabled INTEGER NOT NULL DEFAULT 0,
+                last_used_step INTEGER,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+                username TEXT NOT NULL,
+                code_hash TEXT NOT NULL,
+                used_at TEXT,
+                PRIMARY KEY (username, code_hash)
+            )",
+            [],
+        )?;
+
+        self.conn().execute(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id TEXT PRIMARY KEY,
+                timestamp TEXT NOT NULL,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                ip TEXT,
+                details TEXT NOT NULL
             )",
             [],
         )?;
@@ -37,11 +377,24 @@ impl Database {
         Ok(())
     }
 
+    fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<(), SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let exists = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .filter_map(|name| name.ok())
+            .any(|name| name == column);
+        if !exists {
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+        }
+        Ok(())
+    }
+
     pub fn save_invoice(&self, invoice: &Invoice) -> Result<(), SqliteError> {
-        self.conn.execute(
+        self.conn().execute(
             "INSERT INTO invoices (
-                id, address, amount, description, status, created_at, expires_at
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                id, address, amount, description, status, created_at, expires_at, derivation_path, store_id
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
             params![
                 invoice.id,
                 invoice.address,
@@ -49,7 +402,9 @@ impl Database {
                 invoice.description,
                 format!("{:?}", invoice.status),
                 invoice.created_at.to_rfc3339(),
-                invoice.expires_at.to_rfc3339()
+                invoice.expires_at.to_rfc3339(),
+                invoice.derivation_path,
+                invoice.store_id
             ],
         )?;
         
@@ -58,8 +413,9 @@ impl Database {
     }
 
     pub fn get_invoice(&self, id: &str) -> Result<Option<Invoice>, SqliteError> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, address, amount, description, status, created_at, expires_at 
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, address, amount, description, status, created_at, expires_at, derivation_path, store_id
              FROM invoices WHERE id = ?"
         )?;
         
@@ -70,7 +426,10 @@ impl Database {
             
             let status = match status_str.as_str() {
                 "Pending" => InvoiceStatus::Pending,
-                "Paid" => InvoiceStatus::Paid,
+                "Processing" => InvoiceStatus::Processing,
+                // Invoices saved before confirmations were tracked
+                "Settled" | "Paid" => InvoiceStatus::Settled,
+                "Invalid" => InvoiceStatus::Invalid,
                 "Expired" => InvoiceStatus::Expired,
                 _ => InvoiceStatus::Pending, // Default
             };
@@ -91,6 +450,8 @@ impl Database {
                 status,
                 created_at,
                 expires_at,
+                derivation_path: row.get(7)?,
+                store_id: row.get(8)?,
             })
         });
         
@@ -101,8 +462,21 @@ impl Database {
         }
     }
 
+    // Invoice paying to `address`, if any
+    pub fn get_invoice_id_by_address(&self, address: &str) -> Result<Option<String>, SqliteError> {
+        match self.conn().query_row(
+            "SELECT id FROM invoices WHERE address = ? LIMIT 1",
+            params![address],
+            |row| row.get(0),
+        ) {
+            Ok(id) => Ok(Some(id)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn update_invoice_status(&self, id: &str, status: InvoiceStatus) -> Result<(), SqliteError> {
-        self.conn.execute(
+        self.conn().execute(
             "UPDATE invoices SET status = ? WHERE id = ?",
             params![format!("{:?}", status), id],
         )?;
@@ -111,13 +485,14 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_pending_invoices(&self) -> Result<Vec<Invoice>, SqliteError> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, address, amount, description, status, created_at, expires_at 
-             FROM invoices WHERE status = 'Pending'"
+    pub fn list_invoices_by_status(&self, status: InvoiceStatus) -> Result<Vec<Invoice>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, address, amount, description, status, created_at, expires_at, derivation_path, store_id
+             FROM invoices WHERE status = ?"
         )?;
         
-        let invoice_iter = stmt.query_map([], |row| {
+        let invoice_iter = stmt.query_map(params![format!("{:?}", status)], |row| {
             let created_at_str: String = row.get(5)?;
             let expires_at_str: String = row.get(6)?;
             
@@ -134,9 +509,11 @@ impl Database {
                 address: row.get(1)?,
                 amount: row.get(2)?,
                 description: row.get(3)?,
-                status: InvoiceStatus::Pending,
+                status,
                 created_at,
                 expires_at,
+                derivation_path: row.get(7)?,
+                store_id: row.get(8)?,
             })
         })?;
         
@@ -147,4 +524,1428 @@ impl Database {
         
         Ok(invoices)
     }
+
+    pub fn add_invoice_event(&self, event: &InvoiceEvent) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO invoice_events (id, invoice_id, event_type, message, details, created_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                Uuid::new_v4().to_string(),
+                event.invoice_id,
+                event.event_type,
+                event.message,
+                event.details.to_string(),
+                event.created_at.to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_invoice_events(&self, invoice_id: &str) -> Result<Vec<InvoiceEvent>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT invoice_id, event_type, message, details, created_at
+             FROM invoice_events WHERE invoice_id = ? ORDER BY created_at"
+        )?;
+
+        let event_iter = stmt.query_map(params![invoice_id], |row| {
+            let details_str: String = row.get(3)?;
+            let created_at_str: String = row.get(4)?;
+            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(4, "created_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+
+            Ok(InvoiceEvent {
+                invoice_id: row.get(0)?,
+                event_type: row.get(1)?,
+                message: row.get(2)?,
+                details: serde_json::from_str(&details_str).unwrap_or(serde_json::Value::Null),
+                created_at,
+            })
+        })?;
+
+        let mut events = Vec::new();
+        for event in event_iter {
+            events.push(event?);
+        }
+
+        Ok(events)
+    }
+
+    // Insert a payment or update its confirmation status. The last risk
+    // assessment is kept once the payment confirms, and a payment accepted
+    // at 0-conf stays accepted.
+    pub fn save_invoice_payment(&self, payment: &InvoicePayment) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO invoice_payments (
+                txid, vout, invoice_id, value, status, block_height, block_hash, seen_at, inputs, replaced_by,
+                risk_score, risk_factors, zero_conf_accepted
+             ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (txid, vout) DO UPDATE
+             SET status = excluded.status, block_height = excluded.block_height, block_hash = excluded.block_hash,
+                 inputs = CASE WHEN excluded.inputs = '[]' THEN inputs ELSE excluded.inputs END,
+                 replaced_by = excluded.replaced_by,
+                 risk_factors = CASE WHEN excluded.risk_score IS NULL THEN risk_factors ELSE excluded.risk_factors END,
+                 risk_score = COALESCE(excluded.risk_score, risk_score),
+                 zero_conf_accepted = CASE WHEN excluded.status = 'Invalid' THEN 0
+                     ELSE MAX(zero_conf_accepted, excluded.zero_conf_accepted) END",
+            params![
+                payment.txid,
+                payment.vout,
+                payment.invoice_id,
+                payment.value,
+                format!("{:?}", payment.status),
+                payment.block_height,
+                payment.block_hash,
+                payment.seen_at.to_rfc3339(),
+                serde_json::to_string(&payment.inputs).unwrap_or_else(|_| "[]".to_string()),
+                payment.replaced_by,
+                payment.risk_score,
+                serde_json::to_string(&payment.risk_factors).unwrap_or_else(|_| "[]".to_string()),
+                payment.zero_conf_accepted
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_invoice_payments(&self, invoice_id: &str) -> Result<Vec<InvoicePayment>, SqliteError> {
+        self.query_invoice_payments("WHERE invoice_id = ? ORDER BY seen_at", params![invoice_id])
+    }
+
+    // Invoices with a payment accepted unconfirmed that is still unconfirmed
+    // or mined above `height`
+    pub fn list_invoices_with_unconfirmed_zero_conf(&self, height: u32) -> Result<Vec<String>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT invoice_id FROM invoice_payments
+             WHERE zero_conf_accepted = 1 AND status != 'Invalid' AND (block_height IS NULL OR block_height > ?)"
+        )?;
+        let invoice_ids = stmt.query_map(params![height], |row| row.get(0))?;
+        invoice_ids.collect()
+    }
+
+    // Payments confirmed at or above `height`, which a reorg from there undoes
+    pub fn list_payments_confirmed_from(&self, height: u32) -> Result<Vec<InvoicePayment>, SqliteError> {
+        self.query_invoice_payments(
+            "WHERE status = 'Confirmed' AND block_height >= ? ORDER BY block_height",
+            params![height],
+        )
+    }
+
+    fn query_invoice_payments(
+        &self,
+        clause: &str,
+        args: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<InvoicePayment>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT invoice_id, txid, vout, value, status, block_height, block_hash, seen_at, inputs, replaced_by,
+                    risk_score, risk_factors, zero_conf_accepted
+             FROM invoice_payments {}",
+            clause
+        ))?;
+
+        let payment_iter = stmt.query_map(args, |row| {
+            let status_str: String = row.get(4)?;
+            let seen_at_str: String = row.get(7)?;
+            let inputs_str: String = row.get(8)?;
+            let risk_factors_str: String = row.get(11)?;
+
+            let seen_at = DateTime::parse_from_rfc3339(&seen_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(7, "seen_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+
+            let status = match status_str.as_str() {
+                "Confirmed" => PaymentStatus::Confirmed,
+                "Invalid" => PaymentStatus::Invalid,
+                _ => PaymentStatus::Unconfirmed,
+            };
+
+            Ok(InvoicePayment {
+                invoice_id: row.get(0)?,
+                txid: row.get(1)?,
+                vout: row.get(2)?,
+                value: row.get(3)?,
+                status,
+                block_height: row.get(5)?,
+                block_hash: row.get(6)?,
+                seen_at,
+                inputs: serde_json::from_str(&inputs_str).unwrap_or_default(),
+                replaced_by: row.get(9)?,
+                risk_score: row.get(10)?,
+                risk_factors: serde_json::from_str(&risk_factors_str).unwrap_or_default(),
+                zero_conf_accepted: row.get(12)?,
+            })
+        })?;
+
+        let mut payments = Vec::new();
+        for payment in payment_iter {
+            payments.push(payment?);
+        }
+
+        Ok(payments)
+    }
+
+    pub fn save_block_hash(&self, height: u32, hash: &str) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO chain_blocks (height, hash) VALUES (?, ?)",
+            params![height, hash],
+        )?;
+        Ok(())
+    }
+
+    // Highest first
+    pub fn list_block_hashes(&self) -> Result<Vec<(u32, String)>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT height, hash FROM chain_blocks ORDER BY height DESC")?;
+        let blocks = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        blocks.collect()
+    }
+
+    // Forget blocks at or above `height` (reorged out) or below `keep_from`
+    pub fn prune_block_hashes(&self, height: u32, keep_from: u32) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "DELETE FROM chain_blocks WHERE height >= ? OR height < ?",
+            params![height, keep_from],
+        )?;
+        Ok(())
+    }
+
+    // Treat wallet outputs confirmed at or above `height` as unconfirmed
+    // until the next wallet sync
+    pub fn unconfirm_utxos_from(&self, height: u32) -> Result<usize, SqliteError> {
+        self.conn().execute(
+            "UPDATE wallet_utxos SET block_height = NULL WHERE block_height >= ?",
+            params![height],
+        )
+    }
+
+    // Save (or replace) the wallet a store's invoices pay into
+    pub fn save_store_wallet(&self, wallet: &StoreWallet) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO store_wallets (store_id, descriptor, source, label, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                wallet.store_id,
+                wallet.descriptor,
+                wallet.source,
+                wallet.label,
+                wallet.created_at.to_rfc3339()
+            ],
+        )?;
+
+        info!("Wallet saved for store {}", wallet.store_id);
+        Ok(())
+    }
+
+    pub fn get_store_wallet(&self, store_id: &str) -> Result<Option<StoreWallet>, SqliteError> {
+        let result = self.conn().query_row(
+            "SELECT store_id, descriptor, source, label, created_at FROM store_wallets WHERE store_id = ?",
+            params![store_id],
+            |row| {
+                let created_at_str: String = row.get(4)?;
+                let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                    .map_err(|_| rusqlite::Error::InvalidColumnType(4, "created_at".to_string(), rusqlite::types::Type::Text))?
+                    .with_timezone(&Utc);
+                Ok(StoreWallet {
+                    store_id: row.get(0)?,
+                    descriptor: row.get(1)?,
+                    source: row.get(2)?,
+                    label: row.get(3)?,
+                    created_at,
+                })
+            },
+        );
+
+        match result {
+            Ok(wallet) => Ok(Some(wallet)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn list_store_wallet_ids(&self) -> Result<Vec<String>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT store_id FROM store_wallets")?;
+        let ids = stmt.query_map([], |row| row.get(0))?;
+        ids.collect()
+    }
+
+    // Replace a store's UTXO set with the outputs found by a sync, keeping
+    // when each was first seen
+    pub fn replace_store_utxos(&self, store_id: &str, utxos: &[Utxo]) -> Result<(), SqliteError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS synced_outpoints (txid TEXT NOT NULL, vout INTEGER NOT NULL)",
+            [],
+        )?;
+        tx.execute("DELETE FROM synced_outpoints", [])?;
+
+        for utxo in utxos {
+            tx.execute(
+                "INSERT INTO wallet_utxos (
+                    txid, vout, store_id, address, value, script_pubkey, derivation_path, block_height, invoice_id, first_seen
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT(txid, vout) DO UPDATE SET block_height = excluded.block_height, invoice_id = excluded.invoice_id",
+                params![
+                    utxo.txid,
+                    utxo.vout,
+                    store_id,
+                    utxo.address,
+                    utxo.value,
+                    utxo.script_pubkey,
+                    utxo.derivation_path,
+                    utxo.block_height,
+                    utxo.invoice_id,
+                    utxo.first_seen.to_rfc3339()
+                ],
+            )?;
+            tx.execute(
+                "INSERT INTO synced_outpoints (txid, vout) VALUES (?, ?)",
+                params![utxo.txid, utxo.vout],
+            )?;
+        }
+
+        // Anything no longer reported has been spent
+        tx.execute(
+            "DELETE FROM wallet_utxos WHERE store_id = ? AND NOT EXISTS (
+                SELECT 1 FROM synced_outpoints s WHERE s.txid = wallet_utxos.txid AND s.vout = wallet_utxos.vout
+            )",
+            params![store_id],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO wallet_syncs (store_id, synced_at) VALUES (?, ?)",
+            params![store_id, Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()
+    }
+
+    pub fn list_utxos(&self, store_id: &str) -> Result<Vec<Utxo>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT txid, vout, store_id, address, value, script_pubkey, derivation_path, block_height, invoice_id, first_seen
+             FROM wallet_utxos WHERE store_id = ? ORDER BY first_seen, txid, vout"
+        )?;
+
+        let utxos = stmt.query_map(params![store_id], |row| {
+            let first_seen_str: String = row.get(9)?;
+            let first_seen = DateTime::parse_from_rfc3339(&first_seen_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(9, "first_seen".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+            let block_height: Option<u32> = row.get(7)?;
+
+            Ok(Utxo {
+                txid: row.get(0)?,
+                vout: row.get(1)?,
+                store_id: row.get(2)?,
+                address: row.get(3)?,
+                value: row.get(4)?,
+                script_pubkey: row.get(5)?,
+                derivation_path: row.get(6)?,
+                confirmed: block_height.is_some(),
+                block_height,
+                invoice_id: row.get(8)?,
+                first_seen,
+            })
+        })?;
+        utxos.collect()
+    }
+
+    // Outpoints held by an unexpired reservation
+    pub fn reserved_outpoints(&self, store_id: &str, now: DateTime<Utc>) -> Result<Vec<(String, u32)>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT txid, vout FROM utxo_reservations WHERE store_id = ? AND reserved_until > ?"
+        )?;
+        let outpoints = stmt.query_map(params![store_id, now.to_rfc3339()], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        outpoints.collect()
+    }
+
+    // Hold `outpoints` for `spend_txid` until `until`, replacing expired
+    // reservations and extending the spend's own
+    pub fn reserve_utxos(
+        &self,
+        store_id: &str,
+        spend_txid: &str,
+        outpoints: &[(String, u32)],
+        until: DateTime<Utc>,
+    ) -> Result<(), SqliteError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM utxo_reservations WHERE reserved_until <= ?", params![Utc::now().to_rfc3339()])?;
+        for (txid, vout) in outpoints {
+            tx.execute(
+                "INSERT OR REPLACE INTO utxo_reservations (txid, vout, store_id, spend_txid, reserved_until)
+                 VALUES (?, ?, ?, ?, ?)",
+                params![txid, vout, store_id, spend_txid, until.to_rfc3339()],
+            )?;
+        }
+        tx.commit()
+    }
+
+    pub fn release_utxos(&self, spend_txid: &str) -> Result<(), SqliteError> {
+        self.conn().execute("DELETE FROM utxo_reservations WHERE spend_txid = ?", params![spend_txid])?;
+        Ok(())
+    }
+
+    // Drop UTXOs a broadcast transaction spent, rather than waiting for the
+    // next sync to notice, along with their reservations
+    pub fn remove_spent_utxos(&self, outpoints: &[(String, u32)]) -> Result<(), SqliteError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for (txid, vout) in outpoints {
+            tx.execute("DELETE FROM wallet_utxos WHERE txid = ? AND vout = ?", params![txid, vout])?;
+            tx.execute("DELETE FROM utxo_reservations WHERE txid = ? AND vout = ?", params![txid, vout])?;
+        }
+        tx.commit()
+    }
+
+    pub fn last_wallet_sync(&self, store_id: &str) -> Result<Option<DateTime<Utc>>, SqliteError> {
+        let result = self.conn().query_row(
+            "SELECT synced_at FROM wallet_syncs WHERE store_id = ?",
+            params![store_id],
+            |row| row.get::<_, String>(0),
+        );
+
+        match result {
+            Ok(synced_at) => DateTime::parse_from_rfc3339(&synced_at)
+                .map(|synced_at| Some(synced_at.with_timezone(&Utc)))
+                .map_err(|_| rusqlite::Error::InvalidColumnType(0, "synced_at".to_string(), rusqlite::types::Type::Text)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_spending_policy(&self, store_id: &str, policy: &SpendingPolicy) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO spending_policies (
+                store_id, daily_limit, weekly_limit, allowed_destinations, max_fee_rate,
+                large_amount_threshold, large_amount_delay_secs, approvals_required,
+                approval_threshold, proposal_ttl_secs, max_acceleration_fee, updated_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                store_id,
+                policy.daily_limit,
+                policy.weekly_limit,
+                serde_json::to_string(&policy.allowed_destinations).unwrap_or_else(|_| "[]".to_string()),
+                policy.max_fee_rate,
+                policy.large_amount_threshold,
+                policy.large_amount_delay_secs,
+                policy.approvals_required,
+                policy.approval_threshold,
+                policy.proposal_ttl_secs,
+                policy.max_acceleration_fee,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+
+        info!("Spending policy saved for store {}", store_id);
+        Ok(())
+    }
+
+    pub fn get_spending_policy(&self, store_id: &str) -> Result<Option<SpendingPolicy>, SqliteError> {
+        let result = self.conn().query_row(
+            "SELECT daily_limit, weekly_limit, allowed_destinations, max_fee_rate,
+                    large_amount_threshold, large_amount_delay_secs, approvals_required,
+                    approval_threshold, proposal_ttl_secs, max_acceleration_fee
+             FROM spending_policies WHERE store_id = ?",
+            params![store_id],
+            |row| {
+                let destinations: String = row.get(2)?;
+                Ok(SpendingPolicy {
+                    daily_limit: row.get(0)?,
+                    weekly_limit: row.get(1)?,
+                    allowed_destinations: serde_json::from_str(&destinations).unwrap_or_default(),
+                    max_fee_rate: row.get(3)?,
+                    large_amount_threshold: row.get(4)?,
+                    large_amount_delay_secs: row.get(5)?,
+                    approvals_required: row.get(6)?,
+                    approval_threshold: row.get(7)?,
+                    proposal_ttl_secs: row.get(8)?,
+                    max_acceleration_fee: row.get(9)?,
+                })
+            },
+        );
+
+        match result {
+            Ok(policy) => Ok(Some(policy)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_zero_conf_policy(&self, store_id: &str, policy: &ZeroConfPolicy) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO zero_conf_policies (store_id, enabled, max_amount, max_risk_score)
+             VALUES (?, ?, ?, ?)",
+            params![store_id, policy.enabled, policy.max_amount, policy.max_risk_score],
+        )?;
+
+        info!("0-conf policy saved for store {}", store_id);
+        Ok(())
+    }
+
+    pub fn get_zero_conf_policy(&self, store_id: &str) -> Result<Option<ZeroConfPolicy>, SqliteError> {
+        let result = self.conn().query_row(
+            "SELECT enabled, max_amount, max_risk_score FROM zero_conf_policies WHERE store_id = ?",
+            params![store_id],
+            |row| {
+                Ok(ZeroConfPolicy {
+                    enabled: row.get(0)?,
+                    max_amount: row.get(1)?,
+                    max_risk_score: row.get(2)?,
+                })
+            },
+        );
+
+        match result {
+            Ok(policy) => Ok(Some(policy)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn record_outgoing_spend(&self, txid: &str, store_id: &str, amount: u64) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO outgoing_spends (txid, store_id, amount, created_at) VALUES (?, ?, ?, ?)",
+            params![txid, store_id, amount, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn has_outgoing_spend(&self, txid: &str) -> Result<bool, SqliteError> {
+        self.conn().query_row(
+            "SELECT EXISTS(SELECT 1 FROM outgoing_spends WHERE txid = ?)",
+            params![txid],
+            |row| row.get(0),
+        )
+    }
+
+    // Total sent by a store since `since`
+    pub fn outgoing_spent_since(&self, store_id: &str, since: DateTime<Utc>) -> Result<u64, SqliteError> {
+        self.conn().query_row(
+            "SELECT COALESCE(SUM(amount), 0) FROM outgoing_spends WHERE store_id = ? AND created_at >= ?",
+            params![store_id, since.to_rfc3339()],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn save_pending_acceleration(&self, txid: &str, invoice_id: &str, details: &serde_json::Value) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO pending_accelerations (txid, invoice_id, details, created_at) VALUES (?, ?, ?, ?)",
+            params![txid, invoice_id, details.to_string(), Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    // The invoice and details of a prepared acceleration, removing it so it
+    // is only reported once
+    pub fn take_pending_acceleration(&self, txid: &str) -> Result<Option<(String, serde_json::Value)>, SqliteError> {
+        let conn = self.conn();
+        let pending = conn.query_row(
+            "SELECT invoice_id, details FROM pending_accelerations WHERE txid = ?",
+            params![txid],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        );
+        let (invoice_id, details) = match pending {
+            Ok(pending) => pending,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        conn.execute("DELETE FROM pending_accelerations WHERE txid = ?", params![txid])?;
+        Ok(Some((invoice_id, serde_json::from_str(&details).unwrap_or_default())))
+    }
+
+    // When a large spend was first submitted, recording it now if it is new
+    pub fn delayed_spend_requested_at(&self, txid: &str, store_id: &str, amount: u64) -> Result<DateTime<Utc>, SqliteError> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO delayed_spends (txid, store_id, amount, requested_at) VALUES (?, ?, ?, ?)",
+            params![txid, store_id, amount, Utc::now().to_rfc3339()],
+        )?;
+        let requested_at: String = self.conn().query_row(
+            "SELECT requested_at FROM delayed_spends WHERE txid = ?",
+            params![txid],
+            |row| row.get(0),
+        )?;
+        DateTime::parse_from_rfc3339(&requested_at)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|_| rusqlite::Error::InvalidColumnType(0, "requested_at".to_string(), rusqlite::types::Type::Text))
+    }
+
+    // No longer count a replaced transaction against the limits; its
+    // replacement is counted instead
+    pub fn forget_outgoing_spend(&self, txid: &str) -> Result<(), SqliteError> {
+        self.conn().execute("DELETE FROM outgoing_spends WHERE txid = ?", params![txid])?;
+        Ok(())
+    }
+
+    pub fn save_outgoing_transaction(&self, tx: &OutgoingTransaction) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO outgoing_transactions (
+                txid, store_id, psbt, amount, fee, vsize, change_vout, status, replaced_by, block_height, broadcast_at,
+                tx_hex, last_broadcast_at, broadcast_count
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                tx.txid,
+                tx.store_id,
+                tx.psbt,
+                tx.amount,
+                tx.fee,
+                tx.vsize,
+                tx.change_vout,
+                format!("{:?}", tx.status),
+                tx.replaced_by,
+                tx.block_height,
+                tx.broadcast_at.to_rfc3339(),
+                tx.tx_hex,
+                tx.last_broadcast_at.to_rfc3339(),
+                tx.broadcast_count
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_outgoing_status(
+        &self,
+        txid: &str,
+        status: OutgoingStatus,
+        replaced_by: Option<&str>,
+        block_height: Option<u32>,
+    ) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE outgoing_transactions
+             SET status = ?, replaced_by = COALESCE(?, replaced_by), block_height = ?
+             WHERE txid = ?",
+            params![format!("{:?}", status), replaced_by, block_height, txid],
+        )?;
+
+        info!("Outgoing transaction {} is now {:?}", txid, status);
+        Ok(())
+    }
+
+    pub fn record_rebroadcast(&self, txid: &str, at: DateTime<Utc>) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE outgoing_transactions
+             SET last_broadcast_at = ?, broadcast_count = broadcast_count + 1
+             WHERE txid = ?",
+            params![at.to_rfc3339(), txid],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_outgoing_transaction(&self, txid: &str) -> Result<Option<OutgoingTransaction>, SqliteError> {
+        Ok(self.query_outgoing_transactions("WHERE txid = ?", params![txid])?.pop())
+    }
+
+    // Newest first; all stores when `store_id` is None
+    pub fn list_outgoing_transactions(
+        &self,
+        store_id: Option<&str>,
+        status: Option<OutgoingStatus>,
+    ) -> Result<Vec<OutgoingTransaction>, SqliteError> {
+        let status = status.map(|status| format!("{:?}", status));
+        self.query_outgoing_transactions(
+            "WHERE (?1 IS NULL OR store_id = ?1) AND (?2 IS NULL OR status = ?2) ORDER BY broadcast_at DESC",
+            params![store_id, status],
+        )
+    }
+
+    fn query_outgoing_transactions(
+        &self,
+        clause: &str,
+        args: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<OutgoingTransaction>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT txid, store_id, psbt, amount, fee, vsize, change_vout, status, replaced_by, block_height, broadcast_at,
+                    tx_hex, last_broadcast_at, broadcast_count
+             FROM outgoing_transactions {}",
+            clause
+        ))?;
+
+        let tx_iter = stmt.query_map(args, |row| {
+            let status_str: String = row.get(7)?;
+            let broadcast_at_str: String = row.get(10)?;
+
+            let broadcast_at = DateTime::parse_from_rfc3339(&broadcast_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(10, "broadcast_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+            // Rows from before rebroadcasting was tracked
+            let last_broadcast_at = row
+                .get::<_, Option<String>>(12)?
+                .and_then(|at| DateTime::parse_from_rfc3339(&at).ok())
+                .map(|at| at.with_timezone(&Utc))
+                .unwrap_or(broadcast_at);
+
+            let status = match status_str.as_str() {
+                "Confirmed" => OutgoingStatus::Confirmed,
+                "Replaced" => OutgoingStatus::Replaced,
+                _ => OutgoingStatus::Unconfirmed,
+            };
+
+            Ok(OutgoingTransaction {
+                txid: row.get(0)?,
+                store_id: row.get(1)?,
+                psbt: row.get(2)?,
+                amount: row.get(3)?,
+                fee: row.get(4)?,
+                vsize: row.get(5)?,
+                change_vout: row.get(6)?,
+                status,
+                replaced_by: row.get(8)?,
+                block_height: row.get(9)?,
+                broadcast_at,
+                tx_hex: row.get(11)?,
+                last_broadcast_at,
+                broadcast_count: row.get(13)?,
+            })
+        })?;
+
+        let mut transactions = Vec::new();
+        for tx in tx_iter {
+            transactions.push(tx?);
+        }
+
+        Ok(transactions)
+    }
+
+    pub fn save_psbt_export(&self, export: &PsbtExport) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO psbt_exports (id, store_id, psbt, amount, created_by, created_at, status, txid)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                export.id,
+                export.store_id,
+                export.psbt,
+                export.amount,
+                export.created_by,
+                export.created_at.to_rfc3339(),
+                format!("{:?}", export.status),
+                export.txid
+            ],
+        )?;
+
+        info!("PSBT export {} saved to database", export.id);
+        Ok(())
+    }
+
+    pub fn update_psbt_export(&self, id: &str, psbt: &str, status: ExportStatus, txid: Option<&str>) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE psbt_exports SET psbt = ?, status = ?, txid = COALESCE(?, txid) WHERE id = ?",
+            params![psbt, format!("{:?}", status), txid, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_psbt_export(&self, id: &str) -> Result<Option<PsbtExport>, SqliteError> {
+        let result = self.conn().query_row(
+            "SELECT id, store_id, psbt, amount, created_by, created_at, status, txid FROM psbt_exports WHERE id = ?",
+            params![id],
+            |row| {
+                let created_at_str: String = row.get(5)?;
+                let status_str: String = row.get(6)?;
+
+                let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                    .map_err(|_| rusqlite::Error::InvalidColumnType(5, "created_at".to_string(), rusqlite::types::Type::Text))?
+                    .with_timezone(&Utc);
+                let status = match status_str.as_str() {
+                    "Broadcast" => ExportStatus::Broadcast,
+                    _ => ExportStatus::Pending,
+                };
+
+                Ok(PsbtExport {
+                    id: row.get(0)?,
+                    store_id: row.get(1)?,
+                    psbt: row.get(2)?,
+                    amount: row.get(3)?,
+                    created_by: row.get(4)?,
+                    created_at,
+                    status,
+                    txid: row.get(7)?,
+                })
+            },
+        );
+
+        match result {
+            Ok(export) => Ok(Some(export)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_proposal(&self, proposal: &SpendProposal) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO spend_proposals (
+                id, store_id, psbt, description, amount, created_by, created_at, expires_at,
+                status, required_approvals, txid
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                proposal.id,
+                proposal.store_id,
+                proposal.psbt,
+                proposal.description,
+                proposal.amount,
+                proposal.created_by,
+                proposal.created_at.to_rfc3339(),
+                proposal.expires_at.to_rfc3339(),
+                format!("{:?}", proposal.status),
+                proposal.required_approvals,
+                proposal.txid
+            ],
+        )?;
+
+        info!("Spend proposal {} saved to database", proposal.id);
+        Ok(())
+    }
+
+    pub fn update_proposal_status(&self, id: &str, status: ProposalStatus, txid: Option<&str>) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE spend_proposals SET status = ?, txid = COALESCE(?, txid) WHERE id = ?",
+            params![format!("{:?}", status), txid, id],
+        )?;
+
+        info!("Spend proposal {} status updated to {:?}", id, status);
+        Ok(())
+    }
+
+    // Store the proposal's PSBT with signatures collected so far
+    pub fn update_proposal_psbt(&self, id: &str, psbt: &str) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE spend_proposals SET psbt = ? WHERE id = ?",
+            params![psbt, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_proposal(&self, id: &str) -> Result<Option<SpendProposal>, SqliteError> {
+        Ok(self.query_proposals("WHERE id = ?", params![id])?.pop())
+    }
+
+    pub fn list_proposals(&self, status: Option<ProposalStatus>) -> Result<Vec<SpendProposal>, SqliteError> {
+        match status {
+            Some(status) => self.query_proposals(
+                "WHERE status = ? ORDER BY created_at DESC",
+                params![format!("{:?}", status)],
+            ),
+            None => self.query_proposals("ORDER BY created_at DESC", params![]),
+        }
+    }
+
+    fn query_proposals(&self, clause: &str, args: &[&dyn rusqlite::ToSql]) -> Result<Vec<SpendProposal>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, store_id, psbt, description, amount, created_by, created_at, expires_at,
+                    status, required_approvals, txid
+             FROM spend_proposals {}",
+            clause
+        ))?;
+
+        let proposal_iter = stmt.query_map(args, |row| {
+            let created_at_str: String = row.get(6)?;
+            let expires_at_str: String = row.get(7)?;
+            let status_str: String = row.get(8)?;
+
+            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(6, "created_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+            let expires_at = DateTime::parse_from_rfc3339(&expires_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(7, "expires_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+
+            let status = match status_str.as_str() {
+                "Approved" => ProposalStatus::Approved,
+                "Rejected" => ProposalStatus::Rejected,
+                "Expired" => ProposalStatus::Expired,
+                "Broadcast" => ProposalStatus::Broadcast,
+                _ => ProposalStatus::Pending,
+            };
+
+            Ok(SpendProposal {
+                id: row.get(0)?,
+                store_id: row.get(1)?,
+                psbt: row.get(2)?,
+                description: row.get(3)?,
+                amount: row.get(4)?,
+                created_by: row.get(5)?,
+                created_at,
+                expires_at,
+                status,
+                required_approvals: row.get(9)?,
+                votes: Vec::new(),
+                txid: row.get(10)?,
+            })
+        })?;
+
+        let mut proposals = proposal_iter.collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+        drop(conn);
+        for proposal in &mut proposals {
+            proposal.votes = self.get_proposal_votes(&proposal.id)?;
+        }
+
+        Ok(proposals)
+    }
+
+    fn get_proposal_votes(&self, proposal_id: &str) -> Result<Vec<ProposalVote>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT username, approve, comment, created_at
+             FROM proposal_votes WHERE proposal_id = ? ORDER BY created_at"
+        )?;
+
+        let vote_iter = stmt.query_map(params![proposal_id], |row| {
+            let created_at_str: String = row.get(3)?;
+            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(3, "created_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+            Ok(ProposalVote {
+                username: row.get(0)?,
+                approve: row.get(1)?,
+                comment: row.get(2)?,
+                created_at,
+            })
+        })?;
+
+        let mut votes = Vec::new();
+        for vote in vote_iter {
+            votes.push(vote?);
+        }
+
+        Ok(votes)
+    }
+
+    // Record a vote; returns false if the user has already voted
+    pub fn add_proposal_vote(&self, proposal_id: &str, vote: &ProposalVote) -> Result<bool, SqliteError> {
+        let inserted = self.conn().execute(
+            "INSERT OR IGNORE INTO proposal_votes (proposal_id, username, approve, comment, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                proposal_id,
+                vote.username,
+                vote.approve,
+                vote.comment,
+                vote.created_at.to_rfc3339()
+            ],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    // Mark pending proposals past their expiry as expired, returning their ids
+    pub fn expire_proposals(&self, now: DateTime<Utc>) -> Result<Vec<String>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id FROM spend_proposals WHERE status = 'Pending' AND expires_at < ?"
+        )?;
+        let ids = stmt
+            .query_map(params![now.to_rfc3339()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+        drop(conn);
+
+        for id in &ids {
+            self.update_proposal_status(id, ProposalStatus::Expired, None)?;
+        }
+        Ok(ids)
+    }
+
+    pub fn save_payout(&self, payout: &Payout) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO payouts (
+                id, store_id, destination, amount, currency, description, status, created_by, created_at, txid,
+                proposal_id
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                payout.id,
+                payout.store_id,
+                payout.destination,
+                payout.amount,
+                payout.currency,
+                payout.description,
+                format!("{:?}", payout.status),
+                payout.created_by,
+                payout.created_at.to_rfc3339(),
+                payout.txid,
+                payout.proposal_id
+            ],
+        )?;
+
+        info!("Payout {} saved to database", payout.id);
+        Ok(())
+    }
+
+    pub fn update_payout_status(&self, id: &str, status: PayoutStatus, txid: Option<&str>) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE payouts SET status = ?, txid = COALESCE(?, txid) WHERE id = ?",
+            params![format!("{:?}", status), txid, id],
+        )?;
+
+        info!("Payout {} status updated to {:?}", id, status);
+        Ok(())
+    }
+
+    // Move a payout from one status to another, unless something else moved
+    // it first. Returns whether it did.
+    pub fn transition_payout(&self, id: &str, from: PayoutStatus, to: PayoutStatus) -> Result<bool, SqliteError> {
+        let updated = self.conn().execute(
+            "UPDATE payouts SET status = ? WHERE id = ? AND status = ?",
+            params![format!("{:?}", to), id, format!("{:?}", from)],
+        )?;
+        if updated > 0 {
+            info!("Payout {} status updated to {:?}", id, to);
+        }
+        Ok(updated > 0)
+    }
+
+    // Put a payout that was not sent back in the queue, detached from any
+    // transaction or proposal
+    pub fn requeue_payout(&self, id: &str, status: PayoutStatus) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE payouts SET status = ?, txid = NULL, proposal_id = NULL WHERE id = ?",
+            params![format!("{:?}", status), id],
+        )?;
+
+        info!("Payout {} requeued as {:?}", id, status);
+        Ok(())
+    }
+
+    pub fn set_payout_proposal(&self, id: &str, proposal_id: &str) -> Result<(), SqliteError> {
+        self.conn().execute("UPDATE payouts SET proposal_id = ? WHERE id = ?", params![proposal_id, id])?;
+        Ok(())
+    }
+
+    pub fn get_payout(&self, id: &str) -> Result<Option<Payout>, SqliteError> {
+        Ok(self.query_payouts("WHERE id = ?", params![id])?.pop())
+    }
+
+    // A store's payouts, oldest first so batches pay out in order
+    pub fn list_payouts(&self, store_id: &str, status: Option<PayoutStatus>) -> Result<Vec<Payout>, SqliteError> {
+        match status {
+            Some(status) => self.query_payouts(
+                "WHERE store_id = ? AND status = ? ORDER BY created_at",
+                params![store_id, format!("{:?}", status)],
+            ),
+            None => self.query_payouts("WHERE store_id = ? ORDER BY created_at", params![store_id]),
+        }
+    }
+
+    // Stores with at least one payout in the given status
+    pub fn list_payout_store_ids(&self, status: PayoutStatus) -> Result<Vec<String>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT DISTINCT store_id FROM payouts WHERE status = ?")?;
+        let store_ids = stmt
+            .query_map(params![format!("{:?}", status)], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(store_ids)
+    }
+
+    fn query_payouts(&self, clause: &str, args: &[&dyn rusqlite::ToSql]) -> Result<Vec<Payout>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, store_id, destination, amount, currency, description, status, created_by, created_at, txid,
+                    proposal_id
+             FROM payouts {}",
+            clause
+        ))?;
+
+        let payout_iter = stmt.query_map(args, |row| {
+            let status_str: String = row.get(6)?;
+            let created_at_str: String = row.get(8)?;
+
+            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(8, "created_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+
+            let status = match status_str.as_str() {
+                "AwaitingPayment" => PayoutStatus::AwaitingPayment,
+                "InProgress" => PayoutStatus::InProgress,
+                "Completed" => PayoutStatus::Completed,
+                "Cancelled" => PayoutStatus::Cancelled,
+                _ => PayoutStatus::AwaitingApproval,
+            };
+
+            Ok(Payout {
+                id: row.get(0)?,
+                store_id: row.get(1)?,
+                destination: row.get(2)?,
+                amount: row.get(3)?,
+                currency: row.get(4)?,
+                description: row.get(5)?,
+                status,
+                created_by: row.get(7)?,
+                created_at,
+                txid: row.get(9)?,
+                proposal_id: row.get(10)?,
+            })
+        })?;
+
+        let mut payouts = Vec::new();
+        for payout in payout_iter {
+            payouts.push(payout?);
+        }
+
+        Ok(payouts)
+    }
+
+    pub fn save_refund(&self, refund: &Refund) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO refunds (
+                id, invoice_id, store_id, kind, rate, amount, status, destination, payout_id,
+                created_by, created_at, claimed_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                refund.id,
+                refund.invoice_id,
+                refund.store_id,
+                format!("{:?}", refund.kind),
+                format!("{:?}", refund.rate),
+                refund.amount,
+                format!("{:?}", refund.status),
+                refund.destination,
+                refund.payout_id,
+                refund.created_by,
+                refund.created_at.to_rfc3339(),
+                refund.claimed_at.map(|claimed_at| claimed_at.to_rfc3339())
+            ],
+        )?;
+
+        info!("Refund {} saved to database", refund.id);
+        Ok(())
+    }
+
+    pub fn get_refund(&self, id: &str) -> Result<Option<Refund>, SqliteError> {
+        Ok(self.query_refunds("WHERE id = ?", params![id])?.pop())
+    }
+
+    pub fn list_refunds(&self, invoice_id: &str) -> Result<Vec<Refund>, SqliteError> {
+        self.query_refunds("WHERE invoice_id = ? ORDER BY created_at", params![invoice_id])
+    }
+
+    // Satoshis already promised back to an invoice's buyer
+    pub fn refunded_amount(&self, invoice_id: &str) -> Result<u64, SqliteError> {
+        self.conn().query_row(
+            "SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE invoice_id = ? AND status != 'Cancelled'",
+            params![invoice_id],
+            |row| row.get(0),
+        )
+    }
+
+    // Record the buyer's address. Returns false if the refund was no longer
+    // awaiting a claim, so it can only be claimed once.
+    pub fn claim_refund(&self, id: &str, destination: &str, claimed_at: DateTime<Utc>) -> Result<bool, SqliteError> {
+        let updated = self.conn().execute(
+            "UPDATE refunds SET status = 'Claimed', destination = ?, claimed_at = ?
+             WHERE id = ? AND status = 'AwaitingClaim'",
+            params![destination, claimed_at.to_rfc3339(), id],
+        )?;
+        Ok(updated == 1)
+    }
+
+    // Only refunds still awaiting a claim can be cancelled; false if the
+    // buyer claimed it first
+    pub fn cancel_refund(&self, id: &str) -> Result<bool, SqliteError> {
+        let updated = self.conn().execute(
+            "UPDATE refunds SET status = 'Cancelled' WHERE id = ? AND status = 'AwaitingClaim'",
+            params![id],
+        )?;
+        Ok(updated == 1)
+    }
+
+    pub fn update_refund_status(&self, id: &str, status: RefundStatus, payout_id: Option<&str>) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE refunds SET status = ?, payout_id = COALESCE(?, payout_id) WHERE id = ?",
+            params![format!("{:?}", status), payout_id, id],
+        )?;
+
+        info!("Refund {} status updated to {:?}", id, status);
+        Ok(())
+    }
+
+    fn query_refunds(&self, clause: &str, args: &[&dyn rusqlite::ToSql]) -> Result<Vec<Refund>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, invoice_id, store_id, kind, rate, amount, status, destination, payout_id,
+                    created_by, created_at, claimed_at
+             FROM refunds {}",
+            clause
+        ))?;
+
+        let refund_iter = stmt.query_map(args, |row| {
+            let kind_str: String = row.get(3)?;
+            let rate_str: String = row.get(4)?;
+            let status_str: String = row.get(6)?;
+            let created_at_str: String = row.get(10)?;
+            let claimed_at_str: Option<String> = row.get(11)?;
+
+            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(10, "created_at".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+            let claimed_at = match claimed_at_str {
+                Some(claimed_at) => Some(
+                    DateTime::parse_from_rfc3339(&claimed_at)
+                        .map_err(|_| rusqlite::Error::InvalidColumnType(11, "claimed_at".to_string(), rusqlite::types::Type::Text))?
+                        .with_timezone(&Utc),
+                ),
+                None => None,
+            };
+
+            let kind = match kind_str.as_str() {
+                "Overpaid" => RefundKind::Overpaid,
+                "Custom" => RefundKind::Custom,
+                _ => RefundKind::Full,
+            };
+            let rate = match rate_str.as_str() {
+                "Current" => RefundRate::Current,
+                _ => RefundRate::Original,
+            };
+            let status = match status_str.as_str() {
+                "Claimed" => RefundStatus::Claimed,
+                "Cancelled" => RefundStatus::Cancelled,
+                _ => RefundStatus::AwaitingClaim,
+            };
+
+            Ok(Refund {
+                id: row.get(0)?,
+                invoice_id: row.get(1)?,
+                store_id: row.get(2)?,
+                kind,
+                rate,
+                amount: row.get(5)?,
+                status,
+                destination: row.get(7)?,
+                payout_id: row.get(8)?,
+                created_by: row.get(9)?,
+                created_at,
+                claimed_at,
+            })
+        })?;
+
+        let mut refunds = Vec::new();
+        for refund in refund_iter {
+            refunds.push(refund?);
+        }
+
+        Ok(refunds)
+    }
+
+    pub fn save_user_password(&self, username: &str, password_hash: &str) -> Result<(), SqliteError> {
+        let now = Utc::now().to_rfc3339();
+        self.conn().execute(
+            "INSERT INTO users (username, password_hash, created_at, updated_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(username) DO UPDATE SET password_hash = excluded.password_hash, updated_at = excluded.updated_at",
+            params![username, password_hash, now, now],
+        )?;
+        info!("Password set for {}", username);
+        Ok(())
+    }
+
+    pub fn get_password_hash(&self, username: &str) -> Result<Option<String>, SqliteError> {
+        let result = self.conn().query_row(
+            "SELECT password_hash FROM users WHERE username = ?",
+            params![username],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(hash) => Ok(Some(hash)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_user_roles(&self, username: &str, roles: &[Role]) -> Result<(), SqliteError> {
+        self.conn().execute("DELETE FROM user_roles WHERE username = ?", params![username])?;
+        for role in roles {
+            self.conn().execute(
+                "INSERT OR IGNORE INTO user_roles (username, role) VALUES (?, ?)",
+                params![username, role.as_str()],
+            )?;
+        }
+
+        info!("Roles updated for {}", username);
+        Ok(())
+    }
+
+    pub fn get_user_roles(&self, username: &str) -> Result<Vec<Role>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT role FROM user_roles WHERE username = ?")?;
+        let role_iter = stmt.query_map(params![username], |row| row.get::<_, String>(0))?;
+
+        let mut roles = Vec::new();
+        for role in role_iter {
+            if role? == Role::Approver.as_str() {
+                roles.push(Role::Approver);
+            }
+        }
+
+        Ok(roles)
+    }
+
+    // Reserve the next receive index for an account key
+    pub fn next_derivation_index(&self, account: &str) -> Result<u32, SqliteError> {
+        self.conn().execute(
+            "INSERT INTO derivation_indexes (account, next_index) VALUES (?, 1)
+             ON CONFLICT(account) DO UPDATE SET next_index = next_index + 1",
+            params![account],
+        )?;
+        let next: u32 = self.conn().query_row(
+            "SELECT next_index FROM derivation_indexes WHERE account = ?",
+            params![account],
+            |row| row.get(0),
+        )?;
+        Ok(next - 1)
+    }
+
+    // Number of receive indexes reserved so far for an account key
+    pub fn derivation_index(&self, account: &str) -> Result<u32, SqliteError> {
+        match self.conn().query_row(
+            "SELECT next_index FROM derivation_indexes WHERE account = ?",
+            params![account],
+            |row| row.get(0),
+        ) {
+            Ok(next) => Ok(next),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Store a new (not yet confirmed) TOTP secret, replacing any previous enrollment
+    pub fn save_user_totp(&self, username: &str, secret: &str) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO user_totp (
+                username, secret, enabled, last_used_step, created_at
+            ) VALUES (?, ?, 0, NULL, ?)",
+            params![username, secret, Utc::now().to_rfc3339()],
+        )?;
+        self.conn().execute(
+            "DELETE FROM totp_recovery_codes WHERE username = ?",
+            params![username],
+        )?;
+
+        info!("TOTP secret stored for user {}", username);
+        Ok(())
+    }
+
+    pub fn get_user_totp(&self, username: &str) -> Result<Option<UserTotp>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT secret, enabled, last_used_step FROM user_totp WHERE username = ?"
+        )?;
+
+        let totp_result = stmt.query_row(params![username], |row| {
+            let enabled: i64 = row.get(1)?;
+            let last_used_step: Option<i64> = row.get(2)?;
+            Ok(UserTotp {
+                secret: row.get(0)?,
+                enabled: enabled != 0,
+                last_used_step: last_used_step.map(|step| step as u64),
+            })
+        });
+
+        match totp_result {
+            Ok(totp) => Ok(Some(totp)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn enable_user_totp(&self, username: &str, last_used_step: u64) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE user_totp SET enabled = 1, last_used_step = ? WHERE username = ?",
+            params![last_used_step as i64, username],
+        )?;
+
+        info!("TOTP enabled for user {}", username);
+        Ok(())
+    }
+
+    pub fn update_totp_last_used_step(&self, username: &str, step: u64) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "UPDATE user_totp SET last_used_step = ? WHERE username = ?",
+            params![step as i64, username],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_user_totp(&self, username: &str) -> Result<(), SqliteError> {
+        self.conn().execute("DELETE FROM user_totp WHERE username = ?", params![username])?;
+        self.conn().execute(
+            "DELETE FROM totp_recovery_codes WHERE username = ?",
+            params![username],
+        )?;
+
+        info!("TOTP disabled for user {}", username);
+        Ok(())
+    }
+
+    pub fn save_recovery_codes(&self, username: &str, code_hashes: &[String]) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "DELETE FROM totp_recovery_codes WHERE username = ?",
+            params![username],
+        )?;
+        for code_hash in code_hashes {
+            self.conn().execute(
+                "INSERT INTO totp_recovery_codes (username, code_hash, used_at) VALUES (?, ?, NULL)",
+                params![username, code_hash],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Mark a recovery code as used. Returns false if the code is unknown or already used.
+    pub fn consume_recovery_code(&self, username: &str, code_hash: &str) -> Result<bool, SqliteError> {
+        let updated = self.conn().execute(
+            "UPDATE totp_recovery_codes SET used_at = ?
+             WHERE username = ? AND code_hash = ? AND used_at IS NULL",
+            params![Utc::now().to_rfc3339(), username, code_hash],
+        )?;
+        Ok(updated == 1)
+    }
+
+    pub fn log_audit_event(&self, actor: &str, action: &str, ip: Option<&str>, details: serde_json::Value) -> Result<(), SqliteError> {
+        self.conn().execute(
+            "INSERT INTO audit_log (id, timestamp, actor, action, ip, details) VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                Uuid::new_v4().to_string(),
+                Utc::now().to_rfc3339(),
+                actor,
+                action,
+                ip,
+                details.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_audit_events(&self, limit: u32) -> Result<Vec<AuditEvent>, SqliteError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, timestamp, actor, action, ip, details
+             FROM audit_log ORDER BY timestamp DESC LIMIT ?"
+        )?;
+
+        let event_iter = stmt.query_map(params![limit], |row| {
+            let timestamp_str: String = row.get(1)?;
+            let details_str: String = row.get(5)?;
+
+            let timestamp = DateTime::parse_from_rfc3339(&timestamp_str)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(1, "timestamp".to_string(), rusqlite::types::Type::Text))?
+                .with_timezone(&Utc);
+
+            Ok(AuditEvent {
+                id: row.get(0)?,
+                timestamp,
+                actor: row.get(2)?,
+                action: row.get(3)?,
+                ip: row.get(4)?,
+                details: serde_json::from_str(&details_str).unwrap_or(serde_json::Value::Null),
+            })
+        })?;
+
+        let mut events = Vec::new();
+        for event in event_iter {
+            events.push(event?);
+        }
+
+        Ok(events)
+    }
 }
@@ -0,0 +1,365 @@
+use bitcoin::{OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+use bitcoin::secp256k1::Secp256k1;
+use log::{error, info};
+use rusqlite::Error as SqliteError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::str::FromStr;
+
+use crate::models::{Invoice, OutgoingStatus, OutgoingTransaction, Utxo};
+use crate::psbt::{self, Psbt, PsbtError, WalletDescriptor};
+use crate::state::AppState;
+use crate::tx_builder::{self, DUST_LIMIT};
+use crate::wallet::{self, CHANGE_CHAIN};
+
+// BIP125 replacements must pay at least this much more per vbyte of their
+// own size than the transactions they replace
+const INCREMENTAL_RELAY_FEE_RATE: f64 = 1.0;
+
+#[derive(Debug)]
+pub enum BumpError {
+    NotFound,
+    NotUnconfirmed(OutgoingStatus),
+    NotReplaceable,
+    NoChangeOutput,
+    InsufficientChange { available: u64, required: u64 },
+    FeeRateTooLow { current: f64 },
+    NothingToAccelerate,
+    FeeBudgetExceeded { fee: u64, budget: u64 },
+    NoWallet,
+    Blockchain(String),
+    Derivation(PsbtError),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for BumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BumpError::NotFound => write!(f, "Transaction is not tracked"),
+            BumpError::NotUnconfirmed(status) => write!(f, "Transaction is {:?}", status),
+            BumpError::NotReplaceable => write!(f, "Transaction does not signal replace-by-fee; use CPFP"),
+            BumpError::NoChangeOutput => write!(f, "Transaction has no change output to take the extra fee from"),
+            BumpError::InsufficientChange { available, required } => write!(
+                f,
+                "Change output of {} sat cannot cover the {} sat needed",
+                available, required
+            ),
+            BumpError::FeeRateTooLow { current } => {
+                write!(f, "Fee rate must be above the current {:.1} sat/vB", current)
+            }
+            BumpError::NothingToAccelerate => write!(f, "Invoice has no unconfirmed payment in the wallet to accelerate"),
+            BumpError::FeeBudgetExceeded { fee, budget } => write!(
+                f,
+                "Acceleration would pay {} sat, above the policy's budget of {} sat",
+                fee, budget
+            ),
+            BumpError::NoWallet => write!(f, "No wallet configured for this store"),
+            BumpError::Blockchain(msg) => write!(f, "Blockchain backend error: {}", msg),
+            BumpError::Derivation(e) => write!(f, "{}", e),
+            BumpError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BumpError {}
+
+impl From<SqliteError> for BumpError {
+    fn from(error: SqliteError) -> Self {
+        BumpError::Database(error)
+    }
+}
+
+impl From<PsbtError> for BumpError {
+    fn from(error: PsbtError) -> Self {
+        BumpError::Derivation(error)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BumpMethod {
+    Rbf,  // Replace the transaction, paying more from its change
+    Cpfp, // Spend its change in a child paying for both
+}
+
+#[derive(Debug)]
+pub struct BumpedTransaction {
+    pub psbt: Psbt,
+    pub method: BumpMethod,
+    pub fee: u64, // Of the new transaction alone
+    pub vsize: u64,
+    // Of the replacement, or of parent and child together for CPFP
+    pub effective_fee_rate: f64,
+}
+
+// Build an unsigned PSBT that gets a stuck outgoing transaction confirmed at
+// `fee_rate` sat/vB. It is signed and broadcast like any other spend.
+pub async fn bump(
+    data: &AppState,
+    txid: &str,
+    method: BumpMethod,
+    fee_rate: f64,
+) -> Result<(OutgoingTransaction, BumpedTransaction), BumpError> {
+    let original = data.db.get_outgoing_transaction(txid)?.ok_or(BumpError::NotFound)?;
+    if original.status != OutgoingStatus::Unconfirmed {
+        return Err(BumpError::NotUnconfirmed(original.status));
+    }
+    let current = original.fee as f64 / original.vsize as f64;
+    if !(fee_rate > current && fee_rate.is_finite()) {
+        return Err(BumpError::FeeRateTooLow { current });
+    }
+
+    let bumped = match method {
+        BumpMethod::Rbf => replace_by_fee(&original, fee_rate)?,
+        BumpMethod::Cpfp => child_pays_for_parent(data, &original, fee_rate).await?,
+    };
+    info!(
+        "Prepared {:?} bump of {} to {:.1} sat/vB (fee {} sat)",
+        method, txid, bumped.effective_fee_rate, bumped.fee
+    );
+    Ok((original, bumped))
+}
+
+// Same inputs and payments, with the extra fee taken from the change output.
+// Change that would end up as dust is dropped and goes to the fee as well.
+fn replace_by_fee(original: &OutgoingTransaction, fee_rate: f64) -> Result<BumpedTransaction, BumpError> {
+    let mut psbt = psbt::decode_psbt(&original.psbt)?;
+    if !psbt.unsigned_tx.is_explicitly_rbf() {
+        return Err(BumpError::NotReplaceable);
+    }
+    let change_vout = original.change_vout.ok_or(BumpError::NoChangeOutput)? as usize;
+
+    let required_fee = ((fee_rate * original.vsize as f64).ceil() as u64)
+        .max(original.fee + (INCREMENTAL_RELAY_FEE_RATE * original.vsize as f64).ceil() as u64);
+    let extra = required_fee - original.fee;
+    let change = psbt.unsigned_tx.output[change_vout].value;
+    if change < extra {
+        return Err(BumpError::InsufficientChange { available: change, required: extra });
+    }
+
+    let mut fee = required_fee;
+    if change - extra < DUST_LIMIT {
+        psbt.unsigned_tx.output.remove(change_vout);
+        psbt.outputs.remove(change_vout);
+        fee = original.fee + change;
+    } else {
+        psbt.unsigned_tx.output[change_vout].value = change - extra;
+    }
+
+    // Signatures commit to the old outputs
+    for input in psbt.inputs.iter_mut() {
+        input.partial_sigs.clear();
+        input.final_script_sig = None;
+        input.final_script_witness = None;
+    }
+
+    Ok(BumpedTransaction {
+        psbt,
+        method: BumpMethod::Rbf,
+        fee,
+        vsize: original.vsize,
+        effective_fee_rate: fee as f64 / original.vsize as f64,
+    })
+}
+
+// Spend an invoice's unconfirmed payment back to the wallet so the payer's
+// low-fee transaction confirms at `fee_rate`, within the store policy's
+// acceleration budget. Returns the accelerated output.
+pub async fn accelerate_invoice(
+    data: &AppState,
+    invoice: &Invoice,
+    fee_rate: f64,
+) -> Result<(Utxo, BumpedTransaction), BumpError> {
+    let wallet = data.wallet_for(&invoice.store_id).ok_or(BumpError::NoWallet)?;
+    let utxo = data
+        .db
+        .list_utxos(&invoice.store_id)?
+        .into_iter()
+        .filter(|utxo| !utxo.confirmed && utxo.invoice_id.as_deref() == Some(invoice.id.as_str()))
+        .max_by_key(|utxo| utxo.value)
+        .ok_or(BumpError::NothingToAccelerate)?;
+
+    let parent = data
+        .blockchain_client
+        .get_tx_info(&utxo.txid)
+        .await
+        .map_err(BumpError::Blockchain)?;
+    if parent.status.confirmed {
+        return Err(BumpError::NothingToAccelerate);
+    }
+    let parent_vsize = parent.weight.div_ceil(4);
+    let current = parent.fee as f64 / parent_vsize as f64;
+    if !(fee_rate > current && fee_rate.is_finite()) {
+        return Err(BumpError::FeeRateTooLow { current });
+    }
+
+    let script_pubkey = ScriptBuf::from_bytes(
+        hex::decode(&utxo.script_pubkey).map_err(|e| PsbtError::MissingInputData(e.to_string()))?,
+    );
+    let mut input = bitcoin::psbt::Input {
+        witness_utxo: Some(TxOut { value: utxo.value, script_pubkey }),
+        ..Default::default()
+    };
+    if let Some(derived) = tx_builder::derive_utxo(&wallet, &utxo)? {
+        for (public_key, key_source) in derived.keys {
+            input.bip32_derivation.insert(public_key.inner, key_source);
+        }
+        input.redeem_script = derived.redeem_script;
+        input.witness_script = derived.witness_script;
+    }
+    let txid = Txid::from_str(&utxo.txid).map_err(|e| PsbtError::MissingInputData(e.to_string()))?;
+
+    let child = build_child(data, &wallet, OutPoint::new(txid, utxo.vout), input, parent.fee, parent_vsize, fee_rate).await?;
+    let policy = data.db.get_spending_policy(&invoice.store_id)?.unwrap_or_default();
+    if let Some(budget) = policy.max_acceleration_fee {
+        if child.fee > budget {
+            return Err(BumpError::FeeBudgetExceeded { fee: child.fee, budget });
+        }
+    }
+
+    info!(
+        "Prepared acceleration of invoice {} payment {}:{} to {:.1} sat/vB (fee {} sat)",
+        invoice.id, utxo.txid, utxo.vout, child.effective_fee_rate, child.fee
+    );
+    Ok((utxo, child))
+}
+
+// Note a prepared invoice acceleration on the invoice's timeline once its
+// child, prepared as `unsigned_txid`, is broadcast as `txid`
+pub fn record_acceleration(data: &AppState, unsigned_txid: &str, txid: &str) {
+    let (invoice_id, mut details) = match data.db.take_pending_acceleration(unsigned_txid) {
+        Ok(Some(pending)) => pending,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to look up acceleration {}: {}", unsigned_txid, e);
+            return;
+        }
+    };
+    details["txid"] = json!(txid);
+    let message = format!(
+        "Payment {} accelerated: child {} pays {} sat for {:.1} sat/vB",
+        details["parent_txid"].as_str().unwrap_or_default(),
+        txid,
+        details["fee"],
+        details["effective_fee_rate"].as_f64().unwrap_or_default()
+    );
+    data.record_invoice_event(&invoice_id, "payment_accelerated", message, details);
+}
+
+// A child spending the original's change back to the wallet, paying enough
+// that parent and child together reach `fee_rate`
+async fn child_pays_for_parent(
+    data: &AppState,
+    original: &OutgoingTransaction,
+    fee_rate: f64,
+) -> Result<BumpedTransaction, BumpError> {
+    let wallet = data.wallet_for(&original.store_id).ok_or(BumpError::NoWallet)?;
+    let parent = psbt::decode_psbt(&original.psbt)?;
+    let change_vout = original.change_vout.ok_or(BumpError::NoChangeOutput)?;
+    let parent_txid = Txid::from_str(&original.txid).map_err(|e| PsbtError::MissingInputData(e.to_string()))?;
+
+    // The parent's change output already carries the scripts and key
+    // origins needed to sign for it
+    let parent_change = &parent.outputs[change_vout as usize];
+    let input = bitcoin::psbt::Input {
+        witness_utxo: Some(parent.unsigned_tx.output[change_vout as usize].clone()),
+        bip32_derivation: parent_change.bip32_derivation.clone(),
+        redeem_script: parent_change.redeem_script.clone(),
+        witness_script: parent_change.witness_script.clone(),
+        ..Default::default()
+    };
+
+    build_child(data, &wallet, OutPoint::new(parent_txid, change_vout), input, original.fee, original.vsize, fee_rate).await
+}
+
+// Spend one unconfirmed output of the wallet back to a fresh change address,
+// with a fee bringing the parent (`parent_fee` over `parent_vsize`) and the
+// child together to `fee_rate`. `input` must carry the spent output.
+pub async fn build_child(
+    data: &AppState,
+    wallet: &WalletDescriptor,
+    outpoint: OutPoint,
+    input: bitcoin::psbt::Input,
+    parent_fee: u64,
+    parent_vsize: u64,
+    fee_rate: f64,
+) -> Result<BumpedTransaction, BumpError> {
+    let spent = input
+        .witness_utxo
+        .clone()
+        .ok_or_else(|| PsbtError::MissingInputData(format!("value of {}", outpoint)))?;
+
+    let secp = Secp256k1::verification_only();
+    let index = data.db.next_derivation_index(&wallet::change_account(wallet))?;
+    let derived = wallet.derive(&secp, CHANGE_CHAIN, index)?;
+
+    let mut tx = Transaction {
+        version: 2,
+        lock_time: tx_builder::anti_fee_sniping_locktime(data).await,
+        input: Vec::new(),
+        output: vec![TxOut { value: 0, script_pubkey: derived.script_pubkey.clone() }],
+    };
+    let weight = tx.weight().to_wu()
+        + if tx_builder::is_segwit(wallet) { 2 } else { 0 }
+        + tx_builder::input_weight(wallet);
+    let vsize = weight.div_ceil(4);
+
+    let package_fee = (fee_rate * (parent_vsize + vsize) as f64).ceil() as u64;
+    let fee = package_fee
+        .saturating_sub(parent_fee)
+        .max((INCREMENTAL_RELAY_FEE_RATE * vsize as f64).ceil() as u64);
+    if spent.value < fee + DUST_LIMIT {
+        return Err(BumpError::InsufficientChange { available: spent.value, required: fee + DUST_LIMIT });
+    }
+    tx.output[0].value = spent.value - fee;
+    tx.input.push(TxIn {
+        previous_output: outpoint,
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        witness: Witness::new(),
+    });
+
+    let mut psbt = Psbt::from_unsigned_tx(tx).map_err(|e| PsbtError::InvalidEncoding(e.to_string()))?;
+    psbt.inputs[0] = input;
+    let output = &mut psbt.outputs[0];
+    for (public_key, key_source) in derived.keys {
+        output.bip32_derivation.insert(public_key.inner, key_source);
+    }
+    output.redeem_script = derived.redeem_script;
+    output.witness_script = derived.witness_script;
+
+    Ok(BumpedTransaction {
+        psbt,
+        method: BumpMethod::Cpfp,
+        fee,
+        vsize,
+        effective_fee_rate: (parent_fee + fee) as f64 / (parent_vsize + vsize) as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_types(data: &AppState) -> Vec<String> {
+        data.db.list_invoice_events("invoice").unwrap().into_iter().map(|event| event.event_type).collect()
+    }
+
+    #[test]
+    fn accelerations_are_noted_once_broadcast() {
+        let data = AppState::new(":memory:");
+        let details = json!({ "parent_txid": "parent", "txid": "unsigned", "fee": 2_000, "effective_fee_rate": 12.5 });
+        data.db.save_pending_acceleration("unsigned", "invoice", &details).unwrap();
+
+        // Other broadcasts leave it pending
+        record_acceleration(&data, "other", "other");
+        assert!(event_types(&data).is_empty());
+
+        record_acceleration(&data, "unsigned", "signed");
+        record_acceleration(&data, "unsigned", "signed");
+        let events = data.db.list_invoice_events("invoice").unwrap();
+        assert_eq!(event_types(&data), ["payment_accelerated"]);
+        assert_eq!(events[0].details["txid"], "signed");
+        assert_eq!(events[0].message, "Payment parent accelerated: child signed pays 2000 sat for 12.5 sat/vB");
+    }
+}
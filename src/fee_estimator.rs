@@ -0,0 +1,181 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::blockchain::BlockchainClient;
+use crate::core_rpc::CoreRpc;
+
+// Confirmation targets (in blocks) estimates are reported for
+pub const TARGET_BUCKETS: [u32; 8] = [1, 2, 3, 6, 12, 24, 144, 1008];
+pub const DEFAULT_TARGET_BLOCKS: u32 = 6;
+const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+const DEFAULT_FEE_RATE_FLOOR: f64 = 1.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeBucket {
+    pub target_blocks: u32,
+    pub fee_rate: f64, // sat/vB
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeEstimates {
+    pub buckets: Vec<FeeBucket>,
+    // Sources that answered; empty when every bucket is the floor
+    pub sources: Vec<String>,
+    pub errors: Vec<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl FeeEstimates {
+    // Fee rate for confirmation within `target_blocks`, from the nearest
+    // bucket at or below the target
+    pub fn fee_rate_for(&self, target_blocks: u32) -> f64 {
+        self.buckets
+            .iter()
+            .rev()
+            .find(|bucket| bucket.target_blocks <= target_blocks)
+            .or_else(|| self.buckets.first())
+            .map_or(DEFAULT_FEE_RATE_FLOOR, |bucket| bucket.fee_rate)
+    }
+}
+
+// Combines fee estimates from the Esplora backend and, when configured,
+// Bitcoin Core. Estimates are cached; when every source fails the configured
+// floor is used.
+pub struct FeeEstimator {
+    core_rpc: Option<CoreRpc>,
+    floor: f64,
+    cache_ttl: Duration,
+    cache: Mutex<Option<(Instant, FeeEstimates)>>,
+}
+
+impl FeeEstimator {
+    // BITCOIN_RPC_URL (with BITCOIN_RPC_USER/BITCOIN_RPC_PASSWORD) enables
+    // Core estimates; FEE_RATE_FLOOR (sat/vB, default 1) and
+    // FEE_CACHE_TTL_SECS (default 60) tune the rest
+    pub fn from_env() -> Self {
+        let core_rpc = CoreRpc::from_env();
+        let floor = std::env::var("FEE_RATE_FLOOR")
+            .ok()
+            .and_then(|floor| floor.parse::<f64>().ok())
+            .filter(|floor| *floor > 0.0)
+            .unwrap_or(DEFAULT_FEE_RATE_FLOOR);
+        let cache_ttl = std::env::var("FEE_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_TTL_SECS);
+
+        Self {
+            core_rpc,
+            floor,
+            cache_ttl: Duration::from_secs(cache_ttl),
+            cache: Mutex::new(None),
+        }
+    }
+
+    // Current estimates, from the cache while it is fresh
+    pub async fn estimates(&self, blockchain: &BlockchainClient) -> FeeEstimates {
+        let mut cache = self.cache.lock().await;
+        if let Some((fetched, estimates)) = cache.as_ref() {
+            if fetched.elapsed() < self.cache_ttl {
+                return estimates.clone();
+            }
+        }
+
+        let estimates = self.fetch(blockchain).await;
+        // Don't hold on to a floor-only result; retry on the next request
+        if !estimates.sources.is_empty() {
+            *cache = Some((Instant::now(), estimates.clone()));
+        }
+        estimates
+    }
+
+    pub async fn fee_rate_for(&self, blockchain: &BlockchainClient, target_blocks: u32) -> f64 {
+        self.estimates(blockchain).await.fee_rate_for(target_blocks)
+    }
+
+    async fn fetch(&self, blockchain: &BlockchainClient) -> FeeEstimates {
+        let mut sources = Vec::new();
+        let mut errors = Vec::new();
+        let mut by_source: Vec<HashMap<u32, f64>> = Vec::new();
+
+        match blockchain.get_fee_estimates().await {
+            Ok(estimates) => {
+                sources.push("esplora".to_string());
+                by_source.push(estimates);
+            }
+            Err(e) => errors.push(format!("esplora: {}", e)),
+        }
+        if let Some(rpc) = &self.core_rpc {
+            match self.core_estimates(rpc).await {
+                Ok(estimates) => {
+                    sources.push("bitcoind".to_string());
+                    by_source.push(estimates);
+                }
+                Err(e) => errors.push(format!("bitcoind: {}", e)),
+            }
+        }
+        for error in &errors {
+            warn!("Fee estimate source failed: {}", error);
+        }
+
+        // Take the highest estimate any source gives for a bucket, so one
+        // lagging source cannot leave transactions stuck, and never ask for
+        // more to wait longer
+        let mut buckets: Vec<FeeBucket> = Vec::with_capacity(TARGET_BUCKETS.len());
+        for target in TARGET_BUCKETS {
+            let estimate = by_source
+                .iter()
+                .filter_map(|estimates| nearest_estimate(estimates, target))
+                .fold(self.floor, f64::max);
+            let fee_rate = match buckets.last() {
+                Some(previous) => estimate.min(previous.fee_rate),
+                None => estimate,
+            };
+            buckets.push(FeeBucket {
+                target_blocks: target,
+                fee_rate: (fee_rate * 100.0).round() / 100.0,
+            });
+        }
+
+        FeeEstimates {
+            buckets,
+            sources,
+            errors,
+            fetched_at: Utc::now(),
+        }
+    }
+
+    async fn core_estimates(&self, rpc: &CoreRpc) -> Result<HashMap<u32, f64>, String> {
+        let mut estimates = HashMap::new();
+        for target in TARGET_BUCKETS {
+            let result = rpc
+                .call("estimatesmartfee", json!([target]))
+                .await
+                .map_err(|e| format!("estimatesmartfee failed: {}", e))?;
+            // BTC/kvB; missing while the node lacks data for the target
+            if let Some(btc_per_kvb) = result["feerate"].as_f64() {
+                estimates.insert(target, btc_per_kvb * 100_000_000.0 / 1000.0);
+            }
+        }
+
+        if estimates.is_empty() {
+            return Err("no estimates available yet".to_string());
+        }
+        Ok(estimates)
+    }
+}
+
+// The estimate for the largest target not above `target`, since sources
+// report different sets of targets
+fn nearest_estimate(estimates: &HashMap<u32, f64>, target: u32) -> Option<f64> {
+    estimates
+        .iter()
+        .filter(|(blocks, _)| **blocks <= target)
+        .max_by_key(|(blocks, _)| **blocks)
+        .map(|(_, fee_rate)| *fee_rate)
+}
@@ -1,24 +1,389 @@
 
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use bitcoin::bip32::DerivationPath;
 use bitcoin::{Address, Network};
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::key::{PublicKey, PrivateKey};
-use bitcoin::consensus::Decodable;
-use rand;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use log::info;
 use uuid::Uuid;
-use std::str::FromStr;
+use zeroize::Zeroizing;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::models::{Invoice, InvoiceStatus, PaymentRequest};
+use crate::models::{
+    ExportStatus, Invoice, InvoiceStatus, OutgoingStatus, PaymentRequest, PayoutStatus, ProposalStatus, PsbtExport, Refund, RefundKind, RefundRate,
+    Role, SpendProposal, SpendingPolicy, StoreWallet, ZeroConfPolicy, DEFAULT_STORE_ID,
+};
 use crate::state::AppState;
 use crate::auth;
+use crate::login_guard::LoginAttempt;
+use crate::totp::{self, TwoFactorError};
+use crate::users::{self, UserError};
+use crate::psbt::{self, AccountKey, Psbt, PsbtError, ScriptType, WalletDescriptor};
+use crate::signer::{Signer, SignerError};
+use crate::fee_bump::{self, BumpError, BumpMethod};
+use crate::fee_estimator;
+use crate::trezor::{PromptReply, TrezorError};
+use crate::address_verifier::{self, VerifyError};
+use crate::air_gap::{self, ExportFormat};
+use crate::broadcast::{self, BroadcastError};
+use crate::chain_watcher;
+use crate::payouts::{self, NewPayout, PayoutError};
+use crate::proposals::{self, NewProposal, ProposalError};
+use crate::refunds::{self, NewRefund, RefundError};
+use crate::spending_policy::{self, PolicyError, PolicyEvaluation, PolicyViolation};
+use crate::tx_builder::{self, BuildError, ChangeOutput, Destination, SelectionAlgorithm};
+use crate::tx_decoder;
+use crate::tx_validation;
+use crate::wallet::{self, WalletError};
+use crate::wallet_setup::{self, ImportedWallet, WalletSetupError, WalletSource};
+use crate::zero_conf;
+
+// Header carrying the TOTP (or recovery) code for step-up authentication
+const SECOND_FACTOR_HEADER: &str = "X-2FA-Code";
+const TOTP_ISSUER: &str = "BTC Pay Server";
+pub const ADMIN_USERNAME: &str = "admin";
+const DEFAULT_AUDIT_LIMIT: u32 = 100;
 
 #[derive(Deserialize)]
 pub struct AuthRequest {
     username: String,
     password: String,
+    #[serde(default)]
+    totp_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SignPsbtRequest {
+    psbt: String, // Base64 encoded PSBT (BIP174)
+    #[serde(default)]
+    signer: SignerKind,
+    #[serde(default = "crate::models::default_store_id")]
+    store_id: String, // Store whose wallet and spending policy apply
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SignerKind {
+    #[default]
+    Trezor,
+    Software,
+}
+
+#[derive(Serialize)]
+pub struct SignPsbtResponse {
+    psbt: String,
+    complete: bool,
+    txid: Option<String>,
+    fee: Option<u64>, // Fee in satoshis, when all input values are known
+    // Signatures other cosigners still need to add
+    missing_signatures: usize,
+}
+
+#[derive(Deserialize)]
+pub struct CombinePsbtRequest {
+    psbts: Vec<String>, // Copies of one PSBT signed by different cosigners
+    #[serde(default = "crate::models::default_store_id")]
+    store_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ProposalSignaturesRequest {
+    psbt: String, // The proposal's PSBT signed by a cosigner
+}
+
+#[derive(Deserialize)]
+pub struct BuildTransactionRequest {
+    destinations: Vec<Destination>,
+    // sat/vB; estimated for `target_blocks` when not given
+    fee_rate: Option<f64>,
+    target_blocks: Option<u32>,
+    #[serde(default = "crate::models::default_store_id")]
+    store_id: String,
+    // Also spend outputs that are not confirmed yet
+    #[serde(default)]
+    include_unconfirmed: bool,
+}
+
+#[derive(Serialize)]
+pub struct BuildTransactionResponse {
+    psbt: String,
+    fee: u64,
+    fee_rate: f64, // Requested or estimated sat/vB
+    vsize: u64,
+    algorithm: SelectionAlgorithm,
+    inputs: Vec<String>, // Spent outpoints, "txid:vout"
+    change: Option<ChangeOutput>,
+    // The inputs are held back from other builds until then
+    reserved_until: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct ReleaseTransactionRequest {
+    psbt: String, // Base64 PSBT returned by /transaction/build
+}
+
+#[derive(Deserialize)]
+pub struct ExportPsbtRequest {
+    psbt: String, // Base64 encoded PSBT (BIP174)
+    #[serde(default = "crate::models::default_store_id")]
+    store_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportDownloadQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    // Bytes of PSBT data per QR frame (ur and bbqr formats)
+    fragment_len: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct QrFramesResponse {
+    format: ExportFormat,
+    // Show in order, looping, as an animated QR code
+    frames: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DecodeTransactionRequest {
+    data: String, // Base64/hex PSBT or hex raw transaction
+    #[serde(default = "crate::models::default_store_id")]
+    store_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateProposalRequest {
+    psbt: String, // Base64 encoded PSBT (BIP174)
+    #[serde(default = "crate::models::default_store_id")]
+    store_id: String,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProposalQuery {
+    status: Option<ProposalStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct ProposalVoteRequest {
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SignProposalRequest {
+    #[serde(default)]
+    signer: SignerKind,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserRoles {
+    roles: Vec<Role>,
+}
+
+#[derive(Deserialize)]
+pub struct SetPasswordRequest {
+    password: String,
+    // Users changing their own password must confirm the current one
+    #[serde(default)]
+    current_password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockRequest {
+    username: Option<String>,
+    ip: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LockoutInfo {
+    username: String,
+    remaining_secs: u64,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollmentResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TrezorConnectRequest {
+    device_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TrezorPinRequest {
+    pin: String, // Matrix positions (1-9) of the PIN digits shown on the device
+}
+
+#[derive(Deserialize)]
+pub struct TrezorPassphraseRequest {
+    #[serde(default)]
+    passphrase: String,
+    #[serde(default)]
+    on_device: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SignerXpubRequest {
+    path: String, // e.g. "m/84'/1'/0'"
+}
+
+#[derive(Deserialize)]
+pub struct SignerAddressRequest {
+    path: String, // e.g. "m/84'/1'/0'/0/0"
+    #[serde(default = "default_script_type")]
+    script_type: ScriptType,
+}
+
+fn default_script_type() -> ScriptType {
+    ScriptType::NativeSegwit
+}
+
+#[derive(Deserialize)]
+pub struct VerifyAddressQuery {
+    // Set to false to compare without showing the address on the device
+    display: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct WalletSetupRequest {
+    source: WalletSource,
+    // xpub, descriptor or wallet file contents, depending on the source
+    data: Option<String>,
+    script_type: Option<ScriptType>,
+    fingerprint: Option<String>,
+    derivation_path: Option<String>,
+    account: Option<u32>, // Account index to read from the Trezor
+    label: Option<String>,
+    // Without confirm the wallet is only validated and previewed
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Serialize)]
+pub struct WalletKeyInfo {
+    fingerprint: String,
+    derivation_path: String,
+    xpub: String,
+}
+
+#[derive(Serialize)]
+pub struct WalletSetupResponse {
+    store_id: String,
+    descriptor: String,
+    // Single-key wallets
+    fingerprint: Option<String>,
+    derivation_path: Option<String>,
+    xpub: Option<String>,
+    script_type: Option<ScriptType>,
+    // Multisig wallets
+    threshold: Option<usize>,
+    cosigners: Vec<WalletKeyInfo>,
+    label: Option<String>,
+    receive_addresses: Vec<String>,
+    warnings: Vec<String>,
+    saved: bool,
+}
+
+#[derive(Deserialize)]
+pub struct OutgoingQuery {
+    store_id: Option<String>,
+    status: Option<OutgoingStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct BumpTransactionRequest {
+    method: BumpMethod,
+    // sat/vB; estimated for `target_blocks` when absent
+    fee_rate: Option<f64>,
+    target_blocks: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct AccelerateInvoiceRequest {
+    fee_rate: Option<f64>,
+    target_blocks: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct AccelerateInvoiceResponse {
+    invoice_id: String,
+    parent_txid: String,
+    txid: String, // Of the child, once signed
+    psbt: String, // Unsigned; sign it through /transaction/sign
+    fee: u64,
+    vsize: u64,
+    effective_fee_rate: f64,
+}
+
+#[derive(Serialize)]
+pub struct BumpTransactionResponse {
+    original_txid: String,
+    store_id: String,
+    method: BumpMethod,
+    psbt: String, // Unsigned; sign it through /transaction/sign
+    fee: u64,
+    vsize: u64,
+    effective_fee_rate: f64,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePayoutRequest {
+    destination: String,
+    // Whole satoshis, or a decimal string with `currency: "BTC"`
+    amount: serde_json::Value,
+    currency: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PayoutQuery {
+    status: Option<PayoutStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRefundRequest {
+    kind: RefundKind,
+    amount: Option<u64>, // Satoshis, for custom refunds
+    #[serde(default)]
+    rate: RefundRate,
+}
+
+#[derive(Deserialize)]
+pub struct ClaimRefundRequest {
+    destination: String,
+}
+
+#[derive(Serialize)]
+pub struct RefundResponse {
+    #[serde(flatten)]
+    refund: Refund,
+    // Link for the buyer to submit their address
+    claim_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct UtxoQuery {
+    #[serde(default)]
+    confirmed_only: bool,
 }
 
 #[derive(Serialize)]
@@ -32,15 +397,27 @@ pub async fn create_invoice(
 ) -> impl Responder {
     let payment_req = payment_req.into_inner();
 
-    // Generate a new Bitcoin address
-    let secp = Secp256k1::new();
-    let mut rng = rand::thread_rng();
-    // Generate a secret key first
-    let secret_key = bitcoin::secp256k1::SecretKey::new(&mut rng);
-    // Create private key with the secret key and network
-    let private_key = PrivateKey::new(secret_key, Network::Testnet);
-    let public_key = PublicKey::from_private_key(&secp, &private_key);
-    let address = Address::p2pkh(&public_key, Network::Testnet);
+    // Derive the next receive address of the wallet when an account key is
+    // configured, so the address can be verified on the hardware wallet
+    let (address, derivation_path) = match data.wallet_for(&payment_req.store_id) {
+        Some(wallet) => match address_verifier::derive_receive_address(&data, &wallet, Network::Testnet) {
+            Ok((address, path)) => (address, Some(path.to_string())),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error deriving address: {}", e)),
+        },
+        // Other stores never receive to keys that aren't theirs
+        None if payment_req.store_id != DEFAULT_STORE_ID => return wallet_error_response(WalletError::NoWallet),
+        None => {
+            // Generate a new Bitcoin address
+            let secp = Secp256k1::new();
+            let mut rng = rand::thread_rng();
+            // Generate a secret key first
+            let secret_key = bitcoin::secp256k1::SecretKey::new(&mut rng);
+            // Create private key with the secret key and network
+            let private_key = PrivateKey::new(secret_key, Network::Testnet);
+            let public_key = PublicKey::from_private_key(&secp, &private_key);
+            (Address::p2pkh(&public_key, Network::Testnet), None)
+        }
+    };
 
     // Create a new invoice
     let id = Uuid::new_v4().to_string();
@@ -55,9 +432,14 @@ pub async fn create_invoice(
         status: InvoiceStatus::Pending,
         created_at: now,
         expires_at,
+        derivation_path,
+        store_id: payment_req.store_id,
     };
 
-    // Store the invoice
+    // Store the invoice; the chain watcher and wallet sync look it up in the database
+    if let Err(e) = data.db.save_invoice(&invoice) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
     {
         let mut invoices = data.invoices.lock().unwrap();
         invoices.insert(id.clone(), invoice.clone());
@@ -82,92 +464,2052 @@ pub async fn check_payment_status(
     id: web::Path<String>,
     data: web::Data<AppState>,
 ) -> impl Responder {
-    let invoice_id = id.into_inner();
-    let mut invoices = data.invoices.lock().unwrap();
-    
-    if let Some(invoice) = invoices.get_mut(&invoice_id) {
-        // Create blockchain client and check for transactions
-        let blockchain_client = crate::blockchain::BlockchainClient::new("https://mempool.space/api".to_string());
-        
-        // Parse the invoice address
-        match Address::from_str(&invoice.address) {
-            Ok(unchecked_address) => {
-                // Convert to checked address with the appropriate network
-                let address = unchecked_address.require_network(Network::Testnet).unwrap();
-                
-                // Check for transactions to this address
-                match blockchain_client.check_address_transactions(&address).await {
-                    Ok(has_transactions) => {
-                        if has_transactions {
-                            invoice.status = InvoiceStatus::Paid;
-                        } else {
-                            // Check for expiry
-                            let now = Utc::now();
-                            if now > invoice.expires_at {
-                                invoice.status = InvoiceStatus::Expired;
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        log::error!("Error checking transactions: {}", e);
-                    }
-                }
-            },
-            Err(e) => {
-                log::error!("Error parsing address: {}", e);
-            }
-        }
+    let invoice = match data.invoice(&id) {
+        Ok(Some(invoice)) => invoice,
+        Ok(None) => return HttpResponse::NotFound().body("Invoice not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+
+    // Payments are tracked by the chain watcher; only the status is refreshed
+    // here. If that fails the status it last recorded is returned.
+    if let Err(e) = chain_watcher::refresh_status(&data, &invoice).await {
+        log::error!("Failed to refresh the status of invoice {}: {}", invoice.id, e);
+    }
+    match data.invoice(&id) {
+        Ok(Some(invoice)) => HttpResponse::Ok().json(invoice),
+        Ok(None) => HttpResponse::NotFound().body("Invoice not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
 
 pub async fn generate_token(
+    http_req: HttpRequest,
     req: web::Json<AuthRequest>,
     jwt_secret: web::Data<String>,
+    data: web::Data<AppState>,
 ) -> impl Responder {
-    // In a real application, validate credentials against a database
-    // This is a simplified example for demonstration
-    if req.username == "admin" && req.password == "secure_password" {
-        match auth::generate_token(&req.username, jwt_secret.get_ref().as_bytes()) {
-            Ok(token) => HttpResponse::Ok().json(TokenResponse { token }),
-            Err(_) => HttpResponse::InternalServerError().body("Could not generate token"),
+    let ip = client_ip(&http_req);
+
+    // Refuse attempts while the username or IP is delayed, locked out or
+    // already trying
+    let attempt = match data.login_guard.begin(&req.username, &ip) {
+        Ok(attempt) => attempt,
+        Err(blocked) => {
+            record_audit(&data, &req.username, "login.blocked", Some(&ip), json!({
+                "reason": format!("{:?}", blocked.reason),
+                "retry_after_secs": blocked.retry_after.as_secs(),
+            }));
+            return HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", blocked.retry_after.as_secs().max(1).to_string()))
+                .body(blocked.to_string());
         }
-    } else {
-        HttpResponse::Unauthorized().body("Invalid credentials")
+    };
+
+    match users::authenticate(&data.db, &req.username, &req.password) {
+        Ok(()) => {}
+        Err(UserError::Database(e)) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+        }
+        Err(_) => {
+            record_failed_login(&data, attempt, &req.username, &ip, "invalid_credentials");
+            return HttpResponse::Unauthorized().body("Invalid credentials");
+        }
+    }
+
+    // Users with TOTP enabled must also present a valid code
+    if let Err(e) = totp::check_second_factor(&data.db, &req.username, req.totp_code.as_deref()) {
+        if matches!(e, TwoFactorError::InvalidCode) {
+            record_failed_login(&data, attempt, &req.username, &ip, "invalid_2fa_code");
+        }
+        return second_factor_error_response(e);
+    }
+
+    attempt.record_success();
+    record_audit(&data, &req.username, "login.succeeded", Some(&ip), json!({}));
+
+    match auth::generate_token(&req.username, jwt_secret.get_ref().as_bytes()) {
+        Ok(token) => HttpResponse::Ok().json(TokenResponse { token }),
+        Err(_) => HttpResponse::InternalServerError().body("Could not generate token"),
     }
 }
 
-        
-        HttpResponse::Ok().json(invoice.clone())
-    } else {
-        HttpResponse::NotFound().body("Invoice not found")
+// Decode a transaction or PSBT so it can be reviewed before signing
+pub async fn decode_transaction(
+    decode_req: web::Json<DecodeTransactionRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match tx_decoder::decode(&data, &decode_req.data, &decode_req.store_id, Network::Testnet).await {
+        Ok(decoded) => HttpResponse::Ok().json(decoded),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
     }
 }
 
 pub async fn sign_transaction(
-    tx_data: web::Json<String>,
-    _data: web::Data<AppState>,
-) -> impl Responder {
-    // Parse the transaction hex - extract the string from JSON first
-    match bitcoin::Transaction::consensus_decode(&mut hex::decode(&tx_data.into_inner()).unwrap().as_slice()) {
-        Ok(unsigned_tx) => {
-            // Create Trezor client and sign transaction
-            let trezor_client = crate::trezor::TrezorClient::new();
-            
-            match trezor_client.sign_transaction(&unsigned_tx).await {
-                Ok(signed_tx) => {
-                    // Create blockchain client to broadcast the transaction
-                    let blockchain_client = crate::blockchain::BlockchainClient::new("https://mempool.space/api".to_string());
-                    
-                    // Serialize the signed transaction to hex
-                    let tx_hex = hex::encode(bitcoin::consensus::encode::serialize(&signed_tx));
-                    
-                    // Broadcast the transaction
-                    match blockchain_client.broadcast_transaction(&tx_hex).await {
-                        Ok(txid) => HttpResponse::Ok().json(txid),
-                        Err(e) => HttpResponse::InternalServerError().body(format!("Error broadcasting: {}", e))
-                    }
-                },
-                Err(e) => HttpResponse::InternalServerError().body(format!("Error signing: {}", e))
-            }
+    req: HttpRequest,
+    sign_req: web::Json<SignPsbtRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Signing moves funds, so require a fresh second factor
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let (psbt, wallet) = match prepare_psbt(&data, &sign_req.psbt, &sign_req.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+
+    // Nothing is signed unless the store's spending policy allows it
+    let evaluation = match check_direct_spend(&req, &data, &username, &sign_req.store_id, &psbt, &wallet) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+
+    match sign_and_broadcast(&data, psbt, sign_req.signer, &sign_req.store_id, evaluation.amount).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(response) => response,
+    }
+}
+
+// Combine copies of a PSBT signed by different cosigners (Trezors, the
+// software signer, air-gapped devices) and broadcast once every input has
+// enough signatures
+pub async fn combine_transaction(
+    req: HttpRequest,
+    body: web::Json<CombinePsbtRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let combined = match psbt::combine_psbts(&body.psbts) {
+        Ok(combined) => combined,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (psbt, wallet) = match complete_psbt(&data, combined, &body.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+    if let Err(e) = tx_validation::verify_partial_signatures(&psbt) {
+        return HttpResponse::UnprocessableEntity().body(format!("Invalid signature: {}", e));
+    }
+
+    let evaluation = match check_direct_spend(&req, &data, &username, &body.store_id, &psbt, &wallet) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+
+    match finalize_and_broadcast(&data, psbt, &body.store_id, evaluation.amount).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(response) => response,
+    }
+}
+
+// Prepare a PSBT for an air-gapped signer: the UTXO data and key origins it
+// needs are added and the spending policy is checked up front, then the PSBT
+// is kept until its signed copy is uploaded
+pub async fn export_transaction(
+    req: HttpRequest,
+    body: web::Json<ExportPsbtRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+
+    let (psbt, wallet) = match prepare_psbt(&data, &body.psbt, &body.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+    let evaluation = match check_direct_spend(&req, &data, &username, &body.store_id, &psbt, &wallet) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+
+    let export = PsbtExport {
+        id: Uuid::new_v4().to_string(),
+        store_id: body.store_id.clone(),
+        psbt: psbt::encode_psbt(&psbt),
+        amount: evaluation.amount,
+        created_by: username.clone(),
+        created_at: Utc::now(),
+        status: ExportStatus::Pending,
+        txid: None,
+    };
+    if let Err(e) = data.db.save_psbt_export(&export) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    record_audit(&data, &username, "psbt.exported", Some(&client_ip(&req)), json!({
+        "export_id": export.id,
+        "store_id": export.store_id,
+        "amount": export.amount,
+    }));
+    HttpResponse::Ok().json(export)
+}
+
+pub async fn get_psbt_export(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.get_psbt_export(&id) {
+        Ok(Some(export)) => HttpResponse::Ok().json(export),
+        Ok(None) => HttpResponse::NotFound().body("PSBT export not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Download an exported PSBT as a .psbt file, base64 text, or animated QR
+// frames for signers that only have a camera
+pub async fn download_psbt_export(
+    id: web::Path<String>,
+    query: web::Query<ExportDownloadQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let export = match data.db.get_psbt_export(&id) {
+        Ok(Some(export)) => export,
+        Ok(None) => return HttpResponse::NotFound().body("PSBT export not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+    let psbt = match psbt::decode_psbt(&export.psbt) {
+        Ok(psbt) => psbt,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let fragment_len = query.fragment_len.unwrap_or(air_gap::DEFAULT_FRAGMENT_LEN);
+    let frames = match query.format {
+        ExportFormat::Base64 => return HttpResponse::Ok().content_type("text/plain").body(export.psbt),
+        ExportFormat::Binary => {
+            return HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.psbt\"", export.id)))
+                .body(psbt.serialize())
+        }
+        ExportFormat::Ur => air_gap::ur_frames(&psbt, fragment_len),
+        ExportFormat::Bbqr => match air_gap::bbqr_frames(&psbt, fragment_len) {
+            Ok(frames) => frames,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
         },
-        Err(e) => HttpResponse::BadRequest().body(format!("Invalid transaction: {}", e))
+    };
+    HttpResponse::Ok().json(QrFramesResponse { format: query.format, frames })
+}
+
+// Upload the PSBT signed on an air-gapped device (binary, base64/hex, or the
+// scanned UR/BBQr frames one per line). Its signatures are merged into the
+// export, which is broadcast once every input is signed.
+pub async fn import_psbt_export(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let export = match data.db.get_psbt_export(&id) {
+        Ok(Some(export)) => export,
+        Ok(None) => return HttpResponse::NotFound().body("PSBT export not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+    if export.status == ExportStatus::Broadcast {
+        return HttpResponse::Conflict().body("PSBT export has already been broadcast");
+    }
+
+    let uploaded = match air_gap::decode_upload(&body) {
+        Ok(uploaded) => uploaded,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    // Combining fails unless the upload signs the exported transaction
+    let combined = match psbt::combine_psbts(&[export.psbt.clone(), psbt::encode_psbt(&uploaded)]) {
+        Ok(combined) => combined,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (psbt, wallet) = match complete_psbt(&data, combined, &export.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+    if let Err(e) = tx_validation::verify_partial_signatures(&psbt) {
+        return HttpResponse::UnprocessableEntity().body(format!("Invalid signature: {}", e));
+    }
+
+    // Limits may have been used up since the export
+    let evaluation = match check_direct_spend(&req, &data, &username, &export.store_id, &psbt, &wallet) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+
+    // Keep the signatures collected so far for the next upload
+    if let Err(e) = data.db.update_psbt_export(&export.id, &psbt::encode_psbt(&psbt), ExportStatus::Pending, None) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    let response = match finalize_and_broadcast(&data, psbt, &export.store_id, evaluation.amount).await {
+        Ok(response) => response,
+        Err(response) => return response,
+    };
+    if let Some(txid) = &response.txid {
+        if let Err(e) = data.db.update_psbt_export(&export.id, &response.psbt, ExportStatus::Broadcast, Some(txid)) {
+            log::error!("Failed to mark PSBT export {} as broadcast: {}", export.id, e);
+        }
+    }
+
+    record_audit(&data, &username, "psbt.imported", Some(&client_ip(&req)), json!({
+        "export_id": export.id,
+        "complete": response.complete,
+        "missing_signatures": response.missing_signatures,
+        "txid": response.txid,
+    }));
+    HttpResponse::Ok().json(response)
+}
+
+// Fee rate estimates per confirmation target, from every configured source
+pub async fn get_fee_estimates(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.fee_estimator.estimates(&data.blockchain_client).await)
+}
+
+fn build_error_response(error: BuildError) -> HttpResponse {
+    match error {
+        BuildError::NoWallet => HttpResponse::NotFound().body(error.to_string()),
+        BuildError::InvalidDestination(_) | BuildError::InvalidFeeRate => HttpResponse::BadRequest().body(error.to_string()),
+        BuildError::InsufficientFunds { .. } => HttpResponse::UnprocessableEntity().body(error.to_string()),
+        BuildError::Derivation(_) | BuildError::Database(_) => HttpResponse::InternalServerError().body(error.to_string()),
     }
 }
+
+// Build an unsigned PSBT from the store wallet's UTXOs, ready for
+// /transaction/sign, /transaction/export or a spend proposal
+pub async fn build_transaction(
+    body: web::Json<BuildTransactionRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let fee_rate = match body.fee_rate {
+        Some(fee_rate) => fee_rate,
+        None => {
+            let target_blocks = body.target_blocks.unwrap_or(fee_estimator::DEFAULT_TARGET_BLOCKS);
+            data.fee_estimator.fee_rate_for(&data.blockchain_client, target_blocks).await
+        }
+    };
+    let built = match tx_builder::build(
+        &data,
+        &body.store_id,
+        &body.destinations,
+        fee_rate,
+        body.include_unconfirmed,
+        Network::Testnet,
+    )
+    .await
+    {
+        Ok(built) => built,
+        Err(e) => return build_error_response(e),
+    };
+    // Hardware signers also want the full previous transactions
+    let (psbt, _) = match complete_psbt(&data, built.psbt, &body.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+
+    HttpResponse::Ok().json(BuildTransactionResponse {
+        psbt: psbt::encode_psbt(&psbt),
+        fee: built.fee,
+        fee_rate,
+        vsize: built.vsize,
+        algorithm: built.algorithm,
+        inputs: built.inputs.iter().map(|utxo| format!("{}:{}", utxo.txid, utxo.vout)).collect(),
+        change: built.change,
+        reserved_until: built.reserved_until,
+    })
+}
+
+// Give back the inputs of a built transaction that will not be sent
+pub async fn release_transaction(
+    body: web::Json<ReleaseTransactionRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let psbt = match psbt::decode_psbt(&body.psbt) {
+        Ok(psbt) => psbt,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    tx_builder::release(&data, &psbt.unsigned_tx);
+    HttpResponse::NoContent().finish()
+}
+
+
+pub async fn list_outgoing_transactions(
+    query: web::Query<OutgoingQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.list_outgoing_transactions(query.store_id.as_deref(), query.status) {
+        Ok(transactions) => HttpResponse::Ok().json(transactions),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn get_outgoing_transaction(
+    txid: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.get_outgoing_transaction(&txid) {
+        Ok(Some(tx)) => HttpResponse::Ok().json(tx),
+        Ok(None) => HttpResponse::NotFound().body("Transaction is not tracked"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+fn bump_error_response(error: BumpError) -> HttpResponse {
+    match error {
+        BumpError::NotFound | BumpError::NoWallet => HttpResponse::NotFound().body(error.to_string()),
+        BumpError::NotUnconfirmed(_)
+        | BumpError::NotReplaceable
+        | BumpError::NoChangeOutput
+        | BumpError::InsufficientChange { .. } => HttpResponse::Conflict().body(error.to_string()),
+        BumpError::NothingToAccelerate => HttpResponse::Conflict().body(error.to_string()),
+        BumpError::FeeRateTooLow { .. } => HttpResponse::BadRequest().body(error.to_string()),
+        BumpError::FeeBudgetExceeded { .. } => HttpResponse::Forbidden().body(error.to_string()),
+        BumpError::Blockchain(_) => HttpResponse::BadGateway().body(error.to_string()),
+        BumpError::Derivation(_) | BumpError::Database(_) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Prepare a fee bump of a stuck outgoing transaction, by replacement or by a
+// child spending its change. The PSBT goes through the usual signing flow.
+pub async fn bump_transaction(
+    req: HttpRequest,
+    txid: web::Path<String>,
+    body: web::Json<BumpTransactionRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    let fee_rate = match body.fee_rate {
+        Some(fee_rate) => fee_rate,
+        None => {
+            let target_blocks = body.target_blocks.unwrap_or(fee_estimator::DEFAULT_TARGET_BLOCKS);
+            data.fee_estimator.fee_rate_for(&data.blockchain_client, target_blocks).await
+        }
+    };
+
+    let (original, bumped) = match fee_bump::bump(&data, &txid, body.method, fee_rate).await {
+        Ok(bumped) => bumped,
+        Err(e) => return bump_error_response(e),
+    };
+    // Hardware signers also want the full previous transactions
+    let (psbt, _) = match complete_psbt(&data, bumped.psbt, &original.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+
+    record_audit(&data, &username, "transaction.bump_prepared", Some(&client_ip(&req)), json!({
+        "txid": original.txid,
+        "store_id": original.store_id,
+        "method": bumped.method,
+        "fee": bumped.fee,
+        "effective_fee_rate": bumped.effective_fee_rate,
+    }));
+    HttpResponse::Ok().json(BumpTransactionResponse {
+        original_txid: original.txid,
+        store_id: original.store_id,
+        method: bumped.method,
+        psbt: psbt::encode_psbt(&psbt),
+        fee: bumped.fee,
+        vsize: bumped.vsize,
+        effective_fee_rate: bumped.effective_fee_rate,
+    })
+}
+
+
+// Speed up an invoice's low-fee incoming payment by spending it back to the
+// wallet at a higher fee rate (CPFP). The PSBT goes through the usual signing
+// flow and the invoice's timeline notes the acceleration.
+pub async fn accelerate_invoice(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<AccelerateInvoiceRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    let invoice = match data.invoice(&id) {
+        Ok(Some(invoice)) => invoice,
+        Ok(None) => return HttpResponse::NotFound().body("Invoice not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+    let fee_rate = match body.fee_rate {
+        Some(fee_rate) => fee_rate,
+        None => {
+            let target_blocks = body.target_blocks.unwrap_or(fee_estimator::DEFAULT_TARGET_BLOCKS);
+            data.fee_estimator.fee_rate_for(&data.blockchain_client, target_blocks).await
+        }
+    };
+
+    let (utxo, child) = match fee_bump::accelerate_invoice(&data, &invoice, fee_rate).await {
+        Ok(accelerated) => accelerated,
+        Err(e) => return bump_error_response(e),
+    };
+    let txid = child.psbt.unsigned_tx.txid().to_string();
+    let (psbt, _) = match complete_psbt(&data, child.psbt, &invoice.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+
+    let details = json!({
+        "parent_txid": utxo.txid,
+        "txid": txid,
+        "fee": child.fee,
+        "effective_fee_rate": child.effective_fee_rate,
+        "requested_by": username,
+    });
+    // Noted on the invoice's timeline once the child is broadcast
+    if let Err(e) = data.db.save_pending_acceleration(&txid, &invoice.id, &details) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+    record_audit(&data, &username, "invoice.acceleration_prepared", Some(&client_ip(&req)), json!({
+        "invoice_id": invoice.id,
+        "acceleration": details,
+    }));
+
+    HttpResponse::Ok().json(AccelerateInvoiceResponse {
+        invoice_id: invoice.id,
+        parent_txid: utxo.txid,
+        txid,
+        psbt: psbt::encode_psbt(&psbt),
+        fee: child.fee,
+        vsize: child.vsize,
+        effective_fee_rate: child.effective_fee_rate,
+    })
+}
+
+pub async fn get_invoice_timeline(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.invoice(&id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("Invoice not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+    match data.db.list_invoice_events(&id) {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Payments seen for an invoice, with what is still due
+pub async fn get_invoice_payments(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let invoice = match data.invoice(&id) {
+        Ok(Some(invoice)) => invoice,
+        Ok(None) => return HttpResponse::NotFound().body("Invoice not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+    let tip = match data.blockchain_client.get_tip_height().await {
+        Ok(tip) => tip,
+        Err(e) => return HttpResponse::BadGateway().body(e),
+    };
+    match chain_watcher::summarize(&data, &invoice, tip) {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Decode a submitted PSBT and add the UTXO data and key origins signers need
+// to sign and show the fee
+async fn prepare_psbt(data: &AppState, encoded: &str, store_id: &str) -> Result<(Psbt, WalletDescriptor), HttpResponse> {
+    let psbt = psbt::decode_psbt(encoded).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+    complete_psbt(data, psbt, store_id).await
+}
+
+// The PSBT may only spend from the named store's wallet; key origins the
+// client supplied are replaced by the wallet's own
+async fn complete_psbt(data: &AppState, mut psbt: Psbt, store_id: &str) -> Result<(Psbt, WalletDescriptor), HttpResponse> {
+    match psbt::fill_utxos(&mut psbt, &data.blockchain_client).await {
+        Ok(()) => {}
+        Err(e @ PsbtError::Blockchain(_)) => return Err(HttpResponse::BadGateway().body(e.to_string())),
+        Err(e) => return Err(psbt_input_error_response(e)),
+    }
+    let wallet = data.wallet_for(store_id).ok_or_else(|| wallet_error_response(WalletError::NoWallet))?;
+    let bounds = wallet::derivation_bounds(&data.db, &wallet)
+        .map_err(|e| HttpResponse::InternalServerError().body(format!("Database error: {}", e)))?;
+    if let Err(e) = psbt::fill_bip32_derivations(&mut psbt, &wallet, bounds) {
+        return Err(psbt_input_error_response(e));
+    }
+
+    Ok((psbt, wallet))
+}
+
+// Inputs the store's wallet can't account for are the client's mistake
+fn psbt_input_error_response(error: PsbtError) -> HttpResponse {
+    match error {
+        PsbtError::ForeignInput(_) | PsbtError::MissingInputData(_) | PsbtError::InputMismatch(_) => {
+            HttpResponse::BadRequest().body(error.to_string())
+        }
+        _ => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Evaluate and audit the store's spending policy. With `allow_delay` a
+// pending time delay is not treated as a violation (it runs while a proposal
+// collects approvals).
+fn check_spending_policy(
+    req: &HttpRequest,
+    data: &AppState,
+    username: &str,
+    store_id: &str,
+    psbt: &Psbt,
+    wallet: &WalletDescriptor,
+    allow_delay: bool,
+) -> Result<PolicyEvaluation, Box<HttpResponse>> {
+    let evaluation = spending_policy::evaluate(&data.db, store_id, psbt, wallet, Network::Testnet).map_err(|e| {
+        Box::new(match e {
+            PolicyError::Derivation(e) => psbt_input_error_response(e),
+            e => HttpResponse::InternalServerError().body(e.to_string()),
+        })
+    })?;
+    record_audit(data, username, "policy.evaluated", Some(&client_ip(req)), json!(evaluation));
+
+    let errors: Vec<String> = evaluation
+        .violations
+        .iter()
+        .filter(|violation| !(allow_delay && matches!(violation, PolicyViolation::DelayRequired { .. })))
+        .map(|violation| violation.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Err(Box::new(HttpResponse::Forbidden().json(json!({
+            "error": "Transaction violates the store's spending policy",
+            "violations": errors,
+            "evaluation": evaluation,
+        }))));
+    }
+
+    Ok(evaluation)
+}
+
+// Spending policy check for spends signed straight away rather than as a
+// spend proposal: those needing approvals are turned away with a hint
+fn check_direct_spend(
+    req: &HttpRequest,
+    data: &AppState,
+    username: &str,
+    store_id: &str,
+    psbt: &Psbt,
+    wallet: &WalletDescriptor,
+) -> Result<PolicyEvaluation, Box<HttpResponse>> {
+    let evaluation = check_spending_policy(req, data, username, store_id, psbt, wallet, false)?;
+    if evaluation.approvals_required > 0 {
+        return Err(Box::new(HttpResponse::Conflict().body(format!(
+            "This spend needs {} approval(s); submit it as a spend proposal",
+            evaluation.approvals_required
+        ))));
+    }
+    Ok(evaluation)
+}
+
+// Sign, validate and broadcast a PSBT that passed the spending policy
+async fn sign_and_broadcast(
+    data: &AppState,
+    psbt: Psbt,
+    signer: SignerKind,
+    store_id: &str,
+    amount: u64,
+) -> Result<SignPsbtResponse, HttpResponse> {
+    let signed_psbt = sign_psbt_with(data, psbt, signer).await?;
+    finalize_and_broadcast(data, signed_psbt, store_id, amount).await
+}
+
+async fn sign_psbt_with(data: &AppState, psbt: Psbt, signer: SignerKind) -> Result<Psbt, HttpResponse> {
+    match sign_with(signer, data, psbt).await {
+        Ok(signed_psbt) => Ok(signed_psbt),
+        Err(SignerError::DeviceUnavailable(e)) => {
+            Err(HttpResponse::ServiceUnavailable().body(format!("Error connecting to signer: {}", e)))
+        }
+        Err(e) => Err(HttpResponse::InternalServerError().body(format!("Error signing: {}", e))),
+    }
+}
+
+// Validate and broadcast a PSBT once every input has enough signatures;
+// otherwise return it for the remaining signers
+async fn finalize_and_broadcast(
+    data: &AppState,
+    signed_psbt: Psbt,
+    store_id: &str,
+    amount: u64,
+) -> Result<SignPsbtResponse, HttpResponse> {
+    let fee = signed_psbt.fee().ok().map(|fee| fee.to_sat());
+
+    // Only finalize and broadcast once every input carries enough signatures
+    if !psbt::is_fully_signed(&signed_psbt) {
+        return Ok(SignPsbtResponse {
+            psbt: psbt::encode_psbt(&signed_psbt),
+            complete: false,
+            txid: None,
+            fee,
+            missing_signatures: psbt::missing_signatures(&signed_psbt),
+        });
+    }
+
+    let encoded = psbt::encode_psbt(&signed_psbt);
+    match broadcast::broadcast_psbt(data, signed_psbt, store_id, amount).await {
+        Ok(txid) => Ok(SignPsbtResponse {
+            psbt: encoded,
+            complete: true,
+            txid: Some(txid),
+            fee,
+            missing_signatures: 0,
+        }),
+        Err(BroadcastError::Finalize(e)) => Err(HttpResponse::BadRequest().body(e.to_string())),
+        Err(BroadcastError::Invalid(report)) => Err(HttpResponse::UnprocessableEntity().json(report)),
+        Err(BroadcastError::Rejected { reason, message }) => Err(HttpResponse::UnprocessableEntity().json(json!({
+            "reason": reason,
+            "message": message,
+        }))),
+        Err(BroadcastError::LimitExceeded(violations)) => Err(HttpResponse::Forbidden().json(json!({
+            "error": "Transaction violates the store's spending policy",
+            "violations": violations.iter().map(|violation| violation.to_string()).collect::<Vec<String>>(),
+        }))),
+        Err(BroadcastError::Backend(msg)) => Err(HttpResponse::BadGateway().body(msg)),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+// Sign with the requested signer, returning the PSBT with any signatures added
+async fn sign_with(kind: SignerKind, data: &AppState, mut psbt: Psbt) -> Result<Psbt, SignerError> {
+    // PIN, passphrase and confirmation prompts are answered through the
+    // /trezor/session endpoints while this call is pending
+    signer_for(kind, data).await?.sign_psbt(&mut psbt).await?;
+    Ok(psbt)
+}
+
+// The requested signer, connecting the Trezor first if needed
+async fn signer_for(kind: SignerKind, data: &AppState) -> Result<&dyn Signer, SignerError> {
+    match kind {
+        SignerKind::Trezor => {
+            if !data.trezor_client.is_connected() {
+                data.trezor_client.connect(None).await?;
+            }
+            Ok(&data.trezor_client)
+        }
+        SignerKind::Software => match &data.software_signer {
+            Some(signer) => Ok(signer),
+            None => Err(SignerError::DeviceUnavailable("no software signer is configured".to_string())),
+        },
+    }
+}
+
+fn signer_error_response(error: SignerError) -> HttpResponse {
+    match error {
+        SignerError::DeviceUnavailable(_) => HttpResponse::ServiceUnavailable().body(error.to_string()),
+        SignerError::KeyDerivation(_) => HttpResponse::BadRequest().body(error.to_string()),
+        _ => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Extended public key a signer holds at a derivation path
+pub async fn get_signer_xpub(
+    kind: web::Path<SignerKind>,
+    body: web::Json<SignerXpubRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let path = match body.path.parse::<DerivationPath>() {
+        Ok(path) => path,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid derivation path: {}", e)),
+    };
+    let signer = match signer_for(kind.into_inner(), &data).await {
+        Ok(signer) => signer,
+        Err(e) => return signer_error_response(e),
+    };
+    let fingerprint = match signer.fingerprint().await {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => return signer_error_response(e),
+    };
+    match signer.get_xpub(&path).await {
+        Ok(xpub) => HttpResponse::Ok().json(json!({
+            "fingerprint": fingerprint.to_string(),
+            "path": path.to_string(),
+            "xpub": xpub.to_string(),
+        })),
+        Err(e) => signer_error_response(e),
+    }
+}
+
+// Derive an address on a signer; the Trezor also shows it on its screen for
+// the user to compare
+pub async fn display_signer_address(
+    kind: web::Path<SignerKind>,
+    body: web::Json<SignerAddressRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let path = match body.path.parse::<DerivationPath>() {
+        Ok(path) => path,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid derivation path: {}", e)),
+    };
+    let signer = match signer_for(kind.into_inner(), &data).await {
+        Ok(signer) => signer,
+        Err(e) => return signer_error_response(e),
+    };
+    match signer.display_address(&path, body.script_type).await {
+        Ok(address) => HttpResponse::Ok().json(json!({
+            "path": path.to_string(),
+            "address": address,
+        })),
+        Err(e) => signer_error_response(e),
+    }
+}
+
+// Username of the authenticated caller, as stored by auth::validator
+fn authenticated_user(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<auth::Claims>().map(|claims| claims.sub.clone())
+}
+
+fn second_factor_error_response(error: TwoFactorError) -> HttpResponse {
+    match error {
+        TwoFactorError::NotAuthenticated | TwoFactorError::CodeRequired | TwoFactorError::InvalidCode => {
+            HttpResponse::Unauthorized().body(error.to_string())
+        }
+        TwoFactorError::NotEnrolled => HttpResponse::BadRequest().body(error.to_string()),
+        TwoFactorError::InvalidSecret | TwoFactorError::Database(_) => {
+            log::error!("Two-factor check failed: {}", error);
+            HttpResponse::InternalServerError().body("Could not verify second factor")
+        }
+    }
+}
+
+// Step-up check for sensitive operations: users with TOTP enabled must send a
+// current code in the X-2FA-Code header. Returns the authenticated username.
+pub(crate) fn require_step_up(req: &HttpRequest, data: &AppState) -> Result<String, TwoFactorError> {
+    let username = authenticated_user(req).ok_or(TwoFactorError::NotAuthenticated)?;
+
+    let code = req
+        .headers()
+        .get(SECOND_FACTOR_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    totp::check_second_factor(&data.db, &username, code)?;
+
+    Ok(username)
+}
+
+pub async fn enroll_totp(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+
+    match data.db.get_user_totp(&username) {
+        Ok(Some(existing)) if existing.enabled => {
+            return HttpResponse::Conflict().body("Two-factor authentication is already enabled");
+        }
+        Ok(_) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+
+    let secret = totp::generate_secret();
+    if let Err(e) = data.db.save_user_totp(&username, &secret) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    info!("TOTP enrollment started for {}", username);
+    HttpResponse::Ok().json(TotpEnrollmentResponse {
+        otpauth_uri: totp::provisioning_uri(TOTP_ISSUER, &username, &secret),
+        secret,
+    })
+}
+
+pub async fn confirm_totp(
+    req: HttpRequest,
+    body: web::Json<TotpCodeRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+
+    let pending = match data.db.get_user_totp(&username) {
+        Ok(Some(totp)) if !totp.enabled => totp,
+        Ok(Some(_)) => return HttpResponse::Conflict().body("Two-factor authentication is already enabled"),
+        Ok(None) => return second_factor_error_response(TwoFactorError::NotEnrolled),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+
+    let step = match totp::verify_code(&pending.secret, &body.code, None) {
+        Ok(step) => step,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+    if let Err(e) = data.db.save_recovery_codes(&username, &hashes)
+        .and_then(|_| data.db.enable_user_totp(&username, step))
+    {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    info!("TOTP enabled for {}", username);
+    HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes })
+}
+
+pub async fn regenerate_recovery_codes(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    match data.db.get_user_totp(&username) {
+        Ok(Some(totp)) if totp.enabled => {}
+        Ok(_) => return second_factor_error_response(TwoFactorError::NotEnrolled),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+    match data.db.save_recovery_codes(&username, &hashes) {
+        Ok(()) => HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn disable_totp(
+    req: HttpRequest,
+    body: web::Json<TotpCodeRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+
+    match data.db.get_user_totp(&username) {
+        Ok(Some(totp)) if totp.enabled => {}
+        Ok(_) => return second_factor_error_response(TwoFactorError::NotEnrolled),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+
+    if let Err(e) = totp::check_second_factor(&data.db, &username, Some(&body.code)) {
+        return second_factor_error_response(e);
+    }
+
+    match data.db.delete_user_totp(&username) {
+        Ok(()) => HttpResponse::Ok().body("Two-factor authentication disabled"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+fn client_ip(req: &HttpRequest) -> String {
+    match req.app_data::<web::Data<AppState>>() {
+        Some(data) => data.client_ip(req),
+        None => "unknown".to_string(),
+    }
+}
+
+fn is_admin(req: &HttpRequest) -> bool {
+    authenticated_user(req).as_deref() == Some(ADMIN_USERNAME)
+}
+
+// Audit failures are logged but never block the request being audited
+fn record_audit(data: &AppState, actor: &str, action: &str, ip: Option<&str>, details: serde_json::Value) {
+    if let Err(e) = data.db.log_audit_event(actor, action, ip, details) {
+        log::error!("Failed to write audit event {}: {}", action, e);
+    }
+}
+
+fn record_failed_login(data: &AppState, attempt: LoginAttempt, username: &str, ip: &str, reason: &str) {
+    let outcome = attempt.record_failure();
+    record_audit(data, username, "login.failed", Some(ip), json!({
+        "reason": reason,
+        "username_failures": outcome.username_failures,
+        "ip_failures": outcome.ip_failures,
+    }));
+    if outcome.username_locked {
+        record_audit(data, username, "account.locked", Some(ip), json!({}));
+    }
+    if outcome.ip_locked {
+        record_audit(data, username, "ip.locked", Some(ip), json!({}));
+    }
+}
+
+pub async fn list_lockouts(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+
+    let lockouts: Vec<LockoutInfo> = data
+        .login_guard
+        .locked_usernames()
+        .into_iter()
+        .map(|(username, remaining)| LockoutInfo {
+            username,
+            remaining_secs: remaining.as_secs(),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(lockouts)
+}
+
+pub async fn unlock_account(
+    req: HttpRequest,
+    body: web::Json<UnlockRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+    if body.username.is_none() && body.ip.is_none() {
+        return HttpResponse::BadRequest().body("Either username or ip is required");
+    }
+
+    let admin = authenticated_user(&req).unwrap_or_default();
+    let mut unlocked = false;
+    if let Some(username) = &body.username {
+        unlocked |= data.login_guard.unlock(username);
+    }
+    if let Some(ip) = &body.ip {
+        unlocked |= data.login_guard.unlock_ip(ip);
+    }
+
+    record_audit(&data, &admin, "account.unlocked", Some(&client_ip(&req)), json!({
+        "username": body.username,
+        "ip": body.ip,
+        "had_failures": unlocked,
+    }));
+
+    info!("Login lockout cleared by {}", admin);
+    HttpResponse::Ok().json(json!({ "unlocked": unlocked }))
+}
+
+pub async fn get_audit_log(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+
+    match data.db.get_audit_events(query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT)) {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+fn trezor_error_response(error: TrezorError) -> HttpResponse {
+    match error {
+        TrezorError::DeviceNotFound => HttpResponse::NotFound().body(error.to_string()),
+        TrezorError::NoPendingPrompt => HttpResponse::Conflict().body(error.to_string()),
+        TrezorError::Cancelled(_) | TrezorError::Failure { .. } => HttpResponse::BadRequest().body(error.to_string()),
+        _ => HttpResponse::BadGateway().body(error.to_string()),
+    }
+}
+
+pub async fn list_trezor_devices(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.trezor_client.list_devices().await)
+}
+
+pub async fn connect_trezor(
+    req: HttpRequest,
+    body: web::Json<TrezorConnectRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = authenticated_user(&req).unwrap_or_default();
+
+    match data.trezor_client.connect(body.into_inner().device_path).await {
+        Ok(features) => {
+            record_audit(&data, &username, "trezor.connected", Some(&client_ip(&req)), json!({
+                "device_id": features.device_id,
+                "label": features.label,
+                "firmware": features.firmware_version(),
+            }));
+            HttpResponse::Ok().json(data.trezor_client.status())
+        }
+        Err(e) => trezor_error_response(e),
+    }
+}
+
+pub async fn disconnect_trezor(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Err(e) = data.trezor_client.disconnect().await {
+        return trezor_error_response(e);
+    }
+
+    let username = authenticated_user(&req).unwrap_or_default();
+    record_audit(&data, &username, "trezor.disconnected", Some(&client_ip(&req)), json!({}));
+    HttpResponse::Ok().json(data.trezor_client.status())
+}
+
+// Polled by the UI to learn whether the device is waiting for a PIN,
+// passphrase or button press
+pub async fn get_trezor_session(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.trezor_client.status())
+}
+
+pub async fn submit_trezor_pin(
+    body: web::Json<TrezorPinRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let pin = Zeroizing::new(body.into_inner().pin);
+    if pin.is_empty() || pin.len() > 50 || !pin.chars().all(|c| ('1'..='9').contains(&c)) {
+        return HttpResponse::BadRequest().body("PIN must be matrix positions 1-9");
+    }
+
+    match data.trezor_client.respond(PromptReply::Pin(pin)) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => trezor_error_response(e),
+    }
+}
+
+pub async fn submit_trezor_passphrase(
+    body: web::Json<TrezorPassphraseRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let reply = PromptReply::Passphrase {
+        passphrase: Zeroizing::new(body.passphrase),
+        on_device: body.on_device,
+    };
+
+    match data.trezor_client.respond(reply) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => trezor_error_response(e),
+    }
+}
+
+pub async fn cancel_trezor_prompt(data: web::Data<AppState>) -> impl Responder {
+    match data.trezor_client.respond(PromptReply::Cancel) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => trezor_error_response(e),
+    }
+}
+
+// Have the Trezor derive (and by default display) the invoice's receive
+// address and report whether it matches the one the server issued
+pub async fn verify_invoice_address(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<VerifyAddressQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let invoice = match data.invoices.lock().unwrap().get(&id.into_inner()) {
+        Some(invoice) => invoice.clone(),
+        None => return HttpResponse::NotFound().body("Invoice not found"),
+    };
+
+    let check = match address_verifier::verify_invoice_address(&data, &invoice, query.display.unwrap_or(true)).await {
+        Ok(check) => check,
+        Err(VerifyError::Trezor(e)) => return trezor_error_response(e),
+        Err(e @ VerifyError::WrongDevice(_)) => return HttpResponse::Conflict().body(e.to_string()),
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    let username = authenticated_user(&req).unwrap_or_default();
+    let action = if check.matches { "address.verified" } else { "address.mismatch" };
+    record_audit(&data, &username, action, Some(&client_ip(&req)), json!(check));
+
+    HttpResponse::Ok().json(check)
+}
+
+pub async fn get_address_sample_report(data: web::Data<AppState>) -> impl Responder {
+    match data.address_sample_report.lock().unwrap().clone() {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().body("No address sample check has run"),
+    }
+}
+
+fn wallet_setup_error_response(error: WalletSetupError) -> HttpResponse {
+    match error {
+        WalletSetupError::Trezor(e) => trezor_error_response(e),
+        _ => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+fn wallet_setup_response(
+    store_id: String,
+    wallet: &WalletDescriptor,
+    label: Option<String>,
+    warnings: Vec<String>,
+    saved: bool,
+) -> HttpResponse {
+    let receive_addresses = match wallet_setup::receive_addresses(wallet, wallet_setup::PREVIEW_ADDRESS_COUNT, Network::Testnet) {
+        Ok(addresses) => addresses,
+        Err(e) => return wallet_setup_error_response(e),
+    };
+
+    let key_info = |key: &AccountKey| WalletKeyInfo {
+        fingerprint: key.fingerprint.to_string(),
+        derivation_path: key.path.to_string(),
+        xpub: key.xpub.to_string(),
+    };
+    let single = wallet.single();
+    let (threshold, cosigners) = match wallet {
+        WalletDescriptor::Single(_) => (None, Vec::new()),
+        WalletDescriptor::Multisig(multisig) => (Some(multisig.threshold), multisig.keys.iter().map(key_info).collect()),
+    };
+
+    HttpResponse::Ok().json(WalletSetupResponse {
+        store_id,
+        descriptor: wallet.to_string(),
+        fingerprint: single.map(|account| account.fingerprint.to_string()),
+        derivation_path: single.map(|account| account.path.to_string()),
+        xpub: single.map(|account| account.xpub.to_string()),
+        script_type: single.map(|account| account.script_type),
+        threshold,
+        cosigners,
+        label,
+        receive_addresses,
+        warnings,
+        saved,
+    })
+}
+
+pub async fn get_store_wallet(
+    store_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let store_id = store_id.into_inner();
+    let wallet = match data.db.get_store_wallet(&store_id) {
+        Ok(Some(wallet)) => wallet,
+        Ok(None) => return HttpResponse::NotFound().body("No wallet configured for this store"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    };
+
+    match wallet.descriptor.parse::<WalletDescriptor>() {
+        Ok(descriptor) => wallet_setup_response(store_id, &descriptor, wallet.label, Vec::new(), true),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Stored wallet is invalid: {}", e)),
+    }
+}
+
+// Set up the wallet a store's invoices pay into, either from the connected
+// Trezor or from an imported xpub, descriptor or wallet file. Without
+// `confirm` the wallet is only validated and its first receive addresses
+// returned so they can be checked against the wallet software.
+pub async fn setup_store_wallet(
+    req: HttpRequest,
+    store_id: web::Path<String>,
+    body: web::Json<WalletSetupRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let store_id = store_id.into_inner();
+    let body = body.into_inner();
+    let network = Network::Testnet;
+
+    let imported = match (body.source, body.data.as_deref()) {
+        (WalletSource::Trezor, _) => {
+            let script_type = body.script_type.unwrap_or(ScriptType::NativeSegwit);
+            wallet_setup::from_trezor(&data.trezor_client, script_type, body.account.unwrap_or(0), network).await
+        }
+        (WalletSource::Xpub, Some(key)) => wallet_setup::from_extended_key(
+            key,
+            body.script_type,
+            body.fingerprint.as_deref(),
+            body.derivation_path.as_deref(),
+            network,
+        ),
+        (WalletSource::Descriptor, Some(descriptor)) => wallet_setup::from_descriptor(descriptor, network),
+        (WalletSource::File, Some(contents)) => wallet_setup::from_wallet_file(contents, network),
+        (_, None) => return HttpResponse::BadRequest().body("data is required for this wallet source"),
+    };
+    let ImportedWallet { wallet: descriptor, label, warnings } = match imported {
+        Ok(imported) => imported,
+        Err(e) => return wallet_setup_error_response(e),
+    };
+    let label = body.label.or(label);
+
+    if !body.confirm {
+        return wallet_setup_response(store_id, &descriptor, label, warnings, false);
+    }
+
+    // Changing where payments go is sensitive: require step-up 2FA
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let wallet = StoreWallet {
+        store_id: store_id.clone(),
+        descriptor: descriptor.to_string(),
+        source: body.source.as_str().to_string(),
+        label: label.clone(),
+        created_at: Utc::now(),
+    };
+    if let Err(e) = data.db.save_store_wallet(&wallet) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    record_audit(&data, &username, "wallet.configured", Some(&client_ip(&req)), json!({
+        "store_id": store_id,
+        "descriptor": wallet.descriptor,
+        "source": wallet.source,
+    }));
+
+    info!("Wallet for store {} set up from {}", store_id, wallet.source);
+    wallet_setup_response(store_id, &descriptor, label, warnings, true)
+}
+
+fn wallet_error_response(error: WalletError) -> HttpResponse {
+    match error {
+        WalletError::NoWallet => HttpResponse::NotFound().body(error.to_string()),
+        WalletError::Blockchain(_) => HttpResponse::BadGateway().body(error.to_string()),
+        WalletError::Derivation(_) | WalletError::Database(_) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+pub async fn get_wallet_balance(
+    store_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match wallet::balance(&data, &store_id) {
+        Ok(balance) => HttpResponse::Ok().json(balance),
+        Err(e) => wallet_error_response(e),
+    }
+}
+
+pub async fn list_wallet_utxos(
+    store_id: web::Path<String>,
+    query: web::Query<UtxoQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.list_utxos(&store_id) {
+        Ok(utxos) => {
+            let utxos: Vec<_> = utxos.into_iter().filter(|utxo| utxo.confirmed || !query.confirmed_only).collect();
+            HttpResponse::Ok().json(utxos)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Refresh the store's UTXO set now instead of waiting for the background sync
+pub async fn sync_store_wallet(
+    store_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Err(e) = wallet::sync(&data, &store_id, Network::Testnet).await {
+        return wallet_error_response(e);
+    }
+    match wallet::balance(&data, &store_id) {
+        Ok(balance) => HttpResponse::Ok().json(balance),
+        Err(e) => wallet_error_response(e),
+    }
+}
+
+pub async fn get_spending_policy(
+    store_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.get_spending_policy(&store_id) {
+        Ok(Some(policy)) => HttpResponse::Ok().json(policy),
+        Ok(None) => HttpResponse::NotFound().body("No spending policy is configured for this store"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn set_spending_policy(
+    req: HttpRequest,
+    store_id: web::Path<String>,
+    policy: web::Json<SpendingPolicy>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+    // Loosening limits is as sensitive as spending: require step-up 2FA
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let store_id = store_id.into_inner();
+    let policy = policy.into_inner();
+    if let Err(e) = spending_policy::validate_policy(&policy, Network::Testnet) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    if let Err(e) = data.db.save_spending_policy(&store_id, &policy) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    record_audit(&data, &username, "policy.updated", Some(&client_ip(&req)), json!({
+        "store_id": store_id,
+        "policy": policy,
+    }));
+    HttpResponse::Ok().json(policy)
+}
+
+pub async fn get_zero_conf_policy(
+    store_id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.get_zero_conf_policy(&store_id) {
+        Ok(Some(policy)) => HttpResponse::Ok().json(policy),
+        Ok(None) => HttpResponse::NotFound().body("No 0-conf policy is configured for this store"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn set_zero_conf_policy(
+    req: HttpRequest,
+    store_id: web::Path<String>,
+    policy: web::Json<ZeroConfPolicy>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+    // Accepting unconfirmed payments risks funds like loosening spend limits
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let store_id = store_id.into_inner();
+    let policy = policy.into_inner();
+    if let Err(e) = zero_conf::validate_policy(&policy) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    if let Err(e) = data.db.save_zero_conf_policy(&store_id, &policy) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    record_audit(&data, &username, "zero_conf_policy.updated", Some(&client_ip(&req)), json!({
+        "store_id": store_id,
+        "policy": policy,
+    }));
+    HttpResponse::Ok().json(policy)
+}
+
+fn proposal_error_response(error: ProposalError) -> HttpResponse {
+    match error {
+        ProposalError::NotFound => HttpResponse::NotFound().body(error.to_string()),
+        ProposalError::NotApprover | ProposalError::OwnProposal => HttpResponse::Forbidden().body(error.to_string()),
+        ProposalError::NotPending(_) | ProposalError::NotApproved(_) | ProposalError::AlreadyVoted => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        ProposalError::Database(_) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Submit a PSBT as a spend proposal for approvers to sign off
+pub async fn create_proposal(
+    req: HttpRequest,
+    body: web::Json<CreateProposalRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    let body = body.into_inner();
+
+    let (psbt, wallet) = match prepare_psbt(&data, &body.psbt, &body.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+    let evaluation = match check_spending_policy(&req, &data, &username, &body.store_id, &psbt, &wallet, true) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+    let ttl_secs = data
+        .db
+        .get_spending_policy(&body.store_id)
+        .ok()
+        .flatten()
+        .and_then(|policy| policy.proposal_ttl_secs);
+
+    let new = NewProposal {
+        store_id: body.store_id,
+        psbt: psbt::encode_psbt(&psbt),
+        description: body.description,
+        amount: evaluation.amount,
+        created_by: username.clone(),
+        required_approvals: evaluation.approvals_required,
+        ttl_secs,
+    };
+    match proposals::create(&data, new).await {
+        Ok(proposal) => {
+            record_audit(&data, &username, "proposal.created", Some(&client_ip(&req)), json!({
+                "proposal_id": proposal.id,
+                "store_id": proposal.store_id,
+                "amount": proposal.amount,
+                "required_approvals": proposal.required_approvals,
+            }));
+            HttpResponse::Created().json(proposal)
+        }
+        Err(e) => proposal_error_response(e),
+    }
+}
+
+pub async fn list_proposals(
+    query: web::Query<ProposalQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.list_proposals(query.status) {
+        Ok(proposals) => HttpResponse::Ok().json(proposals),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn get_proposal(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match proposals::get(&data, &id).await {
+        Ok(proposal) => HttpResponse::Ok().json(proposal),
+        Err(e) => proposal_error_response(e),
+    }
+}
+
+pub async fn approve_proposal(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ProposalVoteRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    vote_on_proposal(req, id.into_inner(), body.into_inner(), data, true).await
+}
+
+pub async fn reject_proposal(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ProposalVoteRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    vote_on_proposal(req, id.into_inner(), body.into_inner(), data, false).await
+}
+
+async fn vote_on_proposal(
+    req: HttpRequest,
+    id: String,
+    body: ProposalVoteRequest,
+    data: web::Data<AppState>,
+    approve: bool,
+) -> HttpResponse {
+    // Approvals authorize spending, so require a fresh second factor
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+    let is_approver = is_admin(&req)
+        || data
+            .db
+            .get_user_roles(&username)
+            .map(|roles| roles.contains(&Role::Approver))
+            .unwrap_or(false);
+
+    match proposals::vote(&data, &id, &username, is_approver, approve, body.comment).await {
+        Ok(proposal) => {
+            let action = if approve { "proposal.approved" } else { "proposal.rejected" };
+            record_audit(&data, &username, action, Some(&client_ip(&req)), json!({
+                "proposal_id": proposal.id,
+                "status": proposal.status,
+                "approvals": proposal.approval_count(),
+                "required_approvals": proposal.required_approvals,
+            }));
+            HttpResponse::Ok().json(proposal)
+        }
+        Err(e) => proposal_error_response(e),
+    }
+}
+
+// Sign a proposal once it has reached its quorum, broadcasting it when this
+// completes the signatures
+pub async fn sign_proposal(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<SignProposalRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let mut proposal = match approved_proposal(&data, &id).await {
+        Ok(proposal) => proposal,
+        Err(response) => return response,
+    };
+    let psbt = match psbt::decode_psbt(&proposal.psbt) {
+        Ok(psbt) => psbt,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    // Limits may have been used up while the proposal waited for approvals
+    let wallet = match data.wallet_for(&proposal.store_id) {
+        Some(wallet) => wallet,
+        None => return wallet_error_response(WalletError::NoWallet),
+    };
+    let evaluation = match check_spending_policy(&req, &data, &username, &proposal.store_id, &psbt, &wallet, false) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+
+    let signed_psbt = match sign_psbt_with(&data, psbt, body.signer).await {
+        Ok(signed_psbt) => signed_psbt,
+        Err(response) => return response,
+    };
+    finish_proposal_signing(&req, &data, &username, &mut proposal, signed_psbt, evaluation.amount).await
+}
+
+// Add signatures from a cosigner's copy of an approved proposal's PSBT, e.g.
+// one signed on an air-gapped device
+pub async fn add_proposal_signatures(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ProposalSignaturesRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    let mut proposal = match approved_proposal(&data, &id).await {
+        Ok(proposal) => proposal,
+        Err(response) => return response,
+    };
+    let combined = match psbt::combine_psbts(&[proposal.psbt.clone(), body.into_inner().psbt]) {
+        Ok(combined) => combined,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (psbt, wallet) = match complete_psbt(&data, combined, &proposal.store_id).await {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+    if let Err(e) = tx_validation::verify_partial_signatures(&psbt) {
+        return HttpResponse::UnprocessableEntity().body(format!("Invalid signature: {}", e));
+    }
+
+    let evaluation = match check_spending_policy(&req, &data, &username, &proposal.store_id, &psbt, &wallet, false) {
+        Ok(evaluation) => evaluation,
+        Err(response) => return *response,
+    };
+    finish_proposal_signing(&req, &data, &username, &mut proposal, psbt, evaluation.amount).await
+}
+
+async fn approved_proposal(data: &AppState, id: &str) -> Result<SpendProposal, HttpResponse> {
+    let proposal = proposals::get(data, id).await.map_err(proposal_error_response)?;
+    if proposal.status != ProposalStatus::Approved {
+        return Err(proposal_error_response(ProposalError::NotApproved(proposal.status)));
+    }
+    Ok(proposal)
+}
+
+// Keep the signatures collected so far with the proposal, and broadcast it
+// once the signing threshold is met
+async fn finish_proposal_signing(
+    req: &HttpRequest,
+    data: &AppState,
+    username: &str,
+    proposal: &mut SpendProposal,
+    psbt: Psbt,
+    amount: u64,
+) -> HttpResponse {
+    if let Err(e) = data.db.update_proposal_psbt(&proposal.id, &psbt::encode_psbt(&psbt)) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    let response = match finalize_and_broadcast(data, psbt, &proposal.store_id, amount).await {
+        Ok(response) => response,
+        Err(response) => return response,
+    };
+    if let Some(txid) = &response.txid {
+        if let Err(e) = proposals::mark_broadcast(data, proposal, txid).await {
+            log::error!("Failed to mark proposal {} as broadcast: {}", proposal.id, e);
+        }
+    }
+
+    record_audit(data, username, "proposal.signed", Some(&client_ip(req)), json!({
+        "proposal_id": proposal.id,
+        "complete": response.complete,
+        "missing_signatures": response.missing_signatures,
+        "txid": response.txid,
+    }));
+    HttpResponse::Ok().json(response)
+}
+
+fn payout_error_response(error: PayoutError) -> HttpResponse {
+    match error {
+        PayoutError::NotFound => HttpResponse::NotFound().body(error.to_string()),
+        PayoutError::InvalidDestination(_)
+        | PayoutError::InvalidAmount(_)
+        | PayoutError::UnsupportedCurrency(_)
+        | PayoutError::InvalidCsv { .. } => HttpResponse::BadRequest().body(error.to_string()),
+        PayoutError::InvalidStatus(_) => HttpResponse::Conflict().body(error.to_string()),
+        PayoutError::Proposal(_) | PayoutError::Database(_) => {
+            HttpResponse::InternalServerError().body(error.to_string())
+        }
+    }
+}
+
+async fn queue_payouts(
+    req: &HttpRequest,
+    data: &AppState,
+    username: &str,
+    store_id: &str,
+    new_payouts: Vec<NewPayout>,
+) -> HttpResponse {
+    match payouts::create(data, store_id, username, new_payouts, Network::Testnet).await {
+        Ok(payouts) => {
+            record_audit(data, username, "payout.created", Some(&client_ip(req)), json!({
+                "store_id": store_id,
+                "payout_ids": payouts.iter().map(|payout| payout.id.as_str()).collect::<Vec<_>>(),
+                "amount": payouts.iter().map(|payout| payout.amount).sum::<u64>(),
+            }));
+            HttpResponse::Created().json(payouts)
+        }
+        Err(e) => payout_error_response(e),
+    }
+}
+
+// Queue a payout; it is sent once an approver has approved it
+pub async fn create_payout(
+    req: HttpRequest,
+    store_id: web::Path<String>,
+    body: web::Json<CreatePayoutRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    let body = body.into_inner();
+    let amount = match body.amount {
+        serde_json::Value::String(amount) => amount,
+        amount => amount.to_string(),
+    };
+
+    let new_payout = NewPayout {
+        destination: body.destination,
+        amount,
+        currency: body.currency,
+        description: body.description,
+    };
+    queue_payouts(&req, &data, &username, &store_id, vec![new_payout]).await
+}
+
+// Queue payouts from a CSV body of "destination,amount[,currency][,description]" lines
+pub async fn upload_payouts_csv(
+    req: HttpRequest,
+    store_id: web::Path<String>,
+    body: String,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    let new_payouts = match payouts::parse_csv(&body) {
+        Ok(new_payouts) => new_payouts,
+        Err(e) => return payout_error_response(e),
+    };
+    queue_payouts(&req, &data, &username, &store_id, new_payouts).await
+}
+
+pub async fn list_payouts(
+    store_id: web::Path<String>,
+    query: web::Query<PayoutQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.db.list_payouts(&store_id, query.status) {
+        Ok(payouts) => HttpResponse::Ok().json(payouts),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn get_payout(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match payouts::get(&data, &id) {
+        Ok(payout) => HttpResponse::Ok().json(payout),
+        Err(e) => payout_error_response(e),
+    }
+}
+
+// Approving releases funds to the payout processor, so it takes an approver
+// and a fresh second factor
+pub async fn approve_payout(
+    req: HttpRequest,
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match require_step_up(&req, &data) {
+        Ok(username) => username,
+        Err(e) => return second_factor_error_response(e),
+    };
+    let is_approver = is_admin(&req)
+        || data
+            .db
+            .get_user_roles(&username)
+            .map(|roles| roles.contains(&Role::Approver))
+            .unwrap_or(false);
+    if !is_approver {
+        return HttpResponse::Forbidden().body("The approver role is required to approve payouts");
+    }
+
+    match payouts::approve(&data, &id).await {
+        Ok(payout) => {
+            record_audit(&data, &username, "payout.approved", Some(&client_ip(&req)), json!({
+                "payout_id": payout.id,
+                "store_id": payout.store_id,
+                "destination": payout.destination,
+                "amount": payout.amount,
+            }));
+            HttpResponse::Ok().json(payout)
+        }
+        Err(e) => payout_error_response(e),
+    }
+}
+
+pub async fn cancel_payout(
+    req: HttpRequest,
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+
+    match payouts::cancel(&data, &id).await {
+        Ok(payout) => {
+            record_audit(&data, &username, "payout.cancelled", Some(&client_ip(&req)), json!({
+                "payout_id": payout.id,
+                "store_id": payout.store_id,
+            }));
+            HttpResponse::Ok().json(payout)
+        }
+        Err(e) => payout_error_response(e),
+    }
+}
+
+fn refund_error_response(error: RefundError) -> HttpResponse {
+    match error {
+        RefundError::InvoiceNotFound | RefundError::NotFound => HttpResponse::NotFound().body(error.to_string()),
+        RefundError::InvalidAmount { .. } | RefundError::InvalidDestination(_) => {
+            HttpResponse::BadRequest().body(error.to_string())
+        }
+        RefundError::NothingToRefund | RefundError::NotOverpaid | RefundError::InvalidStatus(_) => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        RefundError::Payout(e) => payout_error_response(e),
+        RefundError::Database(_) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+fn refund_response(refund: Refund) -> RefundResponse {
+    RefundResponse {
+        claim_url: format!("/api/public/refunds/{}/claim", refund.id),
+        refund,
+    }
+}
+
+pub async fn get_refundable_amount(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match refunds::refundable(&data, &id) {
+        Ok(refundable) => HttpResponse::Ok().json(refundable),
+        Err(e) => refund_error_response(e),
+    }
+}
+
+// Offer the buyer a refund; the response carries the link they claim it with
+pub async fn create_refund(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<CreateRefundRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    let new_refund = NewRefund {
+        kind: body.kind,
+        amount: body.amount,
+        rate: body.rate,
+    };
+
+    match refunds::create(&data, &id, &username, new_refund).await {
+        Ok(refund) => {
+            record_audit(&data, &username, "refund.created", Some(&client_ip(&req)), json!({
+                "refund_id": refund.id,
+                "invoice_id": refund.invoice_id,
+                "kind": refund.kind,
+                "amount": refund.amount,
+            }));
+            HttpResponse::Created().json(refund_response(refund))
+        }
+        Err(e) => refund_error_response(e),
+    }
+}
+
+pub async fn list_refunds(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match refunds::list(&data, &id) {
+        Ok(refunds) => HttpResponse::Ok().json(refunds.into_iter().map(refund_response).collect::<Vec<_>>()),
+        Err(e) => refund_error_response(e),
+    }
+}
+
+pub async fn cancel_refund(
+    req: HttpRequest,
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = match authenticated_user(&req) {
+        Some(username) => username,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+
+    match refunds::cancel(&data, &id).await {
+        Ok(refund) => {
+            record_audit(&data, &username, "refund.cancelled", Some(&client_ip(&req)), json!({
+                "refund_id": refund.id,
+                "invoice_id": refund.invoice_id,
+            }));
+            HttpResponse::Ok().json(refund)
+        }
+        Err(e) => refund_error_response(e),
+    }
+}
+
+// Public view of a refund for the buyer's claim page
+pub async fn get_refund_claim(
+    id: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match refunds::get(&data, &id) {
+        Ok(refund) => HttpResponse::Ok().json(json!({
+            "id": refund.id,
+            "invoice_id": refund.invoice_id,
+            "amount": refund.amount,
+            "status": refund.status,
+            "destination": refund.destination,
+        })),
+        Err(e) => refund_error_response(e),
+    }
+}
+
+pub async fn claim_refund(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ClaimRefundRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match refunds::claim(&data, &id, &body.destination, Network::Testnet).await {
+        Ok(refund) => {
+            record_audit(&data, "buyer", "refund.claimed", Some(&client_ip(&req)), json!({
+                "refund_id": refund.id,
+                "invoice_id": refund.invoice_id,
+                "destination": refund.destination,
+                "payout_id": refund.payout_id,
+            }));
+            HttpResponse::Ok().json(json!({
+                "id": refund.id,
+                "amount": refund.amount,
+                "status": refund.status,
+                "destination": refund.destination,
+            }))
+        }
+        Err(e) => refund_error_response(e),
+    }
+}
+
+pub async fn get_user_roles(
+    req: HttpRequest,
+    username: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+
+    match data.db.get_user_roles(&username) {
+        Ok(roles) => HttpResponse::Ok().json(UserRoles { roles }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn set_user_roles(
+    req: HttpRequest,
+    username: web::Path<String>,
+    body: web::Json<UserRoles>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().body("Admin access required");
+    }
+    let admin = match require_step_up(&req, &data) {
+        Ok(admin) => admin,
+        Err(e) => return second_factor_error_response(e),
+    };
+
+    if let Err(e) = data.db.set_user_roles(&username, &body.roles) {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    record_audit(&data, &admin, "roles.updated", Some(&client_ip(&req)), json!({
+        "username": username.as_str(),
+        "roles": body.roles,
+    }));
+    HttpResponse::Ok().json(body.into_inner())
+}
+
+// Create a user or change a password. Admins may set anyone's (with step-up
+// authentication); everyone else only their own, confirming the current one.
+pub async fn set_user_password(
+    req: HttpRequest,
+    username: web::Path<String>,
+    body: web::Json<SetPasswordRequest>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let actor = match authenticated_user(&req) {
+        Some(actor) => actor,
+        None => return HttpResponse::Unauthorized().body("Not authenticated"),
+    };
+    if is_admin(&req) {
+        if let Err(e) = require_step_up(&req, &data) {
+            return second_factor_error_response(e);
+        }
+    } else if actor != username.as_str() {
+        return HttpResponse::Forbidden().body("Admin access required");
+    } else {
+        let current = body.current_password.as_deref().unwrap_or_default();
+        match users::authenticate(&data.db, &actor, current) {
+            Ok(()) => {}
+            Err(UserError::Database(e)) => {
+                return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+            }
+            Err(_) => return HttpResponse::Forbidden().body("Current password is incorrect"),
+        }
+    }
+
+    match users::set_password(&data.db, &username, &body.password) {
+        Ok(()) => {}
+        Err(UserError::Database(e)) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+        }
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    }
+
+    record_audit(&data, &actor, "user.password_set", Some(&client_ip(&req)), json!({
+        "username": username.as_str(),
+    }));
+    HttpResponse::NoContent().finish()
+}
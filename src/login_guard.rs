@@ -0,0 +1,379 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use log::warn;
+
+// Failed attempts before a username is temporarily locked
+const MAX_USERNAME_FAILURES: u32 = 5;
+// Failed attempts from one IP (across all usernames) before it is locked out
+const MAX_IP_FAILURES: u32 = 20;
+// Delay after the first failure, doubled for each further failure
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+// First lockout duration, doubled for each repeated lockout
+const BASE_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+const MAX_LOCKOUT: Duration = Duration::from_secs(24 * 3600);
+// Failure records older than this are forgotten
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 3600);
+// Records kept per map; the least recently failed are dropped beyond this
+const MAX_TRACKED: usize = 10_000;
+// Addresses remembered per username as having logged in successfully
+const MAX_KNOWN_IPS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockReason {
+    Delay,
+    UsernameLocked,
+    IpLocked,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoginBlocked {
+    pub reason: BlockReason,
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for LoginBlocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            BlockReason::Delay => write!(f, "Too many failed login attempts, retry in {} seconds", self.retry_after.as_secs().max(1)),
+            BlockReason::UsernameLocked => write!(f, "Account temporarily locked, retry in {} seconds", self.retry_after.as_secs().max(1)),
+            BlockReason::IpLocked => write!(f, "Too many failed login attempts from this address, retry in {} seconds", self.retry_after.as_secs().max(1)),
+        }
+    }
+}
+
+// Outcome of recording a failed attempt, used for audit logging
+#[derive(Debug, Clone, Copy)]
+pub struct FailureOutcome {
+    pub username_failures: u32,
+    pub ip_failures: u32,
+    pub username_locked: bool,
+    pub ip_locked: bool,
+}
+
+#[derive(Debug, Clone)]
+struct FailureRecord {
+    failures: u32,
+    lockouts: u32,
+    last_failure: Instant,
+    next_attempt_at: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailureRecord {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            lockouts: 0,
+            last_failure: now,
+            next_attempt_at: now,
+            locked_until: None,
+        }
+    }
+
+    // Register a failure; returns true if this failure triggered a lockout
+    fn register_failure(&mut self, now: Instant, max_failures: u32) -> bool {
+        self.failures += 1;
+        self.last_failure = now;
+
+        let exponent = (self.failures - 1).min(16);
+        let delay = BASE_DELAY.saturating_mul(1u32 << exponent).min(MAX_DELAY);
+        self.next_attempt_at = now + delay;
+
+        if self.failures >= max_failures {
+            let exponent = self.lockouts.min(16);
+            let lockout = BASE_LOCKOUT.saturating_mul(1u32 << exponent).min(MAX_LOCKOUT);
+            self.locked_until = Some(now + lockout);
+            self.lockouts += 1;
+            self.failures = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn blocked_for(&self, now: Instant) -> Option<(Duration, bool)> {
+        if let Some(locked_until) = self.locked_until {
+            if locked_until > now {
+                return Some((locked_until - now, true));
+            }
+        }
+        if self.next_attempt_at > now {
+            return Some((self.next_attempt_at - now, false));
+        }
+        None
+    }
+}
+
+// Tracks failed logins per username and per IP, applying progressive delays
+// and temporary lockouts. A username's lockout doesn't apply from addresses
+// it has logged in from before, so failures from elsewhere can't keep an
+// account (like the only admin) locked out; those addresses are still
+// limited by their own IP record. Records are kept in memory only, so a
+// restart clears every delay and lockout.
+pub struct LoginGuard {
+    records: Mutex<Records>,
+}
+
+#[derive(Default)]
+struct Records {
+    usernames: HashMap<String, FailureRecord>,
+    ips: HashMap<String, FailureRecord>,
+    known_ips: HashMap<String, Vec<String>>,
+    // Usernames and IPs with an attempt still being verified
+    pending_usernames: HashSet<String>,
+    pending_ips: HashSet<String>,
+}
+
+impl Records {
+    fn is_known_ip(&self, username: &str, ip: &str) -> bool {
+        self.known_ips
+            .get(username)
+            .is_some_and(|ips| ips.iter().any(|known| known == ip))
+    }
+}
+
+// A login attempt let through by `LoginGuard::begin`. Until its outcome is
+// recorded or it is dropped, further attempts for the same username or from
+// the same IP are held back, so parallel guesses can't all pass the check
+// before the first failure counts.
+pub struct LoginAttempt<'a> {
+    guard: &'a LoginGuard,
+    username: String,
+    ip: String,
+    known_ip: bool,
+}
+
+impl LoginGuard {
+    pub fn new() -> Self {
+        Self { records: Mutex::new(Records::default()) }
+    }
+
+    fn records(&self) -> MutexGuard<'_, Records> {
+        self.records.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Check whether a login attempt may proceed, holding back others for the
+    // same username or IP until it is finished
+    pub fn begin(&self, username: &str, ip: &str) -> Result<LoginAttempt<'_>, LoginBlocked> {
+        let now = Instant::now();
+        let mut records = self.records();
+
+        if let Some(record) = records.ips.get(ip) {
+            if let Some((retry_after, locked)) = record.blocked_for(now) {
+                let reason = if locked { BlockReason::IpLocked } else { BlockReason::Delay };
+                return Err(LoginBlocked { reason, retry_after });
+            }
+        }
+        let known_ip = records.is_known_ip(username, ip);
+        if !known_ip {
+            if let Some(record) = records.usernames.get(username) {
+                if let Some((retry_after, locked)) = record.blocked_for(now) {
+                    let reason = if locked { BlockReason::UsernameLocked } else { BlockReason::Delay };
+                    return Err(LoginBlocked { reason, retry_after });
+                }
+            }
+        }
+        // Attempts from known addresses don't hold the username, so others
+        // can't keep it busy
+        if records.pending_ips.contains(ip) || (!known_ip && records.pending_usernames.contains(username)) {
+            return Err(LoginBlocked { reason: BlockReason::Delay, retry_after: BASE_DELAY });
+        }
+
+        records.pending_ips.insert(ip.to_string());
+        if !known_ip {
+            records.pending_usernames.insert(username.to_string());
+        }
+        Ok(LoginAttempt {
+            guard: self,
+            username: username.to_string(),
+            ip: ip.to_string(),
+            known_ip,
+        })
+    }
+
+    // Administrative unlock; returns true if the username had a failure record
+    pub fn unlock(&self, username: &str) -> bool {
+        self.records().usernames.remove(username).is_some()
+    }
+
+    pub fn unlock_ip(&self, ip: &str) -> bool {
+        self.records().ips.remove(ip).is_some()
+    }
+
+    // Usernames that are currently locked, with the remaining lockout time
+    pub fn locked_usernames(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        self.records()
+            .usernames
+            .iter()
+            .filter_map(|(username, record)| match record.blocked_for(now) {
+                Some((remaining, true)) => Some((username.clone(), remaining)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The record for a key, created if needed. Stale records are pruned
+    // first, and if the map is still full the least recently failed one
+    // makes room.
+    fn record_for<'a>(records: &'a mut HashMap<String, FailureRecord>, key: &str, now: Instant) -> &'a mut FailureRecord {
+        if !records.contains_key(key) {
+            Self::prune(records, now);
+            if records.len() >= MAX_TRACKED {
+                let oldest = records
+                    .iter()
+                    .min_by_key(|(_, record)| record.last_failure)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    records.remove(&oldest);
+                }
+            }
+        }
+        records.entry(key.to_string()).or_insert_with(|| FailureRecord::new(now))
+    }
+
+    fn prune(records: &mut HashMap<String, FailureRecord>, now: Instant) {
+        records.retain(|_, record| {
+            let still_locked = record.locked_until.is_some_and(|until| until > now);
+            still_locked || now.duration_since(record.last_failure) < FAILURE_MEMORY
+        });
+    }
+}
+
+impl LoginAttempt<'_> {
+    pub fn record_failure(self) -> FailureOutcome {
+        let now = Instant::now();
+        let mut records = self.guard.records();
+
+        // Failures from an address the user logged in from before only
+        // count against that address
+        let (username_failures, username_locked) = if self.known_ip {
+            (0, false)
+        } else {
+            let record = LoginGuard::record_for(&mut records.usernames, &self.username, now);
+            let locked = record.register_failure(now, MAX_USERNAME_FAILURES);
+            (record.failures, locked)
+        };
+        let record = LoginGuard::record_for(&mut records.ips, &self.ip, now);
+        let ip_locked = record.register_failure(now, MAX_IP_FAILURES);
+        let ip_failures = record.failures;
+
+        if username_locked {
+            warn!("Account {} locked after repeated failed logins", self.username);
+        }
+        if ip_locked {
+            warn!("IP {} locked out after repeated failed logins", self.ip);
+        }
+
+        FailureOutcome {
+            username_failures,
+            ip_failures,
+            username_locked,
+            ip_locked,
+        }
+    }
+
+    // A successful login clears the username's failure history and marks the
+    // address as known for it. The IP record is kept so one valid account
+    // can't be used to reset stuffing attempts.
+    pub fn record_success(self) {
+        let mut records = self.guard.records();
+        records.usernames.remove(&self.username);
+
+        if !records.known_ips.contains_key(&self.username) && records.known_ips.len() >= MAX_TRACKED {
+            return;
+        }
+        let ips = records.known_ips.entry(self.username.clone()).or_default();
+        ips.retain(|known| *known != self.ip);
+        ips.push(self.ip.clone());
+        if ips.len() > MAX_KNOWN_IPS {
+            ips.remove(0);
+        }
+    }
+}
+
+// Finishing the attempt, or giving up on it (e.g. on a database error), lets
+// the next one through
+impl Drop for LoginAttempt<'_> {
+    fn drop(&mut self) {
+        let mut records = self.guard.records();
+        records.pending_ips.remove(&self.ip);
+        if !self.known_ip {
+            records.pending_usernames.remove(&self.username);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(guard: &LoginGuard, username: &str, ip: &str) -> FailureOutcome {
+        guard.begin(username, ip).unwrap().record_failure()
+    }
+
+    // Backdate the delays so the next attempt isn't held back by them
+    fn skip_delays(guard: &LoginGuard) {
+        let now = Instant::now();
+        let records = &mut *guard.records();
+        for record in records.usernames.values_mut().chain(records.ips.values_mut()) {
+            record.next_attempt_at = now;
+        }
+    }
+
+    #[test]
+    fn username_lockout_spares_known_addresses() {
+        let guard = LoginGuard::new();
+        guard.begin("admin", "10.0.0.1").unwrap().record_success();
+
+        let mut outcome = fail(&guard, "admin", "192.0.2.1");
+        for _ in 1..MAX_USERNAME_FAILURES {
+            skip_delays(&guard);
+            outcome = fail(&guard, "admin", "192.0.2.1");
+        }
+        assert!(outcome.username_locked);
+        assert_eq!(guard.begin("admin", "192.0.2.2").err().unwrap().reason, BlockReason::UsernameLocked);
+        assert!(guard.begin("admin", "10.0.0.1").is_ok());
+
+        // Failures from the known address count against it, not the username
+        let outcome = fail(&guard, "admin", "10.0.0.1");
+        assert_eq!(outcome.username_failures, 0);
+        assert_eq!(outcome.ip_failures, 1);
+    }
+
+    #[test]
+    fn parallel_attempts_wait_for_the_first() {
+        let guard = LoginGuard::new();
+        guard.begin("admin", "10.0.0.1").unwrap().record_success();
+
+        let attempt = guard.begin("alice", "192.0.2.1").unwrap();
+        assert_eq!(guard.begin("alice", "192.0.2.1").err().unwrap().reason, BlockReason::Delay);
+        assert_eq!(guard.begin("alice", "192.0.2.2").err().unwrap().reason, BlockReason::Delay);
+        assert_eq!(guard.begin("bob", "192.0.2.1").err().unwrap().reason, BlockReason::Delay);
+        // Known addresses don't wait for attempts from elsewhere
+        let admin = guard.begin("admin", "192.0.2.3").unwrap();
+        assert!(guard.begin("admin", "10.0.0.1").is_ok());
+        drop(admin);
+
+        // The failure is counted before the next attempt is let through
+        assert_eq!(attempt.record_failure().username_failures, 1);
+        assert_eq!(guard.begin("alice", "192.0.2.2").err().unwrap().reason, BlockReason::Delay);
+        skip_delays(&guard);
+        assert!(guard.begin("alice", "192.0.2.2").is_ok());
+    }
+
+    #[test]
+    fn full_maps_drop_the_least_recent_record() {
+        let now = Instant::now();
+        let mut records = HashMap::new();
+        for index in 0..MAX_TRACKED {
+            records.insert(index.to_string(), FailureRecord::new(now + Duration::from_millis(index as u64)));
+        }
+        LoginGuard::record_for(&mut records, "new", now);
+        assert_eq!(records.len(), MAX_TRACKED);
+        assert!(!records.contains_key("0"));
+        assert!(records.contains_key("new"));
+    }
+}
@@ -1,13 +1,37 @@
 
 mod models;
+mod database;
 mod handlers;
 mod state;
 mod blockchain;
+mod core_rpc;
+mod fee_estimator;
+mod fee_bump;
 mod trezor;
 mod auth;
+mod totp;
+mod users;
+mod login_guard;
+mod psbt;
+mod signer;
+mod address_verifier;
+mod air_gap;
+mod broadcast;
+mod chain_watcher;
+mod p2p;
+mod wallet;
+mod wallet_setup;
+mod tx_validation;
+mod tx_decoder;
+mod tx_builder;
+mod spending_policy;
+mod proposals;
+mod payouts;
+mod refunds;
+mod webhook;
+mod zero_conf;
 
 use actix_web::{web, App, HttpServer, middleware};
-use actix_web_httpauth::middleware::HttpAuthentication;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -65,6 +89,34 @@ async fn main() -> std::io::Result<()> {
 
     // Initialize application state with database
     let app_state = web::Data::new(AppState::new("btc_pay_server.db"));
+    users::bootstrap_admin(&app_state.db, handlers::ADMIN_USERNAME);
+
+    // Optionally compare samples of newly derived addresses with the Trezor
+    if let Some((interval, sample_size)) = address_verifier::sample_check_config() {
+        info!("Checking address samples against the Trezor every {:?}", interval);
+        actix_web::rt::spawn(address_verifier::run_sample_checks(app_state.clone(), interval, sample_size));
+    }
+
+    // Keep store wallets' UTXO sets up to date
+    if let Some(interval) = wallet::sync_interval() {
+        actix_web::rt::spawn(wallet::run_sync(app_state.clone(), interval, bitcoin::Network::Testnet));
+    }
+
+    // Follow broadcast transactions until they confirm, rebroadcasting them
+    actix_web::rt::spawn(broadcast::run_tracker(app_state.clone(), broadcast::rebroadcast_interval()));
+
+    // Record invoice payments and roll back confirmations undone by reorgs
+    if let Some(interval) = chain_watcher::watch_interval() {
+        actix_web::rt::spawn(chain_watcher::run_watcher(app_state.clone(), interval, bitcoin::Network::Testnet));
+    }
+
+    // Expire stale spend proposals
+    actix_web::rt::spawn(proposals::run_expiry(app_state.clone()));
+
+    // Send approved payouts in batches
+    if let Some(interval) = payouts::process_interval() {
+        actix_web::rt::spawn(payouts::run_processor(app_state.clone(), interval, bitcoin::Network::Testnet));
+    }
     
     // Create rate limiter - 100 requests per minute
     let rate_limiter = Arc::new(RateLimiter::new(100, 60));
@@ -73,35 +125,95 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         // Clone rate limiter for this thread
         let rate_limiter = Arc::clone(&rate_limiter);
+        let limiter_state = app_state.clone();
         
         // Rate limiting middleware
-        let rate_limit = move |req: actix_web::dev::ServiceRequest| {
-            let ip = req
-                .connection_info()
-                .realip_remote_addr()
-                .unwrap_or("unknown")
-                .to_owned();
+        let rate_limit = move |req: actix_web::dev::ServiceRequest, srv: &_| {
+            let ip = limiter_state.client_ip(req.request());
                 
             if rate_limiter.is_rate_limited(&ip) {
-                return futures::future::err(
+                return futures::future::Either::Left(futures::future::err(
                     actix_web::error::ErrorTooManyRequests("Rate limit exceeded")
-                );
+                ));
             }
             
-            futures::future::ok(req)
+            futures::future::Either::Right(actix_web::dev::Service::call(srv, req))
         };
         // Public routes don't require authentication
         let public_scope = web::scope("/api/public")
             .route("/invoice", web::post().to(handlers::create_invoice))
             .route("/invoice/{id}", web::get().to(handlers::get_invoice))
-            .route("/invoice/{id}/check", web::get().to(handlers::check_payment_status));
+            .route("/invoice/{id}/check", web::get().to(handlers::check_payment_status))
+            .route("/auth/token", web::post().to(handlers::generate_token))
+            .route("/refunds/{id}", web::get().to(handlers::get_refund_claim))
+            .route("/refunds/{id}/claim", web::post().to(handlers::claim_refund));
             
         // Protected routes require authentication
-        let bearer_auth = HttpAuthentication::bearer(auth::validator);
         let private_scope = web::scope("/api/private")
-            .wrap(bearer_auth)
+            .wrap(actix_web::middleware::from_fn(auth::validator))
+            .route("/fees", web::get().to(handlers::get_fee_estimates))
+            .route("/transaction/build", web::post().to(handlers::build_transaction))
+            .route("/transaction/release", web::post().to(handlers::release_transaction))
             .route("/transaction/sign", web::post().to(handlers::sign_transaction))
-            .route("/auth/token", web::post().to(handlers::generate_token));
+            .route("/transaction/decode", web::post().to(handlers::decode_transaction))
+            .route("/transaction/combine", web::post().to(handlers::combine_transaction))
+            .route("/transactions", web::get().to(handlers::list_outgoing_transactions))
+            .route("/transactions/{txid}", web::get().to(handlers::get_outgoing_transaction))
+            .route("/transactions/{txid}/bump", web::post().to(handlers::bump_transaction))
+            .route("/transaction/export", web::post().to(handlers::export_transaction))
+            .route("/transaction/export/{id}", web::get().to(handlers::get_psbt_export))
+            .route("/transaction/export/{id}/download", web::get().to(handlers::download_psbt_export))
+            .route("/transaction/export/{id}/import", web::post().to(handlers::import_psbt_export))
+            .route("/auth/2fa/enroll", web::post().to(handlers::enroll_totp))
+            .route("/auth/2fa/confirm", web::post().to(handlers::confirm_totp))
+            .route("/auth/2fa/recovery-codes", web::post().to(handlers::regenerate_recovery_codes))
+            .route("/auth/2fa/disable", web::post().to(handlers::disable_totp))
+            .route("/auth/lockouts", web::get().to(handlers::list_lockouts))
+            .route("/auth/unlock", web::post().to(handlers::unlock_account))
+            .route("/audit", web::get().to(handlers::get_audit_log))
+            .route("/trezor/devices", web::get().to(handlers::list_trezor_devices))
+            .route("/trezor/connect", web::post().to(handlers::connect_trezor))
+            .route("/trezor/disconnect", web::post().to(handlers::disconnect_trezor))
+            .route("/trezor/session", web::get().to(handlers::get_trezor_session))
+            .route("/trezor/session/pin", web::post().to(handlers::submit_trezor_pin))
+            .route("/trezor/session/passphrase", web::post().to(handlers::submit_trezor_passphrase))
+            .route("/trezor/session/cancel", web::post().to(handlers::cancel_trezor_prompt))
+            .route("/signers/{kind}/xpub", web::post().to(handlers::get_signer_xpub))
+            .route("/signers/{kind}/address", web::post().to(handlers::display_signer_address))
+            .route("/invoice/{id}/verify-address", web::post().to(handlers::verify_invoice_address))
+            .route("/address-sample-report", web::get().to(handlers::get_address_sample_report))
+            .route("/stores/{store_id}/wallet", web::get().to(handlers::get_store_wallet))
+            .route("/stores/{store_id}/wallet", web::put().to(handlers::setup_store_wallet))
+            .route("/stores/{store_id}/wallet/balance", web::get().to(handlers::get_wallet_balance))
+            .route("/stores/{store_id}/wallet/utxos", web::get().to(handlers::list_wallet_utxos))
+            .route("/stores/{store_id}/wallet/sync", web::post().to(handlers::sync_store_wallet))
+            .route("/stores/{store_id}/policy", web::get().to(handlers::get_spending_policy))
+            .route("/stores/{store_id}/policy", web::put().to(handlers::set_spending_policy))
+            .route("/stores/{store_id}/zero-conf-policy", web::get().to(handlers::get_zero_conf_policy))
+            .route("/stores/{store_id}/zero-conf-policy", web::put().to(handlers::set_zero_conf_policy))
+            .route("/proposals", web::post().to(handlers::create_proposal))
+            .route("/proposals", web::get().to(handlers::list_proposals))
+            .route("/proposals/{id}", web::get().to(handlers::get_proposal))
+            .route("/proposals/{id}/approve", web::post().to(handlers::approve_proposal))
+            .route("/proposals/{id}/reject", web::post().to(handlers::reject_proposal))
+            .route("/proposals/{id}/sign", web::post().to(handlers::sign_proposal))
+            .route("/proposals/{id}/signatures", web::post().to(handlers::add_proposal_signatures))
+            .route("/stores/{store_id}/payouts", web::post().to(handlers::create_payout))
+            .route("/stores/{store_id}/payouts", web::get().to(handlers::list_payouts))
+            .route("/stores/{store_id}/payouts/csv", web::post().to(handlers::upload_payouts_csv))
+            .route("/payouts/{id}", web::get().to(handlers::get_payout))
+            .route("/payouts/{id}/approve", web::post().to(handlers::approve_payout))
+            .route("/payouts/{id}/cancel", web::post().to(handlers::cancel_payout))
+            .route("/invoice/{id}/accelerate", web::post().to(handlers::accelerate_invoice))
+            .route("/invoice/{id}/timeline", web::get().to(handlers::get_invoice_timeline))
+            .route("/invoice/{id}/payments", web::get().to(handlers::get_invoice_payments))
+            .route("/invoice/{id}/refundable", web::get().to(handlers::get_refundable_amount))
+            .route("/invoice/{id}/refunds", web::post().to(handlers::create_refund))
+            .route("/invoice/{id}/refunds", web::get().to(handlers::list_refunds))
+            .route("/refunds/{id}/cancel", web::post().to(handlers::cancel_refund))
+            .route("/users/{username}/roles", web::get().to(handlers::get_user_roles))
+            .route("/users/{username}/roles", web::put().to(handlers::set_user_roles))
+            .route("/users/{username}/password", web::put().to(handlers::set_user_password));
             
         App::new()
             .app_data(jwt_secret.clone())
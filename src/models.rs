@@ -2,17 +2,28 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 
+// Store used when a payment request doesn't name one
+pub const DEFAULT_STORE_ID: &str = "default";
+
+pub fn default_store_id() -> String {
+    DEFAULT_STORE_ID.to_string()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PaymentRequest {
     pub amount: u64,        // Amount in satoshis
     pub description: String, // Payment description
     pub expiry: u64,        // Expiry in seconds
+    #[serde(default = "default_store_id")]
+    pub store_id: String,   // Store whose wallet receives the payment
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum InvoiceStatus {
     Pending,
-    Paid,
+    Processing, // Paid in full, waiting for confirmations
+    Settled,
+    Invalid, // Expired after the payments it had disappeared from the chain
     Expired,
 }
 
@@ -23,6 +34,13 @@ pub struct Invoice {
     pub amount: u64,
     pub description: String,
     pub status: InvoiceStatus,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    // Path of the receive address from the wallet's master key, when the
+    // address was derived from the configured account key
+    pub derivation_path: Option<String>,
+    pub store_id: String,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WebhookConfig {
@@ -30,14 +48,293 @@ pub struct WebhookConfig {
     pub secret: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct UserTotp {
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct WebhookEvent {
-    pub event_type: String,
-    pub invoice_id: String,
+pub struct AuditEvent {
+    pub id: String,
     pub timestamp: DateTime<Utc>,
-    pub data: serde_json::Value,
+    pub actor: String,
+    pub action: String,
+    pub ip: Option<String>,
+    pub details: serde_json::Value,
 }
 
+// The wallet a store's invoices pay into, saved as an output descriptor
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoreWallet {
+    pub store_id: String,
+    pub descriptor: String,
+    pub source: String, // trezor, xpub, descriptor or file
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Rules outgoing transactions from a store's wallet must satisfy before
+// they are signed. Unset limits are not enforced.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SpendingPolicy {
+    pub daily_limit: Option<u64>,        // Satoshis per rolling 24 hours
+    pub weekly_limit: Option<u64>,       // Satoshis per rolling 7 days
+    pub allowed_destinations: Vec<String>, // Empty allows any address
+    pub max_fee_rate: Option<f64>,       // sat/vB
+    pub large_amount_threshold: Option<u64>,
+    pub large_amount_delay_secs: u64,    // Wait before a large spend can be signed
+    pub approvals_required: u32,         // Approvers needed before signing (0 disables)
+    pub approval_threshold: Option<u64>, // Spends below this skip approval
+    pub proposal_ttl_secs: Option<u64>,  // Pending proposals expire after this
+    pub max_acceleration_fee: Option<u64>, // Satoshis a CPFP of an incoming payment may pay
+}
+
+// Whether a store treats unconfirmed invoice payments as final. Only
+// payments with a risk score up to `max_risk_score` are, and only while the
+// invoice's accepted unconfirmed payments total at most `max_amount`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ZeroConfPolicy {
+    pub enabled: bool,
+    pub max_amount: u64,     // Satoshis per invoice
+    pub max_risk_score: u32, // 0 to 100
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Approver, // May approve or reject spend proposals
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Approver => "approver",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Pending,
+    Approved,
+    Rejected,
+    Expired,
+    Broadcast,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProposalVote {
+    pub username: String,
+    pub approve: bool,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// An outgoing transaction waiting for approvers before it can be signed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpendProposal {
+    pub id: String,
+    pub store_id: String,
+    pub psbt: String,
+    pub description: Option<String>,
+    pub amount: u64,
+    pub created_by: String,
     pub created_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
+    pub status: ProposalStatus,
+    pub required_approvals: u32,
+    pub votes: Vec<ProposalVote>,
+    pub txid: Option<String>,
+}
+
+impl SpendProposal {
+    pub fn approval_count(&self) -> u32 {
+        self.votes.iter().filter(|vote| vote.approve).count() as u32
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ExportStatus {
+    Pending,
+    Broadcast,
+}
+
+// A PSBT handed to an air-gapped signer, waiting for its signed copy
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PsbtExport {
+    pub id: String,
+    pub store_id: String,
+    pub psbt: String, // Base64, with any signatures uploaded so far
+    pub amount: u64,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub status: ExportStatus,
+    pub txid: Option<String>,
+}
+
+// An unspent output paying to one of a store wallet's derived addresses
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    pub store_id: String,
+    pub address: String,
+    pub value: u64,
+    pub script_pubkey: String, // Hex
+    pub derivation_path: String,
+    pub confirmed: bool,
+    pub block_height: Option<u32>,
+    // Invoice the output paid, for receive addresses handed out with one
+    pub invoice_id: Option<String>,
+    pub first_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WalletBalance {
+    pub store_id: String,
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+    pub total: u64,
+    pub utxo_count: usize,
+    pub synced_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutStatus {
+    AwaitingApproval,
+    AwaitingPayment, // Approved and queued for the next batch
+    InProgress,      // Being sent, held as a proposal, or waiting for confirmation
+    Completed,
+    Cancelled,
+}
+
+// A queued payment to an external address, sent in batches by the payout processor
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Payout {
+    pub id: String,
+    pub store_id: String,
+    pub destination: String,
+    pub amount: u64, // Satoshis
+    // Currency the amount was requested in
+    pub currency: String,
+    pub description: Option<String>,
+    pub status: PayoutStatus,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub txid: Option<String>,
+    // Spend proposal holding the payout's batch until approvers sign off
+    pub proposal_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundKind {
+    Full,     // Everything the invoice received
+    Overpaid, // What was received beyond the invoice amount
+    Custom,
+}
+
+// Rate a refund is valued at. Invoices are priced in satoshis, so both
+// currently refund the same amount.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundRate {
+    #[default]
+    Original,
+    Current,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RefundStatus {
+    AwaitingClaim, // Waiting for the buyer to submit an address
+    Claimed,       // Queued as a payout
+    Cancelled,
+}
+
+// Money owed back to an invoice's buyer, paid out once they claim it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Refund {
+    pub id: String,
+    pub invoice_id: String,
+    pub store_id: String,
+    pub kind: RefundKind,
+    pub rate: RefundRate,
+    pub amount: u64, // Satoshis
+    pub status: RefundStatus,
+    pub destination: Option<String>,
+    pub payout_id: Option<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub claimed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OutgoingStatus {
+    Unconfirmed,
+    Confirmed,
+    Replaced, // A transaction spending the same inputs was broadcast
+}
+
+// A transaction broadcast from a store's wallet, tracked until it confirms
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutgoingTransaction {
+    pub txid: String,
+    pub store_id: String,
+    pub psbt: String, // Base64, as signed
+    pub amount: u64,  // Leaving the wallet, excluding change and fee
+    pub fee: u64,
+    pub vsize: u64,
+    // Output paying back to the wallet, which CPFP children spend
+    pub change_vout: Option<u32>,
+    pub status: OutgoingStatus,
+    pub replaced_by: Option<String>,
+    pub block_height: Option<u32>,
+    pub broadcast_at: DateTime<Utc>,
+    #[serde(skip_serializing)]
+    pub tx_hex: String,
+    pub last_broadcast_at: DateTime<Utc>,
+    pub broadcast_count: u32,
+}
+
+// Something that happened to an invoice, shown on its timeline
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoiceEvent {
+    pub invoice_id: String,
+    pub event_type: String,
+    pub message: String,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Unconfirmed,
+    Confirmed,
+    Invalid, // Dropped by a reorg or the mempool, or double-spent
+}
+
+// An output paying an invoice's address
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoicePayment {
+    pub invoice_id: String,
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+    pub status: PaymentStatus,
+    pub block_height: Option<u32>,
+    pub block_hash: Option<String>,
+    pub seen_at: DateTime<Utc>,
+    // Outpoints ("txid:vout") the paying transaction spends
+    pub inputs: Vec<String>,
+    // Transaction that spent the same inputs instead
+    pub replaced_by: Option<String>,
+    // 0-conf risk, 0 (safest) to 100, from while the payment was unconfirmed
+    pub risk_score: Option<u32>,
+    pub risk_factors: Vec<String>,
+    // Treated as final before confirming, under the store's 0-conf policy
+    pub zero_conf_accepted: bool,
 }
@@ -0,0 +1,99 @@
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_network::{Reject, VersionMessage};
+use bitcoin::{Network, Transaction};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const USER_AGENT: &str = "/btc-pay-server:0.1/";
+// Magic, command, payload length and checksum
+const HEADER_LEN: usize = 24;
+const MAX_PAYLOAD_LEN: usize = 4 * 1024 * 1024;
+const PEER_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Hand a transaction directly to a peer. Peers don't acknowledge
+// transactions, so a ping follows it: any `reject` for it arrives before the
+// pong. Returns the rejection, if any.
+pub async fn send_transaction(peer: &str, network: Network, tx: &Transaction) -> Result<Option<Reject>, String> {
+    tokio::time::timeout(PEER_TIMEOUT, exchange(peer, network, tx))
+        .await
+        .map_err(|_| format!("{} timed out", peer))?
+}
+
+async fn exchange(peer: &str, network: Network, tx: &Transaction) -> Result<Option<Reject>, String> {
+    let mut stream = TcpStream::connect(peer)
+        .await
+        .map_err(|e| format!("Connecting to {} failed: {}", peer, e))?;
+    let peer_addr = stream.peer_addr().map_err(|e| e.to_string())?;
+    let local_addr = stream.local_addr().map_err(|e| e.to_string())?;
+
+    let version = VersionMessage::new(
+        ServiceFlags::NONE,
+        chrono::Utc::now().timestamp(),
+        Address::new(&peer_addr, ServiceFlags::NONE),
+        Address::new(&local_addr, ServiceFlags::NONE),
+        rand::random(),
+        USER_AGENT.to_string(),
+        0,
+    );
+    send(&mut stream, network, NetworkMessage::Version(version)).await?;
+
+    let ping_nonce: u64 = rand::random();
+    let (mut got_version, mut got_verack, mut sent_tx) = (false, false, false);
+    loop {
+        match receive(&mut stream, network).await? {
+            NetworkMessage::Version(_) => {
+                got_version = true;
+                send(&mut stream, network, NetworkMessage::Verack).await?;
+            }
+            NetworkMessage::Verack => got_verack = true,
+            NetworkMessage::Ping(nonce) => send(&mut stream, network, NetworkMessage::Pong(nonce)).await?,
+            NetworkMessage::Pong(nonce) if nonce == ping_nonce => return Ok(None),
+            NetworkMessage::Reject(reject) if sent_tx && reject.message == "tx" => return Ok(Some(reject)),
+            _ => {}
+        }
+
+        if got_version && got_verack && !sent_tx {
+            send(&mut stream, network, NetworkMessage::Tx(tx.clone())).await?;
+            send(&mut stream, network, NetworkMessage::Ping(ping_nonce)).await?;
+            sent_tx = true;
+        }
+    }
+}
+
+async fn send(stream: &mut TcpStream, network: Network, payload: NetworkMessage) -> Result<(), String> {
+    let message = RawNetworkMessage {
+        magic: network.magic(),
+        payload,
+    };
+    stream
+        .write_all(&serialize(&message))
+        .await
+        .map_err(|e| format!("Writing to peer failed: {}", e))
+}
+
+async fn receive(stream: &mut TcpStream, network: Network) -> Result<NetworkMessage, String> {
+    let mut buffer = vec![0u8; HEADER_LEN];
+    stream
+        .read_exact(&mut buffer)
+        .await
+        .map_err(|e| format!("Reading from peer failed: {}", e))?;
+    let payload_len = u32::from_le_bytes([buffer[16], buffer[17], buffer[18], buffer[19]]) as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(format!("Peer sent an oversized message of {} bytes", payload_len));
+    }
+
+    buffer.resize(HEADER_LEN + payload_len, 0);
+    stream
+        .read_exact(&mut buffer[HEADER_LEN..])
+        .await
+        .map_err(|e| format!("Reading from peer failed: {}", e))?;
+    let message: RawNetworkMessage = deserialize(&buffer).map_err(|e| format!("Invalid message from peer: {}", e))?;
+    if message.magic != network.magic() {
+        return Err("Peer is on a different network".to_string());
+    }
+    Ok(message.payload)
+}
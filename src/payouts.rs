@@ -0,0 +1,755 @@
+use bitcoin::{Address, Network};
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::Error as SqliteError;
+use serde_json::json;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::broadcast;
+use crate::fee_estimator::DEFAULT_TARGET_BLOCKS;
+use crate::models::{OutgoingStatus, Payout, PayoutStatus, ProposalStatus, SpendProposal};
+use crate::proposals::{self, NewProposal, ProposalError};
+use crate::psbt::{self, Psbt};
+use crate::signer::Signer;
+use crate::spending_policy::{self, PolicyViolation};
+use crate::state::AppState;
+use crate::tx_builder::{self, Destination};
+
+const DEFAULT_PROCESS_INTERVAL_SECS: u64 = 600;
+// Payouts sent in one transaction at most; the rest wait for the next run
+const MAX_BATCH_SIZE: usize = 100;
+pub const DUST_LIMIT: u64 = 546;
+const SATS_PER_BTC: u64 = 100_000_000;
+// Proposer of the spend proposals holding payout batches
+const PROCESSOR_USER: &str = "payout-processor";
+// Replacements followed from a payout's original transaction
+const MAX_REPLACEMENTS: usize = 16;
+
+#[derive(Debug)]
+pub enum PayoutError {
+    NotFound,
+    InvalidDestination(String),
+    InvalidAmount(String),
+    UnsupportedCurrency(String),
+    InvalidCsv { line: usize, message: String },
+    InvalidStatus(PayoutStatus),
+    Proposal(ProposalError),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for PayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayoutError::NotFound => write!(f, "Payout not found"),
+            PayoutError::InvalidDestination(msg) => write!(f, "Invalid destination: {}", msg),
+            PayoutError::InvalidAmount(msg) => write!(f, "Invalid amount: {}", msg),
+            PayoutError::UnsupportedCurrency(currency) => {
+                write!(f, "Unsupported currency {}: no exchange rate source is configured", currency)
+            }
+            PayoutError::InvalidCsv { line, message } => write!(f, "Line {}: {}", line, message),
+            PayoutError::InvalidStatus(status) => write!(f, "Payout is {:?}", status),
+            PayoutError::Proposal(e) => write!(f, "{}", e),
+            PayoutError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PayoutError {}
+
+impl From<SqliteError> for PayoutError {
+    fn from(error: SqliteError) -> Self {
+        PayoutError::Database(error)
+    }
+}
+
+impl From<ProposalError> for PayoutError {
+    fn from(error: ProposalError) -> Self {
+        PayoutError::Proposal(error)
+    }
+}
+
+pub struct NewPayout {
+    pub destination: String,
+    pub amount: String,
+    // "SAT" (default) or "BTC"
+    pub currency: Option<String>,
+    pub description: Option<String>,
+}
+
+// Validate and queue payouts for a store. Every payout is checked before any
+// is saved, so a bad CSV line queues nothing.
+pub async fn create(
+    data: &AppState,
+    store_id: &str,
+    created_by: &str,
+    new_payouts: Vec<NewPayout>,
+    network: Network,
+) -> Result<Vec<Payout>, PayoutError> {
+    let now = Utc::now();
+    let mut payouts = Vec::with_capacity(new_payouts.len());
+    for new in new_payouts {
+        let destination = Address::from_str(new.destination.trim())
+            .map_err(|e| PayoutError::InvalidDestination(format!("{}: {}", new.destination, e)))?
+            .require_network(network)
+            .map_err(|e| PayoutError::InvalidDestination(format!("{}: {}", new.destination, e)))?;
+        let currency = new.currency.as_deref().unwrap_or("SAT").trim().to_uppercase();
+        let amount = parse_amount(new.amount.trim(), &currency)?;
+        if amount < DUST_LIMIT {
+            return Err(PayoutError::InvalidAmount(format!("{} sat is below the dust limit", amount)));
+        }
+
+        payouts.push(Payout {
+            id: Uuid::new_v4().to_string(),
+            store_id: store_id.to_string(),
+            destination: destination.to_string(),
+            amount,
+            currency,
+            description: new.description.filter(|description| !description.is_empty()),
+            status: PayoutStatus::AwaitingApproval,
+            created_by: created_by.to_string(),
+            created_at: now,
+            txid: None,
+            proposal_id: None,
+        });
+    }
+
+    for payout in &payouts {
+        data.db.save_payout(payout)?;
+        notify(data, "payout.created", payout).await;
+    }
+    info!("{} payout(s) queued for store {} by {}", payouts.len(), store_id, created_by);
+    Ok(payouts)
+}
+
+// Amount in satoshis, from whole satoshis or a BTC decimal
+fn parse_amount(amount: &str, currency: &str) -> Result<u64, PayoutError> {
+    let invalid = || PayoutError::InvalidAmount(amount.to_string());
+    match currency {
+        "SAT" | "SATS" => amount.parse::<u64>().map_err(|_| invalid()),
+        "BTC" => {
+            let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+            if fraction.len() > 8 || (whole.is_empty() && fraction.is_empty()) {
+                return Err(invalid());
+            }
+            let whole = if whole.is_empty() { 0 } else { whole.parse::<u64>().map_err(|_| invalid())? };
+            let fraction = if fraction.is_empty() {
+                0
+            } else {
+                format!("{:0<8}", fraction).parse::<u64>().map_err(|_| invalid())?
+            };
+            whole
+                .checked_mul(SATS_PER_BTC)
+                .and_then(|sats| sats.checked_add(fraction))
+                .ok_or_else(invalid)
+        }
+        other => Err(PayoutError::UnsupportedCurrency(other.to_string())),
+    }
+}
+
+// Parse "destination,amount[,currency][,description]" lines. A first line
+// starting with "destination" is taken as a header; blank lines are skipped.
+pub fn parse_csv(contents: &str) -> Result<Vec<NewPayout>, PayoutError> {
+    let mut payouts = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && line.to_lowercase().starts_with("destination")) {
+            continue;
+        }
+
+        let mut fields = line.splitn(4, ',').map(|field| field.trim().trim_matches('"').to_string());
+        let destination = fields.next().unwrap_or_default();
+        let amount = fields.next().unwrap_or_default();
+        if destination.is_empty() || amount.is_empty() {
+            return Err(PayoutError::InvalidCsv {
+                line: index + 1,
+                message: "expected destination,amount[,currency][,description]".to_string(),
+            });
+        }
+        payouts.push(NewPayout {
+            destination,
+            amount,
+            currency: fields.next().filter(|currency| !currency.is_empty()),
+            description: fields.next(),
+        });
+    }
+
+    if payouts.is_empty() {
+        return Err(PayoutError::InvalidCsv { line: 1, message: "no payouts found".to_string() });
+    }
+    Ok(payouts)
+}
+
+pub fn get(data: &AppState, id: &str) -> Result<Payout, PayoutError> {
+    data.db.get_payout(id)?.ok_or(PayoutError::NotFound)
+}
+
+// Queue an awaiting payout for the next batch
+pub async fn approve(data: &AppState, id: &str) -> Result<Payout, PayoutError> {
+    let mut payout = get(data, id)?;
+    if payout.status != PayoutStatus::AwaitingApproval
+        || !data.db.transition_payout(id, PayoutStatus::AwaitingApproval, PayoutStatus::AwaitingPayment)?
+    {
+        return Err(PayoutError::InvalidStatus(get(data, id)?.status));
+    }
+    payout.status = PayoutStatus::AwaitingPayment;
+    notify(data, "payout.approved", &payout).await;
+    Ok(payout)
+}
+
+// Payouts can be cancelled until the processor picks them up for a batch.
+// The status only changes if it is still the one checked, so a payout can't
+// be cancelled while its batch is being sent.
+pub async fn cancel(data: &AppState, id: &str) -> Result<Payout, PayoutError> {
+    let mut payout = get(data, id)?;
+    if !matches!(payout.status, PayoutStatus::AwaitingApproval | PayoutStatus::AwaitingPayment)
+        || !data.db.transition_payout(id, payout.status, PayoutStatus::Cancelled)?
+    {
+        return Err(PayoutError::InvalidStatus(get(data, id)?.status));
+    }
+    payout.status = PayoutStatus::Cancelled;
+    notify(data, "payout.cancelled", &payout).await;
+    Ok(payout)
+}
+
+async fn notify(data: &AppState, event_type: &str, payout: &Payout) {
+    data.notify(event_type, json!({
+        "payout_id": payout.id,
+        "store_id": payout.store_id,
+        "destination": payout.destination,
+        "amount": payout.amount,
+        "status": payout.status,
+        "txid": payout.txid,
+    }))
+    .await;
+}
+
+// Periodically send each store's approved payouts in one transaction, signed
+// with the software signer, and complete them once it confirms. Batches the
+// spending policy holds back become spend proposals and go out once approved.
+pub async fn run_processor(data: actix_web::web::Data<AppState>, interval: Duration, network: Network) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        match data.db.list_payout_store_ids(PayoutStatus::InProgress) {
+            Ok(store_ids) => {
+                for store_id in store_ids {
+                    if let Err(e) = complete_confirmed(&data, &store_id).await {
+                        error!("Failed to check payouts of store {}: {}", store_id, e);
+                    }
+                    if let Err(e) = advance_proposals(&data, &store_id, network).await {
+                        error!("Failed to check payout proposals of store {}: {}", store_id, e);
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list in-progress payouts: {}", e),
+        }
+
+        match data.db.list_payout_store_ids(PayoutStatus::AwaitingPayment) {
+            Ok(store_ids) => {
+                for store_id in store_ids {
+                    send_batch(&data, &store_id, network).await;
+                }
+            }
+            Err(e) => error!("Failed to list awaiting payouts: {}", e),
+        }
+    }
+}
+
+async fn send_batch(data: &AppState, store_id: &str, network: Network) {
+    let signer = match &data.software_signer {
+        Some(signer) => signer,
+        None => {
+            warn!("Payouts of store {} are waiting: no software signer is configured", store_id);
+            return;
+        }
+    };
+    // Don't build (and lock up coins for) a batch the signer holds no key for
+    let fingerprint = match signer.fingerprint().await {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            error!("Failed to read the software signer fingerprint: {}", e);
+            return;
+        }
+    };
+    if !data.wallet_for(store_id).is_some_and(|wallet| wallet.fingerprints().contains(&fingerprint)) {
+        warn!("Payouts of store {} are waiting: the software signer holds none of the wallet's keys", store_id);
+        return;
+    }
+    let mut batch = match claim_batch(data, store_id) {
+        Ok(batch) if batch.is_empty() => return,
+        Ok(batch) => batch,
+        Err(e) => {
+            error!("Failed to load payouts of store {}: {}", store_id, e);
+            return;
+        }
+    };
+    let destinations: Vec<Destination> = batch
+        .iter()
+        .map(|payout| Destination { address: payout.destination.clone(), amount: payout.amount })
+        .collect();
+
+    let fee_rate = data.fee_estimator.fee_rate_for(&data.blockchain_client, DEFAULT_TARGET_BLOCKS).await;
+    let built = match tx_builder::build(data, store_id, &destinations, fee_rate, false, network).await {
+        Ok(built) => built,
+        Err(e) => {
+            warn!("Could not build payout batch for store {}: {}", store_id, e);
+            requeue(data, &batch, PayoutStatus::AwaitingPayment);
+            return;
+        }
+    };
+    let mut psbt = built.psbt;
+    // Unless it goes out, the batch gives its inputs back
+    let unsigned_tx = psbt.unsigned_tx.clone();
+
+    // Payouts go through the same policy as any other spend. Batches waiting
+    // out a delay or needing approvals are held as a spend proposal, so the
+    // transaction (and the delay keyed by its txid) stays the same.
+    let wallet = match data.wallet_for(store_id) {
+        Some(wallet) => wallet,
+        None => {
+            warn!("Payouts of store {} are waiting: the store has no wallet", store_id);
+            tx_builder::release(data, &unsigned_tx);
+            requeue(data, &batch, PayoutStatus::AwaitingPayment);
+            return;
+        }
+    };
+    let evaluation = match spending_policy::evaluate(&data.db, store_id, &psbt, &wallet, network) {
+        Ok(evaluation) => evaluation,
+        Err(e) => {
+            error!("Failed to evaluate spending policy for store {}: {}", store_id, e);
+            tx_builder::release(data, &unsigned_tx);
+            requeue(data, &batch, PayoutStatus::AwaitingPayment);
+            return;
+        }
+    };
+    let blocking: Vec<String> = evaluation
+        .violations
+        .iter()
+        .filter(|violation| !matches!(violation, PolicyViolation::DelayRequired { .. }))
+        .map(|violation| violation.to_string())
+        .collect();
+    if !blocking.is_empty() {
+        warn!("Payout batch for store {} held by spending policy: {}", store_id, blocking.join("; "));
+        tx_builder::release(data, &unsigned_tx);
+        requeue(data, &batch, PayoutStatus::AwaitingPayment);
+        return;
+    }
+    if !evaluation.allowed || evaluation.approvals_required > 0 {
+        if let Err(e) = propose(data, store_id, &mut batch, &psbt, evaluation.approvals_required).await {
+            error!("Failed to create a spend proposal for the payouts of store {}: {}", store_id, e);
+            tx_builder::release(data, &unsigned_tx);
+            requeue(data, &batch, PayoutStatus::AwaitingPayment);
+        }
+        return;
+    }
+
+    if let Err(e) = signer.sign_psbt(&mut psbt).await {
+        error!("Failed to sign payout batch for store {}: {}", store_id, e);
+        tx_builder::release(data, &unsigned_tx);
+        requeue(data, &batch, PayoutStatus::AwaitingPayment);
+        return;
+    }
+    if !psbt::is_fully_signed(&psbt) {
+        warn!("Payout batch for store {} needs signatures the software signer cannot provide", store_id);
+        tx_builder::release(data, &unsigned_tx);
+        requeue(data, &batch, PayoutStatus::AwaitingPayment);
+        return;
+    }
+
+    let txid = match broadcast::broadcast_psbt(data, psbt, store_id, evaluation.amount).await {
+        Ok(txid) => txid,
+        Err(e) => {
+            error!("Failed to broadcast payout batch for store {}: {}", store_id, e);
+            tx_builder::release(data, &unsigned_tx);
+            requeue(data, &batch, PayoutStatus::AwaitingPayment);
+            return;
+        }
+    };
+    info!("Sent {} payout(s) for store {} in {} (fee {} sat)", batch.len(), store_id, txid, built.fee);
+    mark_sent(data, &mut batch, &txid).await;
+}
+
+// Pick the store's next batch and move its payouts to InProgress, so they
+// can't be cancelled or picked again while it is sent. Payouts to addresses
+// off the allow-list, or that don't fit in what is left of the spending
+// limits, are left waiting instead of holding up the rest.
+fn claim_batch(data: &AppState, store_id: &str) -> Result<Vec<Payout>, SqliteError> {
+    let policy = data.db.get_spending_policy(store_id)?.unwrap_or_default();
+    let mut allowance = spending_policy::remaining_allowance(&data.db, store_id, &policy)?;
+    let mut batch = Vec::new();
+    for mut payout in data.db.list_payouts(store_id, Some(PayoutStatus::AwaitingPayment))? {
+        if batch.len() == MAX_BATCH_SIZE {
+            break;
+        }
+        if !spending_policy::destination_allowed(&policy, &payout.destination) {
+            warn!("Payout {} is waiting: {} is not on the store's allow-list", payout.id, payout.destination);
+            continue;
+        }
+        if allowance.is_some_and(|remaining| payout.amount > remaining) {
+            continue;
+        }
+        if !data.db.transition_payout(&payout.id, PayoutStatus::AwaitingPayment, PayoutStatus::InProgress)? {
+            continue;
+        }
+        allowance = allowance.map(|remaining| remaining - payout.amount);
+        payout.status = PayoutStatus::InProgress;
+        batch.push(payout);
+    }
+    Ok(batch)
+}
+
+// Put a batch that did not go out back in the queue
+fn requeue(data: &AppState, batch: &[Payout], status: PayoutStatus) {
+    for payout in batch {
+        if let Err(e) = data.db.requeue_payout(&payout.id, status) {
+            error!("Failed to requeue payout {}: {}", payout.id, e);
+        }
+    }
+}
+
+// A payout that stays InProgress without a txid is never sent again, so a
+// failed update here can't lead to paying twice
+async fn mark_sent(data: &AppState, batch: &mut [Payout], txid: &str) {
+    for payout in batch {
+        if let Err(e) = data.db.update_payout_status(&payout.id, PayoutStatus::InProgress, Some(txid)) {
+            error!("Failed to record that payout {} was sent in {}: {}", payout.id, txid, e);
+            continue;
+        }
+        payout.txid = Some(txid.to_string());
+        notify(data, "payout.sent", payout).await;
+    }
+}
+
+async fn propose(
+    data: &AppState,
+    store_id: &str,
+    batch: &mut [Payout],
+    psbt: &Psbt,
+    approvals_required: u32,
+) -> Result<(), PayoutError> {
+    let policy = data.db.get_spending_policy(store_id)?.unwrap_or_default();
+    let new = NewProposal {
+        store_id: store_id.to_string(),
+        psbt: psbt::encode_psbt(psbt),
+        description: Some(format!("Batch of {} payout(s)", batch.len())),
+        amount: batch.iter().map(|payout| payout.amount).sum(),
+        created_by: PROCESSOR_USER.to_string(),
+        required_approvals: approvals_required,
+        ttl_secs: policy.proposal_ttl_secs,
+    };
+    let proposal = proposals::create(data, new).await?;
+    for payout in batch.iter_mut() {
+        data.db.set_payout_proposal(&payout.id, &proposal.id)?;
+        payout.proposal_id = Some(proposal.id.clone());
+    }
+    info!("Payout batch for store {} held as spend proposal {}", store_id, proposal.id);
+    Ok(())
+}
+
+// Follow the spend proposals holding payout batches: send approved ones with
+// the software signer, pick up ones an approver broadcast, and put the
+// payouts of rejected ones back to an operator and of expired ones in the queue
+async fn advance_proposals(data: &AppState, store_id: &str, network: Network) -> Result<(), PayoutError> {
+    let mut held: Vec<(String, Vec<Payout>)> = Vec::new();
+    for payout in data.db.list_payouts(store_id, Some(PayoutStatus::InProgress))? {
+        let proposal_id = match (&payout.txid, &payout.proposal_id) {
+            (None, Some(proposal_id)) => proposal_id.clone(),
+            _ => continue,
+        };
+        match held.iter_mut().find(|(id, _)| *id == proposal_id) {
+            Some((_, batch)) => batch.push(payout),
+            None => held.push((proposal_id, vec![payout])),
+        }
+    }
+
+    for (proposal_id, mut batch) in held {
+        let mut proposal = proposals::get(data, &proposal_id).await?;
+        match proposal.status {
+            ProposalStatus::Pending => {}
+            ProposalStatus::Approved => send_proposal(data, &mut proposal, &mut batch, network).await,
+            ProposalStatus::Broadcast => {
+                if let Some(txid) = proposal.txid.clone() {
+                    mark_sent(data, &mut batch, &txid).await;
+                }
+            }
+            ProposalStatus::Rejected => requeue(data, &batch, PayoutStatus::AwaitingApproval),
+            ProposalStatus::Expired => requeue(data, &batch, PayoutStatus::AwaitingPayment),
+        }
+    }
+    Ok(())
+}
+
+// Sign and broadcast an approved payout proposal once the policy lets it
+// through. Until then, or if broadcasting fails, the same transaction is
+// tried again on the next run.
+async fn send_proposal(data: &AppState, proposal: &mut SpendProposal, batch: &mut [Payout], network: Network) {
+    let signer = match &data.software_signer {
+        Some(signer) => signer,
+        None => {
+            warn!("Payout proposal {} is waiting: no software signer is configured", proposal.id);
+            return;
+        }
+    };
+    let mut psbt = match psbt::decode_psbt(&proposal.psbt) {
+        Ok(psbt) => psbt,
+        Err(e) => {
+            error!("Failed to decode payout proposal {}: {}", proposal.id, e);
+            return;
+        }
+    };
+    let wallet = match data.wallet_for(&proposal.store_id) {
+        Some(wallet) => wallet,
+        None => {
+            warn!("Payout proposal {} is waiting: store {} has no wallet", proposal.id, proposal.store_id);
+            return;
+        }
+    };
+    match spending_policy::evaluate(&data.db, &proposal.store_id, &psbt, &wallet, network) {
+        Ok(evaluation) if evaluation.allowed => {}
+        Ok(evaluation) => {
+            let violations: Vec<String> = evaluation.violations.iter().map(|v| v.to_string()).collect();
+            info!("Payout proposal {} is waiting: {}", proposal.id, violations.join("; "));
+            return;
+        }
+        Err(e) => {
+            error!("Failed to evaluate spending policy for payout proposal {}: {}", proposal.id, e);
+            return;
+        }
+    }
+
+    if let Err(e) = signer.sign_psbt(&mut psbt).await {
+        error!("Failed to sign payout proposal {}: {}", proposal.id, e);
+        return;
+    }
+    if !psbt::is_fully_signed(&psbt) {
+        warn!("Payout proposal {} needs signatures the software signer cannot provide", proposal.id);
+        return;
+    }
+    let txid = match broadcast::broadcast_psbt(data, psbt, &proposal.store_id, proposal.amount).await {
+        Ok(txid) => txid,
+        Err(e) => {
+            error!("Failed to broadcast payout proposal {}: {}", proposal.id, e);
+            return;
+        }
+    };
+    info!("Sent {} payout(s) of proposal {} in {}", batch.len(), proposal.id, txid);
+    if let Err(e) = proposals::mark_broadcast(data, proposal, &txid).await {
+        error!("Failed to mark proposal {} as broadcast: {}", proposal.id, e);
+    }
+    mark_sent(data, batch, &txid).await;
+}
+
+async fn complete_confirmed(data: &AppState, store_id: &str) -> Result<(), PayoutError> {
+    let payouts = data.db.list_payouts(store_id, Some(PayoutStatus::InProgress))?;
+    let mut confirmed_txids: Vec<String> = Vec::new();
+    let mut checked_txids: Vec<String> = Vec::new();
+
+    for mut payout in payouts {
+        if payout.txid.is_none() || !follow_replacement(data, &mut payout).await? {
+            continue;
+        }
+        let txid = payout.txid.clone().unwrap_or_default();
+        // Batched payouts share a transaction; look each one up once
+        if !checked_txids.contains(&txid) {
+            checked_txids.push(txid.clone());
+            match data.blockchain_client.get_tx_status(&txid).await {
+                Ok(Some(status)) if status.confirmed => confirmed_txids.push(txid.clone()),
+                Ok(_) => {}
+                Err(e) => warn!("Could not check payout transaction {}: {}", txid, e),
+            }
+        }
+        if confirmed_txids.contains(&txid) {
+            data.db.update_payout_status(&payout.id, PayoutStatus::Completed, None)?;
+            payout.status = PayoutStatus::Completed;
+            notify(data, "payout.completed", &payout).await;
+        }
+    }
+    Ok(())
+}
+
+// A payout's transaction may have been replaced, by a fee bump or by another
+// spend of its inputs. Move the payout to the transaction that replaced it
+// if that still pays it, otherwise put it back in the queue to be sent
+// again. Returns whether the payout is still in progress.
+async fn follow_replacement(data: &AppState, payout: &mut Payout) -> Result<bool, PayoutError> {
+    let original = match &payout.txid {
+        Some(txid) => txid.clone(),
+        None => return Ok(true),
+    };
+    let mut txid = original.clone();
+    // The transaction now carrying the payout, if its replacements end in one
+    // we know
+    let mut current = None;
+    for _ in 0..MAX_REPLACEMENTS {
+        match data.db.get_outgoing_transaction(&txid)? {
+            Some(tx) if tx.status == OutgoingStatus::Replaced => match tx.replaced_by {
+                Some(replacement) => txid = replacement,
+                // Its inputs were spent by a transaction we don't know
+                None => break,
+            },
+            tx => {
+                current = Some(tx);
+                break;
+            }
+        }
+    }
+
+    match current {
+        // Not replaced, or not tracked at all
+        Some(_) if txid == original => Ok(true),
+        Some(Some(tx)) if pays(&tx.tx_hex, payout) => {
+            data.db.update_payout_status(&payout.id, PayoutStatus::InProgress, Some(&txid))?;
+            info!("Payout {} moved from {} to its replacement {}", payout.id, original, txid);
+            payout.txid = Some(txid);
+            notify(data, "payout.replaced", payout).await;
+            Ok(true)
+        }
+        _ => {
+            warn!("Transaction {} of payout {} was replaced; sending it again", original, payout.id);
+            data.db.requeue_payout(&payout.id, PayoutStatus::AwaitingPayment)?;
+            payout.status = PayoutStatus::AwaitingPayment;
+            payout.txid = None;
+            payout.proposal_id = None;
+            notify(data, "payout.requeued", payout).await;
+            Ok(false)
+        }
+    }
+}
+
+// Whether a raw transaction pays at least the payout's amount to its
+// destination
+fn pays(tx_hex: &str, payout: &Payout) -> bool {
+    let bytes = match hex::decode(tx_hex) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    let tx: bitcoin::Transaction = match bitcoin::consensus::deserialize(&bytes) {
+        Ok(tx) => tx,
+        Err(_) => return false,
+    };
+    let script = match Address::from_str(&payout.destination) {
+        Ok(address) => address.assume_checked().script_pubkey(),
+        Err(_) => return false,
+    };
+    tx.output.iter().any(|output| output.script_pubkey == script && output.value >= payout.amount)
+}
+
+// PAYOUT_PROCESS_INTERVAL_SECS (default 600); 0 disables the processor
+pub fn process_interval() -> Option<Duration> {
+    let secs = std::env::var("PAYOUT_PROCESS_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_PROCESS_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OutgoingTransaction;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::{Transaction, TxIn, TxOut};
+
+    const DESTINATION: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    fn payout(data: &AppState, id: &str) -> Payout {
+        let payout = Payout {
+            id: id.to_string(),
+            store_id: "shop".to_string(),
+            destination: DESTINATION.to_string(),
+            amount: 40_000,
+            currency: "BTC".to_string(),
+            description: None,
+            status: PayoutStatus::InProgress,
+            created_by: "merchant".to_string(),
+            created_at: Utc::now(),
+            txid: Some(format!("{}-tx", id)),
+            proposal_id: Some("proposal".to_string()),
+        };
+        data.db.save_payout(&payout).unwrap();
+        payout
+    }
+
+    fn outgoing(data: &AppState, txid: &str, status: OutgoingStatus, replaced_by: Option<&str>, value: u64) {
+        let destination = Address::from_str(DESTINATION).unwrap().assume_checked();
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut { value, script_pubkey: destination.script_pubkey() }],
+        };
+        data.db
+            .save_outgoing_transaction(&OutgoingTransaction {
+                txid: txid.to_string(),
+                store_id: "shop".to_string(),
+                psbt: String::new(),
+                amount: value,
+                fee: 1_000,
+                vsize: 110,
+                change_vout: None,
+                status,
+                replaced_by: replaced_by.map(str::to_string),
+                block_height: None,
+                broadcast_at: Utc::now(),
+                tx_hex: bitcoin::consensus::encode::serialize_hex(&tx),
+                last_broadcast_at: Utc::now(),
+                broadcast_count: 1,
+            })
+            .unwrap();
+    }
+
+    fn stored(data: &AppState, id: &str) -> Payout {
+        data.db.get_payout(id).unwrap().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn payouts_follow_their_replacements() {
+        let data = AppState::new(":memory:");
+
+        // Fee bumped twice; the last replacement still pays the payout
+        let mut bumped = payout(&data, "bumped");
+        outgoing(&data, "bumped-tx", OutgoingStatus::Replaced, Some("bump-1"), 40_000);
+        outgoing(&data, "bump-1", OutgoingStatus::Replaced, Some("bump-2"), 40_000);
+        outgoing(&data, "bump-2", OutgoingStatus::Unconfirmed, None, 40_000);
+        assert!(follow_replacement(&data, &mut bumped).await.unwrap());
+        assert_eq!(bumped.txid.as_deref(), Some("bump-2"));
+        let bumped = stored(&data, "bumped");
+        assert_eq!(bumped.status, PayoutStatus::InProgress);
+        assert_eq!(bumped.txid.as_deref(), Some("bump-2"));
+
+        // Not replaced, or not tracked: left as it is
+        let mut pending = payout(&data, "pending");
+        outgoing(&data, "pending-tx", OutgoingStatus::Unconfirmed, None, 40_000);
+        assert!(follow_replacement(&data, &mut pending).await.unwrap());
+        assert_eq!(stored(&data, "pending").txid.as_deref(), Some("pending-tx"));
+        let mut untracked = payout(&data, "untracked");
+        assert!(follow_replacement(&data, &mut untracked).await.unwrap());
+        assert_eq!(stored(&data, "untracked").txid.as_deref(), Some("untracked-tx"));
+    }
+
+    #[actix_web::test]
+    async fn payouts_no_longer_paid_are_requeued() {
+        let data = AppState::new(":memory:");
+
+        // Replaced by a transaction paying less than the payout
+        let mut underpaid = payout(&data, "underpaid");
+        outgoing(&data, "underpaid-tx", OutgoingStatus::Replaced, Some("short"), 40_000);
+        outgoing(&data, "short", OutgoingStatus::Unconfirmed, None, 39_999);
+        assert!(!follow_replacement(&data, &mut underpaid).await.unwrap());
+
+        // Inputs spent by a transaction we never saw
+        let mut double_spent = payout(&data, "double-spent");
+        outgoing(&data, "double-spent-tx", OutgoingStatus::Replaced, None, 40_000);
+        assert!(!follow_replacement(&data, &mut double_spent).await.unwrap());
+
+        for id in ["underpaid", "double-spent"] {
+            let payout = stored(&data, id);
+            assert_eq!(payout.status, PayoutStatus::AwaitingPayment);
+            assert_eq!(payout.txid, None);
+            assert_eq!(payout.proposal_id, None);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use chrono::{Duration, Utc};
+use log::{error, info};
+use rusqlite::Error as SqliteError;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::models::{ProposalStatus, ProposalVote, SpendProposal};
+use crate::psbt;
+use crate::state::AppState;
+use crate::tx_builder;
+
+// Pending proposals expire after this unless the store's policy says otherwise
+const DEFAULT_PROPOSAL_TTL_SECS: u64 = 72 * 3600;
+const EXPIRY_CHECK_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug)]
+pub enum ProposalError {
+    NotFound,
+    NotPending(ProposalStatus),
+    NotApproved(ProposalStatus),
+    NotApprover,
+    OwnProposal,
+    AlreadyVoted,
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for ProposalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProposalError::NotFound => write!(f, "Spend proposal not found"),
+            ProposalError::NotPending(status) => write!(f, "Spend proposal is {:?}, not pending", status),
+            ProposalError::NotApproved(status) => write!(f, "Spend proposal is {:?}, not approved", status),
+            ProposalError::NotApprover => write!(f, "The approver role is required to vote on proposals"),
+            ProposalError::OwnProposal => write!(f, "Proposers cannot approve their own proposals"),
+            ProposalError::AlreadyVoted => write!(f, "You have already voted on this proposal"),
+            ProposalError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProposalError {}
+
+impl From<SqliteError> for ProposalError {
+    fn from(error: SqliteError) -> Self {
+        ProposalError::Database(error)
+    }
+}
+
+pub struct NewProposal {
+    pub store_id: String,
+    pub psbt: String,
+    pub description: Option<String>,
+    pub amount: u64,
+    pub created_by: String,
+    pub required_approvals: u32,
+    pub ttl_secs: Option<u64>,
+}
+
+pub async fn create(data: &AppState, new: NewProposal) -> Result<SpendProposal, ProposalError> {
+    let now = Utc::now();
+    let ttl = new.ttl_secs.unwrap_or(DEFAULT_PROPOSAL_TTL_SECS);
+    let proposal = SpendProposal {
+        id: Uuid::new_v4().to_string(),
+        store_id: new.store_id,
+        psbt: new.psbt,
+        description: new.description,
+        amount: new.amount,
+        created_by: new.created_by,
+        created_at: now,
+        expires_at: now + Duration::seconds(ttl as i64),
+        status: ProposalStatus::Pending,
+        // At least one person other than the proposer always signs off
+        required_approvals: new.required_approvals.max(1),
+        votes: Vec::new(),
+        txid: None,
+    };
+    data.db.save_proposal(&proposal)?;
+    // The inputs stay reserved for as long as the proposal can be approved
+    if let Ok(psbt) = psbt::decode_psbt(&proposal.psbt) {
+        let tx = &psbt.unsigned_tx;
+        data.db
+            .reserve_utxos(&proposal.store_id, &tx.txid().to_string(), &tx_builder::outpoints(tx), proposal.expires_at)?;
+    }
+
+    info!("Spend proposal {} created by {}", proposal.id, proposal.created_by);
+    notify(data, "proposal.created", &proposal).await;
+    Ok(proposal)
+}
+
+// Load a proposal, expiring it first if it is stale
+pub async fn get(data: &AppState, id: &str) -> Result<SpendProposal, ProposalError> {
+    let mut proposal = data.db.get_proposal(id)?.ok_or(ProposalError::NotFound)?;
+    if proposal.status == ProposalStatus::Pending && proposal.expires_at < Utc::now() {
+        data.db.update_proposal_status(id, ProposalStatus::Expired, None)?;
+        proposal.status = ProposalStatus::Expired;
+        notify(data, "proposal.expired", &proposal).await;
+    }
+    Ok(proposal)
+}
+
+// Record an approval or rejection. Any rejection rejects the proposal; it is
+// approved once `required_approvals` distinct approvers have approved.
+pub async fn vote(
+    data: &AppState,
+    id: &str,
+    username: &str,
+    is_approver: bool,
+    approve: bool,
+    comment: Option<String>,
+) -> Result<SpendProposal, ProposalError> {
+    let mut proposal = get(data, id).await?;
+    if proposal.status != ProposalStatus::Pending {
+        return Err(ProposalError::NotPending(proposal.status));
+    }
+    if !is_approver {
+        return Err(ProposalError::NotApprover);
+    }
+    if approve && proposal.created_by == username {
+        return Err(ProposalError::OwnProposal);
+    }
+
+    let vote = ProposalVote {
+        username: username.to_string(),
+        approve,
+        comment,
+        created_at: Utc::now(),
+    };
+    if !data.db.add_proposal_vote(id, &vote)? {
+        return Err(ProposalError::AlreadyVoted);
+    }
+    proposal.votes.push(vote);
+
+    let status = if !approve {
+        ProposalStatus::Rejected
+    } else if proposal.approval_count() >= proposal.required_approvals {
+        ProposalStatus::Approved
+    } else {
+        ProposalStatus::Pending
+    };
+
+    if status != proposal.status {
+        data.db.update_proposal_status(id, status, None)?;
+        proposal.status = status;
+        info!("Spend proposal {} is now {:?}", id, status);
+        if status == ProposalStatus::Rejected {
+            if let Ok(psbt) = psbt::decode_psbt(&proposal.psbt) {
+                tx_builder::release(data, &psbt.unsigned_tx);
+            }
+        }
+        let event = if status == ProposalStatus::Approved { "proposal.approved" } else { "proposal.rejected" };
+        notify(data, event, &proposal).await;
+    } else {
+        notify(data, "proposal.voted", &proposal).await;
+    }
+
+    Ok(proposal)
+}
+
+pub async fn mark_broadcast(data: &AppState, proposal: &mut SpendProposal, txid: &str) -> Result<(), ProposalError> {
+    data.db.update_proposal_status(&proposal.id, ProposalStatus::Broadcast, Some(txid))?;
+    proposal.status = ProposalStatus::Broadcast;
+    proposal.txid = Some(txid.to_string());
+    notify(data, "proposal.broadcast", proposal).await;
+    Ok(())
+}
+
+async fn notify(data: &AppState, event_type: &str, proposal: &SpendProposal) {
+    data.notify(event_type, json!({
+        "proposal_id": proposal.id,
+        "store_id": proposal.store_id,
+        "amount": proposal.amount,
+        "description": proposal.description,
+        "status": proposal.status,
+        "approvals": proposal.approval_count(),
+        "required_approvals": proposal.required_approvals,
+        "expires_at": proposal.expires_at,
+        "txid": proposal.txid,
+    }))
+    .await;
+}
+
+// Expire stale proposals in the background so approvers are notified even if
+// nobody looks at them again
+pub async fn run_expiry(data: actix_web::web::Data<AppState>) {
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(EXPIRY_CHECK_INTERVAL_SECS));
+    loop {
+        ticker.tick().await;
+
+        let expired = match data.db.expire_proposals(Utc::now()) {
+            Ok(expired) => expired,
+            Err(e) => {
+                error!("Failed to expire spend proposals: {}", e);
+                continue;
+            }
+        };
+        for id in expired {
+            info!("Spend proposal {} expired", id);
+            if let Ok(Some(proposal)) = data.db.get_proposal(&id) {
+                notify(&data, "proposal.expired", &proposal).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+    use crate::users;
+
+    // Log in and look up the role the way the API does
+    fn approver(data: &AppState, username: &str, password: &str) -> bool {
+        users::authenticate(&data.db, username, password).unwrap();
+        data.db.get_user_roles(username).unwrap().contains(&Role::Approver)
+    }
+
+    fn new_proposal(created_by: &str, required_approvals: u32) -> NewProposal {
+        NewProposal {
+            store_id: "shop".to_string(),
+            psbt: String::new(),
+            description: None,
+            amount: 50_000,
+            created_by: created_by.to_string(),
+            required_approvals,
+            ttl_secs: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn quorum_is_reached_by_distinct_approvers() {
+        let data = AppState::new(":memory:");
+        for (username, password) in [("alice", "alice's long password"), ("bob", "bob's long password"), ("carol", "carol's long password")] {
+            users::set_password(&data.db, username, password).unwrap();
+            data.db.set_user_roles(username, &[Role::Approver]).unwrap();
+        }
+        let proposal = create(&data, new_proposal("alice", 2)).await.unwrap();
+
+        let alice = approver(&data, "alice", "alice's long password");
+        let result = vote(&data, &proposal.id, "alice", alice, true, None).await;
+        assert!(matches!(result, Err(ProposalError::OwnProposal)));
+
+        let bob = approver(&data, "bob", "bob's long password");
+        let proposal = vote(&data, &proposal.id, "bob", bob, true, None).await.unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Pending);
+        let result = vote(&data, &proposal.id, "bob", bob, true, None).await;
+        assert!(matches!(result, Err(ProposalError::AlreadyVoted)));
+
+        let carol = approver(&data, "carol", "carol's long password");
+        let proposal = vote(&data, &proposal.id, "carol", carol, true, None).await.unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Approved);
+        assert_eq!(proposal.approval_count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn one_rejection_rejects_the_proposal() {
+        let data = AppState::new(":memory:");
+        for (username, password) in [("alice", "alice's long password"), ("bob", "bob's long password"), ("carol", "carol's long password")] {
+            users::set_password(&data.db, username, password).unwrap();
+        }
+        data.db.set_user_roles("bob", &[Role::Approver]).unwrap();
+        data.db.set_user_roles("carol", &[Role::Approver]).unwrap();
+        let proposal = create(&data, new_proposal("alice", 1)).await.unwrap();
+
+        // Without the approver role the vote isn't counted
+        let alice = approver(&data, "alice", "alice's long password");
+        let result = vote(&data, &proposal.id, "alice", alice, false, None).await;
+        assert!(matches!(result, Err(ProposalError::NotApprover)));
+
+        let bob = approver(&data, "bob", "bob's long password");
+        let comment = Some("Wrong destination".to_string());
+        let proposal = vote(&data, &proposal.id, "bob", bob, false, comment).await.unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Rejected);
+        assert_eq!(proposal.approval_count(), 0);
+
+        // Approving afterwards can't revive it
+        let carol = approver(&data, "carol", "carol's long password");
+        let result = vote(&data, &proposal.id, "carol", carol, true, None).await;
+        assert!(matches!(result, Err(ProposalError::NotPending(ProposalStatus::Rejected))));
+        assert_eq!(get(&data, &proposal.id).await.unwrap().status, ProposalStatus::Rejected);
+    }
+}
@@ -0,0 +1,940 @@
+use bitcoin::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint, KeySource};
+use bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_1, OP_PUSHNUM_16};
+use bitcoin::blockdata::script::{Builder, Instruction, PushBytesBuf};
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::{Address, Network, PublicKey, Script, ScriptBuf, Transaction, TxOut, Witness};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::blockchain::BlockchainClient;
+
+pub type Psbt = PartiallySignedTransaction;
+
+// OP_CHECKMULTISIG limit on the number of keys
+const MAX_MULTISIG_KEYS: usize = 20;
+
+#[derive(Debug)]
+pub enum PsbtError {
+    InvalidEncoding(String),
+    InvalidAccountKey(String),
+    MissingInputData(String),
+    ForeignInput(usize),
+    InputMismatch(String),
+    Blockchain(String),
+    Finalize(String),
+}
+
+impl std::fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PsbtError::InvalidEncoding(msg) => write!(f, "Invalid PSBT: {}", msg),
+            PsbtError::InvalidAccountKey(msg) => write!(f, "Invalid account key: {}", msg),
+            PsbtError::MissingInputData(msg) => write!(f, "Missing input data: {}", msg),
+            PsbtError::ForeignInput(index) => write!(f, "Input {} does not spend from the store's wallet", index),
+            PsbtError::InputMismatch(msg) => write!(f, "Input data does not match the spent transaction: {}", msg),
+            PsbtError::Blockchain(msg) => write!(f, "Blockchain backend error: {}", msg),
+            PsbtError::Finalize(msg) => write!(f, "Failed to finalize PSBT: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PsbtError {}
+
+// Output script type used by a single-key account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptType {
+    Legacy,       // pkh
+    NestedSegwit, // sh(wpkh)
+    NativeSegwit, // wpkh
+}
+
+// An account-level extended public key with its origin
+// (master fingerprint and derivation path from the master key)
+#[derive(Debug, Clone)]
+pub struct AccountKey {
+    pub fingerprint: Fingerprint,
+    pub path: DerivationPath,
+    pub xpub: ExtendedPubKey,
+    pub script_type: ScriptType,
+}
+
+impl AccountKey {
+    // Public key at <account>/<chain>/<index> (chain 0 = receive, 1 = change)
+    pub fn derive_public_key<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        chain: u32,
+        index: u32,
+    ) -> Result<PublicKey, PsbtError> {
+        let child_path = [
+            ChildNumber::from_normal_idx(chain).map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))?,
+            ChildNumber::from_normal_idx(index).map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))?,
+        ];
+        let child = self
+            .xpub
+            .derive_pub(secp, &child_path)
+            .map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))?;
+        Ok(child.to_pub())
+    }
+
+    // Full key origin (from the master key) for <account>/<chain>/<index>
+    pub fn key_source(&self, chain: u32, index: u32) -> KeySource {
+        let path = self.path.extend([
+            ChildNumber::Normal { index: chain },
+            ChildNumber::Normal { index },
+        ]);
+        (self.fingerprint, path)
+    }
+
+    // `[d34db33f/84'/1'/0']tpub.../0/*`, as used inside descriptors
+    pub fn key_expression(&self) -> String {
+        let path = self.path.to_string();
+        format!("[{}{}]{}/0/*", self.fingerprint, path.trim_start_matches('m'), self.xpub)
+    }
+
+    pub fn script_pubkey(&self, public_key: &PublicKey) -> ScriptBuf {
+        script_for_key(public_key, self.script_type)
+    }
+
+    // Redeem script needed to spend nested segwit outputs
+    pub fn redeem_script(&self, public_key: &PublicKey) -> Option<ScriptBuf> {
+        match self.script_type {
+            ScriptType::NestedSegwit => public_key.wpubkey_hash().map(|wpkh| ScriptBuf::new_v0_p2wpkh(&wpkh)),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for AccountKey {
+    type Err = PsbtError;
+
+    // Parses a key with origin, optionally wrapped in a script type:
+    // `wpkh([d34db33f/84'/1'/0']tpub.../0/*)`, `sh(wpkh(...))`, `pkh(...)`
+    // or a bare `[d34db33f/84'/1'/0']tpub...` (treated as wpkh)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = strip_checksum(s.trim())?;
+        let (script_type, inner) = if let Some(inner) = strip_wrapper(s, "sh(wpkh(", "))") {
+            (ScriptType::NestedSegwit, inner)
+        } else if let Some(inner) = strip_wrapper(s, "wpkh(", ")") {
+            (ScriptType::NativeSegwit, inner)
+        } else if let Some(inner) = strip_wrapper(s, "pkh(", ")") {
+            (ScriptType::Legacy, inner)
+        } else {
+            (ScriptType::NativeSegwit, s)
+        };
+
+        let inner = inner.strip_prefix('[').ok_or_else(|| {
+            PsbtError::InvalidAccountKey("key origin ([fingerprint/path]) is required".to_string())
+        })?;
+        let (origin, key) = inner
+            .split_once(']')
+            .ok_or_else(|| PsbtError::InvalidAccountKey("unterminated key origin".to_string()))?;
+
+        let (fingerprint, path) = match origin.split_once('/') {
+            Some((fingerprint, path)) => (fingerprint, format!("m/{}", path)),
+            None => (origin, "m".to_string()),
+        };
+        let fingerprint = Fingerprint::from_str(fingerprint)
+            .map_err(|e| PsbtError::InvalidAccountKey(format!("fingerprint: {}", e)))?;
+        let path = DerivationPath::from_str(&path.replace('h', "'"))
+            .map_err(|e| PsbtError::InvalidAccountKey(format!("derivation path: {}", e)))?;
+
+        // Drop any receive/change suffix such as /0/* or /<0;1>/*
+        let key = key.split('/').next().unwrap_or(key);
+        let xpub = ExtendedPubKey::from_str(key)
+            .map_err(|e| PsbtError::InvalidAccountKey(format!("xpub: {}", e)))?;
+
+        Ok(AccountKey {
+            fingerprint,
+            path,
+            xpub,
+            script_type,
+        })
+    }
+}
+
+// Formats as an output descriptor with checksum, e.g.
+// `wpkh([d34db33f/84'/1'/0']tpub.../0/*)#2ag6nxcd`
+impl std::fmt::Display for AccountKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key = self.key_expression();
+        let descriptor = match self.script_type {
+            ScriptType::Legacy => format!("pkh({})", key),
+            ScriptType::NestedSegwit => format!("sh(wpkh({}))", key),
+            ScriptType::NativeSegwit => format!("wpkh({})", key),
+        };
+        let checksum = descriptor_checksum(&descriptor).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}#{}", descriptor, checksum)
+    }
+}
+
+// A k-of-n multisig account, `wsh(sortedmulti(k,[fp/path]xpub/0/*,...))`.
+// Keys are sorted per address (BIP 67), so cosigner order does not matter.
+#[derive(Debug, Clone)]
+pub struct MultisigAccount {
+    pub threshold: usize,
+    pub keys: Vec<AccountKey>,
+}
+
+impl FromStr for MultisigAccount {
+    type Err = PsbtError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = strip_checksum(s.trim())?;
+        let inner = strip_wrapper(s, "wsh(sortedmulti(", "))").ok_or_else(|| {
+            PsbtError::InvalidAccountKey("only wsh(sortedmulti(...)) multisig descriptors are supported".to_string())
+        })?;
+
+        let mut parts = inner.split(',');
+        let threshold = parts
+            .next()
+            .and_then(|threshold| threshold.trim().parse::<usize>().ok())
+            .ok_or_else(|| PsbtError::InvalidAccountKey("invalid multisig threshold".to_string()))?;
+        let keys = parts.map(AccountKey::from_str).collect::<Result<Vec<_>, _>>()?;
+
+        if keys.is_empty() || keys.len() > MAX_MULTISIG_KEYS {
+            return Err(PsbtError::InvalidAccountKey(format!(
+                "multisig needs between 1 and {} keys",
+                MAX_MULTISIG_KEYS
+            )));
+        }
+        if threshold == 0 || threshold > keys.len() {
+            return Err(PsbtError::InvalidAccountKey(format!(
+                "threshold {} is not valid for {} keys",
+                threshold,
+                keys.len()
+            )));
+        }
+        for (index, key) in keys.iter().enumerate() {
+            if keys[..index].iter().any(|other| other.xpub == key.xpub) {
+                return Err(PsbtError::InvalidAccountKey(format!("key {} appears twice", key.xpub)));
+            }
+        }
+
+        Ok(MultisigAccount { threshold, keys })
+    }
+}
+
+impl std::fmt::Display for MultisigAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keys: Vec<String> = self.keys.iter().map(|key| key.key_expression()).collect();
+        let descriptor = format!("wsh(sortedmulti({},{}))", self.threshold, keys.join(","));
+        let checksum = descriptor_checksum(&descriptor).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}#{}", descriptor, checksum)
+    }
+}
+
+// The wallet a store receives into and spends from
+#[derive(Debug, Clone)]
+pub enum WalletDescriptor {
+    Single(AccountKey),
+    Multisig(MultisigAccount),
+}
+
+// Scripts and key origins of one address of a wallet
+#[derive(Debug, Clone)]
+pub struct DerivedAddress {
+    pub script_pubkey: ScriptBuf,
+    pub redeem_script: Option<ScriptBuf>,
+    pub witness_script: Option<ScriptBuf>,
+    pub keys: Vec<(PublicKey, KeySource)>,
+    // Full path of the (first) key, recorded with invoices
+    pub path: DerivationPath,
+}
+
+impl WalletDescriptor {
+    pub fn derive<C: Verification>(&self, secp: &Secp256k1<C>, chain: u32, index: u32) -> Result<DerivedAddress, PsbtError> {
+        match self {
+            WalletDescriptor::Single(account) => {
+                let public_key = account.derive_public_key(secp, chain, index)?;
+                let key_source = account.key_source(chain, index);
+                Ok(DerivedAddress {
+                    script_pubkey: account.script_pubkey(&public_key),
+                    redeem_script: account.redeem_script(&public_key),
+                    witness_script: None,
+                    path: key_source.1.clone(),
+                    keys: vec![(public_key, key_source)],
+                })
+            }
+            WalletDescriptor::Multisig(multisig) => {
+                let mut keys = multisig
+                    .keys
+                    .iter()
+                    .map(|key| Ok((key.derive_public_key(secp, chain, index)?, key.key_source(chain, index))))
+                    .collect::<Result<Vec<_>, PsbtError>>()?;
+                let path = keys[0].1 .1.clone();
+                keys.sort_by_key(|(public_key, _)| public_key.inner.serialize());
+                let witness_script = multisig_script(multisig.threshold, keys.iter().map(|(public_key, _)| public_key));
+                Ok(DerivedAddress {
+                    script_pubkey: ScriptBuf::new_v0_p2wsh(&witness_script.wscript_hash()),
+                    redeem_script: None,
+                    witness_script: Some(witness_script),
+                    keys,
+                    path,
+                })
+            }
+        }
+    }
+
+    pub fn address<C: Verification>(&self, secp: &Secp256k1<C>, chain: u32, index: u32, network: Network) -> Result<Address, PsbtError> {
+        let derived = self.derive(secp, chain, index)?;
+        Address::from_script(&derived.script_pubkey, network).map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))
+    }
+
+    // Master fingerprints of every key holder
+    pub fn fingerprints(&self) -> Vec<Fingerprint> {
+        match self {
+            WalletDescriptor::Single(account) => vec![account.fingerprint],
+            WalletDescriptor::Multisig(multisig) => multisig.keys.iter().map(|key| key.fingerprint).collect(),
+        }
+    }
+
+    // Key receive indexes are reserved under
+    pub fn id(&self) -> String {
+        match self {
+            WalletDescriptor::Single(account) => account.xpub.to_string(),
+            WalletDescriptor::Multisig(multisig) => multisig.to_string(),
+        }
+    }
+
+    pub fn single(&self) -> Option<&AccountKey> {
+        match self {
+            WalletDescriptor::Single(account) => Some(account),
+            WalletDescriptor::Multisig(_) => None,
+        }
+    }
+}
+
+impl FromStr for WalletDescriptor {
+    type Err = PsbtError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().starts_with("wsh(") {
+            MultisigAccount::from_str(s).map(WalletDescriptor::Multisig)
+        } else {
+            AccountKey::from_str(s).map(WalletDescriptor::Single)
+        }
+    }
+}
+
+impl std::fmt::Display for WalletDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalletDescriptor::Single(account) => account.fmt(f),
+            WalletDescriptor::Multisig(multisig) => multisig.fmt(f),
+        }
+    }
+}
+
+// `<k> <key>... <n> OP_CHECKMULTISIG`
+pub fn multisig_script<'a>(threshold: usize, keys: impl Iterator<Item = &'a PublicKey>) -> ScriptBuf {
+    let mut builder = Builder::new().push_int(threshold as i64);
+    let mut count = 0;
+    for key in keys {
+        builder = builder.push_key(key);
+        count += 1;
+    }
+    builder.push_int(count).push_opcode(OP_CHECKMULTISIG).into_script()
+}
+
+// Threshold and keys of a multisig script
+pub fn parse_multisig(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    let (last, rest) = instructions.split_last()?;
+    if *last != Instruction::Op(OP_CHECKMULTISIG) {
+        return None;
+    }
+    let (count, rest) = rest.split_last()?;
+    let (threshold, keys) = rest.split_first()?;
+    let threshold = small_int(threshold)?;
+    let keys = keys
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes.as_bytes()).ok(),
+            Instruction::Op(_) => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    if small_int(count)? != keys.len() || threshold == 0 || threshold > keys.len() {
+        return None;
+    }
+    Some((threshold, keys))
+}
+
+fn small_int(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Op(op) if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+            Some((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as usize)
+        }
+        _ => None,
+    }
+}
+
+fn strip_checksum(s: &str) -> Result<&str, PsbtError> {
+    match s.split_once('#') {
+        Some((descriptor, checksum)) => {
+            if descriptor_checksum(descriptor)? != checksum {
+                return Err(PsbtError::InvalidAccountKey("descriptor checksum mismatch".to_string()));
+            }
+            Ok(descriptor)
+        }
+        None => Ok(s),
+    }
+}
+
+// Output descriptor checksum (BIP 380)
+pub fn descriptor_checksum(descriptor: &str) -> Result<String, PsbtError> {
+    const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+    const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+    fn polymod(c: u64, value: u64) -> u64 {
+        let c0 = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ value;
+        for (bit, generator) in [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd]
+            .iter()
+            .enumerate()
+        {
+            if c0 & (1 << bit) != 0 {
+                c ^= generator;
+            }
+        }
+        c
+    }
+
+    let mut c = 1u64;
+    let mut class = 0u64;
+    let mut class_count = 0;
+    for ch in descriptor.chars() {
+        let position = INPUT_CHARSET.find(ch).ok_or_else(|| {
+            PsbtError::InvalidAccountKey(format!("invalid character {:?} in descriptor", ch))
+        })? as u64;
+        c = polymod(c, position & 31);
+        class = class * 3 + (position >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+// Output script paying to a single (compressed) key
+pub fn script_for_key(public_key: &PublicKey, script_type: ScriptType) -> ScriptBuf {
+    let wpkh = public_key
+        .wpubkey_hash()
+        .expect("extended keys are always compressed");
+    match script_type {
+        ScriptType::Legacy => ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+        ScriptType::NestedSegwit => ScriptBuf::new_p2sh(&ScriptBuf::new_v0_p2wpkh(&wpkh).script_hash()),
+        ScriptType::NativeSegwit => ScriptBuf::new_v0_p2wpkh(&wpkh),
+    }
+}
+
+fn strip_wrapper<'a>(s: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    s.strip_prefix(prefix)?.strip_suffix(suffix)
+}
+
+pub fn decode_psbt(encoded: &str) -> Result<Psbt, PsbtError> {
+    Psbt::from_str(encoded.trim()).map_err(|e| PsbtError::InvalidEncoding(e.to_string()))
+}
+
+pub fn encode_psbt(psbt: &Psbt) -> String {
+    psbt.to_string()
+}
+
+// Fetch the transactions spent by the PSBT's inputs and fill in any missing
+// UTXO data. Segwit inputs get both the witness UTXO and the full previous
+// transaction, since hardware signers need the latter to verify amounts.
+// UTXO data supplied with the PSBT must match the previous transaction: a
+// full transaction must hash to the spent txid, and a witness UTXO must be
+// the spent output. Inputs spending legacy scripts carry no witness UTXO, so
+// their amounts always come from the previous transaction.
+pub async fn fill_utxos(psbt: &mut Psbt, blockchain: &BlockchainClient) -> Result<(), PsbtError> {
+    let mut previous_txs: HashMap<bitcoin::Txid, Transaction> = HashMap::new();
+
+    for (index, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+        let input = &mut psbt.inputs[index];
+        let txid = txin.previous_output.txid;
+        if let Some(supplied) = &input.non_witness_utxo {
+            if supplied.txid() != txid {
+                return Err(PsbtError::InputMismatch(format!(
+                    "input {} spends {} but its previous transaction is {}",
+                    index,
+                    txid,
+                    supplied.txid()
+                )));
+            }
+        }
+
+        let previous_tx = match previous_txs.entry(txid) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match &input.non_witness_utxo {
+                Some(supplied) => entry.insert(supplied.clone()),
+                None => {
+                    let tx = blockchain
+                        .get_transaction(&txid)
+                        .await
+                        .map_err(PsbtError::Blockchain)?;
+                    entry.insert(tx)
+                }
+            },
+        };
+
+        let spent_output = previous_tx
+            .output
+            .get(txin.previous_output.vout as usize)
+            .ok_or_else(|| {
+                PsbtError::MissingInputData(format!("output {} does not exist", txin.previous_output))
+            })?;
+
+        let segwit = spent_output.script_pubkey.is_witness_program() || spent_output.script_pubkey.is_p2sh();
+        match &input.witness_utxo {
+            _ if !segwit => input.witness_utxo = None,
+            Some(utxo) if utxo != spent_output => {
+                return Err(PsbtError::InputMismatch(format!(
+                    "input {} claims {} sat but {} holds {} sat",
+                    index, utxo.value, txin.previous_output, spent_output.value
+                )));
+            }
+            Some(_) => {}
+            None => input.witness_utxo = Some(spent_output.clone()),
+        }
+        if input.non_witness_utxo.is_none() {
+            input.non_witness_utxo = Some(previous_tx.clone());
+        }
+    }
+
+    Ok(())
+}
+
+// Look up the derivation that produces `script`. `bounds` caps the indexes
+// searched on each chain (receive, change); see `wallet::derivation_bounds`.
+pub fn find_derivation<C: Verification>(
+    secp: &Secp256k1<C>,
+    wallet: &WalletDescriptor,
+    bounds: [u32; 2],
+    script: &ScriptBuf,
+) -> Result<Option<(u32, u32, DerivedAddress)>, PsbtError> {
+    for (chain, bound) in (0..).zip(bounds) {
+        for index in 0..bound {
+            let derived = wallet.derive(secp, chain, index)?;
+            if &derived.script_pubkey == script {
+                return Ok(Some((chain, index, derived)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Add BIP32 derivation info (and redeem/witness scripts) for every input and
+// output that belongs to the wallet, so signers know which keys to use and
+// can recognise change outputs. Key origins and scripts supplied with the
+// PSBT are dropped first: only the wallet decides what it signs for and what
+// is change. Every input must spend from the wallet. Multisig PSBTs also get
+// the cosigners' xpubs.
+pub fn fill_bip32_derivations(psbt: &mut Psbt, wallet: &WalletDescriptor, bounds: [u32; 2]) -> Result<(), PsbtError> {
+    let secp = Secp256k1::verification_only();
+
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        let spent_script = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(utxo), _) => utxo.script_pubkey.clone(),
+            (None, Some(tx)) => {
+                let vout = psbt.unsigned_tx.input[index].previous_output.vout as usize;
+                match tx.output.get(vout) {
+                    Some(output) => output.script_pubkey.clone(),
+                    None => return Err(PsbtError::MissingInputData(format!("input {} spends a missing output", index))),
+                }
+            }
+            (None, None) => return Err(PsbtError::MissingInputData(format!("input {} has no UTXO data", index))),
+        };
+
+        let derived = find_derivation(&secp, wallet, bounds, &spent_script)?.map(|(_, _, derived)| derived);
+        let derived = derived.ok_or(PsbtError::ForeignInput(index))?;
+        input.bip32_derivation.clear();
+        input.tap_key_origins.clear();
+        for (public_key, key_source) in derived.keys {
+            input.bip32_derivation.insert(public_key.inner, key_source);
+        }
+        input.redeem_script = derived.redeem_script;
+        input.witness_script = derived.witness_script;
+    }
+
+    for (index, output) in psbt.outputs.iter_mut().enumerate() {
+        output.bip32_derivation.clear();
+        output.tap_key_origins.clear();
+        output.redeem_script = None;
+        output.witness_script = None;
+
+        let script = &psbt.unsigned_tx.output[index].script_pubkey;
+        if let Some((_, _, derived)) = find_derivation(&secp, wallet, bounds, script)? {
+            for (public_key, key_source) in derived.keys {
+                output.bip32_derivation.insert(public_key.inner, key_source);
+            }
+            output.redeem_script = derived.redeem_script;
+            output.witness_script = derived.witness_script;
+        }
+    }
+
+    if let WalletDescriptor::Multisig(multisig) = wallet {
+        for key in &multisig.keys {
+            psbt.xpub.insert(key.xpub, (key.fingerprint, key.path.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// The output an input spends, from whichever UTXO field is present
+pub fn spent_output(psbt: &Psbt, index: usize) -> Option<&TxOut> {
+    let input = psbt.inputs.get(index)?;
+    if let Some(utxo) = &input.witness_utxo {
+        return Some(utxo);
+    }
+    let vout = psbt.unsigned_tx.input.get(index)?.previous_output.vout as usize;
+    input.non_witness_utxo.as_ref()?.output.get(vout)
+}
+
+fn is_input_finalized(input: &bitcoin::psbt::Input) -> bool {
+    input.final_script_sig.is_some() || input.final_script_witness.is_some()
+}
+
+// Signatures an input needs: the threshold for multisig, otherwise one
+pub fn required_signatures(input: &bitcoin::psbt::Input) -> usize {
+    input
+        .witness_script
+        .as_ref()
+        .and_then(|script| parse_multisig(script))
+        .map_or(1, |(threshold, _)| threshold)
+}
+
+// Signatures still needed before every input can be finalized
+pub fn missing_signatures(psbt: &Psbt) -> usize {
+    psbt.inputs
+        .iter()
+        .filter(|input| !is_input_finalized(input))
+        .map(|input| required_signatures(input).saturating_sub(input.partial_sigs.len()))
+        .sum()
+}
+
+// True when every input is either finalized or has enough signatures to be
+// finalized
+pub fn is_fully_signed(psbt: &Psbt) -> bool {
+    psbt.inputs
+        .iter()
+        .all(|input| is_input_finalized(input) || input.partial_sigs.len() >= required_signatures(input))
+}
+
+// Build the final scriptSig/witness for every signed single-key or
+// p2wsh multisig input
+pub fn finalize(psbt: &mut Psbt) -> Result<(), PsbtError> {
+    for index in 0..psbt.inputs.len() {
+        if is_input_finalized(&psbt.inputs[index]) {
+            continue;
+        }
+
+        let script_pubkey = spent_output(psbt, index)
+            .map(|output| output.script_pubkey.clone())
+            .ok_or_else(|| PsbtError::MissingInputData(format!("input {} has no UTXO information", index)))?;
+
+        let input = &mut psbt.inputs[index];
+        if script_pubkey.is_v0_p2wsh() {
+            finalize_multisig(input, index, &script_pubkey)?;
+            continue;
+        }
+
+        let (public_key, signature) = input
+            .partial_sigs
+            .iter()
+            .next()
+            .map(|(public_key, signature)| (*public_key, *signature))
+            .ok_or_else(|| PsbtError::Finalize(format!("input {} is not signed", index)))?;
+
+        let signature_bytes = PushBytesBuf::try_from(signature.to_vec())
+            .map_err(|e| PsbtError::Finalize(e.to_string()))?;
+
+        if script_pubkey.is_v0_p2wpkh() {
+            input.final_script_witness = Some(Witness::from_slice(&[signature.to_vec(), public_key.to_bytes()]));
+        } else if script_pubkey.is_p2pkh() {
+            input.final_script_sig = Some(
+                Builder::new()
+                    .push_slice(signature_bytes)
+                    .push_key(&public_key)
+                    .into_script(),
+            );
+        } else if script_pubkey.is_p2sh() {
+            let redeem_script = input
+                .redeem_script
+                .clone()
+                .ok_or_else(|| PsbtError::Finalize(format!("input {} is missing its redeem script", index)))?;
+            if !redeem_script.is_v0_p2wpkh() {
+                return Err(PsbtError::Finalize(format!("input {} has an unsupported redeem script", index)));
+            }
+            let redeem_bytes = PushBytesBuf::try_from(redeem_script.to_bytes())
+                .map_err(|e| PsbtError::Finalize(e.to_string()))?;
+            input.final_script_sig = Some(Builder::new().push_slice(redeem_bytes).into_script());
+            input.final_script_witness = Some(Witness::from_slice(&[signature.to_vec(), public_key.to_bytes()]));
+        } else {
+            return Err(PsbtError::Finalize(format!("input {} has an unsupported script type", index)));
+        }
+
+        clear_finalized(input);
+    }
+
+    Ok(())
+}
+
+// Witness for a p2wsh multisig input: the CHECKMULTISIG dummy, `threshold`
+// signatures in the order their keys appear in the script, then the script
+fn finalize_multisig(input: &mut bitcoin::psbt::Input, index: usize, script_pubkey: &Script) -> Result<(), PsbtError> {
+    let witness_script = input
+        .witness_script
+        .clone()
+        .ok_or_else(|| PsbtError::Finalize(format!("input {} is missing its witness script", index)))?;
+    if ScriptBuf::new_v0_p2wsh(&witness_script.wscript_hash()) != *script_pubkey {
+        return Err(PsbtError::Finalize(format!("input {} witness script does not match its output", index)));
+    }
+    let (threshold, keys) = parse_multisig(&witness_script)
+        .ok_or_else(|| PsbtError::Finalize(format!("input {} has an unsupported witness script", index)))?;
+
+    let signatures: Vec<Vec<u8>> = keys
+        .iter()
+        .filter_map(|key| input.partial_sigs.get(key).map(|signature| signature.to_vec()))
+        .take(threshold)
+        .collect();
+    if signatures.len() < threshold {
+        return Err(PsbtError::Finalize(format!(
+            "input {} has {} of {} required signatures",
+            index,
+            signatures.len(),
+            threshold
+        )));
+    }
+
+    let mut witness = vec![Vec::new()];
+    witness.extend(signatures);
+    witness.push(witness_script.to_bytes());
+    input.final_script_witness = Some(Witness::from_slice(&witness));
+    clear_finalized(input);
+    Ok(())
+}
+
+// Per BIP174 the finalizer removes everything except the UTXO data
+fn clear_finalized(input: &mut bitcoin::psbt::Input) {
+    input.partial_sigs.clear();
+    input.sighash_type = None;
+    input.redeem_script = None;
+    input.witness_script = None;
+    input.bip32_derivation.clear();
+}
+
+// Merge copies of the same PSBT, e.g. signed by different cosigners
+// (the BIP174 combiner role)
+pub fn combine_psbts(encoded: &[String]) -> Result<Psbt, PsbtError> {
+    let mut psbts = encoded.iter().map(|psbt| decode_psbt(psbt));
+    let mut combined = psbts
+        .next()
+        .ok_or_else(|| PsbtError::InvalidEncoding("no PSBTs to combine".to_string()))??;
+    for psbt in psbts {
+        combined
+            .combine(psbt?)
+            .map_err(|e| PsbtError::InvalidEncoding(format!("cannot combine PSBTs: {}", e)))?;
+    }
+    Ok(combined)
+}
+
+// Finalize and extract the network-ready transaction. Fails unless every
+// input is signed.
+pub fn finalize_and_extract(mut psbt: Psbt) -> Result<Transaction, PsbtError> {
+    if !is_fully_signed(&psbt) {
+        return Err(PsbtError::Finalize("not all inputs are signed".to_string()));
+    }
+    finalize(&mut psbt)?;
+    let tx = psbt.extract_tx();
+    info!("Finalized transaction {}", tx.txid());
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::bip32::ExtendedPrivKey;
+
+    fn test_wallet() -> WalletDescriptor {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[7; 32]).unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+        let xpub = ExtendedPubKey::from_priv(&secp, &master.derive_priv(&secp, &path).unwrap());
+        WalletDescriptor::Single(AccountKey {
+            fingerprint: master.fingerprint(&secp),
+            path,
+            xpub,
+            script_type: ScriptType::NativeSegwit,
+        })
+    }
+
+    // Test vectors from BIP 380
+    #[test]
+    fn descriptor_checksum_matches_bip380_vectors() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(strip_checksum("raw(deadbeef)#89f8spxm").unwrap(), "raw(deadbeef)");
+        assert_eq!(strip_checksum("raw(deadbeef)").unwrap(), "raw(deadbeef)");
+    }
+
+    #[test]
+    fn invalid_descriptor_checksums_are_rejected() {
+        for descriptor in [
+            "raw(deadbeef)#",          // Missing checksum
+            "raw(deadbeef)#89f8spxmx", // Too long
+            "raw(deadbeef)#89f8spx",   // Too short
+            "raw(deadbeef)#89f8spxn",  // Error in checksum
+            "raw(deedbeef)#89f8spxm",  // Error in payload
+            "raw(Ü)#00000000",         // Invalid character
+        ] {
+            assert!(strip_checksum(descriptor).is_err(), "{} was accepted", descriptor);
+        }
+    }
+
+    #[test]
+    fn find_derivation_searches_up_to_the_bounds() {
+        let secp = Secp256k1::verification_only();
+        let wallet = test_wallet();
+        let change = wallet.derive(&secp, 1, 150).unwrap();
+
+        let (chain, index, derived) = find_derivation(&secp, &wallet, [20, 170], &change.script_pubkey)
+            .unwrap()
+            .unwrap();
+        assert_eq!((chain, index), (1, 150));
+        assert_eq!(derived.path, change.path);
+
+        assert!(find_derivation(&secp, &wallet, [20, 150], &change.script_pubkey).unwrap().is_none());
+        // The receive chain's bound doesn't extend the change chain's
+        assert!(find_derivation(&secp, &wallet, [170, 20], &change.script_pubkey).unwrap().is_none());
+    }
+
+    fn spend_from(wallet: &WalletDescriptor) -> Psbt {
+        let secp = Secp256k1::verification_only();
+        let tx = Transaction {
+            version: 2,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![TxOut { value: 10_000, script_pubkey: wallet.derive(&secp, 0, 1).unwrap().script_pubkey }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 20_000, script_pubkey: wallet.derive(&secp, 0, 0).unwrap().script_pubkey });
+        psbt
+    }
+
+    #[test]
+    fn client_key_origins_are_replaced_by_the_wallets() {
+        let secp = Secp256k1::verification_only();
+        let wallet = test_wallet();
+        let mut psbt = spend_from(&wallet);
+        let foreign = wallet.derive(&secp, 1, 5).unwrap();
+        for (public_key, key_source) in foreign.keys {
+            psbt.inputs[0].bip32_derivation.insert(public_key.inner, key_source);
+        }
+
+        fill_bip32_derivations(&mut psbt, &wallet, [20, 20]).unwrap();
+        let expected = wallet.derive(&secp, 0, 0).unwrap();
+        let origins: Vec<_> = psbt.inputs[0].bip32_derivation.values().cloned().collect();
+        assert_eq!(origins, expected.keys.into_iter().map(|(_, source)| source).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn inputs_from_other_wallets_are_rejected() {
+        let wallet = test_wallet();
+        let secp = Secp256k1::new();
+        let other_master = ExtendedPrivKey::new_master(Network::Testnet, &[8; 32]).unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+        let other = WalletDescriptor::Single(AccountKey {
+            fingerprint: other_master.fingerprint(&secp),
+            xpub: ExtendedPubKey::from_priv(&secp, &other_master.derive_priv(&secp, &path).unwrap()),
+            path,
+            script_type: ScriptType::NativeSegwit,
+        });
+        let mut psbt = spend_from(&other);
+
+        let result = fill_bip32_derivations(&mut psbt, &wallet, [20, 20]);
+        assert!(matches!(result, Err(PsbtError::ForeignInput(0))));
+    }
+
+    // A previous transaction paying `script`, and a PSBT spending its output
+    fn spend_of(script: ScriptBuf, value: u64) -> (Transaction, Psbt) {
+        let previous = Transaction {
+            version: 2,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![TxOut { value, script_pubkey: script }],
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint { txid: previous.txid(), vout: 0 },
+                ..Default::default()
+            }],
+            output: vec![TxOut { value: value - 1_000, script_pubkey: ScriptBuf::new() }],
+        };
+        (previous, Psbt::from_unsigned_tx(tx).unwrap())
+    }
+
+    // Never reached: every test supplies the previous transaction
+    fn offline_backend() -> BlockchainClient {
+        BlockchainClient::new("http://127.0.0.1:9".to_string())
+    }
+
+    #[actix_web::test]
+    async fn previous_transactions_must_match_the_spent_txid() {
+        let wallet = test_wallet();
+        let script = wallet.derive(&Secp256k1::verification_only(), 0, 0).unwrap().script_pubkey;
+        let (_, mut psbt) = spend_of(script.clone(), 20_000);
+        let (other, _) = spend_of(script, 90_000);
+        psbt.inputs[0].non_witness_utxo = Some(other);
+
+        let result = fill_utxos(&mut psbt, &offline_backend()).await;
+        assert!(matches!(result, Err(PsbtError::InputMismatch(_))));
+    }
+
+    #[actix_web::test]
+    async fn witness_utxos_must_match_the_previous_transaction() {
+        let wallet = test_wallet();
+        let script = wallet.derive(&Secp256k1::verification_only(), 0, 0).unwrap().script_pubkey;
+        let (previous, mut psbt) = spend_of(script.clone(), 20_000);
+        psbt.inputs[0].non_witness_utxo = Some(previous);
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 90_000, script_pubkey: script });
+
+        let result = fill_utxos(&mut psbt, &offline_backend()).await;
+        assert!(matches!(result, Err(PsbtError::InputMismatch(_))));
+    }
+
+    #[actix_web::test]
+    async fn legacy_amounts_come_from_the_previous_transaction() {
+        let secp = Secp256k1::new();
+        let key = bitcoin::PrivateKey::new(bitcoin::secp256k1::SecretKey::from_slice(&[3; 32]).unwrap(), Network::Testnet);
+        let script = ScriptBuf::new_p2pkh(&key.public_key(&secp).pubkey_hash());
+        let (previous, mut psbt) = spend_of(script.clone(), 20_000);
+        psbt.inputs[0].non_witness_utxo = Some(previous);
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 90_000, script_pubkey: script });
+
+        fill_utxos(&mut psbt, &offline_backend()).await.unwrap();
+        assert!(psbt.inputs[0].witness_utxo.is_none());
+        assert_eq!(spent_output(&psbt, 0).unwrap().value, 20_000);
+    }
+}
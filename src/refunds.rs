@@ -0,0 +1,307 @@
+use bitcoin::{Address, Network};
+use chrono::Utc;
+use log::info;
+use rusqlite::Error as SqliteError;
+use serde::Serialize;
+use serde_json::json;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::models::{Invoice, PaymentStatus, Refund, RefundKind, RefundRate, RefundStatus};
+use crate::payouts::{self, NewPayout, PayoutError, DUST_LIMIT};
+use crate::state::AppState;
+
+#[derive(Debug)]
+pub enum RefundError {
+    InvoiceNotFound,
+    NotFound,
+    NothingToRefund,
+    NotOverpaid,
+    InvalidAmount { amount: u64, refundable: u64 },
+    InvalidStatus(RefundStatus),
+    InvalidDestination(String),
+    Payout(PayoutError),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for RefundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefundError::InvoiceNotFound => write!(f, "Invoice not found"),
+            RefundError::NotFound => write!(f, "Refund not found"),
+            RefundError::NothingToRefund => write!(f, "Nothing left to refund on this invoice"),
+            RefundError::NotOverpaid => write!(f, "Invoice was not overpaid"),
+            RefundError::InvalidAmount { amount, refundable } => write!(
+                f,
+                "Cannot refund {} sat: between {} and {} sat can be refunded",
+                amount, DUST_LIMIT, refundable
+            ),
+            RefundError::InvalidStatus(status) => write!(f, "Refund is {:?}", status),
+            RefundError::InvalidDestination(msg) => write!(f, "Invalid destination: {}", msg),
+            RefundError::Payout(e) => write!(f, "{}", e),
+            RefundError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RefundError {}
+
+impl From<SqliteError> for RefundError {
+    fn from(error: SqliteError) -> Self {
+        RefundError::Database(error)
+    }
+}
+
+impl From<PayoutError> for RefundError {
+    fn from(error: PayoutError) -> Self {
+        RefundError::Payout(error)
+    }
+}
+
+pub struct NewRefund {
+    pub kind: RefundKind,
+    pub amount: Option<u64>, // Required for custom refunds
+    pub rate: RefundRate,
+}
+
+// What an invoice received on-chain and how much of it can still be refunded
+#[derive(Debug, Serialize)]
+pub struct RefundableAmount {
+    pub invoice_id: String,
+    pub amount: u64, // Invoiced
+    pub received: u64,
+    // Received but not yet confirmed (nor accepted at 0-conf); refundable
+    // once it is
+    pub unconfirmed: u64,
+    pub refunded: u64,
+    pub overpaid: u64,
+    pub refundable: u64,
+}
+
+fn find_invoice(data: &AppState, id: &str) -> Result<Invoice, RefundError> {
+    data.invoice(id)?.ok_or(RefundError::InvoiceNotFound)
+}
+
+// Based on the payments the chain watcher recorded for the invoice. Payments
+// it found double-spent or reorged out don't count, and unconfirmed ones only
+// once they can't disappear any more.
+fn refundable_amount(data: &AppState, invoice: &Invoice) -> Result<RefundableAmount, RefundError> {
+    let payments = data.db.list_invoice_payments(&invoice.id)?;
+    let valid = || payments.iter().filter(|payment| payment.status != PaymentStatus::Invalid);
+    let received = valid().map(|payment| payment.value).sum::<u64>();
+    let final_amount = valid()
+        .filter(|payment| payment.status == PaymentStatus::Confirmed || payment.zero_conf_accepted)
+        .map(|payment| payment.value)
+        .sum::<u64>();
+    let refunded = data.db.refunded_amount(&invoice.id)?;
+
+    Ok(RefundableAmount {
+        invoice_id: invoice.id.clone(),
+        amount: invoice.amount,
+        received,
+        unconfirmed: received - final_amount,
+        refunded,
+        overpaid: final_amount.saturating_sub(invoice.amount).saturating_sub(refunded),
+        refundable: final_amount.saturating_sub(refunded),
+    })
+}
+
+// Offer a refund of part or all of what an invoice received, e.g. after it
+// was overpaid, paid after expiring or cancelled by the merchant. The buyer
+// claims it with their own address.
+pub async fn create(
+    data: &AppState,
+    invoice_id: &str,
+    created_by: &str,
+    new: NewRefund,
+) -> Result<Refund, RefundError> {
+    let invoice = find_invoice(data, invoice_id)?;
+    let available = refundable_amount(data, &invoice)?;
+    if available.refundable == 0 {
+        return Err(RefundError::NothingToRefund);
+    }
+
+    let amount = match new.kind {
+        RefundKind::Full => available.refundable,
+        RefundKind::Overpaid if available.overpaid == 0 => return Err(RefundError::NotOverpaid),
+        RefundKind::Overpaid => available.overpaid,
+        RefundKind::Custom => new.amount.unwrap_or(0),
+    };
+    if amount < DUST_LIMIT || amount > available.refundable {
+        return Err(RefundError::InvalidAmount { amount, refundable: available.refundable });
+    }
+
+    let refund = Refund {
+        id: Uuid::new_v4().to_string(),
+        invoice_id: invoice.id.clone(),
+        store_id: invoice.store_id.clone(),
+        kind: new.kind,
+        rate: new.rate,
+        amount,
+        status: RefundStatus::AwaitingClaim,
+        destination: None,
+        payout_id: None,
+        created_by: created_by.to_string(),
+        created_at: Utc::now(),
+        claimed_at: None,
+    };
+    data.db.save_refund(&refund)?;
+
+    info!("Refund {} of {} sat created for invoice {} by {}", refund.id, amount, invoice.id, created_by);
+    notify(data, "refund.created", &refund).await;
+    Ok(refund)
+}
+
+pub fn refundable(data: &AppState, invoice_id: &str) -> Result<RefundableAmount, RefundError> {
+    let invoice = find_invoice(data, invoice_id)?;
+    refundable_amount(data, &invoice)
+}
+
+pub fn get(data: &AppState, id: &str) -> Result<Refund, RefundError> {
+    data.db.get_refund(id)?.ok_or(RefundError::NotFound)
+}
+
+pub fn list(data: &AppState, invoice_id: &str) -> Result<Vec<Refund>, RefundError> {
+    find_invoice(data, invoice_id)?;
+    Ok(data.db.list_refunds(invoice_id)?)
+}
+
+// The buyer submits their address; the refund becomes a payout that goes
+// through the usual approval before it is sent
+pub async fn claim(data: &AppState, id: &str, destination: &str, network: Network) -> Result<Refund, RefundError> {
+    let mut refund = get(data, id)?;
+    if refund.status != RefundStatus::AwaitingClaim {
+        return Err(RefundError::InvalidStatus(refund.status));
+    }
+    let destination = Address::from_str(destination.trim())
+        .and_then(|address| address.require_network(network))
+        .map_err(|e| RefundError::InvalidDestination(format!("{}: {}", destination, e)))?
+        .to_string();
+
+    let now = Utc::now();
+    if !data.db.claim_refund(id, &destination, now)? {
+        return Err(RefundError::InvalidStatus(get(data, id)?.status));
+    }
+
+    let new_payout = NewPayout {
+        destination: destination.clone(),
+        amount: refund.amount.to_string(),
+        currency: None,
+        description: Some(format!("Refund of invoice {}", refund.invoice_id)),
+    };
+    let payout = match payouts::create(data, &refund.store_id, &format!("refund:{}", refund.id), vec![new_payout], network).await {
+        Ok(mut payouts) => payouts.remove(0),
+        Err(e) => {
+            // Let the buyer try again
+            data.db.update_refund_status(id, RefundStatus::AwaitingClaim, None)?;
+            return Err(e.into());
+        }
+    };
+    data.db.update_refund_status(id, RefundStatus::Claimed, Some(&payout.id))?;
+
+    refund.status = RefundStatus::Claimed;
+    refund.destination = Some(destination);
+    refund.payout_id = Some(payout.id);
+    refund.claimed_at = Some(now);
+    info!("Refund {} claimed, queued as payout {:?}", refund.id, refund.payout_id);
+    notify(data, "refund.claimed", &refund).await;
+    Ok(refund)
+}
+
+// Withdraw a refund the buyer has not claimed yet
+pub async fn cancel(data: &AppState, id: &str) -> Result<Refund, RefundError> {
+    let mut refund = get(data, id)?;
+    if refund.status != RefundStatus::AwaitingClaim {
+        return Err(RefundError::InvalidStatus(refund.status));
+    }
+    // The buyer may be claiming it at the same time
+    if !data.db.cancel_refund(id)? {
+        return Err(RefundError::InvalidStatus(get(data, id)?.status));
+    }
+    refund.status = RefundStatus::Cancelled;
+    notify(data, "refund.cancelled", &refund).await;
+    Ok(refund)
+}
+
+async fn notify(data: &AppState, event_type: &str, refund: &Refund) {
+    data.notify(event_type, json!({
+        "refund_id": refund.id,
+        "invoice_id": refund.invoice_id,
+        "store_id": refund.store_id,
+        "amount": refund.amount,
+        "status": refund.status,
+        "payout_id": refund.payout_id,
+    }))
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{InvoicePayment, InvoiceStatus};
+
+    fn invoice(data: &AppState) -> Invoice {
+        let invoice = Invoice {
+            id: "invoice".to_string(),
+            address: "tb1qexample".to_string(),
+            amount: 50_000,
+            description: String::new(),
+            status: InvoiceStatus::Settled,
+            created_at: Utc::now(),
+            expires_at: Utc::now(),
+            derivation_path: None,
+            store_id: "shop".to_string(),
+        };
+        data.db.save_invoice(&invoice).unwrap();
+        invoice
+    }
+
+    fn payment(txid: &str, value: u64, status: PaymentStatus) -> InvoicePayment {
+        InvoicePayment {
+            invoice_id: "invoice".to_string(),
+            txid: txid.to_string(),
+            vout: 0,
+            value,
+            status,
+            block_height: (status == PaymentStatus::Confirmed).then_some(100),
+            block_hash: None,
+            seen_at: Utc::now(),
+            inputs: Vec::new(),
+            replaced_by: None,
+            risk_score: None,
+            risk_factors: Vec::new(),
+            zero_conf_accepted: false,
+        }
+    }
+
+    #[test]
+    fn only_final_payments_are_refundable() {
+        let data = AppState::new(":memory:");
+        let invoice = invoice(&data);
+        data.db.save_invoice_payment(&payment("aa", 60_000, PaymentStatus::Confirmed)).unwrap();
+        data.db.save_invoice_payment(&payment("bb", 30_000, PaymentStatus::Invalid)).unwrap();
+        data.db.save_invoice_payment(&payment("cc", 5_000, PaymentStatus::Unconfirmed)).unwrap();
+
+        let available = refundable_amount(&data, &invoice).unwrap();
+        assert_eq!(available.received, 65_000);
+        assert_eq!(available.unconfirmed, 5_000);
+        assert_eq!(available.overpaid, 10_000);
+        assert_eq!(available.refundable, 60_000);
+    }
+
+    #[actix_web::test]
+    async fn claimed_refunds_cannot_be_cancelled() {
+        let data = AppState::new(":memory:");
+        invoice(&data);
+        data.db.save_invoice_payment(&payment("aa", 60_000, PaymentStatus::Confirmed)).unwrap();
+        let new = NewRefund { kind: RefundKind::Overpaid, amount: None, rate: RefundRate::Original };
+        let refund = create(&data, "invoice", "merchant", new).await.unwrap();
+
+        // The claim wins the race: the cancel must not overwrite it
+        assert!(data.db.claim_refund(&refund.id, "tb1qbuyer", Utc::now()).unwrap());
+        assert!(!data.db.cancel_refund(&refund.id).unwrap());
+        let result = cancel(&data, &refund.id).await;
+        assert!(matches!(result, Err(RefundError::InvalidStatus(RefundStatus::Claimed))));
+        assert_eq!(get(&data, &refund.id).unwrap().status, RefundStatus::Claimed);
+    }
+}
@@ -0,0 +1,301 @@
+use async_trait::async_trait;
+use bip39::Mnemonic;
+use bitcoin::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network};
+use log::info;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::os::unix::fs::OpenOptionsExt;
+use zeroize::Zeroizing;
+
+use crate::psbt::{self, Psbt, ScriptType};
+
+const PBKDF2_ITERATIONS: u32 = 210_000;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum SignerError {
+    DeviceUnavailable(String),
+    SigningFailed(String),
+    KeyDerivation(String),
+    Encryption(String),
+}
+
+impl std::fmt::Display for SignerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignerError::DeviceUnavailable(msg) => write!(f, "Signer unavailable: {}", msg),
+            SignerError::SigningFailed(msg) => write!(f, "Signing failed: {}", msg),
+            SignerError::KeyDerivation(msg) => write!(f, "Key derivation failed: {}", msg),
+            SignerError::Encryption(msg) => write!(f, "Seed encryption error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SignerError {}
+
+// Common interface for everything that can hold keys and sign PSBTs
+#[async_trait]
+pub trait Signer: Send + Sync {
+    // Master key fingerprint, matched against the key origins in PSBTs
+    async fn fingerprint(&self) -> Result<Fingerprint, SignerError>;
+
+    // Add this signer's signatures to the PSBT; returns the number of inputs signed
+    async fn sign_psbt(&self, psbt: &mut Psbt) -> Result<usize, SignerError>;
+
+    async fn get_xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, SignerError>;
+
+    // Derive the address at `path` and, where the signer has a screen, show it
+    async fn display_address(&self, path: &DerivationPath, script_type: ScriptType) -> Result<String, SignerError>;
+}
+
+// A BIP39 mnemonic encrypted with a password
+// (PBKDF2-HMAC-SHA256 key derivation, ChaCha20-Poly1305 encryption)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedSeed {
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub iterations: u32,
+}
+
+impl EncryptedSeed {
+    pub fn encrypt(mnemonic: &Mnemonic, password: &str) -> Result<Self, SignerError> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut nonce);
+
+        let key = Self::derive_key(password, &salt, PBKDF2_ITERATIONS)?;
+        let mut in_out = Zeroizing::new(mnemonic.to_string().into_bytes());
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut *in_out)
+            .map_err(|_| SignerError::Encryption("encryption failed".to_string()))?;
+
+        Ok(Self {
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(&*in_out),
+            iterations: PBKDF2_ITERATIONS,
+        })
+    }
+
+    pub fn decrypt(&self, password: &str) -> Result<Mnemonic, SignerError> {
+        let salt = hex::decode(&self.salt).map_err(|e| SignerError::Encryption(e.to_string()))?;
+        let nonce: [u8; NONCE_LEN] = hex::decode(&self.nonce)
+            .map_err(|e| SignerError::Encryption(e.to_string()))?
+            .try_into()
+            .map_err(|_| SignerError::Encryption("invalid nonce length".to_string()))?;
+        let mut in_out = Zeroizing::new(
+            hex::decode(&self.ciphertext).map_err(|e| SignerError::Encryption(e.to_string()))?,
+        );
+
+        let key = Self::derive_key(password, &salt, self.iterations)?;
+        let plaintext = key
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+            .map_err(|_| SignerError::Encryption("wrong password or corrupted seed".to_string()))?;
+
+        let phrase = std::str::from_utf8(plaintext)
+            .map_err(|_| SignerError::Encryption("seed is not valid UTF-8".to_string()))?;
+        Mnemonic::parse(phrase).map_err(|e| SignerError::Encryption(e.to_string()))
+    }
+
+    fn derive_key(password: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey, SignerError> {
+        let iterations = NonZeroU32::new(iterations)
+            .ok_or_else(|| SignerError::Encryption("invalid iteration count".to_string()))?;
+        let mut key_bytes = Zeroizing::new([0u8; KEY_LEN]);
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut *key_bytes);
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &*key_bytes)
+            .map_err(|_| SignerError::Encryption("invalid key".to_string()))?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+// Signs with keys derived from a BIP39 seed held in memory. The seed is
+// zeroized on drop and private keys only live for the duration of a call.
+pub struct SoftwareSigner {
+    seed: Zeroizing<[u8; 64]>,
+    network: Network,
+    fingerprint: Fingerprint,
+}
+
+impl SoftwareSigner {
+    pub fn from_mnemonic(mnemonic: &Mnemonic, passphrase: &str, network: Network) -> Result<Self, SignerError> {
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+        let secp = Secp256k1::new();
+        let mut master = ExtendedPrivKey::new_master(network, &*seed)
+            .map_err(|e| SignerError::KeyDerivation(e.to_string()))?;
+        let fingerprint = master.fingerprint(&secp);
+        master.private_key.non_secure_erase();
+
+        info!("Software signer loaded (fingerprint {})", fingerprint);
+        Ok(Self {
+            seed,
+            network,
+            fingerprint,
+        })
+    }
+
+    pub fn from_encrypted_seed(
+        encrypted: &EncryptedSeed,
+        password: &str,
+        passphrase: &str,
+        network: Network,
+    ) -> Result<Self, SignerError> {
+        let mnemonic = encrypted.decrypt(password)?;
+        Self::from_mnemonic(&mnemonic, passphrase, network)
+    }
+
+    // Load the signer configured through the environment:
+    // SOFTWARE_SIGNER_SEED_FILE (an EncryptedSeed JSON file),
+    // SOFTWARE_SIGNER_PASSWORD and optionally SOFTWARE_SIGNER_PASSPHRASE.
+    // If the seed file does not exist yet and SOFTWARE_SIGNER_MNEMONIC is set,
+    // the mnemonic is encrypted and written to the seed file.
+    pub fn from_env(network: Network) -> Result<Option<Self>, SignerError> {
+        let seed_file = match std::env::var("SOFTWARE_SIGNER_SEED_FILE") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let password = Zeroizing::new(std::env::var("SOFTWARE_SIGNER_PASSWORD").map_err(|_| {
+            SignerError::Encryption("SOFTWARE_SIGNER_PASSWORD is not set".to_string())
+        })?);
+        let passphrase = Zeroizing::new(std::env::var("SOFTWARE_SIGNER_PASSPHRASE").unwrap_or_default());
+
+        if !std::path::Path::new(&seed_file).exists() {
+            let phrase = Zeroizing::new(std::env::var("SOFTWARE_SIGNER_MNEMONIC").map_err(|_| {
+                SignerError::Encryption(format!("seed file {} not found", seed_file))
+            })?);
+            let mnemonic = Mnemonic::parse(phrase.as_str())
+                .map_err(|e| SignerError::KeyDerivation(e.to_string()))?;
+            let encrypted = EncryptedSeed::encrypt(&mnemonic, &password)?;
+            let json = serde_json::to_string_pretty(&encrypted)
+                .map_err(|e| SignerError::Encryption(e.to_string()))?;
+            // Readable by the owner only; never overwrite an existing seed
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&seed_file)
+                .map_err(|e| SignerError::Encryption(format!("creating {}: {}", seed_file, e)))?;
+            file.write_all(json.as_bytes())
+                .map_err(|e| SignerError::Encryption(format!("writing {}: {}", seed_file, e)))?;
+            info!("Encrypted software signer seed written to {}", seed_file);
+        }
+
+        let contents = std::fs::read_to_string(&seed_file)
+            .map_err(|e| SignerError::Encryption(format!("reading {}: {}", seed_file, e)))?;
+        let encrypted: EncryptedSeed = serde_json::from_str(&contents)
+            .map_err(|e| SignerError::Encryption(format!("parsing {}: {}", seed_file, e)))?;
+
+        Self::from_encrypted_seed(&encrypted, &password, &passphrase, network).map(Some)
+    }
+
+    fn master_key(&self) -> Result<ExtendedPrivKey, SignerError> {
+        ExtendedPrivKey::new_master(self.network, &*self.seed)
+            .map_err(|e| SignerError::KeyDerivation(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Signer for SoftwareSigner {
+    async fn fingerprint(&self) -> Result<Fingerprint, SignerError> {
+        Ok(self.fingerprint)
+    }
+
+    async fn sign_psbt(&self, psbt: &mut Psbt) -> Result<usize, SignerError> {
+        let secp = Secp256k1::new();
+        let mut master = self.master_key()?;
+
+        // Inputs without a derivation for our fingerprint are skipped, not errors
+        let result = psbt.sign(&master, &secp);
+        master.private_key.non_secure_erase();
+
+        match result {
+            Ok(signed) => {
+                let count = signed.values().filter(|keys| !keys.is_empty()).count();
+                info!("Software signer signed {} input(s)", count);
+                Ok(count)
+            }
+            Err((_, errors)) => {
+                let details: Vec<String> = errors
+                    .iter()
+                    .map(|(index, error)| format!("input {}: {}", index, error))
+                    .collect();
+                Err(SignerError::SigningFailed(details.join(", ")))
+            }
+        }
+    }
+
+    async fn get_xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, SignerError> {
+        let secp = Secp256k1::new();
+        let mut master = self.master_key()?;
+        let derived = master.derive_priv(&secp, path);
+        master.private_key.non_secure_erase();
+        let mut derived = derived.map_err(|e| SignerError::KeyDerivation(e.to_string()))?;
+        let xpub = ExtendedPubKey::from_priv(&secp, &derived);
+        derived.private_key.non_secure_erase();
+        Ok(xpub)
+    }
+
+    async fn display_address(&self, path: &DerivationPath, script_type: ScriptType) -> Result<String, SignerError> {
+        // No screen to show it on; derive it so callers can compare
+        let xpub = self.get_xpub(path).await?;
+        let script = psbt::script_for_key(&xpub.to_pub(), script_type);
+        Address::from_script(&script, self.network)
+            .map(|address| address.to_string())
+            .map_err(|e| SignerError::KeyDerivation(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // BIP84 test vectors
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn signer(network: Network) -> SoftwareSigner {
+        SoftwareSigner::from_mnemonic(&Mnemonic::parse(MNEMONIC).unwrap(), "", network).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn software_signer_matches_bip84_vectors() {
+        let signer = signer(Network::Bitcoin);
+        let account = DerivationPath::from_str("m/84'/0'/0'").unwrap();
+        let xpub = signer.get_xpub(&account).await.unwrap();
+        assert_eq!(
+            xpub.to_string(),
+            "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V"
+        );
+
+        let receive = DerivationPath::from_str("m/84'/0'/0'/0/0").unwrap();
+        assert_eq!(
+            signer.display_address(&receive, ScriptType::NativeSegwit).await.unwrap(),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        let change = DerivationPath::from_str("m/84'/0'/0'/1/0").unwrap();
+        assert_eq!(
+            signer.display_address(&change, ScriptType::NativeSegwit).await.unwrap(),
+            "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"
+        );
+    }
+
+    #[actix_web::test]
+    async fn software_signer_addresses_follow_the_script_type() {
+        let signer = signer(Network::Testnet);
+        let path = DerivationPath::from_str("m/49'/1'/0'/0/0").unwrap();
+        let xpub = signer.get_xpub(&path).await.unwrap();
+        for script_type in [ScriptType::NativeSegwit, ScriptType::NestedSegwit, ScriptType::Legacy] {
+            let address = signer.display_address(&path, script_type).await.unwrap();
+            let expected = Address::from_script(&psbt::script_for_key(&xpub.to_pub(), script_type), Network::Testnet).unwrap();
+            assert_eq!(address, expected.to_string());
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Error as SqliteError;
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::database::Database;
+use crate::models::SpendingPolicy;
+use crate::psbt::{self, Psbt, PsbtError, WalletDescriptor};
+use crate::tx_decoder;
+use crate::wallet;
+
+#[derive(Debug)]
+pub enum PolicyError {
+    InvalidPolicy(String),
+    Derivation(PsbtError),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::InvalidPolicy(msg) => write!(f, "Invalid spending policy: {}", msg),
+            PolicyError::Derivation(e) => write!(f, "{}", e),
+            PolicyError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl From<SqliteError> for PolicyError {
+    fn from(error: SqliteError) -> Self {
+        PolicyError::Database(error)
+    }
+}
+
+impl From<PsbtError> for PolicyError {
+    fn from(error: PsbtError) -> Self {
+        PolicyError::Derivation(error)
+    }
+}
+
+// Held while re-checking the limits and recording a spend, so concurrent
+// broadcasts can't both fit in what is left of a limit
+static SPEND_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum PolicyViolation {
+    DailyLimit { limit: u64, already_spent: u64, amount: u64 },
+    WeeklyLimit { limit: u64, already_spent: u64, amount: u64 },
+    DestinationNotAllowed { address: String },
+    FeeRateTooHigh { fee_rate: f64, max_fee_rate: f64 },
+    FeeUnknown,
+    DelayRequired { amount: u64, threshold: u64, eligible_at: DateTime<Utc> },
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyViolation::DailyLimit { limit, already_spent, amount } => write!(
+                f,
+                "Spending {} sat would exceed the daily limit of {} sat ({} sat already spent)",
+                amount, limit, already_spent
+            ),
+            PolicyViolation::WeeklyLimit { limit, already_spent, amount } => write!(
+                f,
+                "Spending {} sat would exceed the weekly limit of {} sat ({} sat already spent)",
+                amount, limit, already_spent
+            ),
+            PolicyViolation::DestinationNotAllowed { address } => {
+                write!(f, "Destination {} is not on the allow-list", address)
+            }
+            PolicyViolation::FeeRateTooHigh { fee_rate, max_fee_rate } => {
+                write!(f, "Fee rate {:.1} sat/vB exceeds the maximum of {} sat/vB", fee_rate, max_fee_rate)
+            }
+            PolicyViolation::FeeUnknown => write!(f, "Fee cannot be determined because input values are missing"),
+            PolicyViolation::DelayRequired { amount, threshold, eligible_at } => write!(
+                f,
+                "Spends of {} sat or more must wait; this {} sat spend can be signed after {}",
+                threshold, amount, eligible_at
+            ),
+        }
+    }
+}
+
+// Outcome of checking one transaction against a store's policy
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyEvaluation {
+    pub store_id: String,
+    pub txid: String,
+    pub amount: u64, // Leaving the wallet, excluding change and fee
+    pub fee: Option<u64>,
+    pub fee_rate: Option<f64>,
+    pub destinations: Vec<String>,
+    pub violations: Vec<PolicyViolation>,
+    pub allowed: bool,
+    // Approvals needed (as a spend proposal) before this may be signed
+    pub approvals_required: u32,
+}
+
+// Allow-listed addresses must be valid for the network
+pub fn validate_policy(policy: &SpendingPolicy, network: Network) -> Result<(), PolicyError> {
+    for address in &policy.allowed_destinations {
+        Address::from_str(address)
+            .map_err(|e| PolicyError::InvalidPolicy(format!("{}: {}", address, e)))?
+            .require_network(network)
+            .map_err(|e| PolicyError::InvalidPolicy(format!("{}: {}", address, e)))?;
+    }
+    if policy.large_amount_threshold.is_some() && policy.large_amount_delay_secs == 0 {
+        return Err(PolicyError::InvalidPolicy(
+            "large_amount_delay_secs must be set with large_amount_threshold".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Satoshis the store can still spend before reaching its daily or weekly
+// limit; None if it has neither
+pub fn remaining_allowance(db: &Database, store_id: &str, policy: &SpendingPolicy) -> Result<Option<u64>, SqliteError> {
+    let now = Utc::now();
+    let mut remaining: Option<u64> = None;
+    for (limit, days) in [(policy.daily_limit, 1), (policy.weekly_limit, 7)] {
+        if let Some(limit) = limit {
+            let already_spent = db.outgoing_spent_since(store_id, now - Duration::days(days))?;
+            let left = limit.saturating_sub(already_spent);
+            remaining = Some(remaining.map_or(left, |remaining| remaining.min(left)));
+        }
+    }
+    Ok(remaining)
+}
+
+// Whether the allow-list, if the store has one, includes the address
+pub fn destination_allowed(policy: &SpendingPolicy, address: &str) -> bool {
+    policy.allowed_destinations.is_empty() || policy.allowed_destinations.iter().any(|allowed| allowed == address)
+}
+
+// Count a spend against the store's limits just before it is broadcast.
+// The limits are checked again first: other spends may have been recorded
+// since `evaluate` ran. Returns the limits it would exceed instead of
+// recording it; recording a txid again changes nothing.
+pub fn record_spend(db: &Database, store_id: &str, txid: &str, amount: u64) -> Result<Vec<PolicyViolation>, SqliteError> {
+    let _spend = SPEND_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if db.has_outgoing_spend(txid)? {
+        return Ok(Vec::new());
+    }
+
+    let now = Utc::now();
+    let policy = db.get_spending_policy(store_id)?.unwrap_or_default();
+    let mut violations = Vec::new();
+    if let Some(limit) = policy.daily_limit {
+        let already_spent = db.outgoing_spent_since(store_id, now - Duration::days(1))?;
+        if already_spent + amount > limit {
+            violations.push(PolicyViolation::DailyLimit { limit, already_spent, amount });
+        }
+    }
+    if let Some(limit) = policy.weekly_limit {
+        let already_spent = db.outgoing_spent_since(store_id, now - Duration::days(7))?;
+        if already_spent + amount > limit {
+            violations.push(PolicyViolation::WeeklyLimit { limit, already_spent, amount });
+        }
+    }
+    if violations.is_empty() {
+        db.record_outgoing_spend(txid, store_id, amount)?;
+    }
+    Ok(violations)
+}
+
+// Check a PSBT against the store's spending policy. Every input must spend
+// from the store's own wallet, so a spend can't be checked against a store
+// whose limits don't cover the coins. Outputs paying a script the wallet
+// descriptor derives are treated as change; key origins in the PSBT are not
+// trusted for either. Stores without a policy allow everything.
+pub fn evaluate(
+    db: &Database,
+    store_id: &str,
+    psbt: &Psbt,
+    wallet: &WalletDescriptor,
+    network: Network,
+) -> Result<PolicyEvaluation, PolicyError> {
+    let now = Utc::now();
+    let policy = db.get_spending_policy(store_id)?.unwrap_or_default();
+    let tx = &psbt.unsigned_tx;
+
+    let secp = Secp256k1::verification_only();
+    let bounds = wallet::derivation_bounds(db, wallet)?;
+    for index in 0..psbt.inputs.len() {
+        let spent = psbt::spent_output(psbt, index)
+            .ok_or_else(|| PsbtError::MissingInputData(format!("input {} has no UTXO data", index)))?;
+        if psbt::find_derivation(&secp, wallet, bounds, &spent.script_pubkey)?.is_none() {
+            return Err(PsbtError::ForeignInput(index).into());
+        }
+    }
+
+    let mut amount = 0;
+    let mut destinations = Vec::new();
+    for txout in &tx.output {
+        if psbt::find_derivation(&secp, wallet, bounds, &txout.script_pubkey)?.is_some() {
+            continue;
+        }
+        amount += txout.value;
+        destinations.push(match Address::from_script(&txout.script_pubkey, network) {
+            Ok(address) => address.to_string(),
+            Err(_) => txout.script_pubkey.to_hex_string(),
+        });
+    }
+
+    let spent_outputs: Vec<_> = (0..psbt.inputs.len())
+        .map(|index| psbt::spent_output(psbt, index).cloned())
+        .collect();
+    let fee = psbt.fee().ok().map(|fee| fee.to_sat());
+    let signed = vec![false; tx.input.len()];
+    let witness_scripts: Vec<_> = psbt.inputs.iter().map(|input| input.witness_script.clone()).collect();
+    let vsize = tx_decoder::estimated_weight(tx, &spent_outputs, &witness_scripts, &signed).div_ceil(4);
+    let fee_rate = fee.map(|fee| fee as f64 / vsize as f64);
+
+    let mut violations = Vec::new();
+
+    if let Some(limit) = policy.daily_limit {
+        let already_spent = db.outgoing_spent_since(store_id, now - Duration::days(1))?;
+        if already_spent + amount > limit {
+            violations.push(PolicyViolation::DailyLimit { limit, already_spent, amount });
+        }
+    }
+    if let Some(limit) = policy.weekly_limit {
+        let already_spent = db.outgoing_spent_since(store_id, now - Duration::days(7))?;
+        if already_spent + amount > limit {
+            violations.push(PolicyViolation::WeeklyLimit { limit, already_spent, amount });
+        }
+    }
+
+    for address in &destinations {
+        if !destination_allowed(&policy, address) {
+            violations.push(PolicyViolation::DestinationNotAllowed { address: address.clone() });
+        }
+    }
+
+    if let Some(max_fee_rate) = policy.max_fee_rate {
+        match fee_rate {
+            Some(fee_rate) if fee_rate > max_fee_rate => {
+                violations.push(PolicyViolation::FeeRateTooHigh { fee_rate, max_fee_rate })
+            }
+            Some(_) => {}
+            None => violations.push(PolicyViolation::FeeUnknown),
+        }
+    }
+
+    // The delay starts the first time a large spend is submitted; resubmitting
+    // the same transaction after it has passed lets it through
+    if let Some(threshold) = policy.large_amount_threshold {
+        if amount >= threshold {
+            let requested_at = db.delayed_spend_requested_at(&tx.txid().to_string(), store_id, amount)?;
+            let eligible_at = requested_at + Duration::seconds(policy.large_amount_delay_secs as i64);
+            if now < eligible_at {
+                violations.push(PolicyViolation::DelayRequired { amount, threshold, eligible_at });
+            }
+        }
+    }
+
+    let approvals_required = match policy.approval_threshold {
+        Some(threshold) if amount < threshold => 0,
+        _ => policy.approvals_required,
+    };
+
+    Ok(PolicyEvaluation {
+        store_id: store_id.to_string(),
+        txid: tx.txid().to_string(),
+        amount,
+        fee,
+        fee_rate,
+        destinations,
+        allowed: violations.is_empty(),
+        violations,
+        approvals_required,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::StoreWallet;
+    use crate::psbt::{AccountKey, ScriptType};
+    use crate::state::AppState;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+    use bitcoin::{OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+
+    fn test_wallet(seed: u8) -> WalletDescriptor {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[seed; 32]).unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+        let xpub = ExtendedPubKey::from_priv(&secp, &master.derive_priv(&secp, &path).unwrap());
+        WalletDescriptor::Single(AccountKey {
+            fingerprint: master.fingerprint(&secp),
+            path,
+            xpub,
+            script_type: ScriptType::NativeSegwit,
+        })
+    }
+
+    fn script(wallet: &WalletDescriptor, index: u32) -> ScriptBuf {
+        wallet.derive(&Secp256k1::verification_only(), 0, index).unwrap().script_pubkey
+    }
+
+    // Spend a coin of `from` to `destination`
+    fn spend(from: &WalletDescriptor, destination: ScriptBuf, amount: u64) -> Psbt {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut { value: amount, script_pubkey: destination }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: amount + 1_000, script_pubkey: script(from, 0) });
+        psbt
+    }
+
+    fn save_wallet(db: &Database, store_id: &str, wallet: &WalletDescriptor) {
+        db.save_store_wallet(&StoreWallet {
+            store_id: store_id.to_string(),
+            descriptor: wallet.to_string(),
+            source: "xpub".to_string(),
+            label: None,
+            created_at: Utc::now(),
+        })
+        .unwrap();
+    }
+
+    // Naming a store with a laxer policy (or none) must not let a spend of
+    // another store's coins skip that store's rules
+    #[test]
+    fn spends_cannot_be_checked_against_another_store() {
+        let strict_wallet = test_wallet(1);
+        let lax_wallet = test_wallet(2);
+        let destination = script(&test_wallet(3), 0);
+        let mut state = AppState::new(":memory:");
+        state.wallet = Some(strict_wallet.clone());
+        save_wallet(&state.db, "strict", &strict_wallet);
+        save_wallet(&state.db, "lax", &lax_wallet);
+
+        let allowed = Address::from_script(&script(&test_wallet(4), 0), Network::Testnet).unwrap().to_string();
+        let rules = [
+            ("daily limit", SpendingPolicy { daily_limit: Some(10_000), ..Default::default() }),
+            ("weekly limit", SpendingPolicy { weekly_limit: Some(10_000), ..Default::default() }),
+            ("allow-list", SpendingPolicy { allowed_destinations: vec![allowed], ..Default::default() }),
+            (
+                "large amount delay",
+                SpendingPolicy {
+                    large_amount_threshold: Some(10_000),
+                    large_amount_delay_secs: 3600,
+                    ..Default::default()
+                },
+            ),
+            ("approval quorum", SpendingPolicy { approvals_required: 2, ..Default::default() }),
+        ];
+        for (rule, policy) in rules {
+            state.db.save_spending_policy("strict", &policy).unwrap();
+            let psbt = spend(&strict_wallet, destination.clone(), 50_000);
+
+            let evaluation = evaluate(&state.db, "strict", &psbt, &strict_wallet, Network::Testnet).unwrap();
+            assert!(!evaluation.allowed || evaluation.approvals_required > 0, "{} was not enforced", rule);
+
+            let lax = evaluate(&state.db, "lax", &psbt, &lax_wallet, Network::Testnet);
+            assert!(
+                matches!(lax, Err(PolicyError::Derivation(PsbtError::ForeignInput(0)))),
+                "{} was bypassed through another store's wallet",
+                rule
+            );
+        }
+
+        // A store without a wallet of its own doesn't borrow the global one
+        assert!(state.wallet_for("unconfigured").is_none());
+        assert!(state.wallet_for(crate::models::DEFAULT_STORE_ID).is_some());
+    }
+
+    #[test]
+    fn inputs_without_utxo_data_are_rejected() {
+        let wallet = test_wallet(1);
+        let db = Database::new(":memory:").unwrap();
+        let mut psbt = spend(&wallet, script(&test_wallet(3), 0), 50_000);
+        psbt.inputs[0].witness_utxo = None;
+
+        let result = evaluate(&db, "strict", &psbt, &wallet, Network::Testnet);
+        assert!(matches!(result, Err(PolicyError::Derivation(PsbtError::MissingInputData(_)))));
+    }
+
+    #[test]
+    fn spends_exceeding_a_limit_are_not_recorded() {
+        let db = Database::new(":memory:").unwrap();
+        let policy = SpendingPolicy { daily_limit: Some(100_000), weekly_limit: Some(150_000), ..Default::default() };
+        db.save_spending_policy("shop", &policy).unwrap();
+
+        assert!(record_spend(&db, "shop", "first", 60_000).unwrap().is_empty());
+        // Recording the same transaction again doesn't count it twice
+        assert!(record_spend(&db, "shop", "first", 60_000).unwrap().is_empty());
+        assert_eq!(remaining_allowance(&db, "shop", &policy).unwrap(), Some(40_000));
+
+        // Fit when evaluated, but another spend was recorded since
+        let violations = record_spend(&db, "shop", "second", 50_000).unwrap();
+        assert!(matches!(
+            violations.as_slice(),
+            [PolicyViolation::DailyLimit { limit: 100_000, already_spent: 60_000, amount: 50_000 }]
+        ));
+        assert!(!db.has_outgoing_spend("second").unwrap());
+        assert_eq!(remaining_allowance(&db, "shop", &policy).unwrap(), Some(40_000));
+
+        assert!(record_spend(&db, "shop", "third", 40_000).unwrap().is_empty());
+        assert_eq!(remaining_allowance(&db, "shop", &policy).unwrap(), Some(0));
+        let violations = record_spend(&db, "shop", "fourth", 60_000).unwrap();
+        assert!(matches!(
+            violations.as_slice(),
+            [PolicyViolation::DailyLimit { .. }, PolicyViolation::WeeklyLimit { limit: 150_000, already_spent: 100_000, .. }]
+        ));
+    }
+}
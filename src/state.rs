@@ -1,16 +1,38 @@
 
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Mutex;
-use crate::models::Invoice;
+use actix_web::HttpRequest;
+use crate::models::{Invoice, InvoiceEvent, DEFAULT_STORE_ID};
 use crate::database::Database;
 use crate::blockchain::BlockchainClient;
+use crate::broadcast::Broadcaster;
+use crate::fee_estimator::FeeEstimator;
 use crate::trezor::TrezorClient;
+use crate::login_guard::LoginGuard;
+use crate::psbt::WalletDescriptor;
+use crate::signer::SoftwareSigner;
+use crate::address_verifier::SampleReport;
+use crate::models::WebhookConfig;
+use crate::webhook::WebhookManager;
 
 pub struct AppState {
     pub invoices: Mutex<HashMap<String, Invoice>>,
     pub db: Database,
     pub blockchain_client: BlockchainClient,
+    pub broadcaster: Broadcaster,
+    pub fee_estimator: FeeEstimator,
     pub trezor_client: TrezorClient,
+    pub login_guard: LoginGuard,
+    pub wallet: Option<WalletDescriptor>,
+    pub software_signer: Option<SoftwareSigner>,
+    // Latest periodic address sample check, if enabled
+    pub address_sample_report: Mutex<Option<SampleReport>>,
+    pub webhook_manager: WebhookManager,
+    // Receives operational notifications such as spend proposal updates
+    pub notification_webhook: Option<WebhookConfig>,
+    // Reverse proxies whose X-Forwarded-For header is believed
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 impl AppState {
@@ -18,12 +40,137 @@ impl AppState {
         let db = Database::new(db_path).expect("Failed to initialize database");
         let blockchain_client = BlockchainClient::new("https://blockstream.info/testnet/api".to_string());
         let trezor_client = TrezorClient::new();
+
+        // Wallet used to derive invoice addresses and add BIP32 derivations to
+        // PSBTs, e.g. WALLET_ACCOUNT_KEY="wpkh([d34db33f/84'/1'/0']tpub...)" or
+        // a "wsh(sortedmulti(2,[...]tpub.../0/*,...))" multisig descriptor
+        let wallet = std::env::var("WALLET_ACCOUNT_KEY").ok().and_then(|key| {
+            match key.parse::<WalletDescriptor>() {
+                Ok(wallet) => Some(wallet),
+                Err(e) => {
+                    log::warn!("Ignoring WALLET_ACCOUNT_KEY: {}", e);
+                    None
+                }
+            }
+        });
+
+        // NOTIFICATION_WEBHOOK_URL and NOTIFICATION_WEBHOOK_SECRET
+        let notification_webhook = std::env::var("NOTIFICATION_WEBHOOK_URL").ok().map(|url| WebhookConfig {
+            url,
+            secret: std::env::var("NOTIFICATION_WEBHOOK_SECRET").unwrap_or_default(),
+        });
+
+        // TRUSTED_PROXIES, e.g. "127.0.0.1,::1"; unset trusts no forwarding headers
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .map(|proxies| {
+                proxies
+                    .split(',')
+                    .filter(|proxy| !proxy.trim().is_empty())
+                    .filter_map(|proxy| match proxy.trim().parse::<IpAddr>() {
+                        Ok(ip) => Some(ip),
+                        Err(e) => {
+                            log::warn!("Ignoring trusted proxy {}: {}", proxy, e);
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let software_signer = SoftwareSigner::from_env(bitcoin::Network::Testnet)
+            .expect("Failed to load software signer");
         
         Self {
             invoices: Mutex::new(HashMap::new()),
             db,
             blockchain_client,
+            broadcaster: Broadcaster::from_env(bitcoin::Network::Testnet),
+            fee_estimator: FeeEstimator::from_env(),
             trezor_client,
+            login_guard: LoginGuard::new(),
+            wallet,
+            software_signer,
+            address_sample_report: Mutex::new(None),
+            webhook_manager: WebhookManager::new(),
+            notification_webhook,
+            trusted_proxies,
+        }
+    }
+
+    // Wallet a store's invoices and spends are derived from: the store's saved
+    // wallet. Only the default store falls back to WALLET_ACCOUNT_KEY; any
+    // other store without its own wallet has none, so naming it can never
+    // borrow another store's keys.
+    pub fn wallet_for(&self, store_id: &str) -> Option<WalletDescriptor> {
+        match self.db.get_store_wallet(store_id) {
+            Ok(Some(wallet)) => match wallet.descriptor.parse::<WalletDescriptor>() {
+                Ok(wallet) => Some(wallet),
+                Err(e) => {
+                    log::error!("Stored wallet for {} is invalid: {}", store_id, e);
+                    None
+                }
+            },
+            Ok(None) if store_id == DEFAULT_STORE_ID => self.wallet.clone(),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("Failed to load wallet for {}: {}", store_id, e);
+                None
+            }
+        }
+    }
+
+    // Address a request came from: the peer, or for requests relayed by a
+    // trusted proxy the last address it added to X-Forwarded-For. Forwarding
+    // headers from anyone else are ignored, as clients can set them freely.
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        let peer = match req.peer_addr() {
+            Some(addr) => addr.ip(),
+            None => return "unknown".to_string(),
+        };
+        if self.trusted_proxies.contains(&peer) {
+            let forwarded = req
+                .headers()
+                .get_all("X-Forwarded-For")
+                .last()
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+            if let Some(ip) = forwarded {
+                return ip.to_string();
+            }
+        }
+        peer.to_string()
+    }
+
+    // An invoice from memory, falling back to the database
+    pub fn invoice(&self, id: &str) -> Result<Option<Invoice>, rusqlite::Error> {
+        if let Some(invoice) = self.invoices.lock().unwrap().get(id) {
+            return Ok(Some(invoice.clone()));
+        }
+        self.db.get_invoice(id)
+    }
+
+    // Note an event on an invoice's timeline. Failures are logged.
+    pub fn record_invoice_event(&self, invoice_id: &str, event_type: &str, message: String, details: serde_json::Value) {
+        let event = InvoiceEvent {
+            invoice_id: invoice_id.to_string(),
+            event_type: event_type.to_string(),
+            message,
+            details,
+            created_at: chrono::Utc::now(),
+        };
+        if let Err(e) = self.db.add_invoice_event(&event) {
+            log::error!("Failed to record {} on invoice {}: {}", event_type, invoice_id, e);
+        }
+    }
+
+    // Send an operational notification, if a webhook is configured. Failures
+    // are logged and never fail the caller.
+    pub async fn notify(&self, event_type: &str, data: serde_json::Value) {
+        if let Some(webhook) = &self.notification_webhook {
+            if let Err(e) = self.webhook_manager.notify_event(event_type, data, webhook).await {
+                log::warn!("Notification {} failed: {}", event_type, e);
+            }
         }
     }
 }
@@ -0,0 +1,519 @@
+// Minimal protobuf encoding of the Trezor messages used by TrezorClient.
+// Field numbers follow messages-common.proto, messages-management.proto and
+// messages-bitcoin.proto from the trezor-firmware repository.
+
+use super::TrezorError;
+
+// Message type identifiers (MessageType enum in messages.proto)
+pub mod message_type {
+    pub const INITIALIZE: u16 = 0;
+    pub const FAILURE: u16 = 3;
+    pub const GET_PUBLIC_KEY: u16 = 11;
+    pub const PUBLIC_KEY: u16 = 12;
+    pub const SIGN_TX: u16 = 15;
+    pub const FEATURES: u16 = 17;
+    pub const PIN_MATRIX_REQUEST: u16 = 18;
+    pub const PIN_MATRIX_ACK: u16 = 19;
+    pub const CANCEL: u16 = 20;
+    pub const TX_REQUEST: u16 = 21;
+    pub const TX_ACK: u16 = 22;
+    pub const BUTTON_REQUEST: u16 = 26;
+    pub const BUTTON_ACK: u16 = 27;
+    pub const GET_ADDRESS: u16 = 29;
+    pub const ADDRESS: u16 = 30;
+    pub const PASSPHRASE_REQUEST: u16 = 41;
+    pub const PASSPHRASE_ACK: u16 = 42;
+}
+
+// InputScriptType
+pub const SPEND_ADDRESS: u32 = 0;
+pub const SPEND_WITNESS: u32 = 3;
+pub const SPEND_P2SH_WITNESS: u32 = 4;
+
+// OutputScriptType
+pub const PAY_TO_ADDRESS: u32 = 0;
+pub const PAY_TO_OP_RETURN: u32 = 3;
+pub const PAY_TO_WITNESS: u32 = 4;
+pub const PAY_TO_P2SH_WITNESS: u32 = 5;
+
+// TxRequest.request_type
+pub const TX_INPUT: u32 = 0;
+pub const TX_OUTPUT: u32 = 1;
+pub const TX_META: u32 = 2;
+pub const TX_FINISHED: u32 = 3;
+pub const TX_EXTRA_DATA: u32 = 4;
+
+#[derive(Default)]
+pub struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.raw_varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    pub fn uint(&mut self, field: u32, value: u64) -> &mut Self {
+        self.key(field, 0);
+        self.raw_varint(value);
+        self
+    }
+
+    pub fn bool(&mut self, field: u32, value: bool) -> &mut Self {
+        self.uint(field, value as u64)
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        self.key(field, 2);
+        self.raw_varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) -> &mut Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    // proto2 repeated scalars are not packed
+    pub fn repeated_uint(&mut self, field: u32, values: &[u32]) -> &mut Self {
+        for value in values {
+            self.uint(field, *value as u64);
+        }
+        self
+    }
+
+    pub fn message(&mut self, field: u32, message: &ProtoWriter) -> &mut Self {
+        self.bytes(field, &message.buf)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FieldValue {
+    Varint(u64),
+    Bytes(Vec<u8>),
+    Fixed64(u64),
+    Fixed32(u32),
+}
+
+// Decoded fields of a message, in wire order
+pub struct ProtoMessage {
+    fields: Vec<(u32, FieldValue)>,
+}
+
+impl ProtoMessage {
+    pub fn decode(data: &[u8]) -> Result<Self, TrezorError> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let key = read_varint(data, &mut pos)?;
+            let field = (key >> 3) as u32;
+            let value = match key & 0x07 {
+                0 => FieldValue::Varint(read_varint(data, &mut pos)?),
+                1 => {
+                    let bytes = take(data, &mut pos, 8)?;
+                    FieldValue::Fixed64(u64::from_le_bytes(bytes.try_into().unwrap()))
+                }
+                2 => {
+                    let len = read_varint(data, &mut pos)? as usize;
+                    FieldValue::Bytes(take(data, &mut pos, len)?.to_vec())
+                }
+                5 => {
+                    let bytes = take(data, &mut pos, 4)?;
+                    FieldValue::Fixed32(u32::from_le_bytes(bytes.try_into().unwrap()))
+                }
+                wire_type => {
+                    return Err(TrezorError::Protocol(format!("unsupported wire type {}", wire_type)));
+                }
+            };
+            fields.push((field, value));
+        }
+
+        Ok(Self { fields })
+    }
+
+    pub fn uint(&self, field: u32) -> Option<u64> {
+        self.fields.iter().find_map(|(number, value)| match value {
+            FieldValue::Varint(v) if *number == field => Some(*v),
+            FieldValue::Fixed32(v) if *number == field => Some(*v as u64),
+            FieldValue::Fixed64(v) if *number == field => Some(*v),
+            _ => None,
+        })
+    }
+
+    pub fn bool(&self, field: u32) -> Option<bool> {
+        self.uint(field).map(|value| value != 0)
+    }
+
+    pub fn bytes(&self, field: u32) -> Option<&[u8]> {
+        self.fields.iter().find_map(|(number, value)| match value {
+            FieldValue::Bytes(bytes) if *number == field => Some(bytes.as_slice()),
+            _ => None,
+        })
+    }
+
+    pub fn string(&self, field: u32) -> Option<String> {
+        self.bytes(field).map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    pub fn message(&self, field: u32) -> Result<Option<ProtoMessage>, TrezorError> {
+        self.bytes(field).map(ProtoMessage::decode).transpose()
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, TrezorError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| TrezorError::Protocol("truncated varint".to_string()))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(TrezorError::Protocol("varint too long".to_string()))
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], TrezorError> {
+    let end = pos
+        .checked_add(len)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| TrezorError::Protocol("truncated field".to_string()))?;
+    let slice = &data[*pos..end];
+    *pos = end;
+    Ok(slice)
+}
+
+// Device information returned in response to Initialize
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Features {
+    pub vendor: Option<String>,
+    pub major_version: u32,
+    pub minor_version: u32,
+    pub patch_version: u32,
+    pub device_id: Option<String>,
+    pub pin_protection: bool,
+    pub passphrase_protection: bool,
+    pub label: Option<String>,
+    pub initialized: bool,
+    pub unlocked: Option<bool>,
+    pub model: Option<String>,
+}
+
+impl Features {
+    pub fn decode(data: &[u8]) -> Result<Self, TrezorError> {
+        let message = ProtoMessage::decode(data)?;
+        Ok(Self {
+            vendor: message.string(1),
+            major_version: message.uint(2).unwrap_or_default() as u32,
+            minor_version: message.uint(3).unwrap_or_default() as u32,
+            patch_version: message.uint(4).unwrap_or_default() as u32,
+            device_id: message.string(6),
+            pin_protection: message.bool(7).unwrap_or_default(),
+            passphrase_protection: message.bool(8).unwrap_or_default(),
+            label: message.string(10),
+            initialized: message.bool(12).unwrap_or_default(),
+            unlocked: message.bool(16),
+            model: message.string(21),
+        })
+    }
+
+    pub fn firmware_version(&self) -> String {
+        format!("{}.{}.{}", self.major_version, self.minor_version, self.patch_version)
+    }
+}
+
+// HDNodeType, as returned inside PublicKey
+#[derive(Clone)]
+pub struct HdNode {
+    pub depth: u32,
+    pub parent_fingerprint: u32,
+    pub child_num: u32,
+    pub chain_code: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl HdNode {
+    fn encode(&self) -> ProtoWriter {
+        let mut writer = ProtoWriter::new();
+        writer
+            .uint(1, self.depth as u64)
+            .uint(2, self.parent_fingerprint as u64)
+            .uint(3, self.child_num as u64)
+            .bytes(4, &self.chain_code)
+            .bytes(6, &self.public_key);
+        writer
+    }
+}
+
+// HDNodePathType: a cosigner's account node and the path below it
+#[derive(Clone)]
+pub struct MultisigPubkey {
+    pub node: HdNode,
+    pub address_n: Vec<u32>,
+}
+
+// MultisigRedeemScriptType. Keys are used in the order given, which must be
+// the order they appear in the script.
+#[derive(Clone)]
+pub struct Multisig {
+    pub pubkeys: Vec<MultisigPubkey>,
+    pub signatures: Vec<Vec<u8>>,
+    pub m: u32,
+}
+
+impl Multisig {
+    fn encode(&self) -> ProtoWriter {
+        let mut writer = ProtoWriter::new();
+        for pubkey in &self.pubkeys {
+            let mut path = ProtoWriter::new();
+            path.message(1, &pubkey.node.encode()).repeated_uint(2, &pubkey.address_n);
+            writer.message(1, &path);
+        }
+        for signature in &self.signatures {
+            writer.bytes(2, signature);
+        }
+        writer.uint(3, self.m as u64);
+        writer
+    }
+}
+
+pub struct PublicKeyResponse {
+    pub node: HdNode,
+    pub root_fingerprint: Option<u32>,
+}
+
+impl PublicKeyResponse {
+    pub fn decode(data: &[u8]) -> Result<Self, TrezorError> {
+        let message = ProtoMessage::decode(data)?;
+        let node = message
+            .message(1)?
+            .ok_or_else(|| TrezorError::Protocol("PublicKey without node".to_string()))?;
+        let missing = |field: &str| TrezorError::Protocol(format!("HDNode without {}", field));
+
+        Ok(Self {
+            node: HdNode {
+                depth: node.uint(1).ok_or_else(|| missing("depth"))? as u32,
+                parent_fingerprint: node.uint(2).ok_or_else(|| missing("fingerprint"))? as u32,
+                child_num: node.uint(3).ok_or_else(|| missing("child_num"))? as u32,
+                chain_code: node.bytes(4).ok_or_else(|| missing("chain_code"))?.to_vec(),
+                public_key: node.bytes(6).ok_or_else(|| missing("public_key"))?.to_vec(),
+            },
+            root_fingerprint: message.uint(3).map(|fp| fp as u32),
+        })
+    }
+}
+
+pub fn get_public_key(address_n: &[u32], coin_name: &str, script_type: u32, show_display: bool) -> Vec<u8> {
+    let mut writer = ProtoWriter::new();
+    writer
+        .repeated_uint(1, address_n)
+        .bool(3, show_display)
+        .string(4, coin_name)
+        .uint(5, script_type as u64);
+    writer.into_bytes()
+}
+
+pub fn get_address(address_n: &[u32], coin_name: &str, script_type: u32, show_display: bool) -> Vec<u8> {
+    let mut writer = ProtoWriter::new();
+    writer
+        .repeated_uint(1, address_n)
+        .string(2, coin_name)
+        .bool(3, show_display)
+        .uint(5, script_type as u64);
+    writer.into_bytes()
+}
+
+pub fn sign_tx(outputs_count: u32, inputs_count: u32, coin_name: &str, version: u32, lock_time: u32) -> Vec<u8> {
+    let mut writer = ProtoWriter::new();
+    writer
+        .uint(1, outputs_count as u64)
+        .uint(2, inputs_count as u64)
+        .string(3, coin_name)
+        .uint(4, version as u64)
+        .uint(5, lock_time as u64);
+    writer.into_bytes()
+}
+
+pub fn pin_matrix_ack(pin: &str) -> Vec<u8> {
+    let mut writer = ProtoWriter::new();
+    writer.string(1, pin);
+    writer.into_bytes()
+}
+
+pub fn passphrase_ack(passphrase: Option<&str>, on_device: bool) -> Vec<u8> {
+    let mut writer = ProtoWriter::new();
+    if let Some(passphrase) = passphrase {
+        writer.string(1, passphrase);
+    }
+    if on_device {
+        writer.bool(3, true);
+    }
+    writer.into_bytes()
+}
+
+pub struct Failure {
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn decode(data: &[u8]) -> Result<Self, TrezorError> {
+        let message = ProtoMessage::decode(data)?;
+        Ok(Self {
+            code: message.uint(1).map(|code| code as u32),
+            message: message.string(2).unwrap_or_default(),
+        })
+    }
+}
+
+// TxRequest sent by the device during the SignTx loop
+pub struct TxRequest {
+    pub request_type: u32,
+    pub request_index: Option<u32>,
+    pub tx_hash: Option<Vec<u8>>,
+    pub signature_index: Option<u32>,
+    pub signature: Option<Vec<u8>>,
+}
+
+impl TxRequest {
+    pub fn decode(data: &[u8]) -> Result<Self, TrezorError> {
+        let message = ProtoMessage::decode(data)?;
+        let details = message.message(2)?;
+        let serialized = message.message(3)?;
+
+        Ok(Self {
+            request_type: message.uint(1).unwrap_or(TX_FINISHED as u64) as u32,
+            request_index: details.as_ref().and_then(|d| d.uint(1)).map(|v| v as u32),
+            tx_hash: details.as_ref().and_then(|d| d.bytes(2)).map(|b| b.to_vec()),
+            signature_index: serialized.as_ref().and_then(|s| s.uint(1)).map(|v| v as u32),
+            signature: serialized.as_ref().and_then(|s| s.bytes(2)).map(|b| b.to_vec()),
+        })
+    }
+}
+
+// TxInputType
+#[derive(Default, Clone)]
+pub struct TxInput {
+    pub address_n: Vec<u32>,
+    pub prev_hash: Vec<u8>,
+    pub prev_index: u32,
+    pub script_sig: Option<Vec<u8>>,
+    pub sequence: u32,
+    pub script_type: Option<u32>,
+    pub multisig: Option<Multisig>,
+    pub amount: Option<u64>,
+}
+
+impl TxInput {
+    fn encode(&self) -> ProtoWriter {
+        let mut writer = ProtoWriter::new();
+        writer
+            .repeated_uint(1, &self.address_n)
+            .bytes(2, &self.prev_hash)
+            .uint(3, self.prev_index as u64);
+        if let Some(script_sig) = &self.script_sig {
+            writer.bytes(4, script_sig);
+        }
+        writer.uint(5, self.sequence as u64);
+        if let Some(script_type) = self.script_type {
+            writer.uint(6, script_type as u64);
+        }
+        if let Some(multisig) = &self.multisig {
+            writer.message(7, &multisig.encode());
+        }
+        if let Some(amount) = self.amount {
+            writer.uint(8, amount);
+        }
+        writer
+    }
+}
+
+// TxOutputType (outputs of the transaction being signed)
+#[derive(Default, Clone)]
+pub struct TxOutput {
+    pub address: Option<String>,
+    pub address_n: Vec<u32>,
+    pub amount: u64,
+    pub script_type: u32,
+    pub multisig: Option<Multisig>,
+    pub op_return_data: Option<Vec<u8>>,
+}
+
+impl TxOutput {
+    fn encode(&self) -> ProtoWriter {
+        let mut writer = ProtoWriter::new();
+        if let Some(address) = &self.address {
+            writer.string(1, address);
+        }
+        writer
+            .repeated_uint(2, &self.address_n)
+            .uint(3, self.amount)
+            .uint(4, self.script_type as u64);
+        if let Some(multisig) = &self.multisig {
+            writer.message(5, &multisig.encode());
+        }
+        if let Some(data) = &self.op_return_data {
+            writer.bytes(6, data);
+        }
+        writer
+    }
+}
+
+// Answers to TxRequest, wrapped in the legacy TxAck { tx: TransactionType } message
+pub enum TxAckData {
+    Input(TxInput),
+    Output(TxOutput),
+    // TxOutputBinType: outputs of a previous transaction
+    BinOutput { amount: u64, script_pubkey: Vec<u8> },
+    Meta { version: u32, lock_time: u32, inputs_count: u32, outputs_count: u32 },
+    ExtraData(Vec<u8>),
+}
+
+pub fn tx_ack(data: &TxAckData) -> Vec<u8> {
+    let mut tx = ProtoWriter::new();
+    match data {
+        TxAckData::Input(input) => {
+            tx.message(2, &input.encode());
+        }
+        TxAckData::BinOutput { amount, script_pubkey } => {
+            let mut output = ProtoWriter::new();
+            output.uint(1, *amount).bytes(2, script_pubkey);
+            tx.message(3, &output);
+        }
+        TxAckData::Output(output) => {
+            tx.message(5, &output.encode());
+        }
+        TxAckData::Meta { version, lock_time, inputs_count, outputs_count } => {
+            tx.uint(1, *version as u64)
+                .uint(4, *lock_time as u64)
+                .uint(6, *inputs_count as u64)
+                .uint(7, *outputs_count as u64);
+        }
+        TxAckData::ExtraData(extra) => {
+            tx.bytes(8, extra);
+        }
+    }
+
+    let mut writer = ProtoWriter::new();
+    writer.message(1, &tx);
+    writer.into_bytes()
+}
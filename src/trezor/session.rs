@@ -0,0 +1,86 @@
+// Interactive prompts raised by the device while a call is in progress.
+// The call loop publishes what the device is waiting for and blocks until the
+// web UI answers through TrezorClient::respond.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use zeroize::Zeroizing;
+
+use super::TrezorError;
+
+// How long a PIN or passphrase prompt waits for the UI before giving up
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DevicePrompt {
+    None,
+    // Enter the PIN using the positions of the scrambled matrix shown on the device
+    Pin,
+    Passphrase,
+    // Confirm (or reject) on the device itself
+    Button { code: Option<u32> },
+}
+
+pub enum PromptReply {
+    Pin(Zeroizing<String>),
+    Passphrase { passphrase: Zeroizing<String>, on_device: bool },
+    Cancel,
+}
+
+pub struct PromptState {
+    prompt: Mutex<DevicePrompt>,
+    reply: Mutex<Option<oneshot::Sender<PromptReply>>>,
+}
+
+impl PromptState {
+    pub fn new() -> Self {
+        Self {
+            prompt: Mutex::new(DevicePrompt::None),
+            reply: Mutex::new(None),
+        }
+    }
+
+    pub fn current(&self) -> DevicePrompt {
+        self.prompt.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, prompt: DevicePrompt) {
+        *self.prompt.lock().unwrap() = prompt;
+    }
+
+    // Publish a prompt and wait for the UI to answer it
+    pub async fn ask(&self, prompt: DevicePrompt) -> Result<PromptReply, TrezorError> {
+        let (sender, receiver) = oneshot::channel();
+        *self.reply.lock().unwrap() = Some(sender);
+        self.set(prompt);
+
+        let reply = tokio::time::timeout(PROMPT_TIMEOUT, receiver).await;
+        self.reply.lock().unwrap().take();
+        self.set(DevicePrompt::None);
+
+        match reply {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(TrezorError::Cancelled("prompt abandoned".to_string())),
+            Err(_) => Err(TrezorError::Cancelled("no answer to the device prompt".to_string())),
+        }
+    }
+
+    // Hand the UI's answer to the waiting call
+    pub fn respond(&self, reply: PromptReply) -> Result<(), TrezorError> {
+        let expected = matches!(
+            (&reply, self.current()),
+            (PromptReply::Cancel, DevicePrompt::Pin | DevicePrompt::Passphrase)
+                | (PromptReply::Pin(_), DevicePrompt::Pin)
+                | (PromptReply::Passphrase { .. }, DevicePrompt::Passphrase)
+        );
+        if !expected {
+            return Err(TrezorError::NoPendingPrompt);
+        }
+
+        let sender = self.reply.lock().unwrap().take().ok_or(TrezorError::NoPendingPrompt)?;
+        sender.send(reply).map_err(|_| TrezorError::NoPendingPrompt)
+    }
+}
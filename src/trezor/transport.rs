@@ -0,0 +1,386 @@
+// Transports carrying Trezor protobuf messages: USB HID (feature "hid"),
+// Trezor Bridge (trezord HTTP API) and the firmware emulator's UDP port.
+
+use async_trait::async_trait;
+use log::{debug, info};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use super::TrezorError;
+
+// Wire framing shared by HID and UDP: 64 byte reports, the first one starting
+// with "?##", the message type (u16 BE) and payload length (u32 BE), and
+// continuation reports starting with "?"
+const REPORT_SIZE: usize = 64;
+const HEADER_SIZE: usize = 9;
+
+pub const BRIDGE_URL: &str = "http://127.0.0.1:21325";
+// trezord only answers requests from whitelisted origins
+const BRIDGE_ORIGIN: &str = "http://localhost:8000";
+
+const READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[cfg(feature = "hid")]
+const TREZOR_HID_IDS: [(u16, u16); 2] = [(0x534c, 0x0001), (0x1209, 0x53c1)];
+
+#[async_trait]
+pub trait Transport: Send {
+    // Send one message and wait for the device's reply
+    async fn call(&mut self, message_type: u16, payload: &[u8]) -> Result<(u16, Vec<u8>), TrezorError>;
+
+    // Give the device back (e.g. release the Bridge session)
+    async fn close(&mut self) -> Result<(), TrezorError> {
+        Ok(())
+    }
+}
+
+// A device found during enumeration
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub transport: String,
+}
+
+fn encode_reports(message_type: u16, payload: &[u8]) -> Vec<[u8; REPORT_SIZE]> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(b"##");
+    data.extend_from_slice(&message_type.to_be_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(payload);
+
+    data.chunks(REPORT_SIZE - 1)
+        .map(|chunk| {
+            let mut report = [0u8; REPORT_SIZE];
+            report[0] = b'?';
+            report[1..1 + chunk.len()].copy_from_slice(chunk);
+            report
+        })
+        .collect()
+}
+
+// Reassembles a message from 64 byte reports
+struct ReportReader {
+    message_type: u16,
+    length: usize,
+    data: Vec<u8>,
+}
+
+impl ReportReader {
+    fn start(report: &[u8]) -> Result<Self, TrezorError> {
+        if report.len() < HEADER_SIZE || &report[..3] != b"?##" {
+            return Err(TrezorError::Protocol("unexpected report header".to_string()));
+        }
+        let message_type = u16::from_be_bytes([report[3], report[4]]);
+        let length = u32::from_be_bytes([report[5], report[6], report[7], report[8]]) as usize;
+        let mut reader = Self {
+            message_type,
+            length,
+            data: Vec::with_capacity(length),
+        };
+        reader.push(&report[HEADER_SIZE..]);
+        Ok(reader)
+    }
+
+    fn continue_with(&mut self, report: &[u8]) -> Result<(), TrezorError> {
+        if report.first() != Some(&b'?') {
+            return Err(TrezorError::Protocol("unexpected continuation report".to_string()));
+        }
+        self.push(&report[1..]);
+        Ok(())
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let remaining = self.length - self.data.len();
+        self.data.extend_from_slice(&bytes[..remaining.min(bytes.len())]);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.data.len() >= self.length
+    }
+
+    fn finish(self) -> (u16, Vec<u8>) {
+        (self.message_type, self.data)
+    }
+}
+
+// The emulator listens for the same 64 byte reports over UDP
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub async fn connect(address: &str) -> Result<Self, TrezorError> {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        socket
+            .connect(address)
+            .await
+            .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+
+        // The emulator answers PINGPING with PONGPONG when it is running
+        socket
+            .send(b"PINGPING")
+            .await
+            .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        let mut buf = [0u8; REPORT_SIZE];
+        match tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut buf)).await {
+            Ok(Ok(len)) if &buf[..len] == b"PONGPONG" => {}
+            _ => return Err(TrezorError::DeviceNotFound),
+        }
+
+        info!("Connected to Trezor emulator at {}", address);
+        Ok(Self { socket })
+    }
+
+    async fn read_report(&self) -> Result<[u8; REPORT_SIZE], TrezorError> {
+        let mut report = [0u8; REPORT_SIZE];
+        tokio::time::timeout(READ_TIMEOUT, self.socket.recv(&mut report))
+            .await
+            .map_err(|_| TrezorError::Protocol("timed out waiting for device".to_string()))?
+            .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn call(&mut self, message_type: u16, payload: &[u8]) -> Result<(u16, Vec<u8>), TrezorError> {
+        for report in encode_reports(message_type, payload) {
+            self.socket
+                .send(&report)
+                .await
+                .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        }
+
+        let mut reader = ReportReader::start(&self.read_report().await?)?;
+        while !reader.is_complete() {
+            reader.continue_with(&self.read_report().await?)?;
+        }
+        Ok(reader.finish())
+    }
+}
+
+#[derive(Deserialize)]
+struct BridgeDevice {
+    path: String,
+    session: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BridgeSession {
+    session: String,
+}
+
+// Talks to the device through Trezor Bridge (trezord), which also proxies
+// the emulator when started with -e 21324
+pub struct BridgeTransport {
+    client: reqwest::Client,
+    url: String,
+    session: String,
+}
+
+impl BridgeTransport {
+    async fn post(client: &reqwest::Client, url: &str, body: String) -> Result<reqwest::Response, TrezorError> {
+        let response = client
+            .post(url)
+            .header("Origin", BRIDGE_ORIGIN)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| TrezorError::ConnectionFailed(format!("Trezor Bridge unreachable: {}", e)))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(TrezorError::ConnectionFailed(format!("Trezor Bridge returned {}: {}", status, body)));
+        }
+        Ok(response)
+    }
+
+    pub async fn enumerate(url: &str) -> Result<Vec<DeviceInfo>, TrezorError> {
+        let client = reqwest::Client::new();
+        let devices: Vec<BridgeDevice> = Self::post(&client, &format!("{}/enumerate", url), String::new())
+            .await?
+            .json()
+            .await
+            .map_err(|e| TrezorError::Protocol(e.to_string()))?;
+
+        Ok(devices
+            .into_iter()
+            .map(|device| DeviceInfo {
+                path: format!("bridge:{}", device.path),
+                transport: "bridge".to_string(),
+            })
+            .collect())
+    }
+
+    pub async fn acquire(url: &str, path: &str) -> Result<Self, TrezorError> {
+        let client = reqwest::Client::new();
+        let devices: Vec<BridgeDevice> = Self::post(&client, &format!("{}/enumerate", url), String::new())
+            .await?
+            .json()
+            .await
+            .map_err(|e| TrezorError::Protocol(e.to_string()))?;
+        let device = devices
+            .into_iter()
+            .find(|device| device.path == path)
+            .ok_or(TrezorError::DeviceNotFound)?;
+
+        let previous = device.session.unwrap_or_else(|| "null".to_string());
+        let session: BridgeSession = Self::post(&client, &format!("{}/acquire/{}/{}", url, path, previous), String::new())
+            .await?
+            .json()
+            .await
+            .map_err(|e| TrezorError::Protocol(e.to_string()))?;
+
+        info!("Acquired Trezor {} through Bridge (session {})", path, session.session);
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            session: session.session,
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for BridgeTransport {
+    async fn call(&mut self, message_type: u16, payload: &[u8]) -> Result<(u16, Vec<u8>), TrezorError> {
+        let mut data = Vec::with_capacity(6 + payload.len());
+        data.extend_from_slice(&message_type.to_be_bytes());
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        data.extend_from_slice(payload);
+
+        let url = format!("{}/call/{}", self.url, self.session);
+        let response = Self::post(&self.client, &url, hex::encode(data))
+            .await?
+            .text()
+            .await
+            .map_err(|e| TrezorError::Protocol(e.to_string()))?;
+
+        let bytes = hex::decode(response.trim()).map_err(|e| TrezorError::Protocol(e.to_string()))?;
+        if bytes.len() < 6 {
+            return Err(TrezorError::Protocol("short response from Trezor Bridge".to_string()));
+        }
+        let response_type = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]) as usize;
+        let body = bytes[6..].get(..length)
+            .ok_or_else(|| TrezorError::Protocol("truncated response from Trezor Bridge".to_string()))?;
+        debug!("Bridge call {} -> {}", message_type, response_type);
+        Ok((response_type, body.to_vec()))
+    }
+
+    async fn close(&mut self) -> Result<(), TrezorError> {
+        let url = format!("{}/release/{}", self.url, self.session);
+        Self::post(&self.client, &url, String::new()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "hid")]
+pub struct HidTransport {
+    device: std::sync::Arc<std::sync::Mutex<hidapi::HidDevice>>,
+}
+
+#[cfg(feature = "hid")]
+impl HidTransport {
+    pub fn enumerate() -> Result<Vec<DeviceInfo>, TrezorError> {
+        let api = hidapi::HidApi::new().map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        Ok(api
+            .device_list()
+            .filter(|info| TREZOR_HID_IDS.contains(&(info.vendor_id(), info.product_id())))
+            // Skip the debug link interface
+            .filter(|info| info.interface_number() <= 0 && info.usage_page() != 0x01)
+            .map(|info| DeviceInfo {
+                path: format!("hid:{}", info.path().to_string_lossy()),
+                transport: "hid".to_string(),
+            })
+            .collect())
+    }
+
+    pub fn open(path: &str) -> Result<Self, TrezorError> {
+        let api = hidapi::HidApi::new().map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        let path = std::ffi::CString::new(path).map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        let device = api
+            .open_path(&path)
+            .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+        Ok(Self {
+            device: std::sync::Arc::new(std::sync::Mutex::new(device)),
+        })
+    }
+}
+
+#[cfg(feature = "hid")]
+#[async_trait]
+impl Transport for HidTransport {
+    async fn call(&mut self, message_type: u16, payload: &[u8]) -> Result<(u16, Vec<u8>), TrezorError> {
+        let device = std::sync::Arc::clone(&self.device);
+        let reports = encode_reports(message_type, payload);
+
+        // hidapi is blocking, keep it off the async runtime
+        tokio::task::spawn_blocking(move || {
+            let device = device.lock().unwrap();
+            for report in reports {
+                // hidapi expects the report ID (0) in front of the report
+                let mut buf = [0u8; REPORT_SIZE + 1];
+                buf[1..].copy_from_slice(&report);
+                device.write(&buf).map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+            }
+
+            let read_report = || -> Result<[u8; REPORT_SIZE], TrezorError> {
+                let mut report = [0u8; REPORT_SIZE];
+                let len = device
+                    .read_timeout(&mut report, READ_TIMEOUT.as_millis() as i32)
+                    .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?;
+                if len == 0 {
+                    return Err(TrezorError::Protocol("timed out waiting for device".to_string()));
+                }
+                Ok(report)
+            };
+
+            let mut reader = ReportReader::start(&read_report()?)?;
+            while !reader.is_complete() {
+                reader.continue_with(&read_report()?)?;
+            }
+            Ok(reader.finish())
+        })
+        .await
+        .map_err(|e| TrezorError::ConnectionFailed(e.to_string()))?
+    }
+}
+
+// List devices reachable through Bridge and (with the "hid" feature) USB HID
+pub async fn enumerate() -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+
+    match BridgeTransport::enumerate(BRIDGE_URL).await {
+        Ok(found) => devices.extend(found),
+        Err(e) => debug!("Trezor Bridge enumeration failed: {}", e),
+    }
+
+    #[cfg(feature = "hid")]
+    match HidTransport::enumerate() {
+        Ok(found) => devices.extend(found),
+        Err(e) => debug!("HID enumeration failed: {}", e),
+    }
+
+    devices
+}
+
+// Open a transport for a device path as returned by enumerate():
+// "bridge:<path>", "hid:<path>" or "udp:<host:port>" for the emulator
+pub async fn open(path: &str) -> Result<Box<dyn Transport>, TrezorError> {
+    if let Some(bridge_path) = path.strip_prefix("bridge:") {
+        return Ok(Box::new(BridgeTransport::acquire(BRIDGE_URL, bridge_path).await?));
+    }
+    if let Some(address) = path.strip_prefix("udp:") {
+        return Ok(Box::new(UdpTransport::connect(address).await?));
+    }
+    #[cfg(feature = "hid")]
+    if let Some(hid_path) = path.strip_prefix("hid:") {
+        return Ok(Box::new(HidTransport::open(hid_path)?));
+    }
+
+    Err(TrezorError::ConnectionFailed(format!("unsupported device path {}", path)))
+}
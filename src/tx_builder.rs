@@ -0,0 +1,605 @@
+use bitcoin::absolute::LockTime;
+use bitcoin::bip32::{ChildNumber, DerivationPath};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+use log::{info, warn};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use rusqlite::Error as SqliteError;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::models::Utxo;
+use crate::psbt::{DerivedAddress, Psbt, PsbtError, ScriptType, WalletDescriptor};
+use crate::state::AppState;
+use crate::wallet::{self, CHANGE_CHAIN};
+
+// Branch-and-bound gives up after this many steps
+const BNB_MAX_TRIES: usize = 100_000;
+const KNAPSACK_ITERATIONS: usize = 1000;
+// Knapsack selection aims to leave at least this much change, so the change
+// output is worth spending later
+const MIN_CHANGE: u64 = 5_000;
+pub const DUST_LIMIT: u64 = 546;
+// Weight of an input without its scriptSig and witness: outpoint, sequence
+// and empty scriptSig length, times four
+const TXIN_BASE_WEIGHT: u64 = (32 + 4 + 4 + 1) * 4;
+// Witness of a single-key input: item count, 72-byte signature and 33-byte
+// key with length prefixes (as estimated by `tx_decoder::estimated_weight`)
+const SINGLE_KEY_WITNESS: u64 = 1 + 1 + 72 + 1 + 33;
+// Anti-fee-sniping: sometimes back-date the locktime to blend in with
+// transactions that were delayed before broadcast
+const LOCKTIME_BACKDATE_PERCENT: u32 = 10;
+const LOCKTIME_MAX_BACKDATE: u32 = 100;
+// How long built transactions hold their inputs unless broadcast or released
+const DEFAULT_RESERVATION_SECS: i64 = 3600;
+
+// Held from listing UTXOs until the selection is reserved, so concurrent
+// builds never pick the same coins
+static SELECTION_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug)]
+pub enum BuildError {
+    NoWallet,
+    InvalidDestination(String),
+    InvalidFeeRate,
+    InsufficientFunds { available: u64, required: u64 },
+    Derivation(PsbtError),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::NoWallet => write!(f, "No wallet configured for this store"),
+            BuildError::InvalidDestination(msg) => write!(f, "Invalid destination: {}", msg),
+            BuildError::InvalidFeeRate => write!(f, "Fee rate must be at least 1 sat/vB"),
+            BuildError::InsufficientFunds { available, required } => write!(
+                f,
+                "Insufficient funds: {} sat spendable at this fee rate, {} sat required",
+                available, required
+            ),
+            BuildError::Derivation(e) => write!(f, "{}", e),
+            BuildError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<SqliteError> for BuildError {
+    fn from(error: SqliteError) -> Self {
+        BuildError::Database(error)
+    }
+}
+
+impl From<PsbtError> for BuildError {
+    fn from(error: PsbtError) -> Self {
+        BuildError::Derivation(error)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Destination {
+    pub address: String,
+    pub amount: u64, // Satoshis
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionAlgorithm {
+    BranchAndBound, // Exact match, no change output
+    Knapsack,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangeOutput {
+    pub address: String,
+    pub amount: u64,
+    pub derivation_path: String,
+}
+
+#[derive(Debug)]
+pub struct BuiltTransaction {
+    pub psbt: Psbt,
+    pub fee: u64,
+    pub vsize: u64,
+    pub algorithm: SelectionAlgorithm,
+    pub inputs: Vec<Utxo>,
+    pub change: Option<ChangeOutput>,
+    pub reserved_until: DateTime<Utc>,
+}
+
+// A UTXO with the value it adds once the fee for spending it is paid
+struct Candidate {
+    utxo: Utxo,
+    effective_value: u64,
+}
+
+// Build an unsigned PSBT paying `destinations` from the store's synced
+// UTXOs at `fee_rate` sat/vB, adding a change output when worthwhile. Inputs
+// signal RBF and the locktime is set to the chain tip against fee sniping.
+// The inputs are reserved until broadcast, `release` or the reservation
+// expires.
+pub async fn build(
+    data: &AppState,
+    store_id: &str,
+    destinations: &[Destination],
+    fee_rate: f64,
+    include_unconfirmed: bool,
+    network: Network,
+) -> Result<BuiltTransaction, BuildError> {
+    if !(fee_rate >= 1.0 && fee_rate.is_finite()) {
+        return Err(BuildError::InvalidFeeRate);
+    }
+    let wallet = data.wallet_for(store_id).ok_or(BuildError::NoWallet)?;
+    let outputs = destinations
+        .iter()
+        .map(|destination| parse_destination(destination, network))
+        .collect::<Result<Vec<TxOut>, BuildError>>()?;
+    if outputs.is_empty() {
+        return Err(BuildError::InvalidDestination("at least one destination is required".to_string()));
+    }
+
+    let lock_time = anti_fee_sniping_locktime(data).await;
+
+    let _selection = SELECTION_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let reserved: HashSet<(String, u32)> = data.db.reserved_outpoints(store_id, Utc::now())?.into_iter().collect();
+    let input_weight = input_weight(&wallet);
+    let input_fee = fee_for_weight(input_weight, fee_rate);
+    let mut candidates: Vec<Candidate> = data
+        .db
+        .list_utxos(store_id)?
+        .into_iter()
+        .filter(|utxo| utxo.confirmed || include_unconfirmed)
+        .filter(|utxo| !reserved.contains(&(utxo.txid.clone(), utxo.vout)))
+        .filter(|utxo| utxo.value > input_fee)
+        .map(|utxo| Candidate { effective_value: utxo.value - input_fee, utxo })
+        .collect();
+    candidates.sort_by_key(|candidate| Reverse(candidate.effective_value));
+
+    // Everything but the inputs: version, locktime, counts, the destinations
+    // and the segwit marker
+    let skeleton = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: Vec::new(),
+        output: outputs.clone(),
+    };
+    let base_weight = skeleton.weight().to_wu() + if is_segwit(&wallet) { 2 } else { 0 };
+    let target = outputs.iter().map(|output| output.value).sum::<u64>() + fee_for_weight(base_weight, fee_rate);
+
+    let secp = Secp256k1::verification_only();
+    let change_script = wallet.derive(&secp, CHANGE_CHAIN, 0)?.script_pubkey;
+    let change_output_fee = fee_for_weight(output_weight(&change_script), fee_rate);
+    // Creating change only pays off if it covers its own output and the
+    // input that later spends it
+    let cost_of_change = change_output_fee + input_fee;
+
+    let values: Vec<u64> = candidates.iter().map(|candidate| candidate.effective_value).collect();
+    let (selection, algorithm) = match branch_and_bound(&values, target, cost_of_change) {
+        Some(selection) => (selection, SelectionAlgorithm::BranchAndBound),
+        None => {
+            let with_change = target + change_output_fee + MIN_CHANGE;
+            let selection = knapsack(&values, with_change)
+                .or_else(|| knapsack(&values, target))
+                .ok_or(BuildError::InsufficientFunds {
+                    available: values.iter().sum(),
+                    required: target,
+                })?;
+            (selection, SelectionAlgorithm::Knapsack)
+        }
+    };
+
+    let selected: Vec<Utxo> = selection.into_iter().map(|index| candidates[index].utxo.clone()).collect();
+    let selected_effective: u64 = selected.iter().map(|utxo| utxo.value - input_fee).sum();
+    let excess = selected_effective - target;
+
+    let mut outputs = outputs;
+    let mut change = None;
+    let mut change_derived = None;
+    // Branch-and-bound matches leave less excess than change would cost
+    if algorithm == SelectionAlgorithm::Knapsack && excess >= change_output_fee + DUST_LIMIT {
+        let index = data.db.next_derivation_index(&wallet::change_account(&wallet))?;
+        let derived = wallet.derive(&secp, CHANGE_CHAIN, index)?;
+        let amount = excess - change_output_fee;
+        let address = Address::from_script(&derived.script_pubkey, network)
+            .map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))?;
+        let position = rand::thread_rng().gen_range(0..=outputs.len());
+        outputs.insert(position, TxOut { value: amount, script_pubkey: derived.script_pubkey.clone() });
+        change = Some(ChangeOutput {
+            address: address.to_string(),
+            amount,
+            derivation_path: derived.path.to_string(),
+        });
+        change_derived = Some((position, derived));
+    }
+
+    let inputs = selected
+        .iter()
+        .map(|utxo| {
+            Ok(TxIn {
+                previous_output: OutPoint::new(
+                    Txid::from_str(&utxo.txid).map_err(|e| PsbtError::MissingInputData(e.to_string()))?,
+                    utxo.vout,
+                ),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+        })
+        .collect::<Result<Vec<TxIn>, PsbtError>>()?;
+    let tx = Transaction {
+        version: 2,
+        lock_time,
+        input: inputs,
+        output: outputs,
+    };
+
+    let mut psbt = Psbt::from_unsigned_tx(tx).map_err(|e| PsbtError::InvalidEncoding(e.to_string()))?;
+    for (input, utxo) in psbt.inputs.iter_mut().zip(&selected) {
+        let script_pubkey = ScriptBuf::from_bytes(
+            hex::decode(&utxo.script_pubkey).map_err(|e| PsbtError::MissingInputData(e.to_string()))?,
+        );
+        input.witness_utxo = Some(TxOut { value: utxo.value, script_pubkey });
+        if let Some(derived) = derive_utxo(&wallet, utxo)? {
+            for (public_key, key_source) in derived.keys {
+                input.bip32_derivation.insert(public_key.inner, key_source);
+            }
+            input.redeem_script = derived.redeem_script;
+            input.witness_script = derived.witness_script;
+        }
+    }
+    if let Some((position, derived)) = change_derived {
+        let output = &mut psbt.outputs[position];
+        for (public_key, key_source) in derived.keys {
+            output.bip32_derivation.insert(public_key.inner, key_source);
+        }
+        output.redeem_script = derived.redeem_script;
+        output.witness_script = derived.witness_script;
+    }
+
+    let reserved_until = Utc::now() + reservation_ttl();
+    data.db
+        .reserve_utxos(store_id, &psbt.unsigned_tx.txid().to_string(), &outpoints(&psbt.unsigned_tx), reserved_until)?;
+
+    let input_total: u64 = selected.iter().map(|utxo| utxo.value).sum();
+    let output_total: u64 = psbt.unsigned_tx.output.iter().map(|output| output.value).sum();
+    let vsize = (base_weight + selected.len() as u64 * input_weight + change.as_ref().map_or(0, |_| output_weight(&change_script)))
+        .div_ceil(4);
+    info!(
+        "Built transaction for store {} with {} input(s) using {:?}",
+        store_id,
+        selected.len(),
+        algorithm
+    );
+
+    Ok(BuiltTransaction {
+        psbt,
+        fee: input_total - output_total,
+        vsize,
+        algorithm,
+        inputs: selected,
+        change,
+        reserved_until,
+    })
+}
+
+// Give back the inputs reserved for an unsigned transaction that will not be
+// broadcast
+pub fn release(data: &AppState, tx: &Transaction) {
+    let txid = tx.txid();
+    if let Err(e) = data.db.release_utxos(&txid.to_string()) {
+        warn!("Failed to release the inputs of {}: {}", txid, e);
+    }
+}
+
+pub fn outpoints(tx: &Transaction) -> Vec<(String, u32)> {
+    tx.input
+        .iter()
+        .map(|input| (input.previous_output.txid.to_string(), input.previous_output.vout))
+        .collect()
+}
+
+// UTXO_RESERVATION_SECS (default 3600)
+fn reservation_ttl() -> chrono::Duration {
+    let secs = std::env::var("UTXO_RESERVATION_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_RESERVATION_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+fn parse_destination(destination: &Destination, network: Network) -> Result<TxOut, BuildError> {
+    let address = Address::from_str(&destination.address)
+        .map_err(|e| BuildError::InvalidDestination(format!("{}: {}", destination.address, e)))?
+        .require_network(network)
+        .map_err(|e| BuildError::InvalidDestination(format!("{}: {}", destination.address, e)))?;
+    if destination.amount < DUST_LIMIT {
+        return Err(BuildError::InvalidDestination(format!(
+            "{} sat to {} is below the dust limit",
+            destination.amount, destination.address
+        )));
+    }
+    Ok(TxOut {
+        value: destination.amount,
+        script_pubkey: address.script_pubkey(),
+    })
+}
+
+// Scripts and key origins of a synced UTXO, from the chain and index at the
+// end of its derivation path
+pub fn derive_utxo(wallet: &WalletDescriptor, utxo: &Utxo) -> Result<Option<DerivedAddress>, PsbtError> {
+    let path = DerivationPath::from_str(&utxo.derivation_path).map_err(|e| PsbtError::MissingInputData(e.to_string()))?;
+    let tail: Vec<&ChildNumber> = path.as_ref().iter().rev().take(2).collect();
+    match tail.as_slice() {
+        [ChildNumber::Normal { index }, ChildNumber::Normal { index: chain }] => {
+            let secp = Secp256k1::verification_only();
+            Ok(Some(wallet.derive(&secp, *chain, *index)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn fee_for_weight(weight: u64, fee_rate: f64) -> u64 {
+    (weight as f64 * fee_rate / 4.0).ceil() as u64
+}
+
+pub fn output_weight(script_pubkey: &ScriptBuf) -> u64 {
+    // value, script length and script
+    (8 + 1 + script_pubkey.len() as u64) * 4
+}
+
+pub fn is_segwit(wallet: &WalletDescriptor) -> bool {
+    !matches!(wallet.single().map(|account| account.script_type), Some(ScriptType::Legacy))
+}
+
+// Weight of one signed input of the wallet
+pub fn input_weight(wallet: &WalletDescriptor) -> u64 {
+    match wallet {
+        WalletDescriptor::Single(account) => match account.script_type {
+            ScriptType::NativeSegwit => TXIN_BASE_WEIGHT + SINGLE_KEY_WITNESS,
+            // scriptSig pushing the p2wpkh redeem script
+            ScriptType::NestedSegwit => TXIN_BASE_WEIGHT + 23 * 4 + SINGLE_KEY_WITNESS,
+            ScriptType::Legacy => TXIN_BASE_WEIGHT + (1 + 72 + 1 + 33) * 4,
+        },
+        WalletDescriptor::Multisig(multisig) => {
+            // item count, empty dummy, signatures, then the witness script:
+            // OP_m, n pushed keys, OP_n, OP_CHECKMULTISIG
+            let script_len = 3 + 34 * multisig.keys.len() as u64;
+            TXIN_BASE_WEIGHT + 1 + 1 + multisig.threshold as u64 * (1 + 72) + 3 + script_len
+        }
+    }
+}
+
+// Locktime at the chain tip (occasionally a little earlier) so the
+// transaction cannot be mined in a reorg of past blocks
+pub async fn anti_fee_sniping_locktime(data: &AppState) -> LockTime {
+    let height = match data.blockchain_client.get_tip_height().await {
+        Ok(height) => height,
+        Err(e) => {
+            warn!("Could not fetch tip height, leaving locktime at 0: {}", e);
+            return LockTime::ZERO;
+        }
+    };
+    let mut rng = rand::thread_rng();
+    let height = if rng.gen_range(0..100) < LOCKTIME_BACKDATE_PERCENT {
+        height.saturating_sub(rng.gen_range(0..LOCKTIME_MAX_BACKDATE))
+    } else {
+        height
+    };
+    LockTime::from_height(height).unwrap_or(LockTime::ZERO)
+}
+
+// Depth-first search for a selection whose effective value lands in
+// [target, target + cost_of_change], i.e. needs no change output, preferring
+// the least excess. `values` must be sorted in descending order.
+fn branch_and_bound(values: &[u64], target: u64, cost_of_change: u64) -> Option<Vec<usize>> {
+    let mut available: u64 = values.iter().sum();
+    if available < target {
+        return None;
+    }
+
+    let mut current = 0;
+    let mut path: Vec<bool> = Vec::with_capacity(values.len());
+    let mut best: Option<(u64, Vec<bool>)> = None;
+
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if current + available < target || current > target + cost_of_change {
+            true
+        } else if current >= target {
+            let excess = current - target;
+            if best.as_ref().is_none_or(|(best_excess, _)| excess < *best_excess) {
+                best = Some((excess, path.clone()));
+            }
+            if excess == 0 {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // Undo trailing omissions, then omit the last included UTXO
+            while path.last() == Some(&false) {
+                path.pop();
+                available += values[path.len()];
+            }
+            match path.last_mut() {
+                Some(included) => {
+                    *included = false;
+                    current -= values[path.len() - 1];
+                }
+                None => break,
+            }
+        } else {
+            let depth = path.len();
+            available -= values[depth];
+            // Including a UTXO worth the same as the one just omitted would
+            // only repeat the search
+            if depth > 0 && !path[depth - 1] && values[depth - 1] == values[depth] {
+                path.push(false);
+            } else {
+                path.push(true);
+                current += values[depth];
+            }
+        }
+    }
+
+    best.map(|(_, path)| {
+        path.iter()
+            .enumerate()
+            .filter(|(_, included)| **included)
+            .map(|(index, _)| index)
+            .collect()
+    })
+}
+
+// Fallback when no changeless match exists: the smallest total reaching
+// `target` from randomised subset sums of the smaller UTXOs, or the single
+// smallest UTXO covering it if that is no more wasteful
+fn knapsack(values: &[u64], target: u64) -> Option<Vec<usize>> {
+    if let Some(index) = values.iter().position(|value| *value == target) {
+        return Some(vec![index]);
+    }
+
+    let lowest_larger = values
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| **value > target)
+        .min_by_key(|(_, value)| **value)
+        .map(|(index, _)| index);
+    let smaller: Vec<usize> = (0..values.len()).filter(|index| values[*index] < target).collect();
+    let smaller_total: u64 = smaller.iter().map(|index| values[*index]).sum();
+    if smaller_total == target {
+        return Some(smaller);
+    }
+    if smaller_total < target {
+        return lowest_larger.map(|index| vec![index]);
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut best = vec![true; smaller.len()];
+    let mut best_total = smaller_total;
+    for _ in 0..KNAPSACK_ITERATIONS {
+        if best_total == target {
+            break;
+        }
+        let mut included = vec![false; smaller.len()];
+        let mut total = 0;
+        let mut reached = false;
+        // First pass picks UTXOs at random, the second fills in the rest
+        for pass in 0..2 {
+            if reached {
+                break;
+            }
+            for (position, index) in smaller.iter().enumerate() {
+                let pick = if pass == 0 { rng.gen_bool(0.5) } else { !included[position] };
+                if !pick || included[position] {
+                    continue;
+                }
+                total += values[*index];
+                included[position] = true;
+                if total >= target {
+                    reached = true;
+                    if total < best_total {
+                        best_total = total;
+                        best = included.clone();
+                    }
+                    // Try to do better without this one
+                    total -= values[*index];
+                    included[position] = false;
+                }
+            }
+        }
+    }
+
+    match lowest_larger {
+        Some(index) if values[index] <= best_total => Some(vec![index]),
+        _ => Some(
+            smaller
+                .iter()
+                .zip(best)
+                .filter(|(_, included)| *included)
+                .map(|(index, _)| *index)
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(values: &[u64], selection: &[usize]) -> u64 {
+        selection.iter().map(|index| values[*index]).sum()
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_match() {
+        let values = [10_000, 7_000, 5_000, 3_000, 1_000];
+        let selection = branch_and_bound(&values, 8_000, 0).unwrap();
+        assert_eq!(total(&values, &selection), 8_000);
+    }
+
+    #[test]
+    fn branch_and_bound_accepts_excess_below_cost_of_change() {
+        let values = [6_000, 4_000];
+        assert_eq!(branch_and_bound(&values, 5_000, 1_500), Some(vec![0]));
+        // Change would be cheaper than dropping 1000 sat to fees
+        assert_eq!(branch_and_bound(&values, 5_000, 500), None);
+    }
+
+    #[test]
+    fn branch_and_bound_prefers_least_excess() {
+        let values = [5_200, 5_100];
+        assert_eq!(branch_and_bound(&values, 5_000, 500), Some(vec![1]));
+    }
+
+    #[test]
+    fn branch_and_bound_gives_up_when_funds_are_short() {
+        assert_eq!(branch_and_bound(&[3_000, 1_000], 5_000, 1_000), None);
+        assert_eq!(branch_and_bound(&[], 1, 1_000), None);
+    }
+
+    #[test]
+    fn knapsack_takes_exact_single_utxo() {
+        assert_eq!(knapsack(&[3_000, 2_000, 1_000], 2_000), Some(vec![1]));
+    }
+
+    #[test]
+    fn knapsack_takes_all_smaller_utxos_when_they_add_up() {
+        assert_eq!(knapsack(&[5_000, 2_000, 1_000], 3_000), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn knapsack_falls_back_to_lowest_larger_utxo() {
+        assert_eq!(knapsack(&[9_000, 6_000, 1_000], 5_000), Some(vec![1]));
+        assert_eq!(knapsack(&[1_000, 2_000], 5_000), None);
+    }
+
+    #[test]
+    fn knapsack_combines_smaller_utxos() {
+        let values = [4_000, 3_000, 2_000, 1_000];
+        let selection = knapsack(&values, 6_000).unwrap();
+        assert_eq!(total(&values, &selection), 6_000);
+    }
+
+    #[test]
+    fn knapsack_prefers_larger_utxo_over_more_wasteful_combination() {
+        // The smaller ones can only reach 6000 together; 5500 wastes less
+        let values = [5_500, 4_000, 2_000];
+        assert_eq!(knapsack(&values, 5_200), Some(vec![0]));
+    }
+
+    #[test]
+    fn fee_rounds_up_to_whole_satoshis() {
+        assert_eq!(fee_for_weight(561, 1.0), 141);
+        assert_eq!(fee_for_weight(400, 2.5), 250);
+    }
+}
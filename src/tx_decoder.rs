@@ -0,0 +1,369 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network, Script, ScriptBuf, Transaction, TxOut};
+use log::warn;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::psbt::{self, Psbt, PsbtError, WalletDescriptor};
+use crate::state::AppState;
+use crate::tx_validation::ValidationPolicy;
+use crate::wallet;
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+// Warn when the fee exceeds this share of the amount leaving the wallet
+const HIGH_FEE_PERCENT: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodedKind {
+    Psbt,
+    Transaction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLabel {
+    Change,         // back to the store's wallet
+    InvoicePayout,  // pays an invoice address
+    External,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DecodedInput {
+    pub outpoint: String,
+    pub sequence: u32,
+    pub value: Option<u64>,
+    pub address: Option<String>,
+    // Set when the spent output belongs to the store's wallet
+    pub derivation_path: Option<String>,
+    pub signed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DecodedOutput {
+    pub value: u64,
+    pub address: Option<String>,
+    pub script_pubkey: String,
+    pub label: OutputLabel,
+    pub derivation_path: Option<String>,
+    pub invoice_id: Option<String>,
+    // Whether an external address has been used before, when known
+    pub previously_used: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DecodedTransaction {
+    pub kind: DecodedKind,
+    pub txid: String,
+    pub version: i32,
+    pub lock_time: u32,
+    pub rbf: bool,
+    // PSBTs only: whether every input has a signature
+    pub complete: Option<bool>,
+    pub inputs: Vec<DecodedInput>,
+    pub outputs: Vec<DecodedOutput>,
+    pub input_total: Option<u64>,
+    pub output_total: u64,
+    pub fee: Option<u64>,
+    pub fee_rate: Option<f64>, // sat/vB, estimated for unsigned inputs
+    pub vsize: u64,
+    pub warnings: Vec<String>,
+}
+
+enum Parsed {
+    Psbt(Box<Psbt>),
+    Transaction(Transaction),
+}
+
+// Accepts a base64 or hex PSBT, or a hex raw transaction
+fn parse(encoded: &str) -> Result<Parsed, PsbtError> {
+    if let Ok(psbt) = psbt::decode_psbt(encoded) {
+        return Ok(Parsed::Psbt(Box::new(psbt)));
+    }
+
+    let bytes = hex::decode(encoded.trim())
+        .map_err(|_| PsbtError::InvalidEncoding("expected a base64/hex PSBT or a hex transaction".to_string()))?;
+    if bytes.starts_with(PSBT_MAGIC) {
+        Psbt::deserialize(&bytes)
+            .map(|psbt| Parsed::Psbt(Box::new(psbt)))
+            .map_err(|e| PsbtError::InvalidEncoding(e.to_string()))
+    } else {
+        deserialize(&bytes)
+            .map(Parsed::Transaction)
+            .map_err(|e| PsbtError::InvalidEncoding(format!("invalid transaction: {}", e)))
+    }
+}
+
+// Decode a transaction or PSBT for review, labelling inputs and outputs
+// against the store's wallet and flagging anything unusual
+pub async fn decode(
+    data: &AppState,
+    encoded: &str,
+    store_id: &str,
+    network: Network,
+) -> Result<DecodedTransaction, PsbtError> {
+    let mut warnings = Vec::new();
+
+    let (kind, tx, spent_outputs, witness_scripts, signed, complete) = match parse(encoded)? {
+        Parsed::Psbt(mut psbt) => {
+            if let Err(e) = psbt::fill_utxos(&mut psbt, &data.blockchain_client).await {
+                warnings.push(format!("Could not fetch all input data: {}", e));
+            }
+            let spent: Vec<Option<TxOut>> = (0..psbt.inputs.len())
+                .map(|index| psbt::spent_output(&psbt, index).cloned())
+                .collect();
+            let signed: Vec<bool> = psbt
+                .inputs
+                .iter()
+                .map(|input| {
+                    input.final_script_sig.is_some()
+                        || input.final_script_witness.is_some()
+                        || input.partial_sigs.len() >= psbt::required_signatures(input)
+                })
+                .collect();
+            let witness_scripts: Vec<Option<ScriptBuf>> =
+                psbt.inputs.iter().map(|input| input.witness_script.clone()).collect();
+            let complete = psbt::is_fully_signed(&psbt);
+            (DecodedKind::Psbt, psbt.unsigned_tx, spent, witness_scripts, signed, Some(complete))
+        }
+        Parsed::Transaction(tx) => {
+            let spent = fetch_spent_outputs(data, &tx, &mut warnings).await;
+            let signed: Vec<bool> = tx
+                .input
+                .iter()
+                .map(|input| !input.script_sig.is_empty() || !input.witness.is_empty())
+                .collect();
+            let witness_scripts = vec![None; tx.input.len()];
+            (DecodedKind::Transaction, tx, spent, witness_scripts, signed, None)
+        }
+    };
+
+    let wallet = data.wallet_for(store_id);
+    let bounds = match &wallet {
+        Some(wallet) => wallet::derivation_bounds(&data.db, wallet).unwrap_or_else(|e| {
+            warnings.push(format!("Could not load derivation indexes, so change cannot be identified: {}", e));
+            [0, 0]
+        }),
+        None => {
+            warnings.push("No wallet is configured for this store, so change cannot be identified".to_string());
+            [0, 0]
+        }
+    };
+
+    let mut inputs = Vec::with_capacity(tx.input.len());
+    for (index, (txin, spent)) in tx.input.iter().zip(&spent_outputs).enumerate() {
+        let derivation_path = match (spent, &wallet) {
+            (Some(spent), Some(wallet)) => derivation_path(wallet, bounds, &spent.script_pubkey)?,
+            _ => None,
+        };
+        if spent.is_some() && wallet.is_some() && derivation_path.is_none() {
+            warnings.push(format!("Input {} does not spend from this store's wallet", index));
+        }
+        inputs.push(DecodedInput {
+            outpoint: txin.previous_output.to_string(),
+            sequence: txin.sequence.to_consensus_u32(),
+            value: spent.as_ref().map(|output| output.value),
+            address: spent.as_ref().and_then(|output| address_string(&output.script_pubkey, network)),
+            derivation_path,
+            signed: signed[index],
+        });
+    }
+
+    let mut outputs = Vec::with_capacity(tx.output.len());
+    for (index, txout) in tx.output.iter().enumerate() {
+        let address = Address::from_script(&txout.script_pubkey, network).ok();
+        let derivation_path = match &wallet {
+            Some(wallet) => derivation_path(wallet, bounds, &txout.script_pubkey)?,
+            None => None,
+        };
+        let invoice_id = match &address {
+            Some(address) => invoice_for_address(data, &address.to_string()),
+            None => None,
+        };
+
+        let label = if invoice_id.is_some() {
+            OutputLabel::InvoicePayout
+        } else if derivation_path.is_some() {
+            OutputLabel::Change
+        } else {
+            OutputLabel::External
+        };
+
+        let mut previously_used = None;
+        if let (OutputLabel::External, Some(address)) = (label, &address) {
+            match data.blockchain_client.get_address_tx_count(address).await {
+                Ok(count) => {
+                    previously_used = Some(count > 0);
+                    if count == 0 {
+                        warnings.push(format!("Output {} sends to {}, which has never been used", index, address));
+                    }
+                }
+                Err(e) => warn!("Could not look up history of {}: {}", address, e),
+            }
+        }
+
+        outputs.push(DecodedOutput {
+            value: txout.value,
+            address: address.map(|address| address.to_string()),
+            script_pubkey: txout.script_pubkey.to_hex_string(),
+            label,
+            derivation_path,
+            invoice_id,
+            previously_used,
+        });
+    }
+
+    let output_total: u64 = tx.output.iter().map(|output| output.value).sum();
+    let input_total: Option<u64> = spent_outputs.iter().map(|spent| spent.as_ref().map(|output| output.value)).sum();
+    let vsize = estimated_weight(&tx, &spent_outputs, &witness_scripts, &signed).div_ceil(4);
+
+    let fee = match input_total.map(|total| total.checked_sub(output_total)) {
+        Some(Some(fee)) => Some(fee),
+        Some(None) => {
+            warnings.push("Outputs exceed inputs".to_string());
+            None
+        }
+        None => {
+            warnings.push("Fee is unknown because some input values could not be found".to_string());
+            None
+        }
+    };
+    let fee_rate = fee.map(|fee| fee as f64 / vsize as f64);
+
+    if let (Some(fee), Some(fee_rate)) = (fee, fee_rate) {
+        let policy = ValidationPolicy::from_env();
+        if fee_rate > policy.max_fee_rate || fee > policy.max_fee {
+            warnings.push(format!("Unusually high fee: {} sat ({:.1} sat/vB)", fee, fee_rate));
+        }
+        let sent: u64 = outputs
+            .iter()
+            .filter(|output| output.label != OutputLabel::Change)
+            .map(|output| output.value)
+            .sum();
+        if sent > 0 && fee as f64 > sent as f64 * HIGH_FEE_PERCENT / 100.0 {
+            warnings.push(format!("Fee is {:.1}% of the amount sent", fee as f64 * 100.0 / sent as f64));
+        }
+    }
+
+    Ok(DecodedTransaction {
+        kind,
+        txid: tx.txid().to_string(),
+        version: tx.version,
+        lock_time: tx.lock_time.to_consensus_u32(),
+        rbf: tx.is_explicitly_rbf(),
+        complete,
+        inputs,
+        outputs,
+        input_total,
+        output_total,
+        fee,
+        fee_rate,
+        vsize,
+        warnings,
+    })
+}
+
+// Outputs spent by a raw transaction, fetched from the blockchain backend
+async fn fetch_spent_outputs(data: &AppState, tx: &Transaction, warnings: &mut Vec<String>) -> Vec<Option<TxOut>> {
+    let mut previous_txs: HashMap<bitcoin::Txid, Option<Transaction>> = HashMap::new();
+    let mut spent = Vec::with_capacity(tx.input.len());
+
+    for txin in &tx.input {
+        let txid = txin.previous_output.txid;
+        if let Entry::Vacant(entry) = previous_txs.entry(txid) {
+            let previous = match data.blockchain_client.get_transaction(&txid).await {
+                Ok(previous) => Some(previous),
+                Err(e) => {
+                    warnings.push(format!("Could not fetch input transaction {}: {}", txid, e));
+                    None
+                }
+            };
+            entry.insert(previous);
+        }
+        spent.push(
+            previous_txs[&txid]
+                .as_ref()
+                .and_then(|previous| previous.output.get(txin.previous_output.vout as usize).cloned()),
+        );
+    }
+
+    spent
+}
+
+// Open invoices are kept in memory; older ones are looked up in the database
+fn invoice_for_address(data: &AppState, address: &str) -> Option<String> {
+    let in_memory = data
+        .invoices
+        .lock()
+        .unwrap()
+        .values()
+        .find(|invoice| invoice.address == address)
+        .map(|invoice| invoice.id.clone());
+    in_memory.or_else(|| {
+        data.db.get_invoice_id_by_address(address).unwrap_or_else(|e| {
+            warn!("Invoice lookup for {} failed: {}", address, e);
+            None
+        })
+    })
+}
+
+fn derivation_path(wallet: &WalletDescriptor, bounds: [u32; 2], script: &Script) -> Result<Option<String>, PsbtError> {
+    let secp = Secp256k1::verification_only();
+    Ok(psbt::find_derivation(&secp, wallet, bounds, &script.to_owned())?.map(|(_, _, derived)| derived.path.to_string()))
+}
+
+fn address_string(script: &Script, network: Network) -> Option<String> {
+    Address::from_script(script, network).ok().map(|address| address.to_string())
+}
+
+// Weight once every input is signed, estimating the scriptSig/witness of
+// unsigned single-key inputs and of p2wsh multisig inputs whose witness
+// script is known
+pub fn estimated_weight(
+    tx: &Transaction,
+    spent_outputs: &[Option<TxOut>],
+    witness_scripts: &[Option<ScriptBuf>],
+    signed: &[bool],
+) -> u64 {
+    let mut weight = tx.weight().to_wu();
+    let mut adds_witness = false;
+
+    for (((spent, witness_script), signed), txin) in spent_outputs.iter().zip(witness_scripts).zip(signed).zip(&tx.input) {
+        if *signed || !txin.script_sig.is_empty() || !txin.witness.is_empty() {
+            continue;
+        }
+        let script = match spent {
+            Some(spent) => &spent.script_pubkey,
+            None => continue,
+        };
+        // witness: item count, 72-byte signature and 33-byte key with length prefixes
+        let witness = 1 + 1 + 72 + 1 + 33;
+        if script.is_v0_p2wpkh() {
+            weight += witness;
+            adds_witness = true;
+        } else if script.is_v0_p2wsh() {
+            // item count, empty dummy, `threshold` signatures, witness script
+            let threshold = witness_script
+                .as_ref()
+                .and_then(|script| psbt::parse_multisig(script))
+                .map_or(1, |(threshold, _)| threshold as u64);
+            let script_len = witness_script.as_ref().map_or(0, |script| script.len() as u64);
+            weight += 1 + 1 + threshold * (1 + 72) + 3 + script_len;
+            adds_witness = true;
+        } else if script.is_p2sh() {
+            // scriptSig pushing a p2wpkh redeem script, assumed nested segwit
+            weight += 23 * 4 + witness;
+            adds_witness = true;
+        } else if script.is_p2pkh() {
+            weight += (1 + 72 + 1 + 33) * 4;
+        }
+    }
+
+    // Segwit marker and flag
+    if adds_witness && tx.input.iter().all(|txin| txin.witness.is_empty()) {
+        weight += 2;
+    }
+    weight
+}
@@ -0,0 +1,688 @@
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::secp256k1::{Message, Secp256k1, Verification};
+use bitcoin::sighash::SighashCache;
+use bitcoin::{ecdsa, PublicKey, Script, ScriptBuf, Transaction, TxOut, Witness};
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::psbt::{self, Psbt};
+
+// Standardness limits enforced by Bitcoin Core's mempool
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+const MAX_STANDARD_VERSION: i32 = 2;
+// Grace period for time-based locktimes (Core compares against median time past)
+const LOCKTIME_GRACE_SECS: i64 = 2 * 3600;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationRule {
+    Structure,
+    DuplicateInputs,
+    InputsSigned,
+    ScriptVerification,
+    Dust,
+    Fee,
+    FeeRate,
+    Locktime,
+    Sequence,
+    Version,
+    Weight,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleResult {
+    pub rule: ValidationRule,
+    pub passed: bool,
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub txid: String,
+    pub fee: Option<u64>,
+    pub fee_rate: Option<f64>, // sat/vB
+    pub weight: u64,
+    pub results: Vec<RuleResult>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.results.iter().all(|result| result.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &RuleResult> {
+        self.results.iter().filter(|result| !result.passed)
+    }
+
+    fn check(&mut self, rule: ValidationRule, problems: Vec<String>) {
+        self.results.push(RuleResult {
+            rule,
+            passed: problems.is_empty(),
+            details: if problems.is_empty() { None } else { Some(problems.join("; ")) },
+        });
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failures: Vec<String> = self
+            .failures()
+            .map(|result| format!("{:?}: {}", result.rule, result.details.as_deref().unwrap_or("failed")))
+            .collect();
+        if failures.is_empty() {
+            write!(f, "all checks passed")
+        } else {
+            write!(f, "{}", failures.join(", "))
+        }
+    }
+}
+
+// Fee bounds applied before broadcasting, configurable through
+// MIN_FEE_RATE (sat/vB), MAX_FEE_RATE (sat/vB) and MAX_FEE_SATS
+#[derive(Debug, Clone)]
+pub struct ValidationPolicy {
+    pub min_fee_rate: f64,
+    pub max_fee_rate: f64,
+    pub max_fee: u64,
+}
+
+impl ValidationPolicy {
+    pub fn from_env() -> Self {
+        let env = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<f64>().ok());
+        Self {
+            min_fee_rate: env("MIN_FEE_RATE").unwrap_or(1.0),
+            max_fee_rate: env("MAX_FEE_RATE").unwrap_or(500.0),
+            max_fee: env("MAX_FEE_SATS").map(|fee| fee as u64).unwrap_or(1_000_000),
+        }
+    }
+}
+
+// Check a fully signed transaction against the outputs it spends
+// (`spent_outputs[i]` is the output spent by input i). `tip_height`, when
+// known, is used to reject height locktimes that are not yet final.
+pub fn validate(
+    tx: &Transaction,
+    spent_outputs: &[TxOut],
+    policy: &ValidationPolicy,
+    tip_height: Option<u32>,
+) -> ValidationReport {
+    let weight = tx.weight().to_wu();
+    let mut report = ValidationReport {
+        txid: tx.txid().to_string(),
+        fee: None,
+        fee_rate: None,
+        weight,
+        results: Vec::new(),
+    };
+
+    // Structure
+    let mut problems = Vec::new();
+    if tx.input.is_empty() {
+        problems.push("transaction has no inputs".to_string());
+    }
+    if tx.output.is_empty() {
+        problems.push("transaction has no outputs".to_string());
+    }
+    if spent_outputs.len() != tx.input.len() {
+        problems.push(format!("{} spent outputs given for {} inputs", spent_outputs.len(), tx.input.len()));
+    }
+    report.check(ValidationRule::Structure, problems);
+
+    // Duplicate inputs
+    let mut seen = HashSet::new();
+    let problems = tx
+        .input
+        .iter()
+        .filter(|input| !seen.insert(input.previous_output))
+        .map(|input| format!("{} is spent twice", input.previous_output))
+        .collect();
+    report.check(ValidationRule::DuplicateInputs, problems);
+
+    // Every input signed
+    let problems = tx
+        .input
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| input.script_sig.is_empty() && input.witness.is_empty())
+        .map(|(index, _)| format!("input {} is unsigned", index))
+        .collect();
+    report.check(ValidationRule::InputsSigned, problems);
+
+    // Scripts and signatures
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(tx);
+    let problems = tx
+        .input
+        .iter()
+        .zip(spent_outputs)
+        .enumerate()
+        .filter_map(|(index, (input, spent))| {
+            verify_input(&secp, &mut cache, index, &input.script_sig, &input.witness, spent)
+                .err()
+                .map(|e| format!("input {}: {}", index, e))
+        })
+        .collect();
+    report.check(ValidationRule::ScriptVerification, problems);
+
+    // Dust outputs (OP_RETURN outputs may carry zero value)
+    let problems = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| !output.script_pubkey.is_op_return())
+        .filter(|(_, output)| output.value < output.script_pubkey.dust_value().to_sat())
+        .map(|(index, output)| {
+            format!(
+                "output {} pays {} sat, below the dust limit of {} sat",
+                index,
+                output.value,
+                output.script_pubkey.dust_value().to_sat()
+            )
+        })
+        .collect();
+    report.check(ValidationRule::Dust, problems);
+
+    // Fee and fee rate
+    let input_total: u64 = spent_outputs.iter().map(|output| output.value).sum();
+    let output_total: u64 = tx.output.iter().map(|output| output.value).sum();
+    match input_total.checked_sub(output_total) {
+        Some(fee) => {
+            let fee_rate = fee as f64 / tx.vsize() as f64;
+            report.fee = Some(fee);
+            report.fee_rate = Some(fee_rate);
+
+            let mut problems = Vec::new();
+            if fee > policy.max_fee {
+                problems.push(format!("fee {} sat exceeds the maximum of {} sat", fee, policy.max_fee));
+            }
+            report.check(ValidationRule::Fee, problems);
+
+            let mut problems = Vec::new();
+            if fee_rate < policy.min_fee_rate {
+                problems.push(format!("{:.2} sat/vB is below the minimum of {} sat/vB", fee_rate, policy.min_fee_rate));
+            }
+            if fee_rate > policy.max_fee_rate {
+                problems.push(format!("{:.2} sat/vB exceeds the maximum of {} sat/vB", fee_rate, policy.max_fee_rate));
+            }
+            report.check(ValidationRule::FeeRate, problems);
+        }
+        None => report.check(
+            ValidationRule::Fee,
+            vec![format!("outputs ({} sat) exceed inputs ({} sat)", output_total, input_total)],
+        ),
+    }
+
+    // Locktime: only enforceable when some input has a non-final sequence,
+    // and must already be final to be relayed
+    let lock_time = tx.lock_time.to_consensus_u32();
+    let mut problems = Vec::new();
+    if lock_time != 0 {
+        if !tx.is_lock_time_enabled() {
+            problems.push("locktime is set but every input sequence is final".to_string());
+        }
+        if lock_time < LOCKTIME_THRESHOLD {
+            if let Some(tip) = tip_height {
+                // The next block must be able to include it
+                if lock_time > tip + 1 {
+                    problems.push(format!("locked until block {} (tip is {})", lock_time, tip));
+                }
+            }
+        } else if lock_time as i64 > chrono::Utc::now().timestamp() + LOCKTIME_GRACE_SECS {
+            problems.push(format!("locked until timestamp {}", lock_time));
+        }
+    }
+    report.check(ValidationRule::Locktime, problems);
+
+    // Relative locktimes (BIP 68) need version 2
+    let problems = tx
+        .input
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| input.sequence.is_relative_lock_time() && tx.version < 2)
+        .map(|(index, _)| format!("input {} uses a relative locktime in a version {} transaction", index, tx.version))
+        .collect();
+    report.check(ValidationRule::Sequence, problems);
+
+    let mut problems = Vec::new();
+    if tx.version < 1 || tx.version > MAX_STANDARD_VERSION {
+        problems.push(format!("version {} is not standard", tx.version));
+    }
+    report.check(ValidationRule::Version, problems);
+
+    let mut problems = Vec::new();
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        problems.push(format!("weight {} exceeds the standard limit of {}", weight, MAX_STANDARD_TX_WEIGHT));
+    }
+    report.check(ValidationRule::Weight, problems);
+
+    report
+}
+
+// Verify an input's scriptSig/witness against the output it spends. Covers
+// the single-key script types this server creates: p2wpkh, p2sh-p2wpkh and p2pkh.
+fn verify_input<C: Verification>(
+    secp: &Secp256k1<C>,
+    cache: &mut SighashCache<&Transaction>,
+    index: usize,
+    script_sig: &Script,
+    witness: &Witness,
+    spent: &TxOut,
+) -> Result<(), String> {
+    let script_pubkey = &spent.script_pubkey;
+
+    if script_pubkey.is_v0_p2wpkh() {
+        if !script_sig.is_empty() {
+            return Err("native segwit input has a non-empty scriptSig".to_string());
+        }
+        return verify_p2wpkh(secp, cache, index, script_pubkey, witness, spent.value);
+    }
+
+    if script_pubkey.is_v0_p2wsh() {
+        if !script_sig.is_empty() {
+            return Err("native segwit input has a non-empty scriptSig".to_string());
+        }
+        return verify_p2wsh_multisig(secp, cache, index, script_pubkey, witness, spent.value);
+    }
+
+    if script_pubkey.is_p2sh() {
+        let pushes = script_pushes(script_sig)?;
+        let [redeem_script] = pushes.as_slice() else {
+            return Err("p2sh scriptSig must push only the redeem script".to_string());
+        };
+        let redeem_script = ScriptBuf::from(redeem_script.clone());
+        if &ScriptBuf::new_p2sh(&redeem_script.script_hash()) != script_pubkey {
+            return Err("redeem script does not match the p2sh hash".to_string());
+        }
+        if !redeem_script.is_v0_p2wpkh() {
+            return Err("unsupported p2sh redeem script".to_string());
+        }
+        return verify_p2wpkh(secp, cache, index, &redeem_script, witness, spent.value);
+    }
+
+    if script_pubkey.is_p2pkh() {
+        if !witness.is_empty() {
+            return Err("legacy input has a witness".to_string());
+        }
+        let pushes = script_pushes(script_sig)?;
+        let [signature, public_key] = pushes.as_slice() else {
+            return Err("p2pkh scriptSig must push a signature and a public key".to_string());
+        };
+        let (signature, public_key) = parse_signature_and_key(signature, public_key)?;
+        if &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()) != script_pubkey {
+            return Err("public key does not match the spent output".to_string());
+        }
+        let sighash = cache
+            .legacy_signature_hash(index, script_pubkey, signature.hash_ty.to_u32())
+            .map_err(|e| e.to_string())?;
+        return verify_signature(secp, &sighash[..], &signature, &public_key);
+    }
+
+    Err(format!("cannot verify spends of {}", script_pubkey))
+}
+
+fn verify_p2wpkh<C: Verification>(
+    secp: &Secp256k1<C>,
+    cache: &mut SighashCache<&Transaction>,
+    index: usize,
+    program: &Script,
+    witness: &Witness,
+    value: u64,
+) -> Result<(), String> {
+    if witness.len() != 2 {
+        return Err(format!("p2wpkh witness has {} items, expected 2", witness.len()));
+    }
+    let (signature, public_key) = parse_signature_and_key(
+        witness.nth(0).unwrap_or_default(),
+        witness.nth(1).unwrap_or_default(),
+    )?;
+    let wpkh = public_key
+        .wpubkey_hash()
+        .ok_or_else(|| "segwit inputs require compressed public keys".to_string())?;
+    if ScriptBuf::new_v0_p2wpkh(&wpkh).as_script() != program {
+        return Err("public key does not match the spent output".to_string());
+    }
+
+    let script_code = program
+        .to_owned()
+        .p2wpkh_script_code()
+        .ok_or_else(|| "invalid p2wpkh program".to_string())?;
+    let sighash = cache
+        .segwit_signature_hash(index, &script_code, value, signature.hash_ty)
+        .map_err(|e| e.to_string())?;
+    verify_signature(secp, &sighash[..], &signature, &public_key)
+}
+
+// `<empty> <sig>... <witness script>` for a multisig witness script.
+// CHECKMULTISIG matches signatures to keys in script order, so each
+// signature must belong to a key after the previous signature's key.
+fn verify_p2wsh_multisig<C: Verification>(
+    secp: &Secp256k1<C>,
+    cache: &mut SighashCache<&Transaction>,
+    index: usize,
+    program: &Script,
+    witness: &Witness,
+    value: u64,
+) -> Result<(), String> {
+    let items: Vec<&[u8]> = witness.iter().collect();
+    let Some((witness_script, rest)) = items.split_last() else {
+        return Err("p2wsh witness is empty".to_string());
+    };
+    let witness_script = ScriptBuf::from(witness_script.to_vec());
+    if ScriptBuf::new_v0_p2wsh(&witness_script.wscript_hash()).as_script() != program {
+        return Err("witness script does not match the p2wsh hash".to_string());
+    }
+    let (threshold, keys) =
+        psbt::parse_multisig(&witness_script).ok_or_else(|| "unsupported witness script".to_string())?;
+
+    let [dummy, signatures @ ..] = rest else {
+        return Err("multisig witness is missing the CHECKMULTISIG dummy element".to_string());
+    };
+    // Non-empty dummies are non-standard (BIP 147)
+    if !dummy.is_empty() {
+        return Err("CHECKMULTISIG dummy element must be empty".to_string());
+    }
+    if signatures.len() != threshold {
+        return Err(format!("multisig witness has {} signatures, expected {}", signatures.len(), threshold));
+    }
+
+    let mut keys = keys.iter();
+    for signature in signatures {
+        let signature = ecdsa::Signature::from_slice(signature).map_err(|e| format!("invalid signature: {}", e))?;
+        let sighash = cache
+            .segwit_signature_hash(index, &witness_script, value, signature.hash_ty)
+            .map_err(|e| e.to_string())?;
+        let matched = keys
+            .by_ref()
+            .any(|key| verify_signature(secp, &sighash[..], &signature, key).is_ok());
+        if !matched {
+            return Err("multisig signature does not match any remaining key".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Check every partial signature in a PSBT against the key it is filed
+// under, so bad signatures from an uploaded PSBT are rejected before they
+// count towards a multisig threshold. Legacy inputs are left to `validate`.
+pub fn verify_partial_signatures(psbt: &Psbt) -> Result<(), String> {
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        if input.partial_sigs.is_empty() {
+            continue;
+        }
+        let spent = psbt::spent_output(psbt, index).ok_or_else(|| format!("input {} has no UTXO information", index))?;
+
+        let script_code = if spent.script_pubkey.is_v0_p2wsh() {
+            input
+                .witness_script
+                .clone()
+                .ok_or_else(|| format!("input {} is missing its witness script", index))?
+        } else if spent.script_pubkey.is_v0_p2wpkh() {
+            spent.script_pubkey.p2wpkh_script_code().ok_or_else(|| format!("input {} has an invalid program", index))?
+        } else if let Some(redeem_script) = input.redeem_script.as_ref().filter(|script| script.is_v0_p2wpkh()) {
+            redeem_script.p2wpkh_script_code().ok_or_else(|| format!("input {} has an invalid program", index))?
+        } else {
+            continue;
+        };
+
+        for (public_key, signature) in &input.partial_sigs {
+            let sighash = cache
+                .segwit_signature_hash(index, &script_code, spent.value, signature.hash_ty)
+                .map_err(|e| format!("input {}: {}", index, e))?;
+            verify_signature(&secp, &sighash[..], signature, public_key)
+                .map_err(|e| format!("input {} signature from {}: {}", index, public_key, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_signature_and_key(signature: &[u8], public_key: &[u8]) -> Result<(ecdsa::Signature, PublicKey), String> {
+    let signature = ecdsa::Signature::from_slice(signature).map_err(|e| format!("invalid signature: {}", e))?;
+    let public_key = PublicKey::from_slice(public_key).map_err(|e| format!("invalid public key: {}", e))?;
+    Ok((signature, public_key))
+}
+
+fn verify_signature<C: Verification>(
+    secp: &Secp256k1<C>,
+    sighash: &[u8],
+    signature: &ecdsa::Signature,
+    public_key: &PublicKey,
+) -> Result<(), String> {
+    // High-S signatures are non-standard (BIP 146)
+    let mut normalized = signature.sig;
+    normalized.normalize_s();
+    if normalized != signature.sig {
+        return Err("signature is not low-S".to_string());
+    }
+
+    let message = Message::from_slice(sighash).map_err(|e| e.to_string())?;
+    secp.verify_ecdsa(&message, &signature.sig, &public_key.inner)
+        .map_err(|_| "signature does not verify".to_string())
+}
+
+fn script_pushes(script: &Script) -> Result<Vec<Vec<u8>>, String> {
+    script
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Ok(bytes.as_bytes().to_vec()),
+            Ok(Instruction::Op(_)) => Err("scriptSig must be push-only".to_string()),
+            Err(e) => Err(format!("invalid scriptSig: {}", e)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG;
+    use bitcoin::blockdata::script::{Builder, PushBytesBuf};
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::sighash::EcdsaSighashType;
+    use bitcoin::{OutPoint, Sequence, TxIn};
+
+    const SPENT_VALUE: u64 = 100_000;
+
+    fn policy() -> ValidationPolicy {
+        ValidationPolicy { min_fee_rate: 1.0, max_fee_rate: 100.0, max_fee: 50_000 }
+    }
+
+    fn key(seed: u8) -> (SecretKey, PublicKey) {
+        let secret = SecretKey::from_slice(&[seed; 32]).unwrap();
+        (secret, PublicKey::new(secret.public_key(&Secp256k1::new())))
+    }
+
+    fn p2wpkh(seed: u8) -> ScriptBuf {
+        ScriptBuf::new_v0_p2wpkh(&key(seed).1.wpubkey_hash().unwrap())
+    }
+
+    // One input, one p2wpkh output paying `value`
+    fn transaction(value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: "1111111111111111111111111111111111111111111111111111111111111111".parse().unwrap(),
+                    vout: 0,
+                },
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..TxIn::default()
+            }],
+            output: vec![TxOut { value, script_pubkey: p2wpkh(9) }],
+        }
+    }
+
+    fn spent(script_pubkey: ScriptBuf) -> TxOut {
+        TxOut { value: SPENT_VALUE, script_pubkey }
+    }
+
+    fn passes(tx: &Transaction, spent_outputs: &[TxOut], tip_height: Option<u32>, rule: ValidationRule) -> bool {
+        let report = validate(tx, spent_outputs, &policy(), tip_height);
+        report.results.iter().find(|result| result.rule == rule).map(|result| result.passed).unwrap()
+    }
+
+    fn sign(secret: &SecretKey, sighash: &[u8]) -> Vec<u8> {
+        let message = Message::from_slice(sighash).unwrap();
+        ecdsa::Signature::sighash_all(Secp256k1::new().sign_ecdsa(&message, secret)).to_vec()
+    }
+
+    fn segwit_sighash(tx: &Transaction, script_code: &Script) -> Vec<u8> {
+        let mut cache = SighashCache::new(tx);
+        cache.segwit_signature_hash(0, script_code, SPENT_VALUE, EcdsaSighashType::All).unwrap()[..].to_vec()
+    }
+
+    fn push(bytes: Vec<u8>) -> PushBytesBuf {
+        PushBytesBuf::try_from(bytes).unwrap()
+    }
+
+    // Signs the input of a transaction and returns the output it spends
+    type SignInput = fn(&mut Transaction) -> TxOut;
+
+    fn sign_p2wpkh(tx: &mut Transaction) -> TxOut {
+        let (secret, public_key) = key(1);
+        let script_pubkey = p2wpkh(1);
+        let signature = sign(&secret, &segwit_sighash(tx, &script_pubkey.p2wpkh_script_code().unwrap()));
+        tx.input[0].witness = Witness::from_slice(&[signature, public_key.to_bytes()]);
+        spent(script_pubkey)
+    }
+
+    fn sign_p2sh_p2wpkh(tx: &mut Transaction) -> TxOut {
+        let (secret, public_key) = key(2);
+        let redeem_script = p2wpkh(2);
+        let signature = sign(&secret, &segwit_sighash(tx, &redeem_script.p2wpkh_script_code().unwrap()));
+        tx.input[0].script_sig = Builder::new().push_slice(push(redeem_script.to_bytes())).into_script();
+        tx.input[0].witness = Witness::from_slice(&[signature, public_key.to_bytes()]);
+        spent(ScriptBuf::new_p2sh(&redeem_script.script_hash()))
+    }
+
+    fn sign_p2pkh(tx: &mut Transaction) -> TxOut {
+        let (secret, public_key) = key(3);
+        let script_pubkey = ScriptBuf::new_p2pkh(&public_key.pubkey_hash());
+        let sighash = SighashCache::new(&*tx)
+            .legacy_signature_hash(0, &script_pubkey, EcdsaSighashType::All.to_u32())
+            .unwrap();
+        let signature = sign(&secret, &sighash[..]);
+        tx.input[0].script_sig = Builder::new().push_slice(push(signature)).push_key(&public_key).into_script();
+        spent(script_pubkey)
+    }
+
+    // 2-of-3, signed by the first and last keys
+    fn sign_p2wsh_multisig(tx: &mut Transaction) -> TxOut {
+        let keys = [key(4), key(5), key(6)];
+        let witness_script = Builder::new()
+            .push_int(2)
+            .push_key(&keys[0].1)
+            .push_key(&keys[1].1)
+            .push_key(&keys[2].1)
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let sighash = segwit_sighash(tx, &witness_script);
+        tx.input[0].witness = Witness::from_slice(&[
+            Vec::new(),
+            sign(&keys[0].0, &sighash),
+            sign(&keys[2].0, &sighash),
+            witness_script.to_bytes(),
+        ]);
+        spent(ScriptBuf::new_v0_p2wsh(&witness_script.wscript_hash()))
+    }
+
+    #[test]
+    fn signatures_are_verified_for_each_script_type() {
+        let cases: [(&str, SignInput); 4] = [
+            ("p2wpkh", sign_p2wpkh),
+            ("p2sh-p2wpkh", sign_p2sh_p2wpkh),
+            ("p2pkh", sign_p2pkh),
+            ("p2wsh multisig", sign_p2wsh_multisig),
+        ];
+        for (name, sign) in cases {
+            let mut tx = transaction(99_000);
+            let spent_outputs = [sign(&mut tx)];
+            assert!(passes(&tx, &spent_outputs, None, ValidationRule::ScriptVerification), "{} signed", name);
+
+            // The signatures commit to the outputs
+            tx.output[0].value -= 1;
+            assert!(!passes(&tx, &spent_outputs, None, ValidationRule::ScriptVerification), "{} altered", name);
+        }
+    }
+
+    #[test]
+    fn dust_outputs_are_rejected() {
+        let op_return = TxOut { value: 0, script_pubkey: ScriptBuf::new_op_return(&[1, 2, 3]) };
+        let cases = [
+            ("above the dust limit", TxOut { value: 294, script_pubkey: p2wpkh(9) }, true),
+            ("empty OP_RETURN", op_return, true),
+            ("below the dust limit", TxOut { value: 293, script_pubkey: p2wpkh(9) }, false),
+        ];
+        for (name, output, valid) in cases {
+            let mut tx = transaction(90_000);
+            tx.output.push(output);
+            assert_eq!(passes(&tx, &[spent(p2wpkh(1))], None, ValidationRule::Dust), valid, "{}", name);
+        }
+    }
+
+    #[test]
+    fn fees_are_bounded() {
+        // The unsigned transaction is 82 vB
+        let cases = [
+            ("within the bounds", 99_000, true, true),
+            ("below the minimum rate", SPENT_VALUE - 50, true, false),
+            ("above the maximum rate", SPENT_VALUE - 10_000, true, false),
+            ("above the maximum fee", SPENT_VALUE - 50_001, false, false),
+        ];
+        for (name, value, fee_valid, rate_valid) in cases {
+            let tx = transaction(value);
+            let spent_outputs = [spent(p2wpkh(1))];
+            assert_eq!(passes(&tx, &spent_outputs, None, ValidationRule::Fee), fee_valid, "{} fee", name);
+            assert_eq!(passes(&tx, &spent_outputs, None, ValidationRule::FeeRate), rate_valid, "{} rate", name);
+        }
+
+        let tx = transaction(SPENT_VALUE + 1);
+        assert!(!passes(&tx, &[spent(p2wpkh(1))], None, ValidationRule::Fee), "outputs exceed inputs");
+    }
+
+    #[test]
+    fn locktimes_must_be_final_and_enforceable() {
+        let cases = [
+            ("no locktime", 0, Sequence::MAX, true),
+            ("final at the next block", 101, Sequence::ENABLE_RBF_NO_LOCKTIME, true),
+            ("locked beyond the next block", 102, Sequence::ENABLE_RBF_NO_LOCKTIME, false),
+            ("disabled by final sequences", 50, Sequence::MAX, false),
+        ];
+        for (name, lock_time, sequence, valid) in cases {
+            let mut tx = transaction(99_000);
+            tx.lock_time = LockTime::from_consensus(lock_time);
+            tx.input[0].sequence = sequence;
+            assert_eq!(passes(&tx, &[spent(p2wpkh(1))], Some(100), ValidationRule::Locktime), valid, "{}", name);
+        }
+    }
+
+    #[test]
+    fn relative_locktimes_need_version_2() {
+        let cases = [
+            ("version 2", 2, Sequence::from_height(10), true),
+            ("version 1 without relative locktime", 1, Sequence::ENABLE_RBF_NO_LOCKTIME, true),
+            ("version 1", 1, Sequence::from_height(10), false),
+        ];
+        for (name, version, sequence, valid) in cases {
+            let mut tx = transaction(99_000);
+            tx.version = version;
+            tx.input[0].sequence = sequence;
+            assert_eq!(passes(&tx, &[spent(p2wpkh(1))], None, ValidationRule::Sequence), valid, "{}", name);
+        }
+    }
+
+    #[test]
+    fn weight_is_bounded() {
+        let cases = [("standard", 1_000, true), ("too heavy", 100_000, false)];
+        for (name, data_len, valid) in cases {
+            let mut tx = transaction(99_000);
+            tx.output.push(TxOut { value: 0, script_pubkey: ScriptBuf::from(vec![0x6a; data_len]) });
+            assert_eq!(passes(&tx, &[spent(p2wpkh(1))], None, ValidationRule::Weight), valid, "{}", name);
+        }
+    }
+}
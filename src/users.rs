@@ -0,0 +1,133 @@
+use log::{info, warn};
+use rand::RngCore;
+use ring::pbkdf2;
+use rusqlite::Error as SqliteError;
+use std::num::NonZeroU32;
+
+use crate::database::Database;
+
+// PBKDF2-HMAC-SHA256, stored as "pbkdf2-sha256$<iterations>$<salt>$<hash>"
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ITERATIONS: u32 = 210_000;
+const SALT_BYTES: usize = 16;
+const HASH_BYTES: usize = 32;
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+
+#[derive(Debug)]
+pub enum UserError {
+    PasswordTooShort,
+    InvalidCredentials,
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for UserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserError::PasswordTooShort => {
+                write!(f, "Passwords must be at least {} characters long", MIN_PASSWORD_LENGTH)
+            }
+            UserError::InvalidCredentials => write!(f, "Invalid credentials"),
+            UserError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UserError {}
+
+impl From<SqliteError> for UserError {
+    fn from(error: SqliteError) -> Self {
+        UserError::Database(error)
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; SALT_BYTES];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut hash = [0u8; HASH_BYTES];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(HASH_ITERATIONS), &salt, password.as_bytes(), &mut hash);
+    format!("{}${}${}${}", HASH_SCHEME, HASH_ITERATIONS, hex::encode(salt), hex::encode(hash))
+}
+
+// Constant-time check of a password against a stored hash; malformed hashes
+// never match
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+    let (rounds, salt, hash) = match parts.as_slice() {
+        [HASH_SCHEME, rounds, salt, hash] => (rounds, salt, hash),
+        _ => return false,
+    };
+    let (Some(rounds), Ok(salt), Ok(hash)) = (rounds.parse().ok().and_then(NonZeroU32::new), hex::decode(salt), hex::decode(hash)) else {
+        return false;
+    };
+    pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, rounds, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+fn iterations(rounds: u32) -> NonZeroU32 {
+    NonZeroU32::new(rounds).expect("iteration count is non-zero")
+}
+
+// Create the user, or replace their password
+pub fn set_password(db: &Database, username: &str, password: &str) -> Result<(), UserError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(UserError::PasswordTooShort);
+    }
+    db.save_user_password(username, &hash_password(password))?;
+    Ok(())
+}
+
+// Check a login. Unknown users cost as much as a wrong password, so response
+// times don't reveal which usernames exist.
+pub fn authenticate(db: &Database, username: &str, password: &str) -> Result<(), UserError> {
+    match db.get_password_hash(username)? {
+        Some(stored) if verify_password(password, &stored) => Ok(()),
+        Some(_) => Err(UserError::InvalidCredentials),
+        None => {
+            let mut hash = [0u8; HASH_BYTES];
+            pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(HASH_ITERATIONS), &[0; SALT_BYTES], password.as_bytes(), &mut hash);
+            Err(UserError::InvalidCredentials)
+        }
+    }
+}
+
+// ADMIN_PASSWORD sets the admin's password on first start; later changes go
+// through the API. Without either nobody can log in.
+pub fn bootstrap_admin(db: &Database, admin: &str) {
+    match db.get_password_hash(admin) {
+        Ok(Some(_)) => {}
+        Ok(None) => match std::env::var("ADMIN_PASSWORD") {
+            Ok(password) => match set_password(db, admin, &password) {
+                Ok(()) => info!("Created the {} user from ADMIN_PASSWORD", admin),
+                Err(e) => warn!("Ignoring ADMIN_PASSWORD: {}", e),
+            },
+            Err(_) => warn!("The {} user has no password; set ADMIN_PASSWORD to create it", admin),
+        },
+        Err(e) => warn!("Failed to look up the {} user: {}", admin, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_only_against_their_own_hash() {
+        let hash = hash_password("correct horse battery");
+        assert!(verify_password("correct horse battery", &hash));
+        assert!(!verify_password("correct horse battery!", &hash));
+        // Salted: the same password hashes differently each time
+        assert_ne!(hash, hash_password("correct horse battery"));
+        assert!(!verify_password("correct horse battery", "plaintext"));
+    }
+
+    #[test]
+    fn users_authenticate_with_their_own_password() {
+        let db = Database::new(":memory:").unwrap();
+        set_password(&db, "alice", "alice's long password").unwrap();
+        set_password(&db, "bob", "bob's long password").unwrap();
+
+        assert!(authenticate(&db, "alice", "alice's long password").is_ok());
+        assert!(authenticate(&db, "alice", "bob's long password").is_err());
+        assert!(authenticate(&db, "mallory", "alice's long password").is_err());
+        assert!(matches!(set_password(&db, "carol", "short"), Err(UserError::PasswordTooShort)));
+    }
+}
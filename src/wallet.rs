@@ -0,0 +1,206 @@
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use log::{error, info};
+use rusqlite::Error as SqliteError;
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::models::{Utxo, WalletBalance, DEFAULT_STORE_ID};
+use crate::psbt::{PsbtError, WalletDescriptor};
+use crate::state::AppState;
+
+pub const RECEIVE_CHAIN: u32 = 0;
+pub const CHANGE_CHAIN: u32 = 1;
+// Scanning a chain stops after this many consecutive unused addresses past
+// the last index handed out
+const SCAN_GAP_LIMIT: u32 = 20;
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug)]
+pub enum WalletError {
+    NoWallet,
+    Derivation(PsbtError),
+    Blockchain(String),
+    Database(SqliteError),
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalletError::NoWallet => write!(f, "No wallet configured for this store"),
+            WalletError::Derivation(e) => write!(f, "{}", e),
+            WalletError::Blockchain(msg) => write!(f, "Blockchain backend error: {}", msg),
+            WalletError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+impl From<SqliteError> for WalletError {
+    fn from(error: SqliteError) -> Self {
+        WalletError::Database(error)
+    }
+}
+
+impl From<PsbtError> for WalletError {
+    fn from(error: PsbtError) -> Self {
+        WalletError::Derivation(error)
+    }
+}
+
+// Key change indexes are reserved under (receive indexes use `wallet.id()`)
+pub fn change_account(wallet: &WalletDescriptor) -> String {
+    format!("{}/{}", wallet.id(), CHANGE_CHAIN)
+}
+
+// Indexes to search on each chain when matching scripts to the wallet: every
+// index handed out so far plus the scan gap
+pub fn derivation_bounds(db: &Database, wallet: &WalletDescriptor) -> Result<[u32; 2], SqliteError> {
+    Ok([
+        db.derivation_index(&wallet.id())? + SCAN_GAP_LIMIT,
+        db.derivation_index(&change_account(wallet))? + SCAN_GAP_LIMIT,
+    ])
+}
+
+// Refresh a store's UTXO set from the chain backend. Each chain is scanned
+// up to the last index handed out plus the gap limit.
+pub async fn sync(data: &AppState, store_id: &str, network: Network) -> Result<Vec<Utxo>, WalletError> {
+    let wallet = data.wallet_for(store_id).ok_or(WalletError::NoWallet)?;
+    let mut utxos = Vec::new();
+    for chain in [RECEIVE_CHAIN, CHANGE_CHAIN] {
+        let account = if chain == RECEIVE_CHAIN { wallet.id() } else { change_account(&wallet) };
+        let handed_out = data.db.derivation_index(&account)?;
+        scan_chain(data, store_id, &wallet, chain, handed_out, network, &mut utxos).await?;
+    }
+
+    // Outputs seen by an earlier sync keep when they were first seen
+    let first_seen: HashMap<(String, u32), DateTime<Utc>> = data
+        .db
+        .list_utxos(store_id)?
+        .into_iter()
+        .map(|utxo| ((utxo.txid, utxo.vout), utxo.first_seen))
+        .collect();
+    for utxo in &mut utxos {
+        if let Some(seen) = first_seen.get(&(utxo.txid.clone(), utxo.vout)) {
+            utxo.first_seen = *seen;
+        }
+    }
+
+    data.db.replace_store_utxos(store_id, &utxos)?;
+    info!("Synced wallet of store {}: {} UTXO(s)", store_id, utxos.len());
+    Ok(data.db.list_utxos(store_id)?)
+}
+
+async fn scan_chain(
+    data: &AppState,
+    store_id: &str,
+    wallet: &WalletDescriptor,
+    chain: u32,
+    scan_to: u32,
+    network: Network,
+    utxos: &mut Vec<Utxo>,
+) -> Result<(), WalletError> {
+    let secp = Secp256k1::verification_only();
+    let mut unused = 0;
+    let mut index = 0;
+
+    while index < scan_to || unused < SCAN_GAP_LIMIT {
+        let derived = wallet.derive(&secp, chain, index)?;
+        let address = Address::from_script(&derived.script_pubkey, network)
+            .map_err(|e| PsbtError::InvalidAccountKey(e.to_string()))?;
+        index += 1;
+
+        let tx_count = data
+            .blockchain_client
+            .get_address_tx_count(&address)
+            .await
+            .map_err(WalletError::Blockchain)?;
+        if tx_count == 0 {
+            unused += 1;
+            continue;
+        }
+        unused = 0;
+
+        let outputs = data
+            .blockchain_client
+            .get_address_utxos(&address)
+            .await
+            .map_err(WalletError::Blockchain)?;
+        let address = address.to_string();
+        let invoice_id = match chain {
+            RECEIVE_CHAIN => data.db.get_invoice_id_by_address(&address)?,
+            _ => None,
+        };
+        for output in outputs {
+            utxos.push(Utxo {
+                txid: output.txid,
+                vout: output.vout,
+                store_id: store_id.to_string(),
+                address: address.clone(),
+                value: output.value,
+                script_pubkey: hex::encode(derived.script_pubkey.as_bytes()),
+                derivation_path: derived.path.to_string(),
+                confirmed: output.status.confirmed,
+                block_height: output.status.block_height.filter(|_| output.status.confirmed),
+                invoice_id: invoice_id.clone(),
+                first_seen: Utc::now(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Balance from the last synced UTXO set
+pub fn balance(data: &AppState, store_id: &str) -> Result<WalletBalance, WalletError> {
+    let utxos = data.db.list_utxos(store_id)?;
+    let sum = |confirmed: bool| utxos.iter().filter(|utxo| utxo.confirmed == confirmed).map(|utxo| utxo.value).sum::<u64>();
+    let (confirmed, unconfirmed) = (sum(true), sum(false));
+
+    Ok(WalletBalance {
+        store_id: store_id.to_string(),
+        confirmed,
+        unconfirmed,
+        total: confirmed + unconfirmed,
+        utxo_count: utxos.len(),
+        synced_at: data.db.last_wallet_sync(store_id)?,
+    })
+}
+
+// Periodically sync every store with a wallet, including the default store
+// when WALLET_ACCOUNT_KEY is set
+pub async fn run_sync(data: actix_web::web::Data<AppState>, interval: Duration, network: Network) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let mut store_ids = match data.db.list_store_wallet_ids() {
+            Ok(store_ids) => store_ids,
+            Err(e) => {
+                error!("Failed to list store wallets: {}", e);
+                continue;
+            }
+        };
+        if data.wallet.is_some() && !store_ids.iter().any(|id| id == DEFAULT_STORE_ID) {
+            store_ids.push(DEFAULT_STORE_ID.to_string());
+        }
+
+        for store_id in store_ids {
+            if let Err(e) = sync(&data, &store_id, network).await {
+                error!("Failed to sync wallet of store {}: {}", store_id, e);
+            }
+        }
+    }
+}
+
+// WALLET_SYNC_INTERVAL_SECS (default 60); 0 disables background syncing
+pub fn sync_interval() -> Option<Duration> {
+    let secs = std::env::var("WALLET_SYNC_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_SYNC_INTERVAL_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
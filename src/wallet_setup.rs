@@ -0,0 +1,414 @@
+use bitcoin::base58;
+use bitcoin::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::Network;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::psbt::{AccountKey, MultisigAccount, ScriptType, WalletDescriptor};
+use crate::trezor::{TrezorClient, TrezorError};
+
+// Receive addresses returned for the merchant to compare before saving
+pub const PREVIEW_ADDRESS_COUNT: u32 = 5;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WalletSource {
+    Trezor,
+    Xpub,
+    Descriptor,
+    File,
+}
+
+impl WalletSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WalletSource::Trezor => "trezor",
+            WalletSource::Xpub => "xpub",
+            WalletSource::Descriptor => "descriptor",
+            WalletSource::File => "file",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WalletSetupError {
+    InvalidKey(String),
+    UnsupportedFile(String),
+    WrongNetwork(String),
+    Trezor(TrezorError),
+}
+
+impl std::fmt::Display for WalletSetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalletSetupError::InvalidKey(msg) => write!(f, "Invalid wallet key: {}", msg),
+            WalletSetupError::UnsupportedFile(msg) => write!(f, "Unsupported wallet file: {}", msg),
+            WalletSetupError::WrongNetwork(msg) => write!(f, "Wallet is for the wrong network: {}", msg),
+            WalletSetupError::Trezor(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalletSetupError {}
+
+impl From<TrezorError> for WalletSetupError {
+    fn from(error: TrezorError) -> Self {
+        WalletSetupError::Trezor(error)
+    }
+}
+
+// A parsed wallet, with anything the merchant should double check
+pub struct ImportedWallet {
+    pub wallet: WalletDescriptor,
+    pub label: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ImportedWallet {
+    fn new(wallet: WalletDescriptor) -> Self {
+        Self {
+            wallet,
+            label: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+// SLIP-132 version bytes: (version, mainnet, script type implied by the prefix)
+const SLIP132_VERSIONS: [([u8; 4], bool, Option<ScriptType>); 6] = [
+    ([0x04, 0x88, 0xb2, 0x1e], true, None),                            // xpub
+    ([0x04, 0x9d, 0x7c, 0xb2], true, Some(ScriptType::NestedSegwit)),  // ypub
+    ([0x04, 0xb2, 0x47, 0x46], true, Some(ScriptType::NativeSegwit)),  // zpub
+    ([0x04, 0x35, 0x87, 0xcf], false, None),                           // tpub
+    ([0x04, 0x4a, 0x52, 0x62], false, Some(ScriptType::NestedSegwit)), // upub
+    ([0x04, 0x5f, 0x1c, 0xf6], false, Some(ScriptType::NativeSegwit)), // vpub
+];
+// Zpub/Vpub, used by Electrum for native segwit multisig keys
+const MULTISIG_VERSIONS: [([u8; 4], bool); 2] = [
+    ([0x02, 0xaa, 0x7e, 0xd3], true),  // Zpub
+    ([0x02, 0x57, 0x54, 0x83], false), // Vpub
+];
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+// Decode an xpub/ypub/zpub (or testnet tpub/upub/vpub), returning the script
+// type its prefix implies
+fn decode_extended_key(key: &str) -> Result<(ExtendedPubKey, Option<ScriptType>), WalletSetupError> {
+    let mut data = base58::decode_check(key.trim()).map_err(|e| WalletSetupError::InvalidKey(e.to_string()))?;
+    if data.len() != 78 {
+        return Err(WalletSetupError::InvalidKey("extended key must be 78 bytes".to_string()));
+    }
+
+    let (_, mainnet, script_type) = SLIP132_VERSIONS
+        .iter()
+        .find(|(version, _, _)| data[..4] == version[..])
+        .ok_or_else(|| WalletSetupError::InvalidKey("unknown extended public key version".to_string()))?;
+    data[..4].copy_from_slice(if *mainnet { &XPUB_VERSION } else { &TPUB_VERSION });
+
+    let xpub = ExtendedPubKey::decode(&data).map_err(|e| WalletSetupError::InvalidKey(e.to_string()))?;
+    Ok((xpub, *script_type))
+}
+
+// Standard BIP44/49/84 account path for the script type
+pub fn default_account_path(script_type: ScriptType, network: Network, account: u32) -> DerivationPath {
+    let purpose = match script_type {
+        ScriptType::Legacy => 44,
+        ScriptType::NestedSegwit => 49,
+        ScriptType::NativeSegwit => 84,
+    };
+    let coin_type = if network == Network::Bitcoin { 0 } else { 1 };
+    DerivationPath::from(vec![
+        ChildNumber::Hardened { index: purpose },
+        ChildNumber::Hardened { index: coin_type },
+        ChildNumber::Hardened { index: account },
+    ])
+}
+
+// Decode a Zpub/Vpub cosigner key
+fn decode_multisig_key(key: &str) -> Result<ExtendedPubKey, WalletSetupError> {
+    let mut data = base58::decode_check(key.trim()).map_err(|e| WalletSetupError::InvalidKey(e.to_string()))?;
+    if data.len() != 78 {
+        return Err(WalletSetupError::InvalidKey("extended key must be 78 bytes".to_string()));
+    }
+
+    let (_, mainnet) = MULTISIG_VERSIONS
+        .iter()
+        .find(|(version, _)| data[..4] == version[..])
+        .ok_or_else(|| {
+            WalletSetupError::UnsupportedFile("only native segwit (Zpub/Vpub) multisig keys are supported".to_string())
+        })?;
+    data[..4].copy_from_slice(if *mainnet { &XPUB_VERSION } else { &TPUB_VERSION });
+
+    ExtendedPubKey::decode(&data).map_err(|e| WalletSetupError::InvalidKey(e.to_string()))
+}
+
+fn check_network(wallet: &ImportedWallet, network: Network) -> Result<(), WalletSetupError> {
+    let keys = match &wallet.wallet {
+        WalletDescriptor::Single(account) => std::slice::from_ref(account),
+        WalletDescriptor::Multisig(multisig) => &multisig.keys[..],
+    };
+    for key in keys {
+        let key_is_mainnet = key.xpub.network == Network::Bitcoin;
+        if key_is_mainnet != (network == Network::Bitcoin) {
+            return Err(WalletSetupError::WrongNetwork(format!(
+                "key {} is for {} but the server runs on {}",
+                key.xpub, key.xpub.network, network
+            )));
+        }
+    }
+    Ok(())
+}
+
+// A pasted extended public key. Key origin and script type may be given
+// explicitly; otherwise they are inferred where possible and flagged.
+pub fn from_extended_key(
+    key: &str,
+    script_type: Option<ScriptType>,
+    fingerprint: Option<&str>,
+    derivation_path: Option<&str>,
+    network: Network,
+) -> Result<ImportedWallet, WalletSetupError> {
+    let (xpub, implied_script_type) = decode_extended_key(key)?;
+    let mut warnings = Vec::new();
+
+    let script_type = match (script_type, implied_script_type) {
+        (Some(explicit), _) => explicit,
+        (None, Some(implied)) => implied,
+        (None, None) => {
+            warnings.push("Script type is not encoded in the key; assuming native segwit (wpkh)".to_string());
+            ScriptType::NativeSegwit
+        }
+    };
+
+    let path = match derivation_path {
+        Some(path) => DerivationPath::from_str(&path.replace('h', "'"))
+            .map_err(|e| WalletSetupError::InvalidKey(format!("derivation path: {}", e)))?,
+        None if xpub.depth == 3 => {
+            let account = match xpub.child_number {
+                ChildNumber::Hardened { index } => index,
+                ChildNumber::Normal { index } => index,
+            };
+            let path = default_account_path(script_type, network, account);
+            warnings.push(format!("Derivation path not given; assuming {}", path));
+            path
+        }
+        None => {
+            return Err(WalletSetupError::InvalidKey(
+                "derivation path is required for keys that are not at account depth".to_string(),
+            ))
+        }
+    };
+    if path.len() != xpub.depth as usize {
+        warnings.push(format!("Derivation path {} does not match the key depth {}", path, xpub.depth));
+    }
+
+    let fingerprint = match fingerprint {
+        Some(fingerprint) => Fingerprint::from_str(fingerprint)
+            .map_err(|e| WalletSetupError::InvalidKey(format!("fingerprint: {}", e)))?,
+        None if xpub.depth == 0 => xpub.fingerprint(),
+        None => {
+            warnings.push("Master fingerprint unknown; hardware wallets will not recognise PSBTs for this wallet".to_string());
+            Fingerprint::default()
+        }
+    };
+
+    let wallet = ImportedWallet {
+        wallet: WalletDescriptor::Single(AccountKey {
+            fingerprint,
+            path,
+            xpub,
+            script_type,
+        }),
+        label: None,
+        warnings,
+    };
+    check_network(&wallet, network)?;
+    Ok(wallet)
+}
+
+// An output descriptor such as `wpkh([d34db33f/84'/1'/0']tpub.../0/*)#checksum`
+// or `wsh(sortedmulti(2,[...]tpub.../0/*,[...]tpub.../0/*))#checksum`
+pub fn from_descriptor(descriptor: &str, network: Network) -> Result<ImportedWallet, WalletSetupError> {
+    let descriptor =
+        WalletDescriptor::from_str(descriptor).map_err(|e| WalletSetupError::InvalidKey(e.to_string()))?;
+    let mut wallet = ImportedWallet::new(descriptor);
+    if let WalletDescriptor::Multisig(multisig) = &wallet.wallet {
+        if multisig.keys.iter().any(|key| key.fingerprint == Fingerprint::default()) {
+            wallet
+                .warnings
+                .push("A cosigner's master fingerprint is unknown; its hardware wallet will not recognise PSBTs".to_string());
+        }
+    }
+    check_network(&wallet, network)?;
+    Ok(wallet)
+}
+
+// Wallet export files: Specter/Sparrow JSON (`descriptor`), Electrum wallet
+// files (`keystore`), Coldcard/Sparrow generic JSON (`xfp` with `bip84`...)
+// and plain text descriptor exports
+pub fn from_wallet_file(contents: &str, network: Network) -> Result<ImportedWallet, WalletSetupError> {
+    let json: Value = match serde_json::from_str(contents) {
+        Ok(json) => json,
+        Err(_) => return from_descriptor_text(contents, network),
+    };
+
+    let label = ["label", "name"]
+        .iter()
+        .find_map(|field| json.get(*field).and_then(Value::as_str))
+        .map(str::to_string);
+
+    let mut wallet = if let Some(descriptor) = json.get("descriptor").and_then(Value::as_str) {
+        from_descriptor(descriptor, network)?
+    } else if let Some(keystore) = json.get("keystore") {
+        from_electrum(&json, keystore, network)?
+    } else if let Some(xfp) = json.get("xfp").and_then(Value::as_str) {
+        from_generic_json(&json, xfp, network)?
+    } else {
+        return Err(WalletSetupError::UnsupportedFile(
+            "expected a descriptor, Electrum keystore or xfp field".to_string(),
+        ));
+    };
+
+    wallet.label = wallet.label.or(label);
+    Ok(wallet)
+}
+
+fn from_descriptor_text(contents: &str, network: Network) -> Result<ImportedWallet, WalletSetupError> {
+    let descriptor = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| WalletSetupError::UnsupportedFile("no descriptor found".to_string()))?;
+    from_descriptor(descriptor, network)
+}
+
+fn from_electrum(json: &Value, keystore: &Value, network: Network) -> Result<ImportedWallet, WalletSetupError> {
+    let wallet_type = json.get("wallet_type").and_then(Value::as_str).unwrap_or("standard");
+    if let Some((threshold, count)) = wallet_type.split_once("of") {
+        return from_electrum_multisig(json, threshold, count, network);
+    }
+    if wallet_type != "standard" {
+        return Err(WalletSetupError::UnsupportedFile(format!("Electrum {} wallets are not supported", wallet_type)));
+    }
+
+    let field = |name: &str| keystore.get(name).and_then(Value::as_str);
+    let xpub = field("xpub").ok_or_else(|| WalletSetupError::UnsupportedFile("keystore has no xpub".to_string()))?;
+
+    // Electrum encodes the script type in the key prefix; plain xpubs are p2pkh
+    let (_, implied) = decode_extended_key(xpub)?;
+    let mut wallet = from_extended_key(
+        xpub,
+        Some(implied.unwrap_or(ScriptType::Legacy)),
+        field("root_fingerprint"),
+        field("derivation"),
+        network,
+    )?;
+    wallet.label = field("label").map(str::to_string);
+    Ok(wallet)
+}
+
+// Electrum "2of3"-style wallets keep one keystore per cosigner in x1/, x2/...
+fn from_electrum_multisig(json: &Value, threshold: &str, count: &str, network: Network) -> Result<ImportedWallet, WalletSetupError> {
+    let invalid = || WalletSetupError::UnsupportedFile("invalid Electrum multisig wallet type".to_string());
+    let threshold: usize = threshold.parse().map_err(|_| invalid())?;
+    let count: usize = count.parse().map_err(|_| invalid())?;
+
+    let mut warnings = Vec::new();
+    let mut keys = Vec::with_capacity(count);
+    for index in 1..=count {
+        let keystore = json
+            .get(format!("x{}/", index))
+            .ok_or_else(|| WalletSetupError::UnsupportedFile(format!("keystore x{}/ is missing", index)))?;
+        let field = |name: &str| keystore.get(name).and_then(Value::as_str);
+        let xpub = decode_multisig_key(
+            field("xpub").ok_or_else(|| WalletSetupError::UnsupportedFile(format!("keystore x{}/ has no xpub", index)))?,
+        )?;
+
+        let path = match field("derivation") {
+            Some(path) => DerivationPath::from_str(&path.replace('h', "'"))
+                .map_err(|e| WalletSetupError::InvalidKey(format!("derivation path: {}", e)))?,
+            None => {
+                warnings.push(format!("Cosigner {} has no derivation path; assuming m", index));
+                DerivationPath::master()
+            }
+        };
+        let fingerprint = match field("root_fingerprint") {
+            Some(fingerprint) => Fingerprint::from_str(fingerprint)
+                .map_err(|e| WalletSetupError::InvalidKey(format!("fingerprint: {}", e)))?,
+            // from_descriptor warns about unknown fingerprints
+            None => Fingerprint::default(),
+        };
+        keys.push(AccountKey {
+            fingerprint,
+            path,
+            xpub,
+            script_type: ScriptType::NativeSegwit,
+        });
+    }
+
+    // Round-trip through the descriptor parser for its threshold/key checks
+    let descriptor = MultisigAccount { threshold, keys }.to_string();
+    let mut wallet = from_descriptor(&descriptor, network)?;
+    wallet.warnings.extend(warnings);
+    Ok(wallet)
+}
+
+fn from_generic_json(json: &Value, xfp: &str, network: Network) -> Result<ImportedWallet, WalletSetupError> {
+    let accounts = [
+        ("bip84", ScriptType::NativeSegwit),
+        ("bip49", ScriptType::NestedSegwit),
+        ("bip44", ScriptType::Legacy),
+    ];
+    let (section, script_type) = accounts
+        .iter()
+        .find_map(|(name, script_type)| json.get(*name).map(|section| (section, *script_type)))
+        .ok_or_else(|| WalletSetupError::UnsupportedFile("no bip84, bip49 or bip44 account".to_string()))?;
+
+    let field = |name: &str| section.get(name).and_then(Value::as_str);
+    let xpub = field("xpub")
+        .or_else(|| field("_pub"))
+        .ok_or_else(|| WalletSetupError::UnsupportedFile("account has no xpub".to_string()))?;
+    from_extended_key(xpub, Some(script_type), Some(xfp), field("deriv"), network)
+}
+
+// Read the account key from the connected Trezor
+pub async fn from_trezor(
+    trezor: &TrezorClient,
+    script_type: ScriptType,
+    account: u32,
+    network: Network,
+) -> Result<ImportedWallet, WalletSetupError> {
+    if !trezor.is_connected() {
+        trezor.connect(None).await?;
+    }
+
+    let path = default_account_path(script_type, network, account);
+    let fingerprint = trezor.master_fingerprint().await?;
+    let (xpub, _) = trezor.get_public_key(&path, script_type, false).await?;
+
+    let mut wallet = ImportedWallet::new(WalletDescriptor::Single(AccountKey {
+        fingerprint,
+        path,
+        xpub,
+        script_type,
+    }));
+    wallet.label = trezor.status().features.and_then(|features| features.label);
+    check_network(&wallet, network)?;
+    Ok(wallet)
+}
+
+// First receive addresses of the wallet, for the merchant to compare
+// against their wallet software before saving
+pub fn receive_addresses(wallet: &WalletDescriptor, count: u32, network: Network) -> Result<Vec<String>, WalletSetupError> {
+    let secp = Secp256k1::verification_only();
+    (0..count)
+        .map(|index| {
+            wallet
+                .address(&secp, 0, index, network)
+                .map(|address| address.to_string())
+                .map_err(|e| WalletSetupError::InvalidKey(e.to_string()))
+        })
+        .collect()
+}
@@ -7,7 +7,7 @@ use hmac::{Hmac, Mac};
 use sha2::Sha256;
 use hex::encode;
 
-use crate::models::{Invoice, WebhookConfig, WebhookEvent};
+use crate::models::WebhookConfig;
 
 pub struct WebhookManager {
     client: Client,
@@ -31,25 +31,21 @@ impl WebhookManager {
         encode(result.into_bytes())
     }
 
-    // Send webhook notification about invoice status change
-    pub async fn notify_payment_status(&self, invoice: &Invoice, webhook: &WebhookConfig) -> Result<(), String> {
-        info!("Sending webhook notification for invoice {}", invoice.id);
-        
-        let event = WebhookEvent {
-            event_type: "invoice.updated".to_string(),
-            invoice_id: invoice.id.clone(),
-            timestamp: Utc::now(),
-            data: json!({
-                "status": format!("{:?}", invoice.status),
-                "address": invoice.address,
-                "amount": invoice.amount,
-                "description": invoice.description,
-            }),
-        };
-        
-        let payload = serde_json::to_string(&event)
-            .map_err(|e| format!("JSON serialization error: {}", e))?;
-        
+    // Send a notification that is not about an invoice, e.g. spend proposals
+    pub async fn notify_event(&self, event_type: &str, data: serde_json::Value, webhook: &WebhookConfig) -> Result<(), String> {
+        info!("Sending webhook notification {}", event_type);
+
+        let payload = serde_json::to_string(&json!({
+            "event_type": event_type,
+            "timestamp": Utc::now(),
+            "data": data,
+        }))
+        .map_err(|e| format!("JSON serialization error: {}", e))?;
+
+        self.post(webhook, payload).await
+    }
+
+    async fn post(&self, webhook: &WebhookConfig, payload: String) -> Result<(), String> {
         let signature = self.calculate_signature(&payload, &webhook.secret);
         
         // Send the webhook request
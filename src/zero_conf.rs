@@ -0,0 +1,198 @@
+use std::collections::BTreeSet;
+
+use crate::blockchain::AddressTx;
+use crate::models::ZeroConfPolicy;
+use crate::state::AppState;
+
+// Risk points per factor; a payment's score is their sum, capped at 100
+const RBF_SIGNALED: u32 = 40;
+const FEE_BELOW_ESTIMATES: u32 = 35; // Below the ~6 block estimate
+const FEE_BELOW_NEXT_BLOCKS: u32 = 15; // Below the ~2 block estimate only
+const UNCONFIRMED_ANCESTORS: u32 = 25;
+const OVER_STORE_CAP: u32 = 100;
+// Scaled by the payment's share of the store's cap
+const AMOUNT_WEIGHT: u32 = 10;
+const MAX_RISK_SCORE: u32 = 100;
+// Inputs with a lower sequence opt in to replacement (BIP 125)
+const MAX_NON_RBF_SEQUENCE: u32 = 0xfffffffe;
+
+#[derive(Debug, Clone)]
+pub struct RiskAssessment {
+    pub score: u32,
+    pub factors: Vec<String>,
+}
+
+pub fn validate_policy(policy: &ZeroConfPolicy) -> Result<(), String> {
+    if policy.max_risk_score > MAX_RISK_SCORE {
+        return Err(format!("max_risk_score must be at most {}", MAX_RISK_SCORE));
+    }
+    if policy.enabled && policy.max_amount == 0 {
+        return Err("max_amount must be set to accept unconfirmed payments".to_string());
+    }
+    Ok(())
+}
+
+// Score how likely an unconfirmed payment is to be double-spent or never
+// confirm. `exposure` is the payment's value plus the invoice's other
+// unconfirmed payments already accepted.
+pub async fn assess(
+    data: &AppState,
+    tx: &AddressTx,
+    exposure: u64,
+    policy: Option<&ZeroConfPolicy>,
+) -> Result<RiskAssessment, String> {
+    let estimates = data.fee_estimator.estimates(&data.blockchain_client).await;
+    let mut unconfirmed_parents = false;
+    let parents: BTreeSet<&str> = tx.vin.iter().map(|input| input.txid.as_str()).collect();
+    for parent in parents {
+        if data.blockchain_client.get_tx_status(parent).await?.is_none_or(|status| !status.confirmed) {
+            unconfirmed_parents = true;
+            break;
+        }
+    }
+
+    Ok(score(
+        tx,
+        (estimates.fee_rate_for(6), estimates.fee_rate_for(2)),
+        unconfirmed_parents,
+        exposure,
+        policy,
+    ))
+}
+
+// `fee_rates` are the ~6 and ~2 block estimates in sat/vB
+fn score(
+    tx: &AddressTx,
+    fee_rates: (f64, f64),
+    unconfirmed_parents: bool,
+    exposure: u64,
+    policy: Option<&ZeroConfPolicy>,
+) -> RiskAssessment {
+    let mut score = 0;
+    let mut factors = Vec::new();
+    let mut add = |points: u32, factor: &str| {
+        score += points;
+        factors.push(factor.to_string());
+    };
+
+    if tx.vin.iter().any(|input| input.sequence < MAX_NON_RBF_SEQUENCE) {
+        add(RBF_SIGNALED, "rbf_signaled");
+    }
+
+    let vsize = tx.weight.div_ceil(4).max(1);
+    let fee_rate = tx.fee as f64 / vsize as f64;
+    let (six_blocks, two_blocks) = fee_rates;
+    if fee_rate < six_blocks {
+        add(FEE_BELOW_ESTIMATES, "low_fee_rate");
+    } else if fee_rate < two_blocks {
+        add(FEE_BELOW_NEXT_BLOCKS, "fee_below_next_block");
+    }
+
+    if unconfirmed_parents {
+        add(UNCONFIRMED_ANCESTORS, "unconfirmed_ancestors");
+    }
+
+    if let Some(policy) = policy.filter(|policy| policy.max_amount > 0) {
+        if exposure > policy.max_amount {
+            add(OVER_STORE_CAP, "over_store_cap");
+        } else {
+            let share = (exposure as u128 * AMOUNT_WEIGHT as u128 / policy.max_amount as u128) as u32;
+            if share > 0 {
+                add(share, "large_payment");
+            }
+        }
+    }
+
+    RiskAssessment {
+        score: score.min(MAX_RISK_SCORE),
+        factors,
+    }
+}
+
+// Whether the store's policy treats the payment as final while unconfirmed.
+// The cap covers `exposure`, everything the invoice would then have
+// accepted unconfirmed, so splitting a payment doesn't get around it.
+pub fn accepts(policy: &ZeroConfPolicy, exposure: u64, assessment: &RiskAssessment) -> bool {
+    policy.enabled && exposure <= policy.max_amount && assessment.score <= policy.max_risk_score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{AddressTxInput, TxStatus};
+
+    // Six and two block estimates, in sat/vB
+    const FEE_RATES: (f64, f64) = (5.0, 10.0);
+
+    fn payment_tx(sequence: u32, fee_rate: u64) -> AddressTx {
+        AddressTx {
+            txid: "aa".repeat(32),
+            vin: vec![AddressTxInput { txid: "bb".repeat(32), vout: 0, sequence }],
+            vout: Vec::new(),
+            fee: fee_rate * 150,
+            weight: 600, // 150 vB
+            status: TxStatus { confirmed: false, block_height: None, block_hash: None },
+        }
+    }
+
+    fn policy(max_amount: u64, max_risk_score: u32) -> ZeroConfPolicy {
+        ZeroConfPolicy { enabled: true, max_amount, max_risk_score }
+    }
+
+    #[test]
+    fn well_paid_final_payment_scores_zero() {
+        let assessment = score(&payment_tx(0xffffffff, 20), FEE_RATES, false, 1_000, None);
+        assert_eq!(assessment.score, 0);
+        assert!(assessment.factors.is_empty());
+    }
+
+    #[test]
+    fn risk_factors_add_up() {
+        let assessment = score(&payment_tx(0xfffffffd, 2), FEE_RATES, true, 1_000, None);
+        assert_eq!(assessment.score, RBF_SIGNALED + FEE_BELOW_ESTIMATES + UNCONFIRMED_ANCESTORS);
+        assert_eq!(assessment.factors, ["rbf_signaled", "low_fee_rate", "unconfirmed_ancestors"]);
+
+        let assessment = score(&payment_tx(0xfffffffe, 7), FEE_RATES, false, 1_000, None);
+        assert_eq!(assessment.score, FEE_BELOW_NEXT_BLOCKS);
+        assert_eq!(assessment.factors, ["fee_below_next_block"]);
+    }
+
+    #[test]
+    fn amount_scales_with_share_of_cap() {
+        let policy = policy(100_000, 50);
+        let assessment = score(&payment_tx(0xffffffff, 20), FEE_RATES, false, 50_000, Some(&policy));
+        assert_eq!(assessment.score, AMOUNT_WEIGHT / 2);
+        assert_eq!(assessment.factors, ["large_payment"]);
+
+        let assessment = score(&payment_tx(0xffffffff, 20), FEE_RATES, false, 100_001, Some(&policy));
+        assert_eq!(assessment.factors, ["over_store_cap"]);
+    }
+
+    #[test]
+    fn score_is_capped() {
+        let policy = policy(1_000, 50);
+        let assessment = score(&payment_tx(0, 1), FEE_RATES, true, 5_000, Some(&policy));
+        assert_eq!(assessment.score, MAX_RISK_SCORE);
+    }
+
+    #[test]
+    fn cap_applies_to_everything_accepted_unconfirmed() {
+        let policy = policy(100_000, 50);
+        let low_risk = RiskAssessment { score: 10, factors: Vec::new() };
+        assert!(accepts(&policy, 60_000, &low_risk));
+        // A second 60k payment to the same invoice takes it over the cap
+        assert!(!accepts(&policy, 120_000, &low_risk));
+
+        let high_risk = RiskAssessment { score: 51, factors: Vec::new() };
+        assert!(!accepts(&policy, 1_000, &high_risk));
+        assert!(!accepts(&ZeroConfPolicy { enabled: false, ..policy }, 1_000, &low_risk));
+    }
+
+    #[test]
+    fn policy_validation() {
+        assert!(validate_policy(&policy(100_000, MAX_RISK_SCORE)).is_ok());
+        assert!(validate_policy(&policy(100_000, MAX_RISK_SCORE + 1)).is_err());
+        assert!(validate_policy(&policy(0, 50)).is_err());
+        assert!(validate_policy(&ZeroConfPolicy::default()).is_ok());
+    }
+}
@@ -4,9 +4,6 @@ mod integration_tests {
     use std::process::{Command, Child};
     use std::thread::sleep;
     use std::time::Duration;
-    use std::io::{BufRead, BufReader};
-    use std::fs::File;
-    use std::path::Path;
 
     #[test]
     fn test_server_client_interaction() {